@@ -0,0 +1,207 @@
+//! Address Translation Operations (ATOS), asking the SMMU itself for a translation.
+
+use memory_addr::{PhysAddr, PAGE_SIZE_4K};
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::backend::RegisterBackend;
+use crate::hal::PagingHandler;
+use crate::stream_id::StreamId;
+use crate::walk::Access;
+use crate::{SMMUv3, ARM_SMMU_SYNC_TIMEOUT, GATOS_ADDR, GATOS_CTRL, GATOS_PAR, GATOS_SID, IDR0};
+
+/// Stages of translation looked up by an ATOS request, SMMU_GATOS_ADDR.TYPE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtosStages {
+    /// Stage 1 only, the result is an IPA when stage 2 is enabled.
+    Stage1,
+    /// Stage 2 only, the input address is an IPA.
+    Stage2,
+    /// Both stages, as a transaction of the device would be translated.
+    Stage12,
+}
+
+/// Kind of lookup requested by [`SMMUv3::hw_translate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtosKind {
+    /// Stages looked up, SMMU_GATOS_ADDR.TYPE.
+    pub stages: AtosStages,
+    /// Access whose permissions are checked.
+    pub access: Access,
+    /// Privileged access, SMMU_GATOS_ADDR.PnU.
+    pub privileged: bool,
+}
+
+impl AtosKind {
+    /// Unprivileged `access` translated by both stages.
+    pub const fn new(access: Access) -> Self {
+        Self {
+            stages: AtosStages::Stage12,
+            access,
+            privileged: false,
+        }
+    }
+}
+
+/// Successful translation read from SMMU_GATOS_PAR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtosTranslation {
+    /// Output address of the requested input address.
+    pub pa: PhysAddr,
+    /// Size of the naturally aligned region sharing the translation.
+    pub size: usize,
+    /// Memory attributes, in MAIR_ELx format.
+    pub attr: u8,
+    /// Shareability, SH\[1:0\] encoding.
+    pub sh: u8,
+}
+
+/// Stage responsible for an ATOS fault, SMMU_GATOS_PAR.REASON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtosFaultReason {
+    /// Stage 1, or a fault not attributed to a stage such as C_BAD_STE.
+    Stage1,
+    /// Stage 2 translation of a CD address.
+    Stage2CdFetch,
+    /// Stage 2 translation of a stage 1 table address.
+    Stage2Walk,
+    /// Stage 2 translation of the stage 1 output.
+    Stage2,
+}
+
+/// Reasons an ATOS request does not return a translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtosError {
+    /// SMMU_IDR0.ATOS is clear.
+    Unsupported,
+    /// The SMMU did not clear SMMU_GATOS_CTRL.RUN in time.
+    Timeout,
+    /// The lookup faulted.
+    Fault {
+        /// Event number of the fault from SMMU_GATOS_PAR.FAULTCODE, see
+        /// [`TranslationFault::event_id`](crate::TranslationFault::event_id).
+        code: u8,
+        /// Stage the fault is attributed to, SMMU_GATOS_PAR.REASON.
+        reason: AtosFaultReason,
+    },
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Whether the SMMU implements the ATOS interface used by [`SMMUv3::hw_translate`].
+    pub fn atos_supported(&self) -> bool {
+        self.regs().IDR0.is_set(IDR0::ATOS)
+    }
+
+    /// Ask the SMMU to translate `addr` for stream `sid` through the global ATOS registers.
+    ///
+    /// `ssid` is the SubstreamID of the request, if it carries one. The lookup uses the
+    /// configuration and TLB entries the SMMU holds, comparing it with [`SMMUv3::translate`]
+    /// tells whether they match the tables in memory. The global ATOS registers are shared by
+    /// all CPUs, hence the exclusive borrow.
+    pub fn hw_translate(
+        &mut self,
+        sid: StreamId,
+        ssid: Option<u32>,
+        addr: u64,
+        kind: AtosKind,
+    ) -> Result<AtosTranslation, AtosError> {
+        if !self.atos_supported() {
+            return Err(AtosError::Unsupported);
+        }
+        // The request registers must not be written while a previous lookup runs.
+        self.wait_atos()?;
+
+        let ssid_value = match ssid {
+            Some(ssid) => GATOS_SID::SSID_VALID::SET + GATOS_SID::SUBSTREAMID.val(ssid as u64),
+            None => GATOS_SID::SSID_VALID::CLEAR,
+        };
+        self.regs()
+            .GATOS_SID
+            .write(ssid_value + GATOS_SID::STREAMID.val(sid.as_u32() as u64));
+
+        let stages = match kind.stages {
+            AtosStages::Stage1 => GATOS_ADDR::TYPE::Stage1,
+            AtosStages::Stage2 => GATOS_ADDR::TYPE::Stage2,
+            AtosStages::Stage12 => GATOS_ADDR::TYPE::Stage12,
+        };
+        self.regs().GATOS_ADDR.write(
+            GATOS_ADDR::ADDR.val(addr >> 12)
+                + stages
+                + GATOS_ADDR::PnU.val(kind.privileged as u64)
+                + GATOS_ADDR::RnW.val((kind.access != Access::Write) as u64)
+                + GATOS_ADDR::InD.val((kind.access == Access::Exec) as u64)
+                + GATOS_ADDR::HTTUI::SET,
+        );
+        self.regs().GATOS_CTRL.write(GATOS_CTRL::RUN::SET);
+        self.wait_atos()?;
+
+        decode_par(self.regs().GATOS_PAR.get(), addr)
+    }
+
+    fn wait_atos(&self) -> Result<(), AtosError> {
+        for _timeout in 0..ARM_SMMU_SYNC_TIMEOUT {
+            if !self.regs().GATOS_CTRL.is_set(GATOS_CTRL::RUN) {
+                return Ok(());
+            }
+        }
+        error!("ATOS request timeout");
+        Err(AtosError::Timeout)
+    }
+}
+
+/// Decode the SMMU_GATOS_PAR result of a lookup of `addr`.
+fn decode_par(par: u64, addr: u64) -> Result<AtosTranslation, AtosError> {
+    if GATOS_PAR::FAULT.read(par) != 0 {
+        let reason = match GATOS_PAR::REASON.read(par) {
+            0b00 => AtosFaultReason::Stage1,
+            0b01 => AtosFaultReason::Stage2CdFetch,
+            0b10 => AtosFaultReason::Stage2Walk,
+            _ => AtosFaultReason::Stage2,
+        };
+        return Err(AtosError::Fault {
+            code: GATOS_PAR::FAULTCODE.read(par) as u8,
+            reason,
+        });
+    }
+
+    let out = GATOS_PAR::ADDR.read(par) << 12;
+    let size = if GATOS_PAR::SIZE.read(par) != 0 {
+        // ADDR bits below the lowest 0 bit are all 1 and encode the size.
+        PAGE_SIZE_4K << ((out >> 12).trailing_ones() + 1)
+    } else {
+        PAGE_SIZE_4K
+    };
+    let offset_mask = size as u64 - 1;
+    Ok(AtosTranslation {
+        pa: PhysAddr::from_usize(((out & !offset_mask) | (addr & offset_mask)) as usize),
+        size,
+        attr: GATOS_PAR::ATTR.read(par) as u8,
+        sh: GATOS_PAR::SH.read(par) as u8,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_par() {
+        // 2MB block at 0x8000_0000, Normal WB memory, Inner Shareable.
+        let par = 0xff << 56 | (0x8000_0000 | 0xf_f000) | 1 << 11 | 0b11 << 8;
+        assert_eq!(
+            decode_par(par, 0x21_2345),
+            Ok(AtosTranslation {
+                pa: PhysAddr::from_usize(0x8001_2345),
+                size: 0x20_0000,
+                attr: 0xff,
+                sh: 0b11,
+            })
+        );
+        assert_eq!(
+            decode_par(0x2000 | 0x10 << 4 | 1, 0x1000),
+            Err(AtosError::Fault {
+                code: 0x10,
+                reason: AtosFaultReason::Stage2Walk,
+            })
+        );
+    }
+}
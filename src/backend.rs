@@ -0,0 +1,488 @@
+//! Register access backends.
+//!
+//! The driver reaches the SMMU registers through a [`RegisterBackend`], plain MMIO with [`Mmio`].
+//! Other backends can trace or record accesses, mock the SMMU in tests, or trap to a hypervisor
+//! emulating it.
+//!
+//! [`SMMUv3::regs`](crate::SMMUv3::regs) returns a [`SmmuRegs`] view with one proxy per register
+//! of [`SMMUv3Regs`]. The proxies implement the `tock_registers` interfaces, so registers are
+//! accessed with the usual `read`, `write`, `modify` and `is_set`, forwarded to the backend with
+//! the register offset.
+
+use core::marker::PhantomData;
+use core::mem::offset_of;
+use core::ptr::NonNull;
+
+use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::{RegisterLongName, UIntLike};
+
+use crate::regs::*;
+use crate::SMMUv3Regs;
+
+/// Accesses to the SMMU register frame, `offset` is relative to the base of SMMU page 0.
+pub trait RegisterBackend {
+    /// Read the 32-bit register at `offset`.
+    fn read32(&self, offset: usize) -> u32;
+    /// Write the 32-bit register at `offset`.
+    fn write32(&self, offset: usize, value: u32);
+    /// Read the 64-bit register at `offset`.
+    fn read64(&self, offset: usize) -> u64;
+    /// Write the 64-bit register at `offset`.
+    fn write64(&self, offset: usize, value: u64);
+}
+
+/// Memory mapped registers, the default backend.
+pub struct Mmio {
+    base: NonNull<SMMUv3Regs>,
+}
+
+// SAFETY: registers are accessed with single volatile accesses, the SMMU serializes them.
+unsafe impl Send for Mmio {}
+unsafe impl Sync for Mmio {}
+
+impl Mmio {
+    /// Registers mapped at `base`, which must be the virtual address of SMMU page 0.
+    pub const fn new(base: *mut u8) -> Self {
+        Self {
+            base: NonNull::new(base).unwrap().cast(),
+        }
+    }
+
+    /// The register block, accessed directly.
+    pub const fn regs(&self) -> &SMMUv3Regs {
+        unsafe { self.base.as_ref() }
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        unsafe { self.base.as_ptr().cast::<u8>().add(offset).cast() }
+    }
+}
+
+impl RegisterBackend for Mmio {
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { self.ptr::<u32>(offset).read_volatile() }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { self.ptr::<u32>(offset).write_volatile(value) }
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        unsafe { self.ptr::<u64>(offset).read_volatile() }
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        unsafe { self.ptr::<u64>(offset).write_volatile(value) }
+    }
+}
+
+/// Register widths supported by [`RegisterBackend`].
+pub trait RegisterWidth: UIntLike {
+    /// Read the register at `offset` of `backend`.
+    fn read<B: RegisterBackend + ?Sized>(backend: &B, offset: usize) -> Self;
+    /// Write `value` to the register at `offset` of `backend`.
+    fn write<B: RegisterBackend + ?Sized>(backend: &B, offset: usize, value: Self);
+}
+
+impl RegisterWidth for u32 {
+    fn read<B: RegisterBackend + ?Sized>(backend: &B, offset: usize) -> Self {
+        backend.read32(offset)
+    }
+
+    fn write<B: RegisterBackend + ?Sized>(backend: &B, offset: usize, value: Self) {
+        backend.write32(offset, value)
+    }
+}
+
+impl RegisterWidth for u64 {
+    fn read<B: RegisterBackend + ?Sized>(backend: &B, offset: usize) -> Self {
+        backend.read64(offset)
+    }
+
+    fn write<B: RegisterBackend + ?Sized>(backend: &B, offset: usize, value: Self) {
+        backend.write64(offset, value)
+    }
+}
+
+/// Read-only register at `offset` of a backend.
+pub struct ReadOnlyProxy<'a, B: ?Sized, T: RegisterWidth, R: RegisterLongName = ()> {
+    backend: &'a B,
+    offset: usize,
+    _reg: PhantomData<(T, R)>,
+}
+
+/// Read-write register at `offset` of a backend.
+pub struct ReadWriteProxy<'a, B: ?Sized, T: RegisterWidth, R: RegisterLongName = ()> {
+    backend: &'a B,
+    offset: usize,
+    _reg: PhantomData<(T, R)>,
+}
+
+impl<'a, B: RegisterBackend + ?Sized, T: RegisterWidth, R: RegisterLongName>
+    ReadOnlyProxy<'a, B, T, R>
+{
+    const fn new(backend: &'a B, offset: usize) -> Self {
+        Self {
+            backend,
+            offset,
+            _reg: PhantomData,
+        }
+    }
+}
+
+impl<'a, B: RegisterBackend + ?Sized, T: RegisterWidth, R: RegisterLongName>
+    ReadWriteProxy<'a, B, T, R>
+{
+    const fn new(backend: &'a B, offset: usize) -> Self {
+        Self {
+            backend,
+            offset,
+            _reg: PhantomData,
+        }
+    }
+}
+
+impl<B: RegisterBackend + ?Sized, T: RegisterWidth, R: RegisterLongName> Readable
+    for ReadOnlyProxy<'_, B, T, R>
+{
+    type T = T;
+    type R = R;
+
+    fn get(&self) -> T {
+        T::read(self.backend, self.offset)
+    }
+}
+
+impl<B: RegisterBackend + ?Sized, T: RegisterWidth, R: RegisterLongName> Readable
+    for ReadWriteProxy<'_, B, T, R>
+{
+    type T = T;
+    type R = R;
+
+    fn get(&self) -> T {
+        T::read(self.backend, self.offset)
+    }
+}
+
+impl<B: RegisterBackend + ?Sized, T: RegisterWidth, R: RegisterLongName> Writeable
+    for ReadWriteProxy<'_, B, T, R>
+{
+    type T = T;
+    type R = R;
+
+    fn set(&self, value: T) {
+        T::write(self.backend, self.offset, value)
+    }
+}
+
+macro_rules! register_view {
+    (@offset $name:ident) => {
+        offset_of!(SMMUv3Regs, $name)
+    };
+    (@offset $name:ident $secure:ident) => {
+        offset_of!(SMMUv3Regs, $secure)
+    };
+    ($($name:ident: $proxy:ident<$t:ty $(, $r:ty)?> $(=> $secure:ident)?),* $(,)?) => {
+        /// View of the [`SMMUv3Regs`] registers through a [`RegisterBackend`].
+        #[allow(non_snake_case)]
+        pub struct SmmuRegs<'a, B: RegisterBackend + ?Sized> {
+            $(
+                #[doc = concat!("SMMU_", stringify!($name), ".")]
+                pub $name: $proxy<'a, B, $t $(, $r)?>,
+            )*
+        }
+
+        impl<'a, B: RegisterBackend + ?Sized> SmmuRegs<'a, B> {
+            /// Registers of `backend`, at their [`SMMUv3Regs`] offsets.
+            pub const fn new(backend: &'a B) -> Self {
+                Self {
+                    $($name: $proxy::new(backend, offset_of!(SMMUv3Regs, $name)),)*
+                }
+            }
+
+            /// Registers of `backend`, the Non-secure registers duplicated by the Secure
+            /// programming interface being replaced with their Secure counterpart.
+            pub const fn secure(backend: &'a B) -> Self {
+                Self {
+                    $($name: $proxy::new(backend, register_view!(@offset $name $($secure)?)),)*
+                }
+            }
+        }
+    };
+}
+
+register_view! {
+    IDR0: ReadOnlyProxy<u32, IDR0::Register>,
+    IDR1: ReadOnlyProxy<u32, IDR1::Register>,
+    IDR2: ReadOnlyProxy<u32>,
+    IDR3: ReadOnlyProxy<u32, IDR3::Register>,
+    IDR4: ReadOnlyProxy<u32>,
+    IDR5: ReadOnlyProxy<u32>,
+    IIDR: ReadOnlyProxy<u32>,
+    AIDR: ReadOnlyProxy<u32, AIDR::Register>,
+    CR0: ReadWriteProxy<u32, CR0::Register> => S_CR0,
+    CR0ACK: ReadOnlyProxy<u32, CR0ACK::Register> => S_CR0ACK,
+    CR1: ReadWriteProxy<u32, CR1::Register> => S_CR1,
+    CR2: ReadWriteProxy<u32, CR2::Register> => S_CR2,
+    GBPA: ReadWriteProxy<u32, GBPA::Register> => S_GBPA,
+    IRQ_CTRL: ReadWriteProxy<u32, IRQ_CTRL::Register> => S_IRQ_CTRL,
+    IRQ_CTRLACK: ReadOnlyProxy<u32, IRQ_CTRL::Register> => S_IRQ_CTRLACK,
+    GERROR: ReadOnlyProxy<u32, GERROR::Register> => S_GERROR,
+    GERRORN: ReadWriteProxy<u32, GERROR::Register> => S_GERRORN,
+    GERROR_IRQ_CFG0: ReadWriteProxy<u64, IRQ_CFG0::Register> => S_GERROR_IRQ_CFG0,
+    GERROR_IRQ_CFG1: ReadWriteProxy<u32, IRQ_CFG1::Register> => S_GERROR_IRQ_CFG1,
+    GERROR_IRQ_CFG2: ReadWriteProxy<u32, IRQ_CFG2::Register> => S_GERROR_IRQ_CFG2,
+    STRTAB_BASE: ReadWriteProxy<u64, STRTAB_BASE::Register> => S_STRTAB_BASE,
+    STRTAB_BASE_CFG: ReadWriteProxy<u32, STRTAB_BASE_CFG::Register> => S_STRTAB_BASE_CFG,
+    CMDQ_BASE: ReadWriteProxy<u64, CMDQ_BASE::Register> => S_CMDQ_BASE,
+    CMDQ_PROD: ReadWriteProxy<u32, CMDQ_PROD::Register> => S_CMDQ_PROD,
+    CMDQ_CONS: ReadWriteProxy<u32, CMDQ_CONS::Register> => S_CMDQ_CONS,
+    EVENTQ_BASE: ReadWriteProxy<u64, EVENTQ_BASE::Register> => S_EVENTQ_BASE,
+    EVENTQ_IRQ_CFG0: ReadWriteProxy<u64, IRQ_CFG0::Register> => S_EVENTQ_IRQ_CFG0,
+    EVENTQ_IRQ_CFG1: ReadWriteProxy<u32, IRQ_CFG1::Register> => S_EVENTQ_IRQ_CFG1,
+    EVENTQ_IRQ_CFG2: ReadWriteProxy<u32, IRQ_CFG2::Register> => S_EVENTQ_IRQ_CFG2,
+    PRIQ_BASE: ReadWriteProxy<u64, PRIQ_BASE::Register>,
+    PRIQ_IRQ_CFG0: ReadWriteProxy<u64, IRQ_CFG0::Register>,
+    PRIQ_IRQ_CFG1: ReadWriteProxy<u32, IRQ_CFG1::Register>,
+    PRIQ_IRQ_CFG2: ReadWriteProxy<u32, IRQ_CFG2::Register>,
+    GATOS_CTRL: ReadWriteProxy<u32, GATOS_CTRL::Register>,
+    GATOS_SID: ReadWriteProxy<u64, GATOS_SID::Register>,
+    GATOS_ADDR: ReadWriteProxy<u64, GATOS_ADDR::Register>,
+    GATOS_PAR: ReadWriteProxy<u64, GATOS_PAR::Register>,
+    IDR6: ReadOnlyProxy<u32, IDR6::Register>,
+    DPT_BASE: ReadWriteProxy<u64, DPT_BASE::Register>,
+    DPT_BASE_CFG: ReadWriteProxy<u32, DPT_BASE_CFG::Register>,
+    S_IDR0: ReadOnlyProxy<u32>,
+    S_IDR1: ReadOnlyProxy<u32, S_IDR1::Register>,
+    S_IDR2: ReadOnlyProxy<u32>,
+    S_IDR3: ReadOnlyProxy<u32>,
+    S_IDR4: ReadOnlyProxy<u32>,
+    S_INIT: ReadWriteProxy<u32, S_INIT::Register>,
+    EVENTQ_PROD: ReadWriteProxy<u32, EVENTQ_PROD::Register> => S_EVENTQ_PROD,
+    EVENTQ_CONS: ReadWriteProxy<u32, EVENTQ_CONS::Register> => S_EVENTQ_CONS,
+    PRIQ_PROD: ReadWriteProxy<u32, PRIQ_PROD::Register>,
+    PRIQ_CONS: ReadWriteProxy<u32, PRIQ_CONS::Register>,
+}
+
+/// Offset of the SMMU_CMDQ_CONTROL_PAGE_* registers of control page 0, in SMMU page 0.
+const CMDQ_CONTROL_PAGE_OFFSET: usize = 0x4000;
+/// Distance between the SMMU_CMDQ_CONTROL_PAGE_* registers of successive control pages.
+const CMDQ_CONTROL_PAGE_STRIDE: usize = 32;
+
+/// View of the SMMU_CMDQ_CONTROL_PAGE_* registers of a Command queue control page.
+#[allow(non_snake_case)]
+pub struct CmdqControlPageRegs<'a, B: RegisterBackend + ?Sized> {
+    /// SMMU_CMDQ_CONTROL_PAGE_BASE, the address of the control page.
+    pub BASE: ReadOnlyProxy<'a, B, u64, CMDQ_CONTROL_PAGE_BASE::Register>,
+    /// SMMU_CMDQ_CONTROL_PAGE_CFG, enabling the queues of the page.
+    pub CFG: ReadWriteProxy<'a, B, u32, CMDQ_CONTROL_PAGE_CFG::Register>,
+    /// SMMU_CMDQ_CONTROL_PAGE_STATUS, acknowledging CFG.EN.
+    pub STATUS: ReadOnlyProxy<'a, B, u32, CMDQ_CONTROL_PAGE_STATUS::Register>,
+}
+
+impl<'a, B: RegisterBackend + ?Sized> CmdqControlPageRegs<'a, B> {
+    /// Registers of control page `n` of `backend`.
+    pub const fn new(backend: &'a B, n: usize) -> Self {
+        let offset = CMDQ_CONTROL_PAGE_OFFSET + n * CMDQ_CONTROL_PAGE_STRIDE;
+        Self {
+            BASE: ReadOnlyProxy::new(backend, offset),
+            CFG: ReadWriteProxy::new(backend, offset + 0x8),
+            STATUS: ReadOnlyProxy::new(backend, offset + 0xc),
+        }
+    }
+}
+
+/// View of the registers of an Enhanced Command queue.
+#[allow(non_snake_case)]
+pub struct EcmdqRegs<'a, B: RegisterBackend + ?Sized> {
+    /// SMMU_ECMDQ_BASE, with the SMMU_CMDQ_BASE layout.
+    pub BASE: ReadWriteProxy<'a, B, u64, CMDQ_BASE::Register>,
+    /// SMMU_ECMDQ_PROD.
+    pub PROD: ReadWriteProxy<'a, B, u32, ECMDQ_PROD::Register>,
+    /// SMMU_ECMDQ_CONS.
+    pub CONS: ReadWriteProxy<'a, B, u32, ECMDQ_CONS::Register>,
+}
+
+impl<'a, B: RegisterBackend + ?Sized> EcmdqRegs<'a, B> {
+    /// Registers of the queue at `offset` of `backend`, in its control page.
+    pub const fn new(backend: &'a B, offset: usize) -> Self {
+        Self {
+            BASE: ReadWriteProxy::new(backend, offset),
+            PROD: ReadWriteProxy::new(backend, offset + 0x8),
+            CONS: ReadWriteProxy::new(backend, offset + 0xc),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    use memory_addr::PhysAddr;
+
+    use super::*;
+    use crate::test_utils::HostPagingHandler;
+    use crate::{HttuMode, SMMUv3, StreamId, VmidError};
+
+    /// Registers kept in a map, with writes recorded and CR0 acknowledged right away.
+    #[derive(Default)]
+    struct MockBackend {
+        regs: RefCell<BTreeMap<usize, u64>>,
+        writes: RefCell<Vec<(usize, u64)>>,
+    }
+
+    impl MockBackend {
+        fn read(&self, offset: usize) -> u64 {
+            self.regs.borrow().get(&offset).copied().unwrap_or(0)
+        }
+
+        fn write(&self, offset: usize, value: u64) {
+            self.writes.borrow_mut().push((offset, value));
+            self.regs.borrow_mut().insert(offset, value);
+            if offset == offset_of!(SMMUv3Regs, CR0) {
+                self.regs
+                    .borrow_mut()
+                    .insert(offset_of!(SMMUv3Regs, CR0ACK), value);
+            }
+            if offset == offset_of!(SMMUv3Regs, S_CR0) {
+                self.regs
+                    .borrow_mut()
+                    .insert(offset_of!(SMMUv3Regs, S_CR0ACK), value);
+            }
+            if offset == offset_of!(SMMUv3Regs, S_INIT) {
+                // The invalidation completes right away.
+                self.regs.borrow_mut().insert(offset, 0);
+            }
+        }
+    }
+
+    impl RegisterBackend for MockBackend {
+        fn read32(&self, offset: usize) -> u32 {
+            self.read(offset) as u32
+        }
+
+        fn write32(&self, offset: usize, value: u32) {
+            self.write(offset, value as u64)
+        }
+
+        fn read64(&self, offset: usize) -> u64 {
+            self.read(offset)
+        }
+
+        fn write64(&self, offset: usize, value: u64) {
+            self.write(offset, value)
+        }
+    }
+
+    #[test]
+    fn test_mock_backend() {
+        let mock = MockBackend::default();
+        mock.regs.borrow_mut().insert(
+            offset_of!(SMMUv3Regs, IDR0),
+            IDR0::ST_LEVEL::TwoLevelStreamTableInAdditionToLinearStreamTable.value as u64,
+        );
+        let mut smmu = SMMUv3::<HostPagingHandler, _>::with_backend(mock);
+        smmu.init();
+
+        let writes = smmu.backend().writes.borrow();
+        let offsets: Vec<usize> = writes.iter().map(|&(offset, _)| offset).collect();
+        let cr0 = offsets
+            .iter()
+            .position(|&o| o == offset_of!(SMMUv3Regs, CR0))
+            .unwrap();
+        // The queues and the stream table are set up before the SMMU is enabled.
+        for reg in [
+            offset_of!(SMMUv3Regs, CMDQ_BASE),
+            offset_of!(SMMUv3Regs, EVENTQ_BASE),
+            offset_of!(SMMUv3Regs, STRTAB_BASE),
+        ] {
+            assert!(offsets.iter().position(|&o| o == reg).unwrap() < cr0);
+        }
+        assert!(smmu.regs().CR0ACK.is_set(CR0ACK::SMMUEN));
+    }
+
+    #[test]
+    fn test_httu_clamp() {
+        let mut smmu = SMMUv3::<HostPagingHandler, _>::with_backend(MockBackend::default());
+        for (idr0_httu, supported) in [
+            (IDR0::HTTU::NoFlags, HttuMode::Disabled),
+            (IDR0::HTTU::AccessFlag, HttuMode::AccessFlag),
+            (
+                IDR0::HTTU::AccessFlagDirtyState,
+                HttuMode::AccessFlagDirtyState,
+            ),
+            (
+                IDR0::HTTU::AccessFlagDirtyStateAccessFlagTableDescriptors,
+                HttuMode::AccessFlagDirtyState,
+            ),
+        ] {
+            smmu.backend()
+                .regs
+                .borrow_mut()
+                .insert(offset_of!(SMMUv3Regs, IDR0), idr0_httu.value as u64);
+            assert_eq!(smmu.httu_support(), supported);
+            assert_eq!(smmu.set_httu(HttuMode::AccessFlagDirtyState), supported);
+            assert_eq!(
+                smmu.set_httu(HttuMode::AccessFlag),
+                supported.min(HttuMode::AccessFlag)
+            );
+            assert_eq!(smmu.set_httu(HttuMode::Disabled), HttuMode::Disabled);
+        }
+    }
+
+    #[test]
+    fn test_secure_init() {
+        let mock = MockBackend::default();
+        mock.regs.borrow_mut().insert(
+            offset_of!(SMMUv3Regs, IDR0),
+            IDR0::ST_LEVEL::TwoLevelStreamTableInAdditionToLinearStreamTable.value as u64,
+        );
+        let mut smmu = SMMUv3::<HostPagingHandler, _>::with_backend(mock);
+        assert!(!smmu.set_secure());
+
+        smmu.backend().regs.borrow_mut().insert(
+            offset_of!(SMMUv3Regs, S_IDR1),
+            (S_IDR1::SECURE_IMPL::SET + S_IDR1::S_SIDSIZE.val(8)).value as u64,
+        );
+        assert!(smmu.set_secure());
+        smmu.init();
+        assert_eq!(smmu.sid_bits(), 8);
+
+        // Without SMMU_S_IDR1.SEL2 the Secure streams have no stage 2, and DPTs only apply to
+        // Non-secure transactions.
+        let s2pt = PhysAddr::from_usize(0x8000_0000);
+        assert_eq!(
+            smmu.add_device(StreamId::new(1), 1, s2pt),
+            Err(VmidError::NoStage2)
+        );
+        assert_eq!(smmu.alloc_vmid(), Err(VmidError::NoStage2));
+        smmu.backend()
+            .regs
+            .borrow_mut()
+            .insert(offset_of!(SMMUv3Regs, IDR3), IDR3::DPT::SET.value as u64);
+        assert!(!smmu.dpt_supported());
+
+        let writes = smmu.backend().writes.borrow();
+        let offsets: Vec<usize> = writes.iter().map(|&(offset, _)| offset).collect();
+        let position = |reg| offsets.iter().position(|&o| o == reg).unwrap();
+        // All the caches are invalidated first, then the Secure queues and stream table are set
+        // up before the Secure interface is enabled.
+        assert_eq!(offsets[0], offset_of!(SMMUv3Regs, S_INIT));
+        for reg in [
+            offset_of!(SMMUv3Regs, S_CMDQ_BASE),
+            offset_of!(SMMUv3Regs, S_EVENTQ_BASE),
+            offset_of!(SMMUv3Regs, S_EVENTQ_PROD),
+            offset_of!(SMMUv3Regs, S_STRTAB_BASE),
+        ] {
+            assert!(position(reg) < position(offset_of!(SMMUv3Regs, S_CR0)));
+        }
+        // The Non-secure programming interface is left alone.
+        assert!(offsets.iter().all(|o| (0x8000..0x10000).contains(o)));
+        assert!(smmu.regs().CR0ACK.is_set(CR0ACK::SMMUEN));
+        assert!(!SmmuRegs::new(smmu.backend()).CR0ACK.is_set(CR0ACK::SMMUEN));
+    }
+}
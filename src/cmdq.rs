@@ -0,0 +1,406 @@
+//! Command queue shared by CPUs without a lock.
+//!
+//! As in Linux, producers reserve entries by moving a software copy of the producer index with a
+//! compare-and-swap, write their commands, then mark them valid in a bitmap with one bit per
+//! entry. The first producer of a batch owns it: it waits for the previous owner to publish its
+//! batch, closes its own, waits for the commands of every producer of the batch to be valid and
+//! publishes all of them with a single write of the producer register. The CMD_SYNCs of
+//! concurrent producers are thus published and consumed together, each producer then waiting for
+//! the SMMU to consume its own.
+//!
+//! Producers waiting for a CMD_SYNC hold a shared lock, which keeps the cached consumer index
+//! from moving on, so that the queue cannot wrap past their CMD_SYNC unnoticed.
+
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::sync::atomic::{fence, AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
+
+use memory_addr::{align_up_4k, va, VirtAddr, PAGE_SIZE_4K};
+
+use crate::hal::PagingHandler;
+use crate::queue::{Cmd, MAX_CMD_EVENT_QS};
+
+/// Set in the reserved producer index while the owner of its batch has not closed it.
+const OWNED: u32 = 1 << 31;
+/// Commands reserved at once, longer batches being split.
+const CMDQ_BATCH: usize = 32;
+
+/// A Command queue, or an Enhanced Command queue, accepting commands from several CPUs.
+pub(crate) struct CmdQueue<H: PagingHandler> {
+    base: VirtAddr,
+    /// One bit per entry, toggled when the entry is written.
+    valid_map: VirtAddr,
+    qs: u32,
+    /// Producer index reserved so far, with [`OWNED`].
+    prod: AtomicU32,
+    /// Consumer index last read from the SMMU.
+    cons: AtomicU32,
+    /// Producer index last published to the SMMU.
+    owner_prod: AtomicU32,
+    /// Held shared by producers waiting for a CMD_SYNC, exclusively to update `cons`.
+    lock: AtomicI32,
+    /// Held by the CPU handling a command error.
+    err_lock: AtomicBool,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> CmdQueue<H> {
+    pub const fn uninit() -> Self {
+        Self {
+            base: va!(0xdead_beef),
+            valid_map: va!(0xdead_beef),
+            qs: 0,
+            prod: AtomicU32::new(0),
+            cons: AtomicU32::new(0),
+            owner_prod: AtomicU32::new(0),
+            lock: AtomicI32::new(0),
+            err_lock: AtomicBool::new(false),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Allocate an empty queue of `2^qs` entries.
+    pub fn init(&mut self, qs: u32) {
+        self.qs = qs.clamp(1, MAX_CMD_EVENT_QS);
+        let pages = align_up_4k(self.size() as usize * size_of::<Cmd>()) / PAGE_SIZE_4K;
+        self.base = H::phys_to_virt(H::alloc_pages(pages).expect("Failed to allocate queue"));
+
+        let map_size = align_up_4k((self.size() as usize).div_ceil(64) * size_of::<AtomicU64>());
+        let map = H::alloc_pages(map_size / PAGE_SIZE_4K).expect("Failed to allocate queue");
+        self.valid_map = H::phys_to_virt(map);
+        unsafe { core::ptr::write_bytes(self.valid_map.as_mut_ptr(), 0, map_size) };
+
+        *self.prod.get_mut() = 0;
+        *self.cons.get_mut() = 0;
+        *self.owner_prod.get_mut() = 0;
+        debug!(
+            "Command queue base address: {:?}, qs: {}",
+            self.base, self.qs
+        );
+    }
+
+    pub fn base_addr(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// Replace the entry at `rd`, on which the SMMU stopped with a command error, by a CMD_SYNC
+    /// that it consumes instead once the error is acknowledged.
+    pub fn skip_err(&self, rd: u32) {
+        let idx = rd & (self.size() - 1);
+        let entry = unsafe { (self.base.as_mut_ptr() as *mut Cmd).add(idx as usize) };
+        unsafe { entry.write_volatile(Cmd::cmd_sync()) };
+        H::flush(entry as usize, size_of::<Cmd>());
+    }
+
+    /// Run `handle` unless another CPU is already handling a command error.
+    pub fn handle_err(&self, handle: impl FnOnce()) {
+        if self
+            .err_lock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            handle();
+            self.err_lock.store(false, Ordering::Release);
+        }
+    }
+
+    /// Producer index published to the SMMU.
+    pub fn prod_value(&self) -> u32 {
+        self.owner_prod.load(Ordering::Acquire)
+    }
+
+    fn size(&self) -> u32 {
+        1 << self.qs
+    }
+
+    /// Mask of an index and its wrap bit.
+    fn wrap_mask(&self) -> u32 {
+        (2 << self.qs) - 1
+    }
+
+    fn inc(&self, idx: u32, n: u32) -> u32 {
+        idx.wrapping_add(n) & self.wrap_mask()
+    }
+
+    /// Free entries between the producer and consumer indexes, none when `prod` is stale and
+    /// behind `cons`.
+    fn space(&self, prod: u32, cons: u32) -> u32 {
+        self.size()
+            .saturating_sub(prod.wrapping_sub(cons) & self.wrap_mask())
+    }
+
+    /// Whether the SMMU consumed the entry at `idx`, given its consumer index.
+    fn consumed(&self, idx: u32, cons: u32) -> bool {
+        let ahead = cons.wrapping_sub(idx) & self.wrap_mask();
+        ahead != 0 && ahead <= self.size()
+    }
+
+    fn valid_word(&self, idx: u32) -> (&AtomicU64, u64) {
+        let entry = (idx & (self.size() - 1)) as usize;
+        let word = unsafe { &*(self.valid_map.as_ptr() as *const AtomicU64).add(entry / 64) };
+        (word, 1 << (entry % 64))
+    }
+
+    /// Whether the entry at `idx` was written, its bit being set on even passes over the queue
+    /// and clear on odd ones.
+    fn is_valid(&self, idx: u32) -> bool {
+        let (word, bit) = self.valid_word(idx);
+        (word.load(Ordering::Acquire) & bit != 0) == (idx & self.size() == 0)
+    }
+
+    fn shared_lock(&self) {
+        let mut val = self.lock.load(Ordering::Relaxed);
+        loop {
+            if val < 0 {
+                spin_loop();
+                val = self.lock.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.lock.compare_exchange_weak(
+                val,
+                val + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(cur) => val = cur,
+            }
+        }
+    }
+
+    /// Release the shared lock unless the caller is its last holder.
+    fn shared_tryunlock(&self) -> bool {
+        let mut val = self.lock.load(Ordering::Relaxed);
+        loop {
+            if val == 1 {
+                return false;
+            }
+            match self.lock.compare_exchange_weak(
+                val,
+                val - 1,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(cur) => val = cur,
+            }
+        }
+    }
+
+    fn shared_unlock(&self) {
+        self.lock.fetch_sub(1, Ordering::Release);
+    }
+
+    /// Refresh the cached consumer index, unless producers wait for a CMD_SYNC.
+    fn update_cons(&self, read_cons: &impl Fn() -> u32) {
+        if self
+            .lock
+            .compare_exchange(0, i32::MIN, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.cons.store(read_cons(), Ordering::Relaxed);
+            self.lock.store(0, Ordering::Release);
+        }
+    }
+
+    /// Issue `cmds`, followed by a CMD_SYNC if `sync` is set, returning once the SMMU consumed
+    /// the CMD_SYNC.
+    ///
+    /// `write_prod` publishes a producer index to the SMMU and `read_cons` reads its consumer
+    /// index, wrap bit included.
+    pub fn issue<I: IntoIterator<Item = Cmd>>(
+        &self,
+        cmds: I,
+        sync: bool,
+        write_prod: impl Fn(u32),
+        read_cons: impl Fn() -> u32,
+    ) {
+        let batch = CMDQ_BATCH.min(self.size() as usize - 1);
+        let mut cmds = cmds.into_iter().peekable();
+        let mut buf: [Cmd; CMDQ_BATCH] = core::array::from_fn(|_| Cmd::default());
+        loop {
+            let mut n = 0;
+            while n < batch {
+                match cmds.next() {
+                    Some(cmd) => buf[n] = cmd,
+                    None => break,
+                }
+                n += 1;
+            }
+            if cmds.peek().is_none() {
+                if n != 0 || sync {
+                    self.issue_batch(&buf[..n], sync, &write_prod, &read_cons);
+                }
+                return;
+            }
+            self.issue_batch(&buf[..n], false, &write_prod, &read_cons);
+        }
+    }
+
+    fn issue_batch(
+        &self,
+        cmds: &[Cmd],
+        sync: bool,
+        write_prod: &impl Fn(u32),
+        read_cons: &impl Fn() -> u32,
+    ) {
+        let n = cmds.len() as u32 + sync as u32;
+
+        // Reserve the entries, becoming the owner of a new batch if no batch is open.
+        let mut old = self.prod.load(Ordering::Relaxed);
+        let start = loop {
+            let prod = old & !OWNED;
+            if self.space(prod, self.cons.load(Ordering::Relaxed)) < n {
+                trace!("Command queue is full, try consuming");
+                self.update_cons(read_cons);
+                spin_loop();
+                old = self.prod.load(Ordering::Relaxed);
+                continue;
+            }
+            let head = self.inc(prod, n) | OWNED;
+            match self
+                .prod
+                .compare_exchange_weak(old, head, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break prod,
+                Err(cur) => old = cur,
+            }
+        };
+        let owner = old & OWNED == 0;
+        let end = self.inc(start, n);
+
+        let base = self.base.as_mut_ptr() as *mut Cmd;
+        let entries = cmds.iter().cloned().chain(sync.then(Cmd::cmd_sync));
+        for (i, cmd) in entries.enumerate() {
+            let idx = self.inc(start, i as u32) & (self.size() - 1);
+            let entry = unsafe { base.add(idx as usize) };
+            unsafe { entry.write_volatile(cmd) };
+            H::flush(entry as usize, size_of::<Cmd>());
+        }
+        if sync {
+            self.shared_lock();
+        }
+        let mut idx = start;
+        while idx != end {
+            let (word, bit) = self.valid_word(idx);
+            word.fetch_xor(bit, Ordering::Release);
+            idx = self.inc(idx, 1);
+        }
+
+        if owner {
+            // Publish after the previous batch, with the commands gathered meanwhile.
+            while self.owner_prod.load(Ordering::Acquire) != start {
+                spin_loop();
+            }
+            let prod = self.prod.fetch_and(!OWNED, Ordering::Relaxed) & !OWNED;
+            let mut idx = start;
+            while idx != prod {
+                while !self.is_valid(idx) {
+                    spin_loop();
+                }
+                idx = self.inc(idx, 1);
+            }
+            fence(Ordering::SeqCst);
+            write_prod(prod);
+            self.owner_prod.store(prod, Ordering::Release);
+        }
+
+        if sync {
+            let sync_idx = self.inc(end, self.wrap_mask());
+            let cons = loop {
+                let cons = read_cons();
+                if self.consumed(sync_idx, cons) {
+                    break cons;
+                }
+                spin_loop();
+            };
+            if !self.shared_tryunlock() {
+                self.cons.store(cons, Ordering::Relaxed);
+                self.shared_unlock();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::vec::Vec;
+
+    use super::CmdQueue;
+    use crate::queue::Cmd;
+    use crate::test_utils::HostPagingHandler;
+
+    #[test]
+    fn test_concurrent_issue() {
+        let mut queue = CmdQueue::<HostPagingHandler>::uninit();
+        queue.init(4);
+        let prod = AtomicU32::new(0);
+        let cons = AtomicU32::new(0);
+        let done = AtomicBool::new(false);
+
+        let consumed = std::thread::scope(|s| {
+            let consumer = s.spawn(|| {
+                let mut cmds = Vec::new();
+                while !done.load(Ordering::Acquire) {
+                    let rd = cons.load(Ordering::Relaxed);
+                    if rd == prod.load(Ordering::Acquire) {
+                        continue;
+                    }
+                    let entry = unsafe {
+                        (queue.base_addr().as_ptr() as *const [u64; 2])
+                            .add((rd & 0xf) as usize)
+                            .read_volatile()
+                    };
+                    cmds.push(entry[0]);
+                    cons.store((rd + 1) & 0x1f, Ordering::Release);
+                }
+                cmds
+            });
+            let producers: Vec<_> = (0..4u32)
+                .map(|cpu| {
+                    let queue = &queue;
+                    let (prod, cons) = (&prod, &cons);
+                    s.spawn(move || {
+                        for i in 0..50 {
+                            let cmds =
+                                (0..i % 5).map(|j| Cmd::cmd_cfgi_ste(cpu << 16 | i << 4 | j));
+                            queue.issue(
+                                cmds,
+                                i % 3 != 0,
+                                |p| prod.store(p, Ordering::Release),
+                                || cons.load(Ordering::Acquire),
+                            );
+                        }
+                        queue.issue(
+                            [],
+                            true,
+                            |p| prod.store(p, Ordering::Release),
+                            || cons.load(Ordering::Acquire),
+                        );
+                    })
+                })
+                .collect();
+            for producer in producers {
+                producer.join().unwrap();
+            }
+            done.store(true, Ordering::Release);
+            consumer.join().unwrap()
+        });
+
+        // Every command was consumed once, in the order each CPU issued it.
+        for cpu in 0..4u64 {
+            let sids: Vec<u64> = consumed
+                .iter()
+                .filter(|&&cmd| cmd & 0xff == 0x03 && cmd >> 48 == cpu)
+                .map(|cmd| cmd >> 32)
+                .collect();
+            let expected: Vec<u64> = (0..50u64)
+                .flat_map(|i| (0..i % 5).map(move |j| cpu << 16 | i << 4 | j))
+                .collect();
+            assert_eq!(sids, expected);
+        }
+    }
+}
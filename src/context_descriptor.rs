@@ -0,0 +1,241 @@
+use core::marker::PhantomData;
+
+use memory_addr::{align_up_4k, PhysAddr, PAGE_SIZE_4K};
+
+use crate::hal::PagingHandler;
+use crate::stream_table::HttuMode;
+
+const CTXDESC_CD_DWORDS: usize = 8;
+const CTXDESC_CD_SIZE: usize = CTXDESC_CD_DWORDS << 3;
+
+/// T0SZ, bits [5:0], IR0, bits [9:8], OR0, bits [11:10], SH0, bits [13:12]
+///
+/// Same layout and encoding as the corresponding TCR_EL1 fields for TTB0.
+const CTXDESC_CD_0_TCR_MASK: u64 = 0x3f | 0b11_1111 << 8;
+/// TG0, bits [7:6]
+/// TTB0 granule size, encoded like TCR_EL1.TG0: 0b00 4KB, 0b01 64KB, 0b10 16KB.
+const CTXDESC_CD_0_TG0_OFFSET: u64 = 6;
+/// EPD0, bit [14]
+/// TTB0 translation table walk disable.
+const CTXDESC_CD_0_EPD0: u64 = 1 << 14;
+/// EPD1, bit [30]
+/// TTB1 translation table walk disable, TTB1 is never used for I/O.
+const CTXDESC_CD_0_EPD1: u64 = 1 << 30;
+/// V, bit [31]
+/// CD Valid.
+const CTXDESC_CD_0_V: u64 = 1 << 31;
+/// IPS, bits [34:32]
+/// Intermediate physical address size, encoded like TCR_EL1.IPS.
+const CTXDESC_CD_0_IPS_OFFSET: u64 = 32;
+/// AA64, bit [41]
+/// Use VMSAv8-64 descriptor formats.
+const CTXDESC_CD_0_AA64: u64 = 1 << 41;
+/// HD, bit [42]
+/// Hardware Dirty state update enable, RES0 if SMMU_IDR0.HTTU < 0b10.
+const CTXDESC_CD_0_HD: u64 = 1 << 42;
+/// HA, bit [43]
+/// Hardware Access flag update enable, RES0 if SMMU_IDR0.HTTU == 0b00.
+const CTXDESC_CD_0_HA: u64 = 1 << 43;
+/// R, bit [45]
+/// Record faults from this context in the Event queue.
+const CTXDESC_CD_0_R: u64 = 1 << 45;
+/// A, bit [46]
+/// Abort faulting transactions, instead of terminating them with RAZ/WI behavior.
+const CTXDESC_CD_0_A: u64 = 1 << 46;
+/// ASET, bit [47]
+/// ASID set, 0b1 marks the ASID as not shared with PE broadcast TLB maintenance.
+const CTXDESC_CD_0_ASET: u64 = 1 << 47;
+/// ASID, bits [63:48]
+/// Address Space Identifier tagging the TLB entries of this context.
+const CTXDESC_CD_0_ASID_OFFSET: u64 = 48;
+/// TTB0, bits [115:68]
+/// Address of the TTB0 translation table base, bits [51:4].
+const CTXDESC_CD_1_TTB0_MASK: u64 = ((1 << 52) - 1) & !((1 << 4) - 1);
+
+impl HttuMode {
+    /// CD hardware update bits for this mode.
+    const fn cd_bits(self) -> u64 {
+        match self {
+            HttuMode::Disabled => 0,
+            HttuMode::AccessFlag => CTXDESC_CD_0_HA,
+            HttuMode::AccessFlagDirtyState => CTXDESC_CD_0_HA | CTXDESC_CD_0_HD,
+        }
+    }
+}
+
+/// 5.4 Context Descriptor, configuring stage 1 translation of a stream or substream.
+#[derive(Debug, Clone, Copy)]
+pub struct ContextDescriptor([u64; CTXDESC_CD_DWORDS]);
+
+impl ContextDescriptor {
+    /// An invalid CD, transactions using it are terminated with a C_BAD_CD event.
+    pub const fn invalid_entry() -> Self {
+        Self([0; CTXDESC_CD_DWORDS])
+    }
+
+    /// A VMSAv8-64 stage 1 context translating through TTB0.
+    ///
+    /// `tcr` holds the T0SZ, IRGN0, ORGN0 and SH0 fields in TCR_EL1 layout, `tg0` and `ips` the TCR_EL1.TG0
+    /// and TCR_EL1.IPS encodings, and `mair` the memory attributes indexed by the descriptors.
+    /// `httu` selects CD.{HA, HD}, the caller must have checked it against SMMU_IDR0.HTTU.
+    pub const fn s1_entry(
+        asid: u16,
+        ttb0: PhysAddr,
+        tcr: u64,
+        tg0: u64,
+        ips: u64,
+        mair: u64,
+        httu: HttuMode,
+    ) -> Self {
+        Self([
+            (tcr & CTXDESC_CD_0_TCR_MASK)
+                | tg0 << CTXDESC_CD_0_TG0_OFFSET
+                | CTXDESC_CD_0_EPD1
+                | CTXDESC_CD_0_V
+                | ips << CTXDESC_CD_0_IPS_OFFSET
+                | CTXDESC_CD_0_AA64
+                | CTXDESC_CD_0_R
+                | CTXDESC_CD_0_A
+                | CTXDESC_CD_0_ASET
+                | httu.cd_bits()
+                | (asid as u64) << CTXDESC_CD_0_ASID_OFFSET,
+            ttb0.as_usize() as u64 & CTXDESC_CD_1_TTB0_MASK,
+            0,
+            mair,
+            0,
+            0,
+            0,
+            0,
+        ])
+    }
+
+    /// Whether the CD is valid.
+    pub const fn is_valid(&self) -> bool {
+        self.0[0] & CTXDESC_CD_0_V != 0
+    }
+
+    /// Base address of the TTB0 translation table.
+    pub const fn ttb0(&self) -> PhysAddr {
+        PhysAddr::from_usize((self.0[1] & CTXDESC_CD_1_TTB0_MASK) as usize)
+    }
+
+    /// Replace the TTB0 translation table base.
+    pub fn set_ttb0(&mut self, ttb0: PhysAddr) {
+        self.0[1] = ttb0.as_usize() as u64 & CTXDESC_CD_1_TTB0_MASK;
+    }
+
+    /// The ASID of the context.
+    pub const fn asid(&self) -> u16 {
+        (self.0[0] >> CTXDESC_CD_0_ASID_OFFSET) as u16
+    }
+
+    /// CD.IPS, output address size encoding.
+    pub(crate) const fn ips(&self) -> u64 {
+        (self.0[0] >> CTXDESC_CD_0_IPS_OFFSET) & 0b111
+    }
+
+    /// CD.T0SZ, the TTB0 input region is `64 - t0sz` bits wide.
+    pub(crate) const fn t0sz(&self) -> u32 {
+        (self.0[0] & 0x3f) as u32
+    }
+
+    /// log2 of the TTB0 granule encoded in CD.TG0, `None` for the reserved encoding.
+    pub(crate) const fn tg0_shift(&self) -> Option<u32> {
+        match (self.0[0] >> CTXDESC_CD_0_TG0_OFFSET) & 0b11 {
+            0b00 => Some(12),
+            0b01 => Some(16),
+            0b10 => Some(14),
+            _ => None,
+        }
+    }
+
+    /// Whether CD.AA64 selects VMSAv8-64 descriptors, the only format supported by the walker.
+    pub(crate) const fn is_aa64(&self) -> bool {
+        self.0[0] & CTXDESC_CD_0_AA64 != 0
+    }
+
+    /// Whether CD.EPD0 disables walks of TTB0.
+    pub(crate) const fn epd0(&self) -> bool {
+        self.0[0] & CTXDESC_CD_0_EPD0 != 0
+    }
+
+    /// Whether CD.HA enables hardware Access flag updates.
+    pub(crate) const fn ha(&self) -> bool {
+        self.0[0] & CTXDESC_CD_0_HA != 0
+    }
+}
+
+/// A linear table of Context Descriptors indexed by SubstreamID, referenced by STE.S1ContextPtr.
+pub struct CdTable<H: PagingHandler> {
+    base: PhysAddr,
+    ssid_bits: u32,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> CdTable<H> {
+    /// Allocate a table of `1 << ssid_bits` invalid CDs.
+    ///
+    /// With `ssid_bits == 0` the single CD is used for all traffic of the stream.
+    pub fn new(ssid_bits: u32) -> Self {
+        let size = align_up_4k(CTXDESC_CD_SIZE << ssid_bits);
+        let base = H::alloc_pages(size / PAGE_SIZE_4K).expect("Failed to allocate CD table");
+        let table = Self {
+            base,
+            ssid_bits,
+            _phantom: PhantomData,
+        };
+        for ssid in 0..table.entry_count() {
+            table.set_cd(ssid, &ContextDescriptor::invalid_entry());
+        }
+        table
+    }
+
+    /// Physical base address, as programmed into STE.S1ContextPtr.
+    pub fn base_addr(&self) -> PhysAddr {
+        self.base
+    }
+
+    /// Number of SubstreamID bits the table covers, as programmed into STE.S1CDMax.
+    pub fn ssid_bits(&self) -> u32 {
+        self.ssid_bits
+    }
+
+    /// Number of CDs in the table, one per SubstreamID.
+    pub fn entry_count(&self) -> usize {
+        1 << self.ssid_bits
+    }
+
+    /// Read the CD for `ssid`.
+    pub fn cd(&self, ssid: usize) -> ContextDescriptor {
+        unsafe { self.cd_ptr(ssid).read_volatile() }
+    }
+
+    /// Write the CD for `ssid`.
+    ///
+    /// The word holding CD.V is written last, so the SMMU never observes a partially written valid CD.
+    /// A CD that might be cached must be invalidated with CMD_CFGI_CD afterwards.
+    pub fn set_cd(&self, ssid: usize, cd: &ContextDescriptor) {
+        let ptr = self.cd_ptr(ssid) as *mut u64;
+        unsafe {
+            ptr.write_volatile(0);
+            for (i, word) in cd.0.iter().enumerate().skip(1) {
+                ptr.add(i).write_volatile(*word);
+            }
+            ptr.write_volatile(cd.0[0]);
+        }
+        H::flush(ptr as usize, CTXDESC_CD_SIZE);
+    }
+
+    fn cd_ptr(&self, ssid: usize) -> *mut ContextDescriptor {
+        assert!(ssid < self.entry_count(), "SSID {} out of CD table", ssid);
+        let base = H::phys_to_virt(self.base).as_mut_ptr() as *mut ContextDescriptor;
+        unsafe { base.add(ssid) }
+    }
+}
+
+impl<H: PagingHandler> Drop for CdTable<H> {
+    fn drop(&mut self) {
+        let size = align_up_4k(CTXDESC_CD_SIZE << self.ssid_bits);
+        H::dealloc_pages(self.base, size / PAGE_SIZE_4K);
+    }
+}
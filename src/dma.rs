@@ -0,0 +1,381 @@
+//! DMA domains: IOVA allocation and mapping on top of driver managed I/O page tables.
+//!
+//! A DMA domain is an [`IommuDomain`] translating through a stage 2 table tagged with a private
+//! VMID, along with the IOVA space of that table. StreamIDs are attached to a DMA domain, after
+//! which [`SMMUv3::dma_map`] and [`SMMUv3::dma_unmap`] are addressed by StreamID. Invalidations
+//! for unmapped ranges are queued per domain and issued as one batch followed by a single
+//! CMD_SYNC, the IOVA ranges only become reusable once that batch completed.
+
+use alloc::vec::Vec;
+
+use memory_addr::{PhysAddr, PAGE_SIZE_4K};
+
+use crate::backend::RegisterBackend;
+use crate::domain::{DomainError, IommuDomain};
+use crate::hal::PagingHandler;
+use crate::id_alloc::VmidError;
+use crate::io_pgtable::{
+    IoPageTable, IoPgtableConfig, IoPgtableError, IoProt, IoTlbFlush, TlbContext,
+};
+use crate::iova::IovaAllocator;
+use crate::stream_id::StreamId;
+use crate::{cmd_tlbi_addr, cmd_tlbi_context, SMMUv3, TLBI_RANGE_MAX_CMDS};
+
+/// Number of unmapped ranges a domain queues before flushing them.
+const DMA_FLUSH_BATCH: usize = 64;
+
+/// Direction of a DMA transfer, as seen from the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads the buffer.
+    ToDevice,
+    /// The device writes the buffer.
+    FromDevice,
+    /// The device reads and writes the buffer.
+    Bidirectional,
+}
+
+impl DmaDirection {
+    const fn prot(self) -> IoProt {
+        let access = match self {
+            DmaDirection::ToDevice => IoProt::READ,
+            DmaDirection::FromDevice => IoProt::WRITE,
+            DmaDirection::Bidirectional => IoProt::READ.union(IoProt::WRITE),
+        };
+        access.union(IoProt::CACHE).union(IoProt::NOEXEC)
+    }
+}
+
+/// Handle of a DMA domain created by [`SMMUv3::create_dma_domain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DmaDomainId(usize);
+
+/// Geometry of a DMA domain.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaDomainConfig {
+    /// Page table geometry.
+    pub pgtable: IoPgtableConfig,
+    /// Start of the IOVA aperture, granule aligned.
+    pub iova_base: usize,
+    /// Size of the IOVA aperture, granule aligned.
+    pub iova_size: usize,
+}
+
+impl Default for DmaDomainConfig {
+    /// The whole input address space of the default page table, except the page at IOVA 0.
+    fn default() -> Self {
+        let pgtable = IoPgtableConfig::default();
+        Self {
+            pgtable,
+            iova_base: PAGE_SIZE_4K,
+            iova_size: (1 << pgtable.ias) - PAGE_SIZE_4K,
+        }
+    }
+}
+
+/// Errors reported by the DMA API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The StreamID is not attached to a DMA domain, or the domain does not exist.
+    NoDomain,
+    /// The domain still has StreamIDs attached.
+    Busy,
+    /// Attaching a StreamID to the domain failed.
+    Domain(DomainError),
+    /// No IOVA range of the requested size is free.
+    IovaExhausted,
+    /// Allocating the domain VMID failed.
+    Vmid(VmidError),
+    /// Updating the domain page table failed.
+    Pgtable(IoPgtableError),
+}
+
+impl From<VmidError> for DmaError {
+    fn from(err: VmidError) -> Self {
+        DmaError::Vmid(err)
+    }
+}
+
+impl From<DomainError> for DmaError {
+    fn from(err: DomainError) -> Self {
+        DmaError::Domain(err)
+    }
+}
+
+impl From<IoPgtableError> for DmaError {
+    fn from(err: IoPgtableError) -> Self {
+        DmaError::Pgtable(err)
+    }
+}
+
+/// A leaf or walk cache invalidation waiting in a [`FlushQueue`].
+struct PendingTlbi {
+    iova: usize,
+    size: usize,
+    granule: usize,
+    leaf: bool,
+}
+
+/// Invalidations and IOVA ranges of a domain waiting for the next batched CMD_SYNC.
+struct FlushQueue {
+    ctx: TlbContext,
+    tlbis: Vec<PendingTlbi>,
+    freed: Vec<(usize, usize)>,
+}
+
+impl FlushQueue {
+    /// Issue the queued invalidations and one CMD_SYNC, then release the queued IOVA ranges.
+    fn flush<H: PagingHandler, B: RegisterBackend>(
+        &mut self,
+        smmu: &mut SMMUv3<H, B>,
+        iova: &mut IovaAllocator,
+    ) {
+        let cmds: usize = self.tlbis.iter().map(|t| t.size / t.granule).sum();
+        if cmds > TLBI_RANGE_MAX_CMDS {
+            smmu.add_cmd(cmd_tlbi_context(self.ctx), true);
+        } else if cmds != 0 {
+            let ctx = self.ctx;
+            let cmds = self.tlbis.iter().flat_map(|t| {
+                (t.iova..t.iova + t.size)
+                    .step_by(t.granule)
+                    .map(move |addr| cmd_tlbi_addr(ctx, addr, t.leaf))
+            });
+            smmu.add_cmds(cmds, true);
+        }
+        self.tlbis.clear();
+
+        for (start, size) in self.freed.drain(..) {
+            iova.free(start, size);
+        }
+    }
+}
+
+/// [`IoTlbFlush`] queueing invalidations instead of issuing them right away.
+///
+/// Waits requested by the page table, before it frees a table or splits a block, flush the queue.
+struct DeferredFlush<'a, H: PagingHandler, B: RegisterBackend> {
+    smmu: &'a mut SMMUv3<H, B>,
+    fq: &'a mut FlushQueue,
+    iova: &'a mut IovaAllocator,
+}
+
+impl<H: PagingHandler, B: RegisterBackend> IoTlbFlush for DeferredFlush<'_, H, B> {
+    fn tlb_inv_range(
+        &mut self,
+        _ctx: TlbContext,
+        iova: usize,
+        size: usize,
+        granule: usize,
+        leaf: bool,
+    ) {
+        self.fq.tlbis.push(PendingTlbi {
+            iova,
+            size,
+            granule,
+            leaf,
+        });
+    }
+
+    fn tlb_sync(&mut self) {
+        self.fq.flush(self.smmu, self.iova);
+    }
+}
+
+/// An IOMMU domain along with its page table and IOVA space.
+pub(crate) struct DmaDomain<H: PagingHandler> {
+    domain: IommuDomain<H>,
+    pgtable: IoPageTable<H>,
+    vmid: u16,
+    iova: IovaAllocator,
+    fq: FlushQueue,
+}
+
+impl<H: PagingHandler> DmaDomain<H> {
+    /// Round `[addr, addr + len)` out to the granule.
+    fn granule_range(&self, addr: usize, len: usize) -> (usize, usize) {
+        let granule = self.iova.granule();
+        let start = addr & !(granule - 1);
+        (start, (addr + len).next_multiple_of(granule) - start)
+    }
+
+    /// Unmap `[iova, iova + size)` and queue the range to be freed after the next flush.
+    fn unmap<B: RegisterBackend>(
+        &mut self,
+        smmu: &mut SMMUv3<H, B>,
+        iova: usize,
+        size: usize,
+    ) -> Result<(), DmaError> {
+        let mut flush = DeferredFlush {
+            smmu,
+            fq: &mut self.fq,
+            iova: &mut self.iova,
+        };
+        self.pgtable.unmap_deferred(&mut flush, iova, size)?;
+        self.fq.freed.push((iova, size));
+        if self.fq.freed.len() >= DMA_FLUSH_BATCH {
+            self.fq.flush(smmu, &mut self.iova);
+        }
+        Ok(())
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Create an empty DMA domain with its own page table and VMID.
+    pub fn create_dma_domain(&mut self, cfg: DmaDomainConfig) -> Result<DmaDomainId, DmaError> {
+        let vmid = self.alloc_vmid()?;
+        let pgtable = match IoPageTable::new_s2(cfg.pgtable, vmid) {
+            Ok(pgtable) => pgtable,
+            Err(err) => {
+                self.free_vmid(vmid);
+                return Err(err.into());
+            }
+        };
+        let domain = DmaDomain {
+            domain: self.pgtable_domain(&pgtable),
+            iova: IovaAllocator::new(cfg.iova_base, cfg.iova_size, cfg.pgtable.granule.size()),
+            fq: FlushQueue {
+                ctx: pgtable.tlb_context(),
+                tlbis: Vec::new(),
+                freed: Vec::new(),
+            },
+            pgtable,
+            vmid,
+        };
+
+        let slot = match self.dma_domains.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.dma_domains.push(None);
+                self.dma_domains.len() - 1
+            }
+        };
+        self.dma_domains[slot] = Some(domain);
+        Ok(DmaDomainId(slot))
+    }
+
+    /// Destroy a DMA domain without attached StreamIDs, releasing its page table and VMID.
+    pub fn destroy_dma_domain(&mut self, id: DmaDomainId) -> Result<(), DmaError> {
+        let mut domain = self.take_dma_domain(id)?;
+        if domain.domain.sid_count() != 0 {
+            self.dma_domains[id.0] = Some(domain);
+            return Err(DmaError::Busy);
+        }
+        domain.fq.flush(self, &mut domain.iova);
+        self.free_vmid(domain.vmid);
+        Ok(())
+    }
+
+    /// Translate the DMA of device `sid` through the domain `id`.
+    ///
+    /// A StreamID attached to another DMA domain is moved over.
+    pub fn attach_dma_domain(&mut self, sid: StreamId, id: DmaDomainId) -> Result<(), DmaError> {
+        let mut domain = self.take_dma_domain(id)?;
+        if self.dma_sids.get(&sid).is_some_and(|&old| old != id) {
+            if let Err(err) = self.detach_dma_domain(sid) {
+                self.dma_domains[id.0] = Some(domain);
+                return Err(err);
+            }
+        }
+        let res = self.attach_domain(&mut domain.domain, &[sid]);
+        self.dma_domains[id.0] = Some(domain);
+        res?;
+        self.dma_sids.insert(sid, id);
+        Ok(())
+    }
+
+    /// Detach device `sid` from its DMA domain, returning its stream to bypass.
+    pub fn detach_dma_domain(&mut self, sid: StreamId) -> Result<(), DmaError> {
+        let id = self.dma_domain_of(sid)?;
+        let mut domain = self.take_dma_domain(id)?;
+        self.detach_domain(&mut domain.domain, &[sid]);
+        self.dma_domains[id.0] = Some(domain);
+        self.dma_sids.remove(&sid);
+        Ok(())
+    }
+
+    /// Map `len` bytes at `paddr` for DMA by device `sid`, returning the address the device must
+    /// use.
+    ///
+    /// The mapping covers whole granules, the offset of `paddr` within its granule is preserved.
+    pub fn dma_map(
+        &mut self,
+        sid: StreamId,
+        paddr: PhysAddr,
+        len: usize,
+        dir: DmaDirection,
+    ) -> Result<usize, DmaError> {
+        let id = self.dma_domain_of(sid)?;
+        let mut domain = self.take_dma_domain(id)?;
+        let res = self.dma_map_domain(&mut domain, paddr, len, dir);
+        self.dma_domains[id.0] = Some(domain);
+        res
+    }
+
+    fn dma_map_domain(
+        &mut self,
+        domain: &mut DmaDomain<H>,
+        paddr: PhysAddr,
+        len: usize,
+        dir: DmaDirection,
+    ) -> Result<usize, DmaError> {
+        let (start, size) = domain.granule_range(paddr.as_usize(), len);
+        let offset = paddr.as_usize() - start;
+
+        let iova = match domain.iova.alloc(size, 0) {
+            Some(iova) => iova,
+            None if !domain.fq.freed.is_empty() => {
+                // Ranges waiting for invalidation may be enough, recycle them now.
+                domain.fq.flush(self, &mut domain.iova);
+                domain.iova.alloc(size, 0).ok_or(DmaError::IovaExhausted)?
+            }
+            None => return Err(DmaError::IovaExhausted),
+        };
+
+        if let Err(err) = domain
+            .pgtable
+            .map(iova, PhysAddr::from(start), size, dir.prot())
+        {
+            domain.unmap(self, iova, size)?;
+            return Err(err.into());
+        }
+        Ok(iova + offset)
+    }
+
+    /// Remove a mapping created by [`SMMUv3::dma_map`] with the same `dma_addr` and `len`.
+    ///
+    /// The invalidation is deferred and batched with other unmaps of the domain, the device must
+    /// not access the buffer anymore.
+    pub fn dma_unmap(
+        &mut self,
+        sid: StreamId,
+        dma_addr: usize,
+        len: usize,
+    ) -> Result<(), DmaError> {
+        let id = self.dma_domain_of(sid)?;
+        let mut domain = self.take_dma_domain(id)?;
+        let (iova, size) = domain.granule_range(dma_addr, len);
+        let res = domain.unmap(self, iova, size);
+        self.dma_domains[id.0] = Some(domain);
+        res
+    }
+
+    /// Complete the deferred invalidations of the domain `id` and make its unmapped IOVAs reusable.
+    pub fn flush_dma_domain(&mut self, id: DmaDomainId) -> Result<(), DmaError> {
+        let mut domain = self.take_dma_domain(id)?;
+        domain.fq.flush(self, &mut domain.iova);
+        self.dma_domains[id.0] = Some(domain);
+        Ok(())
+    }
+
+    fn dma_domain_of(&self, sid: StreamId) -> Result<DmaDomainId, DmaError> {
+        self.dma_sids.get(&sid).copied().ok_or(DmaError::NoDomain)
+    }
+
+    /// Move the domain out of its slot, so it can be updated while the command queue is in use.
+    fn take_dma_domain(&mut self, id: DmaDomainId) -> Result<DmaDomain<H>, DmaError> {
+        self.dma_domains
+            .get_mut(id.0)
+            .and_then(Option::take)
+            .ok_or(DmaError::NoDomain)
+    }
+}
@@ -0,0 +1,409 @@
+//! IOMMU domains: translation contexts shared by groups of StreamIDs.
+
+use core::marker::PhantomData;
+
+use memory_addr::{align_up_4k, PhysAddr, VirtAddr, PAGE_SIZE_4K};
+
+use crate::backend::RegisterBackend;
+use crate::context_descriptor::{CdTable, ContextDescriptor};
+use crate::hal::PagingHandler;
+use crate::id_alloc::VmidError;
+use crate::queue::Cmd;
+use crate::stream_id::StreamId;
+use crate::stream_table::DEFAULT_S2VTCR;
+use crate::SMMUv3;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Translation context of an [`IommuDomain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainContext {
+    /// Stage 1 translation through `ttb0`, tagged with `asid`, stage 2 bypassed.
+    Stage1 {
+        /// ASID written to CD 0 of every stream.
+        asid: u16,
+        /// Stage 1 translation table base, written to CD.TTB0.
+        ttb0: PhysAddr,
+    },
+    /// Stage 2 translation through `s2pt_base`, tagged with `vmid`.
+    Stage2 {
+        /// VMID written to STE.S2VMID of every stream.
+        vmid: u16,
+        /// Stage 2 translation table base, written to STE.S2TTB.
+        s2pt_base: PhysAddr,
+    },
+}
+
+/// Reasons a StreamID cannot be attached to a domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainError {
+    /// The StreamID is beyond the stream table.
+    OutOfRange,
+    /// The StreamID is translated by another domain or by [`SMMUv3::add_device`].
+    InUse,
+    /// The StreamID is quarantined, see [`SMMUv3::quarantine`].
+    Quarantined,
+    /// The domain VMID cannot be used.
+    Vmid(VmidError),
+}
+
+impl From<VmidError> for DomainError {
+    fn from(err: VmidError) -> Self {
+        DomainError::Vmid(err)
+    }
+}
+
+/// Set of StreamIDs, a bitmap covering the stream table in pages from [`PagingHandler::alloc_pages`].
+struct SidSet<H: PagingHandler> {
+    base: PhysAddr,
+    bitmap: VirtAddr,
+    count: usize,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> SidSet<H> {
+    fn new() -> Self {
+        let base =
+            H::alloc_pages(Self::size() / PAGE_SIZE_4K).expect("Failed to allocate SID bitmap");
+        let bitmap = H::phys_to_virt(base);
+        unsafe { core::ptr::write_bytes(bitmap.as_mut_ptr(), 0, Self::size()) };
+        Self {
+            base,
+            bitmap,
+            count: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    const fn capacity() -> usize {
+        1 << H::SID_BITS_SET
+    }
+
+    fn size() -> usize {
+        align_up_4k(Self::capacity().div_ceil(8))
+    }
+
+    fn words(&self) -> &[u64] {
+        let len = Self::capacity().div_ceil(BITS_PER_WORD);
+        unsafe { core::slice::from_raw_parts(self.bitmap.as_ptr() as *const u64, len) }
+    }
+
+    fn words_mut(&mut self) -> &mut [u64] {
+        let len = Self::capacity().div_ceil(BITS_PER_WORD);
+        unsafe { core::slice::from_raw_parts_mut(self.bitmap.as_mut_ptr() as *mut u64, len) }
+    }
+
+    fn contains(&self, sid: usize) -> bool {
+        sid < Self::capacity()
+            && self.words()[sid / BITS_PER_WORD] & (1 << (sid % BITS_PER_WORD)) != 0
+    }
+
+    /// Add `sid`, returning false if it was already present.
+    fn insert(&mut self, sid: usize) -> bool {
+        if self.contains(sid) {
+            return false;
+        }
+        self.words_mut()[sid / BITS_PER_WORD] |= 1 << (sid % BITS_PER_WORD);
+        self.count += 1;
+        true
+    }
+
+    /// Remove `sid`, returning false if it was not present.
+    fn remove(&mut self, sid: usize) -> bool {
+        if !self.contains(sid) {
+            return false;
+        }
+        self.words_mut()[sid / BITS_PER_WORD] &= !(1 << (sid % BITS_PER_WORD));
+        self.count -= 1;
+        true
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words().iter().enumerate().flat_map(|(i, &word)| {
+            (0..BITS_PER_WORD)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * BITS_PER_WORD + bit)
+        })
+    }
+
+    /// First member, if any.
+    fn first(&self) -> Option<usize> {
+        self.words()
+            .iter()
+            .position(|&word| word != 0)
+            .map(|i| i * BITS_PER_WORD + self.words()[i].trailing_zeros() as usize)
+    }
+}
+
+impl<H: PagingHandler> Drop for SidSet<H> {
+    fn drop(&mut self) {
+        H::dealloc_pages(self.base, Self::size() / PAGE_SIZE_4K);
+    }
+}
+
+/// A translation context shared by a group of StreamIDs.
+///
+/// All StreamIDs of the domain translate through the same tables with the same VMID or ASID, so
+/// TLB maintenance is done once for the domain rather than per device. Devices behind a PCIe
+/// bridge that masters DMA with several aliased requester IDs are attached with all of their
+/// StreamIDs at once.
+///
+/// Dropping a domain that still has StreamIDs attached leaks its CD table, which their STEs keep
+/// pointing at. Detach them first with [`SMMUv3::detach_domain`].
+pub struct IommuDomain<H: PagingHandler> {
+    ctx: DomainContext,
+    s2vtcr: u64,
+    /// CD table shared by the STEs of a stage 1 domain, the domain CD is at SubstreamID 0.
+    cd_table: Option<CdTable<H>>,
+    sids: SidSet<H>,
+}
+
+impl<H: PagingHandler> IommuDomain<H> {
+    /// A stage 2 domain translating through `s2pt_base`, with the configuration used by [`SMMUv3::add_device`].
+    ///
+    /// `vmid` is usually shared with the VM owning the tables, or obtained from [`SMMUv3::alloc_vmid`].
+    pub fn new_s2(vmid: u16, s2pt_base: PhysAddr) -> Self {
+        Self::new_s2_with_vtcr(vmid, s2pt_base, DEFAULT_S2VTCR)
+    }
+
+    /// A stage 2 domain whose tables are described by `s2vtcr`, in VTCR_EL2 layout.
+    pub fn new_s2_with_vtcr(vmid: u16, s2pt_base: PhysAddr, s2vtcr: u64) -> Self {
+        Self {
+            ctx: DomainContext::Stage2 { vmid, s2pt_base },
+            s2vtcr,
+            cd_table: None,
+            sids: SidSet::new(),
+        }
+    }
+
+    /// A stage 1 domain translating with the context `cd`, whose ASID comes from [`SMMUv3::alloc_asid`].
+    pub fn new_s1(cd: &ContextDescriptor) -> Self {
+        let cd_table = CdTable::new(0);
+        cd_table.set_cd(0, cd);
+        Self {
+            ctx: DomainContext::Stage1 {
+                asid: cd.asid(),
+                ttb0: cd.ttb0(),
+            },
+            s2vtcr: 0,
+            cd_table: Some(cd_table),
+            sids: SidSet::new(),
+        }
+    }
+
+    /// The translation context of the domain.
+    pub fn context(&self) -> DomainContext {
+        self.ctx
+    }
+
+    /// Whether `sid` is attached to the domain.
+    pub fn contains(&self, sid: StreamId) -> bool {
+        self.sids.contains(sid.as_usize())
+    }
+
+    /// Number of StreamIDs attached to the domain.
+    pub fn sid_count(&self) -> usize {
+        self.sids.count
+    }
+
+    /// StreamIDs attached to the domain, in ascending order.
+    pub fn sids(&self) -> impl Iterator<Item = StreamId> + '_ {
+        self.sids.iter().map(|sid| StreamId::new(sid as u32))
+    }
+
+    /// Command invalidating every TLB entry of the domain.
+    fn cmd_tlbi(&self) -> Cmd {
+        match self.ctx {
+            DomainContext::Stage1 { asid, .. } => Cmd::cmd_tlbi_nh_asid(0, asid),
+            DomainContext::Stage2 { vmid, .. } => Cmd::cmd_tlbi_s12_vmall(vmid),
+        }
+    }
+}
+
+impl<H: PagingHandler> Drop for IommuDomain<H> {
+    fn drop(&mut self) {
+        if let Some(sid) = self.sids.first() {
+            warn!(
+                "IOMMU domain dropped with {} StreamIDs attached, sid 0x{:x} still uses its tables",
+                self.sids.count, sid
+            );
+            // The STEs still point the SMMU at the CD table, leak it rather than free it.
+            if let Some(cd_table) = self.cd_table.take() {
+                core::mem::forget(cd_table);
+            }
+        }
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Attach the StreamIDs of one device to `domain`.
+    ///
+    /// `sids` lists every StreamID the device may issue DMA with, such as the requester ID of a
+    /// device and the aliases introduced by PCIe to PCI bridges. StreamIDs already in the domain
+    /// are skipped. StreamIDs translated elsewhere must be detached first, quarantined ones
+    /// removed. Nothing is attached if any of the StreamIDs cannot be.
+    pub fn attach_domain(
+        &mut self,
+        domain: &mut IommuDomain<H>,
+        sids: &[StreamId],
+    ) -> Result<(), DomainError> {
+        for sid in sids {
+            let sid = sid.as_usize();
+            if sid >= self.stream_table.entry_count() {
+                return Err(DomainError::OutOfRange);
+            }
+            if domain.sids.contains(sid) {
+                continue;
+            }
+            if self.is_quarantined(StreamId::new(sid as u32)) {
+                return Err(DomainError::Quarantined);
+            }
+            if self.stream_table.ste(sid).is_translated() {
+                return Err(DomainError::InUse);
+            }
+        }
+
+        // Take the VMID references first, so that a failure leaves every STE as it was.
+        if let DomainContext::Stage2 { vmid, .. } = domain.ctx {
+            let count = sids
+                .iter()
+                .enumerate()
+                .filter(|&(i, sid)| !domain.contains(*sid) && !sids[..i].contains(sid))
+                .count();
+            for taken in 0..count {
+                if let Err(err) = self.get_table_vmid(vmid) {
+                    for _ in 0..taken {
+                        self.vmid_alloc.put(vmid as usize);
+                    }
+                    return Err(err.into());
+                }
+            }
+        }
+
+        for sid in sids {
+            let sid = sid.as_usize();
+            if !domain.sids.insert(sid) {
+                continue;
+            }
+            match domain.ctx {
+                DomainContext::Stage1 { .. } => {
+                    let cd_table = domain.cd_table.as_ref().unwrap();
+                    self.stream_table.set_s1_translated_ste(
+                        sid,
+                        cd_table.base_addr(),
+                        cd_table.ssid_bits(),
+                    );
+                }
+                DomainContext::Stage2 { vmid, s2pt_base } => {
+                    self.stream_table.set_s2_translated_ste(
+                        sid,
+                        vmid as usize,
+                        s2pt_base,
+                        domain.s2vtcr,
+                        self.httu,
+                    );
+                }
+            }
+            self.add_cmds(
+                [
+                    Cmd::cmd_cfgi_ste(sid as u32),
+                    Cmd::cmd_prefetch_config(sid as u32),
+                ],
+                true,
+            );
+        }
+        Ok(())
+    }
+
+    /// Detach StreamIDs from `domain`, returning their streams to bypass.
+    ///
+    /// StreamIDs not attached to the domain are ignored.
+    pub fn detach_domain(&mut self, domain: &mut IommuDomain<H>, sids: &[StreamId]) {
+        for &sid in sids {
+            if domain.sids.remove(sid.as_usize()) {
+                self.remove_device(sid);
+            }
+        }
+        // Stage 2 entries are dropped along with the last VMID reference by `remove_device`.
+        if domain.sid_count() == 0 && matches!(domain.ctx, DomainContext::Stage1 { .. }) {
+            self.add_cmd(domain.cmd_tlbi(), true);
+        }
+    }
+
+    /// Detach every StreamID of `domain`.
+    pub fn detach_all(&mut self, domain: &mut IommuDomain<H>) {
+        while let Some(sid) = domain.sids.first() {
+            self.detach_domain(domain, &[StreamId::new(sid as u32)]);
+        }
+    }
+
+    /// Invalidate all cached translations of `domain`, for every StreamID attached to it.
+    pub fn flush_domain(&self, domain: &IommuDomain<H>) {
+        self.add_cmd(domain.cmd_tlbi(), true);
+    }
+
+    /// Point `domain` at new translation tables, updating all attached StreamIDs at once.
+    ///
+    /// The configuration caches of every attached StreamID are invalidated in a single batch,
+    /// followed by the domain TLB entries. StreamIDs quarantined by [`SMMUv3::quarantine`] keep
+    /// aborting until they are detached.
+    pub fn set_domain_root(&mut self, domain: &mut IommuDomain<H>, root: PhysAddr) {
+        match &mut domain.ctx {
+            DomainContext::Stage1 { ttb0, .. } => {
+                let cd_table = domain.cd_table.as_ref().unwrap();
+                let mut cd = cd_table.cd(0);
+                cd.set_ttb0(root);
+                cd_table.set_cd(0, &cd);
+                *ttb0 = root;
+                let cmds = domain
+                    .sids
+                    .iter()
+                    .map(|sid| Cmd::cmd_cfgi_cd(sid as u32, 0));
+                self.add_cmds(cmds, false);
+            }
+            DomainContext::Stage2 { vmid, s2pt_base } => {
+                *s2pt_base = root;
+                // Quarantined streams dropped their VMID reference and keep aborting.
+                for sid in domain.sids.iter() {
+                    if self.is_quarantined(StreamId::new(sid as u32)) {
+                        continue;
+                    }
+                    self.stream_table.set_s2_translated_ste(
+                        sid,
+                        *vmid as usize,
+                        root,
+                        domain.s2vtcr,
+                        self.httu,
+                    );
+                }
+                let cmds = domain.sids.iter().map(|sid| Cmd::cmd_cfgi_ste(sid as u32));
+                self.add_cmds(cmds, false);
+            }
+        }
+        self.add_cmd(domain.cmd_tlbi(), true);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::HostPagingHandler;
+
+    #[test]
+    fn test_sid_set() {
+        let mut sids = SidSet::<HostPagingHandler>::new();
+        assert_eq!(sids.first(), None);
+
+        // A device and the alias of the PCIe to PCI bridge in front of it.
+        assert!(sids.insert(0x48));
+        assert!(sids.insert(0x08));
+        assert!(!sids.insert(0x48));
+        assert_eq!(sids.count, 2);
+        assert!(sids.iter().eq([0x08, 0x48]));
+        assert!(!sids.contains(0x100));
+
+        assert!(sids.remove(0x08));
+        assert!(!sids.remove(0x08));
+        assert_eq!(sids.first(), Some(0x48));
+    }
+}
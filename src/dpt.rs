@@ -0,0 +1,263 @@
+//! Device Permission Table.
+//!
+//! With SMMU_IDR3.DPT, the SMMU checks the physical address of every Non-secure transaction
+//! against a Device Permission Table (DPT) once SMMU_CR0.DPT_WALK_EN is set, so that a
+//! confidential computing stack can restrict which physical memory non-realm devices reach.
+//!
+//! Like the Granule Protection Table of the PE, the DPT has two levels. Each level 0 descriptor
+//! covers 1GB, either as a block with a single Device Permission Index (DPI), or pointing at a
+//! level 1 table holding the 4-bit DPI of each 4KB granule, 16 granules per 64-bit entry.
+//! [`SMMUv3::enable_dpt`] builds the table with blocks only, which
+//! [`SMMUv3::grant_device_access`] and [`SMMUv3::revoke_device_access`] split into level 1 tables
+//! when the DPI of a granule differs from the rest of its block.
+
+use core::marker::PhantomData;
+
+use memory_addr::{align_down_4k, align_up_4k, pa, va, PhysAddr, VirtAddr, PAGE_SIZE_4K};
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+
+use crate::backend::RegisterBackend;
+use crate::hal::PagingHandler;
+use crate::queue::Cmd;
+use crate::{SMMUv3, ARM_SMMU_SYNC_TIMEOUT, CR0, CR0ACK, DPT_BASE, DPT_BASE_CFG, IDR3};
+
+/// Bits of physical address covered by a level 0 descriptor, SMMU_DPT_BASE_CFG.L0DPTSZ.
+const L0DPTSZ: u32 = 30;
+/// DPIs in a level 1 entry.
+const DPIS_PER_ENTRY: usize = 16;
+/// Level 1 entries covering the memory of a level 0 descriptor.
+const L1_ENTRIES: usize = (1 << (L0DPTSZ as usize - 12)) / DPIS_PER_ENTRY;
+
+/// Level 0 descriptor types, bits [3:0].
+const L0_TYPE_MASK: u64 = 0b1111;
+const L0_BLOCK: u64 = 0b0001;
+const L0_TABLE: u64 = 0b0011;
+/// DPI of a level 0 block, bits [7:4].
+const L0_BLOCK_DPI_SHIFT: u64 = 4;
+/// Address of the level 1 table of a level 0 table descriptor, bits [51:12].
+const L0_TABLE_ADDR_MASK: u64 = ((1 << 52) - 1) & !((1 << 12) - 1);
+
+/// Physical address sizes, indexed by their SMMU_DPT_BASE_CFG.DPTPS encoding.
+const DPTPS_BITS: [u32; 7] = [32, 36, 40, 42, 44, 48, 52];
+/// Above this many CMD_DPTI_PA commands, an update invalidates the whole DPT with CMD_DPTI_ALL.
+const DPTI_MAX_CMDS: usize = 256;
+
+/// Access of non-realm devices to a granule, its Device Permission Index.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevicePermission {
+    /// Transactions to the granule are aborted.
+    None = 0b0000,
+    /// Reads are allowed, writes are aborted.
+    Read = 0b0001,
+    /// Reads and writes are allowed.
+    ReadWrite = 0b0011,
+}
+
+impl DevicePermission {
+    fn from_dpi(dpi: u64) -> Self {
+        match dpi {
+            0b0001 => Self::Read,
+            0b0011 => Self::ReadWrite,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Reasons the Device Permission Table cannot be set up or updated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DptError {
+    /// SMMU_IDR3.DPT is clear.
+    NotSupported,
+    /// [`SMMUv3::enable_dpt`] was not called.
+    NotEnabled,
+    /// The memory lies beyond the physical address space the DPT protects.
+    OutOfRange,
+    /// The SMMU did not acknowledge SMMU_CR0.DPT_WALK_EN.
+    Timeout,
+}
+
+/// The level 0 table and the level 1 tables it points at.
+pub(crate) struct DeviceTable<H: PagingHandler> {
+    l0: VirtAddr,
+    l0_pa: PhysAddr,
+    pa_bits: u32,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> DeviceTable<H> {
+    pub const fn uninit() -> Self {
+        Self {
+            l0: va!(0xdead_beef),
+            l0_pa: pa!(0xdead_beef),
+            pa_bits: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn is_init(&self) -> bool {
+        self.pa_bits != 0
+    }
+
+    /// Allocate the level 0 table, with a block of `default` for each descriptor.
+    fn init(&mut self, pa_bits: u32, default: DevicePermission) {
+        let entries = 1usize << (pa_bits - L0DPTSZ);
+        let size = align_up_4k(entries * size_of::<u64>());
+        let l0 = H::alloc_pages(size / PAGE_SIZE_4K).expect("Failed to allocate DPT");
+        self.l0 = H::phys_to_virt(l0);
+        self.l0_pa = l0;
+        let block = L0_BLOCK | (default as u64) << L0_BLOCK_DPI_SHIFT;
+        for i in 0..entries {
+            *self.l0_entry(i) = block;
+        }
+        H::flush(self.l0.as_usize(), size);
+        self.pa_bits = pa_bits;
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn l0_entry(&self, index: usize) -> &mut u64 {
+        unsafe { &mut *(self.l0.as_mut_ptr() as *mut u64).add(index) }
+    }
+
+    fn l1_entry(desc: u64, granule: usize) -> &'static mut u64 {
+        let l1 = H::phys_to_virt(PhysAddr::from_usize((desc & L0_TABLE_ADDR_MASK) as usize));
+        unsafe { &mut *(l1.as_mut_ptr() as *mut u64).add(granule / DPIS_PER_ENTRY) }
+    }
+
+    fn permission(&self, pa: usize) -> DevicePermission {
+        let desc = *self.l0_entry(pa >> L0DPTSZ);
+        if desc & L0_TYPE_MASK == L0_BLOCK {
+            return DevicePermission::from_dpi(desc >> L0_BLOCK_DPI_SHIFT & 0b1111);
+        }
+        let granule = (pa & ((1 << L0DPTSZ) - 1)) >> 12;
+        let shift = (granule % DPIS_PER_ENTRY) * 4;
+        DevicePermission::from_dpi(*Self::l1_entry(desc, granule) >> shift & 0b1111)
+    }
+
+    /// Set the DPI of the granule at `pa`, returning whether it changed.
+    fn set(&mut self, pa: usize, perm: DevicePermission) -> bool {
+        if self.permission(pa) == perm {
+            return false;
+        }
+        let desc = self.l0_entry(pa >> L0DPTSZ);
+        if *desc & L0_TYPE_MASK == L0_BLOCK {
+            // Split the block into a level 1 table with the same DPI for every granule.
+            let dpi = *desc >> L0_BLOCK_DPI_SHIFT & 0b1111;
+            let size = L1_ENTRIES * size_of::<u64>();
+            let l1 = H::alloc_pages(size / PAGE_SIZE_4K).expect("Failed to allocate DPT");
+            let entries = H::phys_to_virt(l1).as_mut_ptr() as *mut u64;
+            for i in 0..L1_ENTRIES {
+                unsafe { entries.add(i).write(dpi * 0x1111_1111_1111_1111) };
+            }
+            H::flush(entries as usize, size);
+            *desc = L0_TABLE | l1.as_usize() as u64 & L0_TABLE_ADDR_MASK;
+            H::flush(desc as *const u64 as usize, size_of::<u64>());
+        }
+        let granule = (pa & ((1 << L0DPTSZ) - 1)) >> 12;
+        let shift = (granule % DPIS_PER_ENTRY) * 4;
+        let entry = Self::l1_entry(*desc, granule);
+        *entry = *entry & !(0b1111 << shift) | (perm as u64) << shift;
+        H::flush(entry as *const u64 as usize, size_of::<u64>());
+        true
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Whether the SMMU walks Device Permission Tables, SMMU_IDR3.DPT.
+    ///
+    /// Device Permission Tables only apply to Non-secure transactions, so this is false once
+    /// [`SMMUv3::set_secure`] selected the Secure programming interface.
+    pub fn dpt_supported(&self) -> bool {
+        !self.is_secure() && self.regs().IDR3.is_set(IDR3::DPT)
+    }
+
+    /// Build a Device Permission Table protecting the first `2^pa_bits` bytes of physical memory,
+    /// giving `default` access to all of it, and enable DPT walks.
+    ///
+    /// `pa_bits` is rounded up to a physical address size the SMMU supports, transactions beyond
+    /// it being aborted. Calling it again keeps the table already in use.
+    pub fn enable_dpt(&mut self, pa_bits: u32, default: DevicePermission) -> Result<(), DptError> {
+        if !self.dpt_supported() {
+            return Err(DptError::NotSupported);
+        }
+        if self.dpt.is_init() {
+            return Ok(());
+        }
+        let dptps = DPTPS_BITS
+            .iter()
+            .position(|&bits| bits >= pa_bits)
+            .ok_or(DptError::OutOfRange)?;
+        self.dpt.init(DPTPS_BITS[dptps], default);
+
+        self.regs()
+            .DPT_BASE
+            .write(DPT_BASE::ADDR.val(self.dpt.l0_pa.as_usize() as u64 >> 12));
+        self.regs().DPT_BASE_CFG.write(
+            DPT_BASE_CFG::L0DPTSZ::Size1GB
+                + DPT_BASE_CFG::DPTGS::Granule4KB
+                + DPT_BASE_CFG::DPTPS.val(dptps as u32),
+        );
+        self.add_cmd(Cmd::cmd_dpti_all(), true);
+        self.regs().CR0.modify(CR0::DPT_WALK_EN::Enable);
+        if !(0..ARM_SMMU_SYNC_TIMEOUT).any(|_| self.regs().CR0ACK.is_set(CR0ACK::DPT_WALK_EN)) {
+            error!("DPT walk enable timeout");
+            return Err(DptError::Timeout);
+        }
+        info!(
+            "DPT enabled for {} bits of physical address",
+            DPTPS_BITS[dptps]
+        );
+        Ok(())
+    }
+
+    /// Let non-realm devices access `[pa, pa + size)` as `perm` allows, rounded out to 4KB
+    /// granules.
+    ///
+    /// Returns once the SMMU dropped the DPT entries it cached for the range.
+    pub fn grant_device_access(
+        &mut self,
+        pa: PhysAddr,
+        size: usize,
+        perm: DevicePermission,
+    ) -> Result<(), DptError> {
+        if !self.dpt.is_init() {
+            return Err(DptError::NotEnabled);
+        }
+        let start = align_down_4k(pa.as_usize());
+        let end = align_up_4k(pa.as_usize() + size);
+        if end > 1 << self.dpt.pa_bits {
+            return Err(DptError::OutOfRange);
+        }
+
+        let mut changed = false;
+        for granule in (start..end).step_by(PAGE_SIZE_4K) {
+            changed |= self.dpt.set(granule, perm);
+        }
+        if !changed {
+            return Ok(());
+        }
+        if (end - start) / PAGE_SIZE_4K > DPTI_MAX_CMDS {
+            self.add_cmd(Cmd::cmd_dpti_all(), true);
+        } else {
+            let cmds = (start..end)
+                .step_by(PAGE_SIZE_4K)
+                .map(|granule| Cmd::cmd_dpti_pa(granule as u64));
+            self.add_cmds(cmds, true);
+        }
+        Ok(())
+    }
+
+    /// Forbid non-realm devices to access `[pa, pa + size)`, rounded out to 4KB granules.
+    ///
+    /// Returns once the SMMU dropped the DPT entries it cached for the range, so that the memory
+    /// can then be handed to a realm.
+    pub fn revoke_device_access(&mut self, pa: PhysAddr, size: usize) -> Result<(), DptError> {
+        self.grant_device_access(pa, size, DevicePermission::None)
+    }
+
+    /// Access non-realm devices have to the granule at `pa`, `None` without a DPT.
+    pub fn device_permission(&self, pa: PhysAddr) -> Option<DevicePermission> {
+        (self.dpt.is_init() && pa.as_usize() >> self.dpt.pa_bits == 0)
+            .then(|| self.dpt.permission(pa.as_usize()))
+    }
+}
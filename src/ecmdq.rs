@@ -0,0 +1,257 @@
+//! Enhanced Command queues.
+//!
+//! From SMMUv3.3, an SMMU may implement Enhanced Command queues (ECMDQ) next to the Command queue,
+//! each with its own base, producer and consumer registers, grouped in Command queue control pages.
+//! SMMU_IDR6 gives the number of control pages and of queues in each page.
+//!
+//! [`SMMUv3::enable_ecmdqs`] gives one queue to each CPU, and commands are then issued to the
+//! queue of the CPU returned by [`PagingHandler::current_cpu`] instead of the Command queue, so
+//! that CPUs issuing invalidations do not contend on a single queue. CPUs sharing a queue issue
+//! commands to it concurrently, as they do to the Command queue.
+//!
+//! A queue stops on an illegal command, toggling ECMDQ_CONS.ERR. The CPU waiting for its
+//! commands replaces the command by a CMD_SYNC and acknowledges the error by making
+//! ECMDQ_PROD.ERRACK equal to ECMDQ_CONS.ERR, the queue then resuming from the CMD_SYNC.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use memory_addr::PhysAddr;
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::backend::{CmdqControlPageRegs, EcmdqRegs, RegisterBackend};
+use crate::cmdq::CmdQueue;
+use crate::hal::PagingHandler;
+use crate::queue::Cmd;
+use crate::{
+    SMMUv3, ARM_SMMU_SYNC_TIMEOUT, CMDQ_BASE, CMDQ_CONTROL_PAGE_BASE, CMDQ_CONTROL_PAGE_CFG,
+    CMDQ_CONTROL_PAGE_STATUS, ECMDQ_CONS, ECMDQ_PROD, IDR1, IDR6,
+};
+
+/// Enhanced Command queues used at most, CPUs beyond share them.
+const MAX_ECMDQS: usize = 64;
+/// Distance between the registers of successive queues of a control page.
+const ECMDQ_STRIDE: usize = 0x80;
+
+/// Reasons [`SMMUv3::enable_ecmdqs`] fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcmdqError {
+    /// SMMU_IDR1.ECMDQ is clear.
+    NotSupported,
+    /// A control page lies below the base of the SMMU registers.
+    BadControlPage,
+    /// The SMMU did not acknowledge the enable of a control page or queue.
+    Timeout,
+}
+
+/// The Enhanced Command queues in use, the first `count` ones.
+pub(crate) struct Ecmdqs<H: PagingHandler> {
+    queues: [CmdQueue<H>; MAX_ECMDQS],
+    /// Offsets of the queue registers from SMMU page 0.
+    offsets: [usize; MAX_ECMDQS],
+    /// Value last written to the ECMDQ_PROD register of each queue.
+    prods: [AtomicU32; MAX_ECMDQS],
+    /// Serialize the writes of ECMDQ_PROD, so that ERRACK and WR updates are not lost.
+    prod_locks: [AtomicBool; MAX_ECMDQS],
+    count: usize,
+}
+
+impl<H: PagingHandler> Ecmdqs<H> {
+    pub const fn uninit() -> Self {
+        Self {
+            queues: [const { CmdQueue::uninit() }; MAX_ECMDQS],
+            offsets: [0; MAX_ECMDQS],
+            prods: [const { AtomicU32::new(0) }; MAX_ECMDQS],
+            prod_locks: [const { AtomicBool::new(false) }; MAX_ECMDQS],
+            count: 0,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Whether the SMMU implements Enhanced Command queues, SMMU_IDR1.ECMDQ, for the Non-secure
+    /// programming interface.
+    pub fn ecmdq_supported(&self) -> bool {
+        !self.is_secure() && self.regs().IDR1.is_set(IDR1::ECMDQS)
+    }
+
+    /// Number of Enhanced Command queues implemented, according to SMMU_IDR6.
+    pub fn ecmdq_capacity(&self) -> usize {
+        if !self.ecmdq_supported() {
+            return 0;
+        }
+        let idr6 = self.regs().IDR6.extract();
+        1 << (idr6.read(IDR6::LOG2NUMP) + idr6.read(IDR6::LOG2NUMQ))
+    }
+
+    /// Number of Enhanced Command queues in use, 0 when commands go to the Command queue.
+    pub fn ecmdqs(&self) -> usize {
+        self.ecmdqs.count()
+    }
+
+    /// Enable an Enhanced Command queue for each of the `cpus` CPUs, returning the number of
+    /// queues in use.
+    ///
+    /// `base` is the physical address of the SMMU registers, the control pages being located by
+    /// their physical address. With fewer queues than CPUs, CPUs share queues. Calling it again
+    /// keeps the queues already in use.
+    pub fn enable_ecmdqs(&mut self, base: PhysAddr, cpus: usize) -> Result<usize, EcmdqError> {
+        if !self.ecmdq_supported() {
+            return Err(EcmdqError::NotSupported);
+        }
+        if self.ecmdqs.count != 0 {
+            return Ok(self.ecmdqs.count);
+        }
+        let count = cpus.min(self.ecmdq_capacity()).min(MAX_ECMDQS);
+        let log2numq = self.regs().IDR6.read(IDR6::LOG2NUMQ);
+        let qs = H::CMDQ_EVENTQ_BITS_SET;
+
+        for i in 0..count {
+            let page = CmdqControlPageRegs::new(&self.backend, i >> log2numq);
+            let index = i & ((1 << log2numq) - 1);
+            if index == 0 && !page.STATUS.is_set(CMDQ_CONTROL_PAGE_STATUS::ENACK) {
+                page.CFG.write(CMDQ_CONTROL_PAGE_CFG::EN::SET);
+                if !(0..ARM_SMMU_SYNC_TIMEOUT)
+                    .any(|_| page.STATUS.is_set(CMDQ_CONTROL_PAGE_STATUS::ENACK))
+                {
+                    error!("ECMDQ control page {} enable timeout", i >> log2numq);
+                    return Err(EcmdqError::Timeout);
+                }
+            }
+            let page_addr = page.BASE.read(CMDQ_CONTROL_PAGE_BASE::ADDR) << 16;
+            let page_offset = page_addr
+                .checked_sub(base.as_usize() as u64)
+                .ok_or(EcmdqError::BadControlPage)?;
+            let offset = page_offset as usize + index * ECMDQ_STRIDE;
+
+            let queue = &mut self.ecmdqs.queues[i];
+            queue.init(qs);
+            let regs = EcmdqRegs::new(&self.backend, offset);
+            regs.BASE.write(
+                CMDQ_BASE::RA::ReadAllocate
+                    + CMDQ_BASE::ADDR.val(queue.base_addr().as_usize() as u64 >> 5)
+                    + CMDQ_BASE::LOG2SIZE.val(qs as _),
+            );
+            let wr = ECMDQ_PROD::WR.val(queue.prod_value());
+            regs.PROD.write(wr);
+            self.ecmdqs.offsets[i] = offset;
+            self.ecmdqs.prods[i] = AtomicU32::new(wr.value);
+            self.enable_ecmdq(i)?;
+            self.ecmdqs.count = i + 1;
+        }
+        info!("{} ECMDQs enabled", self.ecmdqs.count);
+        Ok(self.ecmdqs.count)
+    }
+
+    /// Disable the Enhanced Command queues in use, keeping them for [`SMMUv3::resume_ecmdqs`].
+    pub(crate) fn suspend_ecmdqs(&self) -> Result<(), EcmdqError> {
+        for i in 0..self.ecmdqs.count {
+            let regs = EcmdqRegs::new(&self.backend, self.ecmdqs.offsets[i]);
+            self.update_ecmdq_prod(i, |prod| prod & !ECMDQ_PROD::EN::SET.value);
+            if !(0..ARM_SMMU_SYNC_TIMEOUT).any(|_| !regs.CONS.is_set(ECMDQ_CONS::ENACK)) {
+                error!("ECMDQ {} disable timeout", i);
+                return Err(EcmdqError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Program and enable again the Enhanced Command queues disabled by
+    /// [`SMMUv3::suspend_ecmdqs`], whose registers may have been reset since.
+    pub(crate) fn resume_ecmdqs(&self) -> Result<(), EcmdqError> {
+        let log2numq = self.regs().IDR6.read(IDR6::LOG2NUMQ);
+        let qs = H::CMDQ_EVENTQ_BITS_SET;
+        for i in 0..self.ecmdqs.count {
+            let page = CmdqControlPageRegs::new(&self.backend, i >> log2numq);
+            if !page.STATUS.is_set(CMDQ_CONTROL_PAGE_STATUS::ENACK) {
+                page.CFG.write(CMDQ_CONTROL_PAGE_CFG::EN::SET);
+                if !(0..ARM_SMMU_SYNC_TIMEOUT)
+                    .any(|_| page.STATUS.is_set(CMDQ_CONTROL_PAGE_STATUS::ENACK))
+                {
+                    error!("ECMDQ control page {} enable timeout", i >> log2numq);
+                    return Err(EcmdqError::Timeout);
+                }
+            }
+            let queue = &self.ecmdqs.queues[i];
+            let regs = EcmdqRegs::new(&self.backend, self.ecmdqs.offsets[i]);
+            regs.BASE.write(
+                CMDQ_BASE::RA::ReadAllocate
+                    + CMDQ_BASE::ADDR.val(queue.base_addr().as_usize() as u64 >> 5)
+                    + CMDQ_BASE::LOG2SIZE.val(qs as _),
+            );
+            regs.CONS.write(ECMDQ_CONS::RD.val(queue.prod_value()));
+            self.enable_ecmdq(i)?;
+        }
+        Ok(())
+    }
+
+    /// Enable queue `i`, acknowledging any error it reports.
+    fn enable_ecmdq(&self, i: usize) -> Result<(), EcmdqError> {
+        let regs = EcmdqRegs::new(&self.backend, self.ecmdqs.offsets[i]);
+        let errack = ECMDQ_PROD::ERRACK.val(regs.CONS.read(ECMDQ_CONS::ERR));
+        let wr = ECMDQ_PROD::WR.val(self.ecmdqs.queues[i].prod_value());
+        self.update_ecmdq_prod(i, |_| (ECMDQ_PROD::EN::SET + errack + wr).value);
+        if !(0..ARM_SMMU_SYNC_TIMEOUT).any(|_| regs.CONS.is_set(ECMDQ_CONS::ENACK)) {
+            error!("ECMDQ {} enable timeout", i);
+            return Err(EcmdqError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Write ECMDQ_PROD of queue `i` with `update` of its last value.
+    fn update_ecmdq_prod(&self, i: usize, update: impl FnOnce(u32) -> u32) {
+        let lock = &self.ecmdqs.prod_locks[i];
+        while lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let prod = update(self.ecmdqs.prods[i].load(Ordering::Relaxed));
+        EcmdqRegs::new(&self.backend, self.ecmdqs.offsets[i])
+            .PROD
+            .set(prod);
+        self.ecmdqs.prods[i].store(prod, Ordering::Relaxed);
+        lock.store(false, Ordering::Release);
+    }
+
+    /// Read ECMDQ_CONS.RD of queue `i`, skipping the command it stopped on if it reports a new
+    /// error.
+    fn read_ecmdq_cons(&self, i: usize) -> u32 {
+        let regs = EcmdqRegs::new(&self.backend, self.ecmdqs.offsets[i]);
+        let cons = regs.CONS.extract();
+        let errack = ECMDQ_PROD::ERRACK.read(self.ecmdqs.prods[i].load(Ordering::Relaxed));
+        if cons.read(ECMDQ_CONS::ERR) != errack {
+            self.update_ecmdq_prod(i, |prod| {
+                // Another CPU may have acknowledged the error meanwhile.
+                let cons = regs.CONS.extract();
+                let err = cons.read(ECMDQ_CONS::ERR);
+                if err == ECMDQ_PROD::ERRACK.read(prod) {
+                    return prod;
+                }
+                warn!("ECMDQ {} ERR code {}", i, cons.read(ECMDQ_CONS::ERR_REASON));
+                self.ecmdqs.queues[i].skip_err(cons.read(ECMDQ_CONS::RD));
+                ECMDQ_PROD::ERRACK.val(err).modify(prod)
+            });
+        }
+        cons.read(ECMDQ_CONS::RD)
+    }
+
+    /// Add a batch of commands to the Enhanced Command queue of the current CPU, see
+    /// [`SMMUv3::add_cmd`].
+    pub(crate) fn add_ecmdq_cmds<I: IntoIterator<Item = Cmd>>(&self, cmds: I, sync: bool) {
+        let index = H::current_cpu() % self.ecmdqs.count;
+        self.ecmdqs.queues[index].issue(
+            cmds,
+            sync,
+            |wr| {
+                self.update_ecmdq_prod(index, |prod| ECMDQ_PROD::WR.val(wr).modify(prod));
+            },
+            || self.read_ecmdq_cons(index),
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Decoding of Event queue records and PRI queue entries.
+
+use crate::queue::{EVTQ_ENT_DWORDS, PRIQ_ENT_DWORDS};
+
+/// 7.3 Event records, Event type numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    /// 0x01, unsupported upstream transaction.
+    UnsupportedTransaction,
+    /// 0x02, StreamID out of the range of the Stream table.
+    BadStreamId,
+    /// 0x03, fetch of an STE aborted.
+    SteFetch,
+    /// 0x04, STE is invalid or misconfigured.
+    BadSte,
+    /// 0x05, ATS translation request on a stream not permitting it.
+    BadAtsRequest,
+    /// 0x06, transaction without SubstreamID on a stream requiring one.
+    StreamDisabled,
+    /// 0x07, ATS translation request not permitted by the stream configuration.
+    TranslationForbidden,
+    /// 0x08, SubstreamID out of the range of the CD table.
+    BadSubstreamId,
+    /// 0x09, fetch of a CD aborted.
+    CdFetch,
+    /// 0x0a, CD is invalid or misconfigured.
+    BadCd,
+    /// 0x0b, external abort during a translation table walk.
+    WalkExternalAbort,
+    /// 0x10, translation fault.
+    Translation,
+    /// 0x11, address size fault.
+    AddressSize,
+    /// 0x12, Access flag fault.
+    Access,
+    /// 0x13, permission fault.
+    Permission,
+    /// 0x20, TLB conflict.
+    TlbConflict,
+    /// 0x21, configuration cache conflict.
+    ConfigConflict,
+    /// 0x24, page request on a stream without PRI.
+    PageRequest,
+    /// 0x25, fetch of a VMS aborted.
+    VmsFetch,
+    /// Implementation defined or unknown event.
+    Other(u8),
+}
+
+impl EventType {
+    /// Decode an event type number.
+    pub const fn from_id(id: u8) -> Self {
+        match id {
+            0x01 => Self::UnsupportedTransaction,
+            0x02 => Self::BadStreamId,
+            0x03 => Self::SteFetch,
+            0x04 => Self::BadSte,
+            0x05 => Self::BadAtsRequest,
+            0x06 => Self::StreamDisabled,
+            0x07 => Self::TranslationForbidden,
+            0x08 => Self::BadSubstreamId,
+            0x09 => Self::CdFetch,
+            0x0a => Self::BadCd,
+            0x0b => Self::WalkExternalAbort,
+            0x10 => Self::Translation,
+            0x11 => Self::AddressSize,
+            0x12 => Self::Access,
+            0x13 => Self::Permission,
+            0x20 => Self::TlbConflict,
+            0x21 => Self::ConfigConflict,
+            0x24 => Self::PageRequest,
+            0x25 => Self::VmsFetch,
+            id => Self::Other(id),
+        }
+    }
+
+    /// Whether the record describes a faulting transaction, with the access fields valid.
+    pub const fn is_translation_fault(&self) -> bool {
+        matches!(
+            self,
+            Self::Translation | Self::AddressSize | Self::Access | Self::Permission
+        )
+    }
+}
+
+const EVTQ_0_ID_MASK: u64 = 0xff;
+const EVTQ_0_SSV: u64 = 1 << 11;
+const EVTQ_0_SSID_OFFSET: u64 = 12;
+const EVTQ_0_SSID_MASK: u64 = 0xf_ffff;
+const EVTQ_0_SID_OFFSET: u64 = 32;
+const EVTQ_0_SID_MASK: u64 = 0xffff_ffff;
+const EVTQ_1_STALL: u64 = 1 << 31;
+const EVTQ_1_PNU: u64 = 1 << 33;
+const EVTQ_1_IND: u64 = 1 << 34;
+const EVTQ_1_RNW: u64 = 1 << 35;
+const EVTQ_1_S2: u64 = 1 << 39;
+/// Output address bits [51:12].
+const ADDR_51_12_MASK: u64 = ((1 << 52) - 1) & !((1 << 12) - 1);
+
+/// An Event queue record, see 7.3 Event records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// Type of the event, from its event number.
+    pub kind: EventType,
+    /// StreamID of the transaction or configuration the event relates to.
+    pub sid: u32,
+    /// SubstreamID of the transaction, if it carried one.
+    pub ssid: Option<u32>,
+    /// Input address of the transaction.
+    pub addr: u64,
+    /// IPA of a stage 2 fault, the stage 1 output that faulted.
+    pub ipa: Option<u64>,
+    /// The transaction was a read, or a write if not.
+    pub read: bool,
+    /// The transaction was an instruction fetch.
+    pub exec: bool,
+    /// The transaction was privileged.
+    pub privileged: bool,
+    /// The fault was raised by stage 2.
+    pub stage2: bool,
+    /// The transaction is stalled, waiting for CMD_RESUME.
+    pub stall: bool,
+    /// The raw record.
+    pub raw: [u64; EVTQ_ENT_DWORDS],
+}
+
+impl Event {
+    /// Decode an Event queue record.
+    pub const fn from_raw(raw: [u64; EVTQ_ENT_DWORDS]) -> Self {
+        let kind = EventType::from_id((raw[0] & EVTQ_0_ID_MASK) as u8);
+        let ssid = if raw[0] & EVTQ_0_SSV != 0 {
+            Some(((raw[0] >> EVTQ_0_SSID_OFFSET) & EVTQ_0_SSID_MASK) as u32)
+        } else {
+            None
+        };
+        let fault = kind.is_translation_fault();
+        let stage2 = fault && raw[1] & EVTQ_1_S2 != 0;
+        Self {
+            kind,
+            sid: (raw[0] >> EVTQ_0_SID_OFFSET) as u32,
+            ssid,
+            addr: raw[2],
+            ipa: if stage2 {
+                Some(raw[3] & ADDR_51_12_MASK)
+            } else {
+                None
+            },
+            read: fault && raw[1] & EVTQ_1_RNW != 0,
+            exec: fault && raw[1] & EVTQ_1_IND != 0,
+            privileged: fault && raw[1] & EVTQ_1_PNU != 0,
+            stage2,
+            stall: fault && raw[1] & EVTQ_1_STALL != 0,
+            raw,
+        }
+    }
+
+    /// Whether the record reports a fault of the stage 1 configuration or translation, which the
+    /// guest owning the stage 1 of the stream handles.
+    pub const fn is_stage1_fault(&self) -> bool {
+        match self.kind {
+            EventType::BadSubstreamId | EventType::CdFetch | EventType::BadCd => true,
+            kind => kind.is_translation_fault() && !self.stage2,
+        }
+    }
+
+    /// The record as reported to a guest, with the StreamID replaced by the guest StreamID
+    /// `vsid`.
+    pub const fn guest_record(&self, vsid: u32) -> [u64; EVTQ_ENT_DWORDS] {
+        let mut raw = self.raw;
+        raw[0] =
+            raw[0] & !(EVTQ_0_SID_MASK << EVTQ_0_SID_OFFSET) | (vsid as u64) << EVTQ_0_SID_OFFSET;
+        raw
+    }
+}
+
+const PRIQ_0_SID_MASK: u64 = 0xffff_ffff;
+const PRIQ_0_SSID_OFFSET: u64 = 32;
+const PRIQ_0_SSID_MASK: u64 = 0xf_ffff;
+const PRIQ_0_PERM_PRIV: u64 = 1 << 58;
+const PRIQ_0_PERM_EXEC: u64 = 1 << 59;
+const PRIQ_0_PERM_READ: u64 = 1 << 60;
+const PRIQ_0_PERM_WRITE: u64 = 1 << 61;
+const PRIQ_0_PRG_LAST: u64 = 1 << 62;
+const PRIQ_0_SSID_V: u64 = 1 << 63;
+const PRIQ_1_PRG_IDX_MASK: u64 = 0x1ff;
+const PRIQ_1_ADDR_MASK: u64 = !((1 << 12) - 1);
+
+/// A PCIe Page Request from the PRI queue, see 8.1 PRI queue entry format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRequest {
+    /// StreamID of the requesting device.
+    pub sid: u32,
+    /// PASID of the request, if it carried one.
+    pub ssid: Option<u32>,
+    /// Page address, 4KB aligned.
+    pub addr: u64,
+    /// Page Request Group index.
+    pub prg_index: u16,
+    /// Last request of its group, the device waits for a response to the group.
+    pub last: bool,
+    /// Read access is requested.
+    pub read: bool,
+    /// Write access is requested.
+    pub write: bool,
+    /// Execute access is requested.
+    pub exec: bool,
+    /// Privileged access is requested.
+    pub privileged: bool,
+}
+
+impl PageRequest {
+    /// Decode a PRI queue entry.
+    pub const fn from_raw(raw: [u64; PRIQ_ENT_DWORDS]) -> Self {
+        let ssid = if raw[0] & PRIQ_0_SSID_V != 0 {
+            Some(((raw[0] >> PRIQ_0_SSID_OFFSET) & PRIQ_0_SSID_MASK) as u32)
+        } else {
+            None
+        };
+        Self {
+            sid: (raw[0] & PRIQ_0_SID_MASK) as u32,
+            ssid,
+            addr: raw[1] & PRIQ_1_ADDR_MASK,
+            prg_index: (raw[1] & PRIQ_1_PRG_IDX_MASK) as u16,
+            last: raw[0] & PRIQ_0_PRG_LAST != 0,
+            read: raw[0] & PRIQ_0_PERM_READ != 0,
+            write: raw[0] & PRIQ_0_PERM_WRITE != 0,
+            exec: raw[0] & PRIQ_0_PERM_EXEC != 0,
+            privileged: raw[0] & PRIQ_0_PERM_PRIV != 0,
+        }
+    }
+}
+
+/// Response to a Page Request Group, CMD_PRI_RESP.Resp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriResponse {
+    /// Invalid Request, the device must not issue further page requests.
+    Denied = 0b00,
+    /// Response Failure, the pages could not be made resident.
+    Failure = 0b01,
+    /// Success, the device retries the translation.
+    Success = 0b10,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_event() {
+        // Stage 2 translation fault on a write with SubstreamID 5.
+        let ev = Event::from_raw([
+            0x10 | 1 << 11 | 5 << 12 | 0x42 << 32,
+            1 << 39,
+            0x1234,
+            0x8000_5000,
+        ]);
+        assert_eq!(ev.kind, EventType::Translation);
+        assert_eq!((ev.sid, ev.ssid), (0x42, Some(5)));
+        assert_eq!((ev.addr, ev.ipa), (0x1234, Some(0x8000_5000)));
+        assert!(ev.stage2 && !ev.read && !ev.exec);
+
+        let ev = Event::from_raw([0x04 | 7 << 32, u64::MAX, 0, 0]);
+        assert_eq!(
+            (ev.kind, ev.sid, ev.ssid, ev.stage2),
+            (EventType::BadSte, 7, None, false)
+        );
+
+        let req = PageRequest::from_raw([
+            3 | 9 << 32 | PRIQ_0_SSID_V | PRIQ_0_PRG_LAST | PRIQ_0_PERM_READ,
+            0x7000 | 0x1a,
+        ]);
+        assert_eq!(
+            (req.sid, req.ssid, req.addr, req.prg_index),
+            (3, Some(9), 0x7000, 0x1a)
+        );
+        assert!(req.last && req.read && !req.write);
+    }
+}
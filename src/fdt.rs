@@ -0,0 +1,637 @@
+//! Discovery of the SMMUs and their masters in a flattened device tree.
+//!
+//! [`Fdt`] reads the `arm,smmu-v3` nodes of a DTB, see the Linux
+//! `Documentation/devicetree/bindings/iommu/arm,smmu-v3.yaml` binding, and the `iommus` and
+//! `iommu-map` properties of the nodes mastering through them. The blob is parsed in place,
+//! without allocating.
+
+use core::str;
+
+use memory_addr::PhysAddr;
+
+use crate::hal::PagingHandler;
+use crate::stream_id::RidMap;
+use crate::SMMUv3;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// Deepest node nesting followed, deeper nodes end the walk.
+const MAX_DEPTH: usize = 16;
+/// Most cells of an interrupt specifier, 3 for a GIC.
+const MAX_IRQ_CELLS: usize = 4;
+
+const SMMUV3_COMPATIBLE: &str = "arm,smmu-v3";
+
+/// Reasons a blob is not a usable device tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    /// The header does not start with the FDT magic.
+    BadMagic,
+    /// The blob is shorter than the header says.
+    Truncated,
+}
+
+fn be32(bytes: &[u8], off: usize) -> Option<u32> {
+    let b = bytes.get(off..off.checked_add(4)?)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+const fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// The NUL terminated string at the start of `bytes`.
+fn c_str(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|&b| b == 0)?;
+    str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Iterator over the cells of a property value.
+fn cells(value: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    value
+        .chunks_exact(4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Iterator over the strings of a stringlist property value.
+fn strings(value: &[u8]) -> impl Iterator<Item = &str> {
+    value
+        .split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| str::from_utf8(s).ok())
+}
+
+/// A flattened device tree blob.
+#[derive(Debug, Clone, Copy)]
+pub struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Check the header of `blob`, which must hold the whole tree.
+    pub fn new(blob: &'a [u8]) -> Result<Self, FdtError> {
+        if be32(blob, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let field = |off| {
+            be32(blob, off)
+                .map(|v| v as usize)
+                .ok_or(FdtError::Truncated)
+        };
+        if field(4)? > blob.len() {
+            return Err(FdtError::Truncated);
+        }
+        let (off_struct, off_strings) = (field(8)?, field(12)?);
+        let (size_strings, size_struct) = (field(32)?, field(36)?);
+        let region = |off: usize, size: usize| {
+            let end = off.checked_add(size).ok_or(FdtError::Truncated)?;
+            blob.get(off..end).ok_or(FdtError::Truncated)
+        };
+        Ok(Self {
+            structs: region(off_struct, size_struct)?,
+            strings: region(off_strings, size_strings)?,
+        })
+    }
+
+    fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            fdt: *self,
+            off: 0,
+            depth: 0,
+            ctx: [NodeContext::ROOT; MAX_DEPTH + 1],
+        }
+    }
+
+    fn node_by_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.nodes().find(|node| node.phandle() == Some(phandle))
+    }
+
+    /// The `arm,smmu-v3` nodes, in tree order.
+    pub fn smmus(&self) -> impl Iterator<Item = FdtSmmu<'a>> + 'a {
+        let fdt = *self;
+        self.nodes()
+            .filter(|node| node.is_compatible(SMMUV3_COMPATIBLE))
+            .filter_map(move |node| FdtSmmu::from_node(&fdt, &node))
+    }
+
+    /// The StreamIDs of the masters, from their `iommus` and `iommu-map` properties referring to
+    /// an `arm,smmu-v3` node.
+    pub fn stream_maps(&self) -> impl Iterator<Item = FdtStreamMap<'a>> + 'a {
+        let fdt = *self;
+        self.nodes()
+            .flat_map(move |node| StreamMaps::new(fdt, node))
+    }
+}
+
+/// Inherited properties, from the parent of a node.
+#[derive(Debug, Clone, Copy)]
+struct NodeContext {
+    address_cells: u32,
+    size_cells: u32,
+    interrupt_parent: Option<u32>,
+}
+
+impl NodeContext {
+    /// Defaults of the children of the root node, see the Devicetree Specification 2.3.5.
+    const ROOT: Self = Self {
+        address_cells: 2,
+        size_cells: 1,
+        interrupt_parent: None,
+    };
+}
+
+/// A node and the properties it inherits from its parent.
+#[derive(Debug, Clone, Copy)]
+struct Node<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// Offset of its first property in the structure block.
+    props: usize,
+    parent: NodeContext,
+}
+
+impl<'a> Node<'a> {
+    fn props(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> {
+        let fdt = self.fdt;
+        let mut off = self.props;
+        core::iter::from_fn(move || loop {
+            match be32(fdt.structs, off)? {
+                FDT_NOP => off += 4,
+                FDT_PROP => {
+                    let len = be32(fdt.structs, off + 4)? as usize;
+                    let name_off = be32(fdt.structs, off + 8)? as usize;
+                    let value = fdt.structs.get(off + 12..off + 12 + len)?;
+                    let name = c_str(fdt.strings.get(name_off..)?)?;
+                    off = align4(off + 12 + len);
+                    return Some((name, value));
+                }
+                _ => return None,
+            }
+        })
+    }
+
+    fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle")
+            .or_else(|| self.prop_u32("linux,phandle"))
+    }
+
+    fn is_compatible(&self, compatible: &str) -> bool {
+        self.prop("compatible")
+            .is_some_and(|v| strings(v).any(|s| s == compatible))
+    }
+
+    fn interrupt_parent(&self) -> Option<u32> {
+        self.prop_u32("interrupt-parent")
+            .or(self.parent.interrupt_parent)
+    }
+
+    /// Context of the children of this node.
+    fn child_context(&self) -> NodeContext {
+        NodeContext {
+            address_cells: self.prop_u32("#address-cells").unwrap_or(2),
+            size_cells: self.prop_u32("#size-cells").unwrap_or(1),
+            interrupt_parent: self.interrupt_parent(),
+        }
+    }
+
+    /// The first address and size of its `reg` property.
+    fn reg(&self) -> Option<(u64, u64)> {
+        let mut reg = cells(self.prop("reg")?);
+        let mut read =
+            |n: u32| (0..n).try_fold(0u64, |acc, _| Some(acc << 32 | reg.next()? as u64));
+        let addr = read(self.parent.address_cells)?;
+        let size = read(self.parent.size_cells)?;
+        Some((addr, size))
+    }
+}
+
+/// Depth first iterator over the nodes of the structure block.
+struct Nodes<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+    depth: usize,
+    /// Context of the nodes at each depth.
+    ctx: [NodeContext; MAX_DEPTH + 1],
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        loop {
+            match be32(self.fdt.structs, self.off)? {
+                FDT_BEGIN_NODE => {
+                    if self.depth == MAX_DEPTH {
+                        return None;
+                    }
+                    let name = c_str(self.fdt.structs.get(self.off + 4..)?)?;
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        props: align4(self.off + 4 + name.len() + 1),
+                        parent: self.ctx[self.depth],
+                    };
+                    self.depth += 1;
+                    self.ctx[self.depth] = node.child_context();
+                    self.off = node.props;
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                    self.off += 4;
+                }
+                FDT_PROP => {
+                    let len = be32(self.fdt.structs, self.off + 4)? as usize;
+                    self.off = align4(self.off + 12 + len);
+                }
+                FDT_NOP => self.off += 4,
+                // FDT_END, or a malformed block.
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// An interrupt specifier, in the format of the interrupt controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqSpec {
+    cells: [u32; MAX_IRQ_CELLS],
+    len: usize,
+}
+
+impl IrqSpec {
+    /// The cells of the specifier, such as type, number and flags for a GIC.
+    pub fn cells(&self) -> &[u32] {
+        &self.cells[..self.len]
+    }
+}
+
+/// The interrupts of an SMMU, by `interrupt-names`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SmmuIrqs {
+    /// Event queue interrupt, `eventq`.
+    pub eventq: Option<IrqSpec>,
+    /// PRI queue interrupt, `priq`.
+    pub priq: Option<IrqSpec>,
+    /// Global error interrupt, `gerror`.
+    pub gerror: Option<IrqSpec>,
+    /// Single interrupt for all the sources, see [`SMMUv3::handle_combined_irq`].
+    pub combined: Option<IrqSpec>,
+}
+
+/// An `arm,smmu-v3` node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdtSmmu<'a> {
+    /// Node name, with its unit address.
+    pub name: &'a str,
+    /// phandle of the node, referred to by the `iommus` and `iommu-map` of its masters.
+    pub phandle: Option<u32>,
+    /// Base of the register frame.
+    pub base: PhysAddr,
+    /// Size of the register frame.
+    pub size: usize,
+    /// Wired interrupts of the SMMU.
+    pub irqs: SmmuIrqs,
+    /// Table walks and queue accesses are coherent with the CPU caches.
+    pub dma_coherent: bool,
+    /// phandle of the MSI controller, such as a GIC ITS.
+    pub msi_parent: Option<u32>,
+}
+
+impl<'a> FdtSmmu<'a> {
+    fn from_node(fdt: &Fdt<'a>, node: &Node<'a>) -> Option<Self> {
+        let Some((base, size)) = node.reg() else {
+            warn!("SMMUv3 node {} without reg", node.name);
+            return None;
+        };
+        Some(Self {
+            name: node.name,
+            phandle: node.phandle(),
+            base: PhysAddr::from_usize(base as usize),
+            size: size as usize,
+            irqs: Self::irqs(fdt, node).unwrap_or_default(),
+            dma_coherent: node.prop("dma-coherent").is_some(),
+            msi_parent: node.prop_u32("msi-parent"),
+        })
+    }
+
+    fn irqs(fdt: &Fdt<'a>, node: &Node<'a>) -> Option<SmmuIrqs> {
+        let controller = fdt.node_by_phandle(node.interrupt_parent()?)?;
+        let n = controller.prop_u32("#interrupt-cells")? as usize;
+        if n == 0 || n > MAX_IRQ_CELLS {
+            return None;
+        }
+        let specs = node.prop("interrupts")?.chunks_exact(4 * n).map(|chunk| {
+            let mut spec = IrqSpec {
+                cells: [0; MAX_IRQ_CELLS],
+                len: n,
+            };
+            for (cell, value) in spec.cells.iter_mut().zip(cells(chunk)) {
+                *cell = value;
+            }
+            spec
+        });
+
+        let mut irqs = SmmuIrqs::default();
+        for (name, spec) in strings(node.prop("interrupt-names")?).zip(specs) {
+            match name {
+                "eventq" => irqs.eventq = Some(spec),
+                "priq" => irqs.priq = Some(spec),
+                "gerror" => irqs.gerror = Some(spec),
+                "combined" => irqs.combined = Some(spec),
+                // CMD_SYNC completion is polled.
+                "cmdq-sync" => {}
+                _ => warn!("SMMUv3 node {}: unknown interrupt {}", node.name, name),
+            }
+        }
+        Some(irqs)
+    }
+
+    /// A driver instance for this SMMU, accessing its registers at `H::phys_to_virt(base)`.
+    pub fn smmu<H: PagingHandler>(&self) -> SMMUv3<H> {
+        SMMUv3::new(H::phys_to_virt(self.base).as_mut_ptr())
+    }
+}
+
+/// StreamIDs of a master node, from one entry of its `iommus` or `iommu-map` property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdtStreamMap<'a> {
+    /// Node name of the master, with its unit address.
+    pub master: &'a str,
+    /// Index of the SMMU in [`Fdt::smmus`].
+    pub smmu: usize,
+    /// First requester ID of an `iommu-map` entry, such as a PCIe requester ID, or `None` for an
+    /// `iommus` entry, naming the StreamID of the master itself.
+    pub rid_base: Option<u32>,
+    /// StreamID of the first requester ID.
+    pub sid_base: u32,
+    /// Number of requester IDs in the entry, 1 for an `iommus` entry.
+    pub len: u32,
+}
+
+impl FdtStreamMap<'_> {
+    /// The mapping of the requester IDs of an `iommu-map` entry to StreamIDs.
+    pub fn rid_map(&self) -> Option<RidMap> {
+        Some(RidMap {
+            rid_base: self.rid_base?,
+            sid_base: self.sid_base,
+            len: self.len,
+        })
+    }
+}
+
+/// Iterator over the entries of the `iommus` and `iommu-map` properties of a node.
+struct StreamMaps<'a> {
+    fdt: Fdt<'a>,
+    master: &'a str,
+    iommus: &'a [u8],
+    iommu_map: &'a [u8],
+}
+
+impl<'a> StreamMaps<'a> {
+    fn new(fdt: Fdt<'a>, node: Node<'a>) -> Self {
+        Self {
+            fdt,
+            master: node.name,
+            iommus: node.prop("iommus").unwrap_or_default(),
+            iommu_map: node.prop("iommu-map").unwrap_or_default(),
+        }
+    }
+
+    /// Index of the SMMU with `phandle`, and its `#iommu-cells`.
+    fn smmu(&self, phandle: u32) -> Option<(Option<usize>, usize)> {
+        let node = self.fdt.node_by_phandle(phandle)?;
+        let iommu_cells = node.prop_u32("#iommu-cells").unwrap_or(1) as usize;
+        let index = self
+            .fdt
+            .smmus()
+            .position(|smmu| smmu.phandle == Some(phandle));
+        Some((index, iommu_cells))
+    }
+
+    /// Split the first `n` cells off `prop`.
+    fn take(prop: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        let (head, tail) = prop.split_at_checked(4 * n)?;
+        *prop = tail;
+        Some(head)
+    }
+
+    /// Next entry of `iommus`: `<phandle sid>`.
+    fn next_iommus(&mut self) -> Option<Option<FdtStreamMap<'a>>> {
+        let phandle = be32(Self::take(&mut self.iommus, 1)?, 0)?;
+        let (smmu, n) = self.smmu(phandle)?;
+        let args = Self::take(&mut self.iommus, n)?;
+        Some(smmu.map(|smmu| FdtStreamMap {
+            master: self.master,
+            smmu,
+            rid_base: None,
+            sid_base: be32(args, 0).unwrap_or(0),
+            len: 1,
+        }))
+    }
+
+    /// Next entry of `iommu-map`: `<rid-base phandle sid-base length>`.
+    fn next_iommu_map(&mut self) -> Option<Option<FdtStreamMap<'a>>> {
+        let head = Self::take(&mut self.iommu_map, 2)?;
+        let (rid_base, phandle) = (be32(head, 0)?, be32(head, 4)?);
+        let (smmu, n) = self.smmu(phandle)?;
+        let sid_base = be32(Self::take(&mut self.iommu_map, n)?, 0).unwrap_or(0);
+        let len = be32(Self::take(&mut self.iommu_map, 1)?, 0)?;
+        Some(smmu.map(|smmu| FdtStreamMap {
+            master: self.master,
+            smmu,
+            rid_base: Some(rid_base),
+            sid_base,
+            len,
+        }))
+    }
+}
+
+impl<'a> Iterator for StreamMaps<'a> {
+    type Item = FdtStreamMap<'a>;
+
+    fn next(&mut self) -> Option<FdtStreamMap<'a>> {
+        // Entries referring to other IOMMUs are skipped, a malformed property ends its walk.
+        while !self.iommus.is_empty() {
+            match self.next_iommus() {
+                Some(Some(map)) => return Some(map),
+                Some(None) => {}
+                None => self.iommus = &[],
+            }
+        }
+        while !self.iommu_map.is_empty() {
+            match self.next_iommu_map() {
+                Some(Some(map)) => return Some(map),
+                Some(None) => {}
+                None => self.iommu_map = &[],
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::StreamId;
+
+    /// Minimal DTB writer, for the nodes and properties of the tests.
+    struct Builder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.structs.extend(FDT_BEGIN_NODE.to_be_bytes());
+            self.structs.extend(name.as_bytes());
+            self.structs.push(0);
+            self.structs.resize(align4(self.structs.len()), 0);
+            self
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_off = self.strings.len() as u32;
+            self.strings.extend(name.as_bytes());
+            self.strings.push(0);
+            self.structs.extend(FDT_PROP.to_be_bytes());
+            self.structs.extend((value.len() as u32).to_be_bytes());
+            self.structs.extend(name_off.to_be_bytes());
+            self.structs.extend(value);
+            self.structs.resize(align4(self.structs.len()), 0);
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.structs.extend(FDT_END_NODE.to_be_bytes());
+            self
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            self.structs.extend(9u32.to_be_bytes());
+            let off_struct = 40;
+            let off_strings = off_struct + self.structs.len();
+            let total = off_strings + self.strings.len();
+            let header = [
+                FDT_MAGIC,
+                total as u32,
+                off_struct as u32,
+                off_strings as u32,
+                40,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structs.len() as u32,
+            ];
+            let mut blob: Vec<u8> = header.iter().flat_map(|c| c.to_be_bytes()).collect();
+            blob.extend(&self.structs);
+            blob.extend(&self.strings);
+            blob
+        }
+    }
+
+    #[test]
+    fn test_parse_smmu() {
+        let mut b = Builder {
+            structs: Vec::new(),
+            strings: Vec::new(),
+        };
+        let blob = b
+            .begin("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .cells("interrupt-parent", &[1])
+            .begin("intc@8000000")
+            .cells("phandle", &[1])
+            .cells("#interrupt-cells", &[3])
+            .end()
+            .begin("its@8080000")
+            .cells("phandle", &[2])
+            .end()
+            .begin("smmuv3@9050000")
+            .prop("compatible", b"arm,smmu-v3\0")
+            .cells("reg", &[0, 0x0905_0000, 0, 0x20000])
+            .cells("interrupts", &[0, 74, 1, 0, 75, 1, 0, 77, 1, 0, 76, 1])
+            .prop("interrupt-names", b"eventq\0priq\0cmdq-sync\0gerror\0")
+            .prop("dma-coherent", &[])
+            .cells("msi-parent", &[2])
+            .cells("#iommu-cells", &[1])
+            .cells("phandle", &[3])
+            .end()
+            .begin("pcie@10000000")
+            .cells("iommu-map", &[0, 3, 0, 0x10000])
+            .end()
+            .begin("ethernet@9060000")
+            .cells("iommus", &[3, 0x42])
+            .end()
+            .end()
+            .finish();
+
+        assert_eq!(Fdt::new(&blob[1..]).unwrap_err(), FdtError::BadMagic);
+        assert_eq!(Fdt::new(&blob[..64]).unwrap_err(), FdtError::Truncated);
+
+        let fdt = Fdt::new(&blob).unwrap();
+        let smmus: Vec<_> = fdt.smmus().collect();
+        assert_eq!(smmus.len(), 1);
+        let smmu = &smmus[0];
+        assert_eq!(smmu.name, "smmuv3@9050000");
+        assert_eq!(
+            (smmu.base, smmu.size),
+            (PhysAddr::from_usize(0x0905_0000), 0x20000)
+        );
+        assert_eq!(smmu.irqs.eventq.unwrap().cells(), &[0, 74, 1]);
+        assert_eq!(smmu.irqs.gerror.unwrap().cells(), &[0, 76, 1]);
+        assert_eq!(smmu.irqs.combined, None);
+        assert!(smmu.dma_coherent);
+        assert_eq!((smmu.phandle, smmu.msi_parent), (Some(3), Some(2)));
+
+        let maps: Vec<_> = fdt.stream_maps().collect();
+        assert_eq!(
+            maps[0].rid_map().unwrap().map(0x0308),
+            Some(StreamId::new(0x0308))
+        );
+        assert_eq!(maps[1].rid_map(), None);
+        assert_eq!(
+            maps,
+            [
+                FdtStreamMap {
+                    master: "pcie@10000000",
+                    smmu: 0,
+                    rid_base: Some(0),
+                    sid_base: 0,
+                    len: 0x10000
+                },
+                FdtStreamMap {
+                    master: "ethernet@9060000",
+                    smmu: 0,
+                    rid_base: None,
+                    sid_base: 0x42,
+                    len: 1
+                },
+            ]
+        );
+    }
+}
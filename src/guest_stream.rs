@@ -0,0 +1,117 @@
+//! StreamIDs of the devices assigned to guests.
+//!
+//! A guest driving an emulated SMMU, see [`VSmmu`], numbers the streams of its devices with its
+//! own StreamIDs. The hypervisor records the guest and guest StreamID of each assigned stream with
+//! [`SMMUv3::set_guest_stream`], so that the stage 1 faults of the stream are reported to the
+//! [`FaultHandler`] with them, to be forwarded to the guest.
+//!
+//! [`VSmmu`]: crate::VSmmu
+//! [`FaultHandler`]: crate::FaultHandler
+
+use core::marker::PhantomData;
+
+use memory_addr::{align_up_4k, va, VirtAddr, PAGE_SIZE_4K};
+
+use crate::backend::RegisterBackend;
+use crate::hal::PagingHandler;
+use crate::stream_id::StreamId;
+use crate::SMMUv3;
+
+/// The guest a stream is assigned to, and the StreamID of the stream in the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestStream {
+    /// Identifier of the guest, chosen by the hypervisor.
+    pub vm: u16,
+    /// Guest StreamID of the stream.
+    pub vsid: StreamId,
+}
+
+/// [`GuestStream`] of a StreamID, valid when `assigned` is not zero.
+#[repr(C)]
+struct GuestStreamEntry {
+    vm: u16,
+    assigned: u16,
+    vsid: u32,
+}
+
+/// Guest StreamIDs of all the StreamIDs.
+///
+/// The entries live in pages obtained from [`PagingHandler::alloc_pages`], allocated the first
+/// time a stream is assigned to a guest.
+pub(crate) struct GuestStreams<H: PagingHandler> {
+    base: VirtAddr,
+    entry_count: usize,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> GuestStreams<H> {
+    pub const fn uninit() -> Self {
+        Self {
+            base: va!(0xdead_beef),
+            entry_count: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn is_init(&self) -> bool {
+        self.entry_count != 0
+    }
+
+    /// Allocate unassigned entries for `entry_count` StreamIDs, unless already done.
+    fn init(&mut self, entry_count: usize) {
+        if self.is_init() {
+            return;
+        }
+        let size = align_up_4k(entry_count * size_of::<GuestStreamEntry>());
+        let base = H::alloc_pages(size / PAGE_SIZE_4K).expect("Failed to allocate guest streams");
+        self.base = H::phys_to_virt(base);
+        unsafe { core::ptr::write_bytes(self.base.as_mut_ptr(), 0, size) };
+        self.entry_count = entry_count;
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn entry(&self, sid: usize) -> &mut GuestStreamEntry {
+        debug_assert!(sid < self.entry_count);
+        unsafe { &mut *(self.base.as_mut_ptr() as *mut GuestStreamEntry).add(sid) }
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Record that `sid` is assigned to `guest`, whose stage 1 faults are then reported with
+    /// [`FaultHandler::on_guest_event`].
+    ///
+    /// The assignment is kept until [`SMMUv3::clear_guest_stream`], whatever happens to the STE.
+    ///
+    /// [`FaultHandler::on_guest_event`]: crate::FaultHandler::on_guest_event
+    pub fn set_guest_stream(&mut self, sid: StreamId, guest: GuestStream) {
+        if sid.as_usize() >= self.stream_table.entry_count() {
+            warn!("Guest stream of out of range sid {:#x}", sid);
+            return;
+        }
+        self.guest_streams.init(self.stream_table.entry_count());
+        *self.guest_streams.entry(sid.as_usize()) = GuestStreamEntry {
+            vm: guest.vm,
+            assigned: 1,
+            vsid: guest.vsid.as_u32(),
+        };
+    }
+
+    /// Forget the guest `sid` is assigned to.
+    pub fn clear_guest_stream(&mut self, sid: StreamId) {
+        if self.guest_stream(sid).is_some() {
+            self.guest_streams.entry(sid.as_usize()).assigned = 0;
+        }
+    }
+
+    /// The guest `sid` is assigned to, if any.
+    pub fn guest_stream(&self, sid: StreamId) -> Option<GuestStream> {
+        if !self.guest_streams.is_init() || sid.as_usize() >= self.guest_streams.entry_count {
+            return None;
+        }
+        let entry = self.guest_streams.entry(sid.as_usize());
+        (entry.assigned != 0).then_some(GuestStream {
+            vm: entry.vm,
+            vsid: StreamId::new(entry.vsid),
+        })
+    }
+}
@@ -16,7 +16,7 @@ pub trait PagingHandler: Sized {
     /// Note: This means that configuring a table that is larger than required by the incoming StreamID span results
     /// in some entries being unreachable, but the table is still aligned to the configured size.
     /// For example, SID_BITS_SET = 16, when alloc page alignment is to 2^(16 + 6) = 2^22 = 4MB.
-    const SID_BITS_SET: u32 ;
+    const SID_BITS_SET: u32;
 
     /// 6.3.26 SMMU_CMDQ_BASE
     /// • The effective base address is aligned by the SMMU to the larger of the queue size in bytes or 32 bytes,
@@ -25,7 +25,7 @@ pub trait PagingHandler: Sized {
     /// and therefore ADDR, to a 4KB boundary
     /// 2^8*16=4096 bytes.this means 256 entries, 16 bytes per entry.
     const CMDQ_EVENTQ_BITS_SET: u32;
-    
+
     /// Request to allocate contiguous 4K-sized pages.
     fn alloc_pages(num_pages: usize) -> Option<PhysAddr>;
     /// Request to free allocated physical pages.
@@ -36,4 +36,10 @@ pub trait PagingHandler: Sized {
     fn phys_to_virt(paddr: PhysAddr) -> VirtAddr;
     ///flush the memory range [start, start+len)
     fn flush(start: usize, len: usize);
+    /// Index of the CPU running the caller, selecting its Enhanced Command queue.
+    ///
+    /// Defaults to 0, all the CPUs then share the first queue.
+    fn current_cpu() -> usize {
+        0
+    }
 }
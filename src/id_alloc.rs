@@ -0,0 +1,343 @@
+use core::marker::PhantomData;
+
+use memory_addr::{align_up_4k, va, VirtAddr, PAGE_SIZE_4K};
+
+use crate::hal::PagingHandler;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Bitmap allocator for ASIDs.
+///
+/// The bitmap lives in pages obtained from [`PagingHandler::alloc_pages`], so even a 16-bit space
+/// costs only 8KB. Identifier 0 is reserved and never handed out.
+pub struct IdAllocator<H: PagingHandler> {
+    bitmap: VirtAddr,
+    id_bits: u32,
+    next: usize,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> IdAllocator<H> {
+    pub const fn uninit() -> Self {
+        Self {
+            bitmap: va!(0xdead_beef),
+            id_bits: 0,
+            next: 1,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Set up an empty allocator for `id_bits` wide identifiers.
+    pub fn init(&mut self, id_bits: u32) {
+        assert!(
+            id_bits <= 16,
+            "identifiers wider than 16 bits are not supported"
+        );
+        self.id_bits = id_bits;
+        let size = Self::bitmap_size(id_bits);
+        let base = H::alloc_pages(size / PAGE_SIZE_4K).expect("Failed to allocate ID bitmap");
+        self.bitmap = H::phys_to_virt(base);
+        unsafe { core::ptr::write_bytes(self.bitmap.as_mut_ptr(), 0, size) };
+        self.next = 1;
+        self.set(0, true);
+    }
+
+    /// Width of the identifiers handed out.
+    pub fn id_bits(&self) -> u32 {
+        self.id_bits
+    }
+
+    /// Number of identifiers, including the reserved identifier 0.
+    pub fn capacity(&self) -> usize {
+        1 << self.id_bits
+    }
+
+    /// Allocate a free identifier, searching round robin so recently freed ones are reused last.
+    pub fn alloc(&mut self) -> Option<u16> {
+        let capacity = self.capacity();
+        let id = (0..capacity)
+            .map(|i| (self.next + i) % capacity)
+            .find(|&id| !self.test(id))?;
+        self.set(id, true);
+        self.next = (id + 1) % capacity;
+        Some(id as u16)
+    }
+
+    /// Release an identifier obtained from [`IdAllocator::alloc`].
+    pub fn free(&mut self, id: u16) {
+        let id = id as usize;
+        if id == 0 || id >= self.capacity() || !self.test(id) {
+            warn!("Freeing unallocated id {}", id);
+            return;
+        }
+        self.set(id, false);
+    }
+
+    fn bitmap_size(id_bits: u32) -> usize {
+        align_up_4k((1usize << id_bits).div_ceil(8))
+    }
+
+    fn word(&self, id: usize) -> *mut u64 {
+        let base = self.bitmap.as_mut_ptr() as *mut u64;
+        unsafe { base.add(id / BITS_PER_WORD) }
+    }
+
+    fn test(&self, id: usize) -> bool {
+        unsafe { *self.word(id) & (1 << (id % BITS_PER_WORD)) != 0 }
+    }
+
+    fn set(&mut self, id: usize, used: bool) {
+        let word = self.word(id);
+        let mask = 1 << (id % BITS_PER_WORD);
+        unsafe {
+            if used {
+                *word |= mask;
+            } else {
+                *word &= !mask;
+            }
+        }
+    }
+}
+
+/// Reasons a VMID cannot be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmidError {
+    /// The VMID is wider than SMMU_IDR0.VMID16 allows.
+    OutOfRange,
+    /// The VMID is allocated to another owner.
+    InUse,
+    /// The VMID was expected to come from [`SMMUv3::alloc_vmid`](crate::SMMUv3::alloc_vmid) but is not allocated.
+    NotAllocated,
+    /// All VMIDs are in use.
+    Exhausted,
+    /// Stage 2 translation is unavailable to the Secure streams, SMMU_S_IDR1.SEL2 being clear.
+    NoStage2,
+}
+
+/// Set in a [`VmidAllocator`] entry when the VMID was handed out by [`VmidAllocator::alloc`].
+const VMID_OWNED: u16 = 1 << 15;
+/// Number of devices attached with the VMID.
+const VMID_DEVICES_MASK: u16 = VMID_OWNED - 1;
+
+/// VMID allocator with per-VMID device reference counts.
+///
+/// A VMID is either allocated for tables private to the SMMU, or shared with the CPU when the
+/// stage 2 tables of a VM are shared, in which case it is held only as long as devices use it.
+/// One 16-bit entry per VMID lives in pages obtained from [`PagingHandler::alloc_pages`].
+pub struct VmidAllocator<H: PagingHandler> {
+    entries: VirtAddr,
+    vmid_bits: u32,
+    next: usize,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> VmidAllocator<H> {
+    pub const fn uninit() -> Self {
+        Self {
+            entries: va!(0xdead_beef),
+            vmid_bits: 0,
+            next: 1,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Set up an empty allocator for `vmid_bits` wide VMIDs, 8 or 16 bits.
+    pub fn init(&mut self, vmid_bits: u32) {
+        assert!(
+            vmid_bits <= 16,
+            "VMIDs wider than 16 bits are not supported"
+        );
+        self.vmid_bits = vmid_bits;
+        let size = align_up_4k(size_of::<u16>() << vmid_bits);
+        let base = H::alloc_pages(size / PAGE_SIZE_4K).expect("Failed to allocate VMID table");
+        self.entries = H::phys_to_virt(base);
+        unsafe { core::ptr::write_bytes(self.entries.as_mut_ptr(), 0, size) };
+        self.next = 1;
+    }
+
+    /// Width of the VMIDs in use.
+    pub fn vmid_bits(&self) -> u32 {
+        self.vmid_bits
+    }
+
+    /// Allocate a VMID nobody else uses.
+    ///
+    /// VMID 0 is never handed out, it is left to a VM sharing its CPU VMID with the SMMU.
+    pub fn alloc(&mut self) -> Result<u16, VmidError> {
+        let capacity = 1 << self.vmid_bits;
+        let vmid = (0..capacity)
+            .map(|i| (self.next + i) % capacity)
+            .find(|&vmid| vmid != 0 && self.entry(vmid) == 0)
+            .ok_or(VmidError::Exhausted)?;
+        self.set_entry(vmid, VMID_OWNED);
+        self.next = (vmid + 1) % capacity;
+        Ok(vmid as u16)
+    }
+
+    /// Release a VMID obtained from [`VmidAllocator::alloc`].
+    ///
+    /// Returns true if no device uses the VMID anymore, so its TLB entries can be invalidated.
+    pub fn free(&mut self, vmid: u16) -> bool {
+        let vmid = vmid as usize;
+        if vmid >> self.vmid_bits != 0 || self.entry(vmid) & VMID_OWNED == 0 {
+            warn!("Freeing unallocated VMID {}", vmid);
+            return false;
+        }
+        let entry = self.entry(vmid) & !VMID_OWNED;
+        self.set_entry(vmid, entry);
+        entry == 0
+    }
+
+    /// Take a device reference on `vmid`, a CPU VMID being shared with the SMMU.
+    ///
+    /// Fails with [`VmidError::InUse`] if the VMID was handed out by [`VmidAllocator::alloc`].
+    pub fn get(&mut self, vmid: usize) -> Result<(), VmidError> {
+        self.check_range(vmid)?;
+        if self.entry(vmid) & VMID_OWNED != 0 {
+            return Err(VmidError::InUse);
+        }
+        self.add_device(vmid)
+    }
+
+    /// Take a device reference on `vmid`, obtained from [`VmidAllocator::alloc`].
+    pub fn get_allocated(&mut self, vmid: usize) -> Result<(), VmidError> {
+        if !self.is_allocated(vmid) {
+            return Err(VmidError::NotAllocated);
+        }
+        self.add_device(vmid)
+    }
+
+    /// Whether `vmid` was handed out by [`VmidAllocator::alloc`] and not freed since.
+    pub fn is_allocated(&self, vmid: usize) -> bool {
+        self.check_range(vmid).is_ok() && self.entry(vmid) & VMID_OWNED != 0
+    }
+
+    /// Drop a device reference on `vmid`.
+    ///
+    /// Returns true when this was the last device using the VMID.
+    pub fn put(&mut self, vmid: usize) -> bool {
+        if vmid >> self.vmid_bits != 0 || self.entry(vmid) & VMID_DEVICES_MASK == 0 {
+            warn!("VMID {} has no attached devices", vmid);
+            return false;
+        }
+        let entry = self.entry(vmid) - 1;
+        self.set_entry(vmid, entry);
+        entry & VMID_DEVICES_MASK == 0
+    }
+
+    /// Number of devices attached with `vmid`.
+    pub fn devices(&self, vmid: u16) -> usize {
+        if (vmid as usize) >> self.vmid_bits != 0 {
+            return 0;
+        }
+        (self.entry(vmid as usize) & VMID_DEVICES_MASK) as usize
+    }
+
+    fn check_range(&self, vmid: usize) -> Result<(), VmidError> {
+        if vmid >> self.vmid_bits != 0 {
+            return Err(VmidError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn add_device(&mut self, vmid: usize) -> Result<(), VmidError> {
+        let entry = self.entry(vmid);
+        if entry & VMID_DEVICES_MASK == VMID_DEVICES_MASK {
+            return Err(VmidError::Exhausted);
+        }
+        self.set_entry(vmid, entry + 1);
+        Ok(())
+    }
+
+    fn entry(&self, vmid: usize) -> u16 {
+        let base = self.entries.as_ptr() as *const u16;
+        unsafe { base.add(vmid).read() }
+    }
+
+    fn set_entry(&mut self, vmid: usize, entry: u16) {
+        let base = self.entries.as_mut_ptr() as *mut u16;
+        unsafe { base.add(vmid).write(entry) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::HostPagingHandler;
+
+    #[test]
+    fn test_vmid_refcount() {
+        let mut vmids = VmidAllocator::<HostPagingHandler>::uninit();
+        vmids.init(8);
+
+        assert_eq!(vmids.get(256), Err(VmidError::OutOfRange));
+
+        // VMID 0 is shared like any CPU VMID.
+        vmids.get(0).unwrap();
+        assert!(vmids.put(0));
+
+        // A CPU VMID shared by two devices is held until both detach.
+        vmids.get(7).unwrap();
+        vmids.get(7).unwrap();
+        assert_eq!(vmids.devices(7), 2);
+        assert!(!vmids.put(7));
+        assert!(vmids.put(7));
+
+        // Allocation skips VMIDs in use, and freeing waits for the devices.
+        vmids.get(1).unwrap();
+        let vmid = vmids.alloc().unwrap();
+        assert_eq!(vmid, 2);
+        vmids.get_allocated(vmid as usize).unwrap();
+        assert!(!vmids.free(vmid));
+        assert!(vmids.put(vmid as usize));
+        assert_eq!(vmids.alloc(), Ok(3));
+    }
+
+    #[test]
+    fn test_vmid_in_use() {
+        let mut vmids = VmidAllocator::<HostPagingHandler>::uninit();
+        vmids.init(8);
+
+        // An allocated VMID cannot be shared as a CPU VMID, nor a CPU VMID used as allocated.
+        let vmid = vmids.alloc().unwrap() as usize;
+        assert_eq!(vmids.get(vmid), Err(VmidError::InUse));
+        assert_eq!(vmids.devices(vmid as u16), 0);
+        vmids.get(vmid + 1).unwrap();
+        assert_eq!(vmids.get_allocated(vmid + 1), Err(VmidError::NotAllocated));
+        assert_eq!(vmids.get_allocated(0), Err(VmidError::NotAllocated));
+
+        // Once freed and unused, the VMID is free to share with the CPU.
+        assert!(vmids.free(vmid as u16));
+        vmids.get(vmid).unwrap();
+        assert!(vmids.put(vmid));
+    }
+
+    #[test]
+    fn test_vmid_refcount_across_free() {
+        let mut vmids = VmidAllocator::<HostPagingHandler>::uninit();
+        vmids.init(8);
+
+        let vmid = vmids.alloc().unwrap();
+        vmids.get_allocated(vmid as usize).unwrap();
+        vmids.get_allocated(vmid as usize).unwrap();
+        assert_eq!(vmids.devices(vmid), 2);
+
+        // Freeing keeps the device references, so the VMID is not reallocated under them.
+        assert!(!vmids.free(vmid));
+        assert!(!vmids.free(vmid));
+        assert_eq!(vmids.devices(vmid), 2);
+        assert_ne!(vmids.alloc(), Ok(vmid));
+        assert!(!vmids.put(vmid as usize));
+        assert!(vmids.put(vmid as usize));
+        assert!(!vmids.put(vmid as usize));
+        assert_eq!(vmids.devices(vmid), 0);
+
+        // The VMID is handed out again once the allocator wraps around.
+        let mut reused = false;
+        while let Ok(next) = vmids.alloc() {
+            assert_ne!(next, 0);
+            reused |= next == vmid;
+        }
+        assert!(reused);
+    }
+}
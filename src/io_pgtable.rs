@@ -0,0 +1,790 @@
+//! VMSAv8-64 I/O translation tables managed by the driver.
+//!
+//! The tables are built from memory handed out by [`PagingHandler::alloc_pages`] and follow the
+//! A-profile VMSAv8-64 descriptor formats, which the SMMU walks through STE.S2TTB for stage 2
+//! and CD.TTB0 for stage 1.
+//! 4KB, 16KB and 64KB granules are supported, the number of levels follows from the input
+//! address size, and block descriptors are used whenever the alignment of a mapping allows it.
+
+use core::marker::PhantomData;
+
+use aarch64_cpu::registers::{TCR_EL1, VTCR_EL2};
+use bitflags::bitflags;
+use memory_addr::{PhysAddr, PAGE_SIZE_4K};
+
+use crate::context_descriptor::ContextDescriptor;
+use crate::hal::PagingHandler;
+use crate::stream_table::HttuMode;
+
+bitflags! {
+    /// Access permissions and memory type of an I/O mapping.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IoProt: u32 {
+        /// Device reads are permitted.
+        const READ = 1 << 0;
+        /// Device writes are permitted.
+        const WRITE = 1 << 1;
+        /// Normal Write-Back cacheable memory, otherwise Normal Non-cacheable.
+        const CACHE = 1 << 2;
+        /// Instruction fetches are not permitted.
+        const NOEXEC = 1 << 3;
+        /// Device-nGnRE memory, takes precedence over [`IoProt::CACHE`].
+        const MMIO = 1 << 4;
+    }
+}
+
+/// Translation granule size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granule {
+    /// 4KB granule, tables resolve 9 bits per level.
+    Size4K,
+    /// 16KB granule, tables resolve 11 bits per level.
+    Size16K,
+    /// 64KB granule, tables resolve 13 bits per level.
+    Size64K,
+}
+
+impl Granule {
+    /// log2 of the granule size.
+    pub const fn shift(self) -> usize {
+        match self {
+            Granule::Size4K => 12,
+            Granule::Size16K => 14,
+            Granule::Size64K => 16,
+        }
+    }
+
+    /// Granule size in bytes, which is also the size of one translation table.
+    pub const fn size(self) -> usize {
+        1 << self.shift()
+    }
+
+    /// Number of IOVA bits resolved by one level of lookup.
+    const fn bits_per_level(self) -> usize {
+        self.shift() - 3
+    }
+}
+
+/// Geometry of an I/O translation table.
+#[derive(Debug, Clone, Copy)]
+pub struct IoPgtableConfig {
+    /// Translation granule.
+    pub granule: Granule,
+    /// Input address size in bits, the IOVA space is `[0, 1 << ias)`.
+    pub ias: usize,
+    /// Output address size in bits, one of 32, 36, 40, 42, 44 or 48.
+    pub oas: usize,
+}
+
+impl Default for IoPgtableConfig {
+    /// Matches the default stage 2 configuration used by [`crate::SMMUv3::add_device`].
+    fn default() -> Self {
+        Self {
+            granule: Granule::Size4K,
+            ias: 39,
+            oas: 40,
+        }
+    }
+}
+
+/// Errors reported by I/O translation table updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPgtableError {
+    /// The configuration cannot be expressed with the supported formats.
+    InvalidConfig,
+    /// An address or size is not aligned to the granule.
+    Unaligned,
+    /// The range lies outside the input or output address space.
+    OutOfRange,
+    /// Part of the range is already mapped.
+    AlreadyMapped,
+    /// [`PagingHandler::alloc_pages`] failed.
+    NoMemory,
+}
+
+/// Translation regime a table belongs to, used to tag TLB invalidations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlbContext {
+    /// Stage 1 translations tagged with an ASID, stage 2 bypassed.
+    Stage1 {
+        /// ASID of the CDs using the table.
+        asid: u16,
+    },
+    /// Stage 2 translations tagged with a VMID.
+    Stage2 {
+        /// VMID of the streams using the table.
+        vmid: u16,
+    },
+}
+
+/// TLB maintenance required when live translation table entries are removed.
+///
+/// Implemented by [`crate::SMMUv3`] through its command queue.
+pub trait IoTlbFlush {
+    /// Invalidate cached translations for `[iova, iova + size)` at `granule` steps.
+    ///
+    /// When `leaf` is false, cached intermediate table entries (walk caches) are invalidated too.
+    fn tlb_inv_range(
+        &mut self,
+        ctx: TlbContext,
+        iova: usize,
+        size: usize,
+        granule: usize,
+        leaf: bool,
+    );
+    /// Wait for all previously issued invalidations to complete.
+    fn tlb_sync(&mut self);
+}
+
+/// Valid, bit [0].
+const PTE_VALID: u64 = 1 << 0;
+/// Table descriptor at levels 0-2, page descriptor at level 3, block descriptor when clear.
+const PTE_TYPE_TABLE: u64 = 1 << 1;
+const PTE_TYPE_PAGE: u64 = 1 << 1;
+/// MemAttr, bits [5:2], stage 2 memory type when FWB is not in use.
+const PTE_S2_MEMATTR_OFFSET: u64 = 2;
+const PTE_S2_MEMATTR_DEVICE_NGNRE: u64 = 0b0001;
+const PTE_S2_MEMATTR_NORMAL_NC: u64 = 0b0101;
+const PTE_S2_MEMATTR_NORMAL_WB: u64 = 0b1111;
+/// S2AP, bits [7:6], stage 2 read and write permission.
+const PTE_S2AP_READ: u64 = 1 << 6;
+const PTE_S2AP_WRITE: u64 = 1 << 7;
+/// SH, bits [9:8], Inner Shareable.
+const PTE_SH_INNER: u64 = 0b11 << 8;
+/// AF, bit [10], Access flag.
+const PTE_AF: u64 = 1 << 10;
+/// XN, bits [54:53], stage 2 execute-never for all exception levels.
+const PTE_S2_XN: u64 = 0b10 << 53;
+/// AttrIndx, bits [4:2], stage 1 index into MAIR.
+const PTE_S1_ATTRINDX_OFFSET: u64 = 2;
+/// AP[1], bit [6], stage 1 access from EL0, which is how the SMMU treats unprivileged transactions.
+const PTE_S1_AP_UNPRIV: u64 = 1 << 6;
+/// AP[2], bit [7], stage 1 read-only.
+const PTE_S1_AP_RDONLY: u64 = 1 << 7;
+/// nG, bit [11], the translation is tagged with the ASID.
+const PTE_S1_NG: u64 = 1 << 11;
+/// PXN, bit [53], and UXN, bit [54], stage 1 execute-never.
+const PTE_S1_XN: u64 = 0b11 << 53;
+
+/// MAIR attribute indexes used by stage 1 descriptors.
+const MAIR_ATTR_IDX_NC: u64 = 0;
+const MAIR_ATTR_IDX_CACHE: u64 = 1;
+const MAIR_ATTR_IDX_DEVICE: u64 = 2;
+/// Normal Non-cacheable, Normal Write-Back RW-allocate and Device-nGnRE.
+const MAIR_ATTR_NC: u64 = 0x44;
+const MAIR_ATTR_WBRWA: u64 = 0xff;
+const MAIR_ATTR_DEVICE: u64 = 0x04;
+/// Output address bits [47:12], further limited to the granule.
+const PTE_ADDR_MASK: u64 = ((1 << 48) - 1) & !(PAGE_SIZE_4K as u64 - 1);
+/// Lower and upper attributes of a block or page descriptor.
+const PTE_ATTR_MASK: u64 = !PTE_ADDR_MASK & !(PTE_VALID | PTE_TYPE_TABLE);
+
+const MAX_LEVEL: usize = 3;
+
+/// A stage 1 or stage 2 I/O translation table.
+///
+/// Table memory is released when the table is dropped, by then it must no longer be referenced by any STE.
+pub struct IoPageTable<H: PagingHandler> {
+    root: PhysAddr,
+    cfg: IoPgtableConfig,
+    start_level: usize,
+    ctx: TlbContext,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> IoPageTable<H> {
+    /// Allocate an empty stage 2 translation table whose TLB entries are tagged with `vmid`.
+    ///
+    /// [`PagingHandler::alloc_pages`] must return memory aligned to the granule size for 16KB and 64KB granules.
+    pub fn new_s2(cfg: IoPgtableConfig, vmid: u16) -> Result<Self, IoPgtableError> {
+        Self::new(cfg, TlbContext::Stage2 { vmid })
+    }
+
+    /// Allocate an empty stage 1 translation table whose TLB entries are tagged with `asid`.
+    ///
+    /// The ASID should come from [`crate::SMMUv3::alloc_asid`].
+    pub fn new_s1(cfg: IoPgtableConfig, asid: u16) -> Result<Self, IoPgtableError> {
+        Self::new(cfg, TlbContext::Stage1 { asid })
+    }
+
+    fn new(cfg: IoPgtableConfig, ctx: TlbContext) -> Result<Self, IoPgtableError> {
+        let shift = cfg.granule.shift();
+        if cfg.ias <= shift || cfg.ias > 48 || Self::ps(cfg.oas).is_none() {
+            return Err(IoPgtableError::InvalidConfig);
+        }
+        let levels = (cfg.ias - shift).div_ceil(cfg.granule.bits_per_level());
+        let start_level = MAX_LEVEL + 1 - levels;
+        // VTCR_EL2.SL0 can only start the walk at level 0 with the 4KB granule.
+        if matches!(ctx, TlbContext::Stage2 { .. })
+            && start_level == 0
+            && cfg.granule != Granule::Size4K
+        {
+            return Err(IoPgtableError::InvalidConfig);
+        }
+
+        Ok(Self {
+            root: Self::alloc_table(cfg.granule)?,
+            cfg,
+            start_level,
+            ctx,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Physical address of the root table, as programmed into STE.S2TTB or CD.TTB0.
+    pub fn root_paddr(&self) -> PhysAddr {
+        self.root
+    }
+
+    /// The table geometry.
+    pub fn config(&self) -> &IoPgtableConfig {
+        &self.cfg
+    }
+
+    /// Number of lookup levels.
+    pub fn levels(&self) -> usize {
+        MAX_LEVEL + 1 - self.start_level
+    }
+
+    /// The translation regime TLB entries of this table are tagged with.
+    pub fn tlb_context(&self) -> TlbContext {
+        self.ctx
+    }
+
+    /// STE.{S2T0SZ, S2SL0, S2IR0, S2OR0, S2SH0, S2TG, S2PS} describing this table, in VTCR_EL2 layout.
+    pub fn vtcr(&self) -> u64 {
+        let tg0 = match self.cfg.granule {
+            Granule::Size4K => VTCR_EL2::TG0::Granule4KB.value,
+            Granule::Size16K => VTCR_EL2::TG0::Granule16KB.value,
+            Granule::Size64K => VTCR_EL2::TG0::Granule64KB.value,
+        };
+        // SL0 counts down from the deepest start level the granule allows.
+        let sl0 = match self.cfg.granule {
+            Granule::Size4K => 2 - self.start_level,
+            Granule::Size16K | Granule::Size64K => MAX_LEVEL - self.start_level,
+        };
+        Self::ps(self.cfg.oas).unwrap()
+            | tg0
+            | VTCR_EL2::SH0::Inner.value
+            | VTCR_EL2::ORGN0::NormalWBRAWA.value
+            | VTCR_EL2::IRGN0::NormalWBRAWA.value
+            | VTCR_EL2::SL0.val(sl0 as u64).value
+            | VTCR_EL2::T0SZ.val((64 - self.cfg.ias) as u64).value
+    }
+
+    /// MAIR value matching the AttrIndx used by stage 1 descriptors, as programmed into CD.MAIR.
+    pub fn mair(&self) -> u64 {
+        MAIR_ATTR_NC << (8 * MAIR_ATTR_IDX_NC)
+            | MAIR_ATTR_WBRWA << (8 * MAIR_ATTR_IDX_CACHE)
+            | MAIR_ATTR_DEVICE << (8 * MAIR_ATTR_IDX_DEVICE)
+    }
+
+    /// Context Descriptor translating through this stage 1 table.
+    ///
+    /// Returns `None` for a stage 2 table.
+    pub fn context_descriptor(&self, httu: HttuMode) -> Option<ContextDescriptor> {
+        let TlbContext::Stage1 { asid } = self.ctx else {
+            return None;
+        };
+        // The CD fields use the TCR_EL1 encodings, but TG0 sits next to T0SZ.
+        let tg0 = match self.cfg.granule {
+            Granule::Size4K => TCR_EL1::TG0::KiB_4.value,
+            Granule::Size16K => TCR_EL1::TG0::KiB_16.value,
+            Granule::Size64K => TCR_EL1::TG0::KiB_64.value,
+        } >> TCR_EL1::TG0.shift;
+        let tcr = TCR_EL1::SH0::Inner.value
+            | TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable.value
+            | TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable.value
+            | TCR_EL1::T0SZ.val((64 - self.cfg.ias) as u64).value;
+        // TCR_EL1.IPS shares the VTCR_EL2.PS encoding.
+        let ips = Self::ps(self.cfg.oas).unwrap() >> VTCR_EL2::PS.shift;
+        Some(ContextDescriptor::s1_entry(
+            asid,
+            self.root,
+            tcr,
+            tg0,
+            ips,
+            self.mair(),
+            httu,
+        ))
+    }
+
+    /// Map `[iova, iova + size)` to `[paddr, paddr + size)`.
+    ///
+    /// Block descriptors are used where both addresses are suitably aligned. Only invalid
+    /// entries are written, which the SMMU never caches, so no TLB invalidation is needed.
+    /// On error, the part of the range mapped so far stays mapped.
+    pub fn map(
+        &mut self,
+        iova: usize,
+        paddr: PhysAddr,
+        size: usize,
+        prot: IoProt,
+    ) -> Result<(), IoPgtableError> {
+        self.check_range(iova, size)?;
+        if !paddr.as_usize().is_multiple_of(self.cfg.granule.size()) {
+            return Err(IoPgtableError::Unaligned);
+        }
+        if paddr
+            .as_usize()
+            .checked_add(size)
+            .is_none_or(|end| end > 1 << self.cfg.oas)
+        {
+            return Err(IoPgtableError::OutOfRange);
+        }
+
+        let attrs = self.leaf_attrs(prot);
+        let mut mapped = 0;
+        while mapped < size {
+            let (iova, paddr) = (iova + mapped, paddr.as_usize() + mapped);
+            let level = self.leaf_level(iova, paddr, size - mapped);
+            let ptep = self.walk_alloc(iova, level)?;
+            // SAFETY: `walk_alloc` returns a pointer into a live table.
+            if unsafe { ptep.read_volatile() } & PTE_VALID != 0 {
+                return Err(IoPgtableError::AlreadyMapped);
+            }
+            let page = if level == MAX_LEVEL { PTE_TYPE_PAGE } else { 0 };
+            self.write_pte(
+                ptep,
+                paddr as u64 & PTE_ADDR_MASK | attrs | page | PTE_VALID,
+            );
+            mapped += self.level_size(level);
+        }
+        Ok(())
+    }
+
+    /// Unmap `[iova, iova + size)`, returning the number of bytes that were mapped.
+    ///
+    /// Cached translations are invalidated through `flush`, with TLBI_S2_IPA for stage 2, and the
+    /// function only returns once the invalidation completed. Block mappings straddling the range
+    /// are split, and tables left without valid entries are freed.
+    pub fn unmap<F: IoTlbFlush>(
+        &mut self,
+        flush: &mut F,
+        iova: usize,
+        size: usize,
+    ) -> Result<usize, IoPgtableError> {
+        let unmapped = self.unmap_deferred(flush, iova, size)?;
+        flush.tlb_sync();
+        Ok(unmapped)
+    }
+
+    /// Like [`IoPageTable::unmap`], but without waiting for the leaf invalidations to complete.
+    ///
+    /// The range must not be reused before the caller has called [`IoTlbFlush::tlb_sync`], which
+    /// lets several unmaps share a single wait. Waits required before a block is split or table
+    /// memory is freed still happen here.
+    pub fn unmap_deferred<F: IoTlbFlush>(
+        &mut self,
+        flush: &mut F,
+        iova: usize,
+        size: usize,
+    ) -> Result<usize, IoPgtableError> {
+        self.check_range(iova, size)?;
+        self.unmap_level(flush, self.root, self.start_level, iova, iova + size)
+    }
+
+    /// Look up the physical address `iova` is mapped to.
+    pub fn iova_to_phys(&self, iova: usize) -> Option<PhysAddr> {
+        if iova >> self.cfg.ias != 0 {
+            return None;
+        }
+        let mut table = self.root;
+        for level in self.start_level..=MAX_LEVEL {
+            let pte = unsafe { self.pte_ptr(table, iova, level).read_volatile() };
+            if pte & PTE_VALID == 0 {
+                return None;
+            }
+            let addr = (pte & PTE_ADDR_MASK) as usize;
+            if level == MAX_LEVEL || pte & PTE_TYPE_TABLE == 0 {
+                return Some(PhysAddr::from(addr + (iova & (self.level_size(level) - 1))));
+            }
+            table = PhysAddr::from(addr);
+        }
+        None
+    }
+
+    fn ps(oas: usize) -> Option<u64> {
+        let ps = match oas {
+            32 => VTCR_EL2::PS::PA_32B_4GB,
+            36 => VTCR_EL2::PS::PA_36B_64GB,
+            40 => VTCR_EL2::PS::PA_40B_1TB,
+            42 => VTCR_EL2::PS::PA_42B_4TB,
+            44 => VTCR_EL2::PS::PA_44B_16TB,
+            48 => VTCR_EL2::PS::PA_48B_256TB,
+            _ => return None,
+        };
+        Some(ps.value)
+    }
+
+    fn check_range(&self, iova: usize, size: usize) -> Result<(), IoPgtableError> {
+        let granule = self.cfg.granule.size();
+        if !iova.is_multiple_of(granule) || !size.is_multiple_of(granule) || size == 0 {
+            return Err(IoPgtableError::Unaligned);
+        }
+        match iova.checked_add(size) {
+            Some(end) if end <= 1 << self.cfg.ias => Ok(()),
+            _ => Err(IoPgtableError::OutOfRange),
+        }
+    }
+
+    fn leaf_attrs(&self, prot: IoProt) -> u64 {
+        if let TlbContext::Stage1 { .. } = self.ctx {
+            return Self::s1_leaf_attrs(prot);
+        }
+        let memattr = if prot.contains(IoProt::MMIO) {
+            PTE_S2_MEMATTR_DEVICE_NGNRE
+        } else if prot.contains(IoProt::CACHE) {
+            PTE_S2_MEMATTR_NORMAL_WB
+        } else {
+            PTE_S2_MEMATTR_NORMAL_NC
+        };
+        let mut attrs = PTE_AF | PTE_SH_INNER | memattr << PTE_S2_MEMATTR_OFFSET;
+        if prot.contains(IoProt::READ) {
+            attrs |= PTE_S2AP_READ;
+        }
+        if prot.contains(IoProt::WRITE) {
+            attrs |= PTE_S2AP_WRITE;
+        }
+        if prot.contains(IoProt::NOEXEC) {
+            attrs |= PTE_S2_XN;
+        }
+        attrs
+    }
+
+    /// Stage 1 descriptors cannot express write-only or inaccessible pages, read access is implied.
+    fn s1_leaf_attrs(prot: IoProt) -> u64 {
+        let attr_idx = if prot.contains(IoProt::MMIO) {
+            MAIR_ATTR_IDX_DEVICE
+        } else if prot.contains(IoProt::CACHE) {
+            MAIR_ATTR_IDX_CACHE
+        } else {
+            MAIR_ATTR_IDX_NC
+        };
+        let mut attrs = PTE_AF
+            | PTE_SH_INNER
+            | PTE_S1_NG
+            | PTE_S1_AP_UNPRIV
+            | attr_idx << PTE_S1_ATTRINDX_OFFSET;
+        if !prot.contains(IoProt::WRITE) {
+            attrs |= PTE_S1_AP_RDONLY;
+        }
+        if prot.contains(IoProt::NOEXEC) {
+            attrs |= PTE_S1_XN;
+        }
+        attrs
+    }
+
+    /// Size of the region translated by one entry at `level`.
+    fn level_size(&self, level: usize) -> usize {
+        1 << (self.cfg.granule.shift() + (MAX_LEVEL - level) * self.cfg.granule.bits_per_level())
+    }
+
+    /// Whether a block descriptor is permitted at `level` for the configured granule.
+    fn block_allowed(&self, level: usize) -> bool {
+        match self.cfg.granule {
+            Granule::Size4K => level >= 1,
+            Granule::Size16K | Granule::Size64K => level >= 2,
+        }
+    }
+
+    /// The shallowest level able to map a leaf at `iova` -> `paddr` within `size` bytes.
+    fn leaf_level(&self, iova: usize, paddr: usize, size: usize) -> usize {
+        (self.start_level..MAX_LEVEL)
+            .find(|&level| {
+                let block = self.level_size(level);
+                self.block_allowed(level) && (iova | paddr).is_multiple_of(block) && size >= block
+            })
+            .unwrap_or(MAX_LEVEL)
+    }
+
+    fn pte_ptr(&self, table: PhysAddr, iova: usize, level: usize) -> *mut u64 {
+        let shift = self.level_size(level).trailing_zeros() as usize;
+        let index = (iova >> shift) & ((1 << self.cfg.granule.bits_per_level()) - 1);
+        let base = H::phys_to_virt(table).as_mut_ptr() as *mut u64;
+        unsafe { base.add(index) }
+    }
+
+    fn write_pte(&self, ptep: *mut u64, pte: u64) {
+        unsafe { ptep.write_volatile(pte) };
+        H::flush(ptep as usize, size_of::<u64>());
+    }
+
+    /// Walk down to `level` for `iova`, allocating missing tables on the way.
+    fn walk_alloc(&mut self, iova: usize, level: usize) -> Result<*mut u64, IoPgtableError> {
+        let mut table = self.root;
+        for l in self.start_level..level {
+            let ptep = self.pte_ptr(table, iova, l);
+            let pte = unsafe { ptep.read_volatile() };
+            if pte & PTE_VALID == 0 {
+                let next = Self::alloc_table(self.cfg.granule)?;
+                self.write_pte(ptep, next.as_usize() as u64 | PTE_TYPE_TABLE | PTE_VALID);
+                table = next;
+            } else if pte & PTE_TYPE_TABLE == 0 {
+                // A block already covers this address.
+                return Err(IoPgtableError::AlreadyMapped);
+            } else {
+                table = PhysAddr::from((pte & PTE_ADDR_MASK) as usize);
+            }
+        }
+        Ok(self.pte_ptr(table, iova, level))
+    }
+
+    fn unmap_level<F: IoTlbFlush>(
+        &mut self,
+        flush: &mut F,
+        table: PhysAddr,
+        level: usize,
+        start: usize,
+        end: usize,
+    ) -> Result<usize, IoPgtableError> {
+        let size = self.level_size(level);
+        let mut unmapped = 0;
+        let mut iova = start;
+        while iova < end {
+            let entry_base = iova & !(size - 1);
+            let entry_end = usize::min(entry_base + size, end);
+            let ptep = self.pte_ptr(table, iova, level);
+            let pte = unsafe { ptep.read_volatile() };
+            let is_table = level < MAX_LEVEL && pte & PTE_TYPE_TABLE != 0;
+            let covered = iova == entry_base && entry_end == entry_base + size;
+
+            if pte & PTE_VALID == 0 {
+                // Nothing mapped here.
+            } else if covered && !is_table {
+                self.write_pte(ptep, 0);
+                flush.tlb_inv_range(self.ctx, entry_base, size, size, true);
+                unmapped += size;
+            } else if covered {
+                // Invalidate the walk caches before the table memory is reused.
+                self.write_pte(ptep, 0);
+                flush.tlb_inv_range(self.ctx, entry_base, size, self.cfg.granule.size(), false);
+                flush.tlb_sync();
+                unmapped +=
+                    self.free_table(PhysAddr::from((pte & PTE_ADDR_MASK) as usize), level + 1);
+            } else {
+                let next = if is_table {
+                    PhysAddr::from((pte & PTE_ADDR_MASK) as usize)
+                } else {
+                    self.split_block(flush, ptep, pte, entry_base, level)?
+                };
+                unmapped += self.unmap_level(flush, next, level + 1, iova, entry_end)?;
+                // A table emptied by successive unmaps would otherwise stop a block being mapped
+                // over its range.
+                if self.table_is_empty(next) {
+                    self.write_pte(ptep, 0);
+                    flush.tlb_inv_range(self.ctx, entry_base, size, self.cfg.granule.size(), false);
+                    flush.tlb_sync();
+                    self.free_table(next, level + 1);
+                }
+            }
+            iova = entry_end;
+        }
+        Ok(unmapped)
+    }
+
+    /// Replace the block descriptor `pte` at `level` by a table of equivalent next-level leaves.
+    ///
+    /// Break-before-make: the block is invalidated and its TLB entries removed before the table is installed.
+    fn split_block<F: IoTlbFlush>(
+        &mut self,
+        flush: &mut F,
+        ptep: *mut u64,
+        pte: u64,
+        iova: usize,
+        level: usize,
+    ) -> Result<PhysAddr, IoPgtableError> {
+        let table = Self::alloc_table(self.cfg.granule)?;
+        let child_size = self.level_size(level + 1);
+        let page = if level + 1 == MAX_LEVEL {
+            PTE_TYPE_PAGE
+        } else {
+            0
+        };
+        let base = (pte & PTE_ADDR_MASK) as usize;
+        for i in 0..(self.level_size(level) / child_size) {
+            let child = self.pte_ptr(table, iova + i * child_size, level + 1);
+            let addr = (base + i * child_size) as u64;
+            unsafe { child.write_volatile(addr | (pte & PTE_ATTR_MASK) | page | PTE_VALID) };
+        }
+        H::flush(H::phys_to_virt(table).as_usize(), self.cfg.granule.size());
+
+        self.write_pte(ptep, 0);
+        flush.tlb_inv_range(
+            self.ctx,
+            iova,
+            self.level_size(level),
+            self.level_size(level),
+            true,
+        );
+        flush.tlb_sync();
+        self.write_pte(ptep, table.as_usize() as u64 | PTE_TYPE_TABLE | PTE_VALID);
+        Ok(table)
+    }
+
+    /// Whether `table` holds no valid entries.
+    fn table_is_empty(&self, table: PhysAddr) -> bool {
+        let base = H::phys_to_virt(table).as_ptr() as *const u64;
+        (0..1 << self.cfg.granule.bits_per_level())
+            .all(|i| unsafe { base.add(i).read_volatile() } & PTE_VALID == 0)
+    }
+
+    /// Free `table` at `level` and everything below it, returning the size of the leaves it held.
+    fn free_table(&self, table: PhysAddr, level: usize) -> usize {
+        let mut mapped = 0;
+        let base = H::phys_to_virt(table).as_ptr() as *const u64;
+        for i in 0..(1 << self.cfg.granule.bits_per_level()) {
+            let pte = unsafe { base.add(i).read_volatile() };
+            if pte & PTE_VALID == 0 {
+                continue;
+            }
+            if level < MAX_LEVEL && pte & PTE_TYPE_TABLE != 0 {
+                mapped +=
+                    self.free_table(PhysAddr::from((pte & PTE_ADDR_MASK) as usize), level + 1);
+            } else {
+                mapped += self.level_size(level);
+            }
+        }
+        H::dealloc_pages(table, self.cfg.granule.size() / PAGE_SIZE_4K);
+        mapped
+    }
+
+    fn alloc_table(granule: Granule) -> Result<PhysAddr, IoPgtableError> {
+        let num_pages = granule.size() / PAGE_SIZE_4K;
+        let table = H::alloc_pages(num_pages).ok_or(IoPgtableError::NoMemory)?;
+        let vaddr = H::phys_to_virt(table);
+        unsafe { core::ptr::write_bytes(vaddr.as_mut_ptr(), 0, granule.size()) };
+        H::flush(vaddr.as_usize(), granule.size());
+        Ok(table)
+    }
+}
+
+impl<H: PagingHandler> Drop for IoPageTable<H> {
+    fn drop(&mut self) {
+        self.free_table(self.root, self.start_level);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use memory_addr::pa;
+
+    use super::*;
+    use crate::test_utils::HostPagingHandler;
+
+    #[derive(Default)]
+    struct RecordingFlush {
+        invalidated: Vec<(usize, usize, bool)>,
+        syncs: usize,
+    }
+
+    impl IoTlbFlush for RecordingFlush {
+        fn tlb_inv_range(
+            &mut self,
+            _ctx: TlbContext,
+            iova: usize,
+            size: usize,
+            _granule: usize,
+            leaf: bool,
+        ) {
+            self.invalidated.push((iova, size, leaf));
+        }
+
+        fn tlb_sync(&mut self) {
+            self.syncs += 1;
+        }
+    }
+
+    const RW: IoProt = IoProt::READ.union(IoProt::WRITE).union(IoProt::CACHE);
+
+    #[test]
+    fn test_s2_geometry() {
+        let pt = IoPageTable::<HostPagingHandler>::new_s2(IoPgtableConfig::default(), 1).unwrap();
+        assert_eq!(pt.levels(), 3);
+        assert_eq!(pt.vtcr(), crate::stream_table::DEFAULT_S2VTCR);
+
+        let cfg = IoPgtableConfig {
+            granule: Granule::Size64K,
+            ias: 42,
+            oas: 48,
+        };
+        let pt = IoPageTable::<HostPagingHandler>::new_s2(cfg, 1).unwrap();
+        assert_eq!(pt.levels(), 2);
+        assert_eq!(pt.vtcr() & 0xff, (1 << 6) | (64 - 42));
+
+        let cfg = IoPgtableConfig {
+            granule: Granule::Size16K,
+            ias: 48,
+            oas: 48,
+        };
+        assert!(IoPageTable::<HostPagingHandler>::new_s2(cfg, 1).is_err());
+    }
+
+    #[test]
+    fn test_s1_context() {
+        let mut pt =
+            IoPageTable::<HostPagingHandler>::new_s1(IoPgtableConfig::default(), 5).unwrap();
+        let mut flush = RecordingFlush::default();
+        pt.map(0x1000, pa!(0x8000_0000), 0x1000, IoProt::READ)
+            .unwrap();
+        assert_eq!(pt.iova_to_phys(0x1010), Some(pa!(0x8000_0010)));
+
+        let cd = pt.context_descriptor(HttuMode::Disabled).unwrap();
+        assert!(cd.is_valid());
+        assert_eq!(cd.asid(), 5);
+
+        // The emptied level 3 and level 2 tables are freed.
+        assert_eq!(pt.unmap(&mut flush, 0, 0x2000), Ok(0x1000));
+        assert_eq!(
+            flush.invalidated,
+            [
+                (0x1000, 0x1000, true),
+                (0, 0x20_0000, false),
+                (0, 0x4000_0000, false)
+            ]
+        );
+
+        let s2 = IoPageTable::<HostPagingHandler>::new_s2(IoPgtableConfig::default(), 1).unwrap();
+        assert!(s2.context_descriptor(HttuMode::Disabled).is_none());
+    }
+
+    #[test]
+    fn test_map_unmap() {
+        let mut pt =
+            IoPageTable::<HostPagingHandler>::new_s2(IoPgtableConfig::default(), 1).unwrap();
+        let mut flush = RecordingFlush::default();
+
+        // 2MB block followed by two 4KB pages.
+        pt.map(0x4000_0000, pa!(0x8020_0000), 0x20_2000, RW)
+            .unwrap();
+        assert_eq!(pt.iova_to_phys(0x4000_1234), Some(pa!(0x8020_1234)));
+        assert_eq!(pt.iova_to_phys(0x4020_1000), Some(pa!(0x8040_1000)));
+        assert_eq!(pt.iova_to_phys(0x4020_2000), None);
+        assert_eq!(
+            pt.map(0x4000_0000, pa!(0x9000_0000), 0x1000, RW),
+            Err(IoPgtableError::AlreadyMapped)
+        );
+
+        // Unmapping a page inside the block splits it.
+        assert_eq!(pt.unmap(&mut flush, 0x4000_1000, 0x1000), Ok(0x1000));
+        assert_eq!(pt.iova_to_phys(0x4000_1000), None);
+        assert_eq!(pt.iova_to_phys(0x4000_2000), Some(pa!(0x8020_2000)));
+        assert_eq!(flush.invalidated[0], (0x4000_0000, 0x20_0000, true));
+        assert_eq!(flush.invalidated[1], (0x4000_1000, 0x1000, true));
+
+        assert_eq!(pt.unmap(&mut flush, 0x4000_0000, 0x40_0000), Ok(0x20_1000));
+        assert_eq!(pt.iova_to_phys(0x4020_0000), None);
+        assert_eq!(flush.syncs, 6);
+
+        // A table emptied by a page unmap is freed, making room for a block.
+        pt.map(0x4000_0000, pa!(0x8000_0000), 0x1000, RW).unwrap();
+        assert_eq!(pt.unmap(&mut flush, 0x4000_0000, 0x1000), Ok(0x1000));
+        pt.map(0x4000_0000, pa!(0x8020_0000), 0x20_0000, RW)
+            .unwrap();
+        assert_eq!(pt.iova_to_phys(0x4000_1234), Some(pa!(0x8020_1234)));
+    }
+}
@@ -0,0 +1,393 @@
+//! Discovery of the SMMUs and their masters in the ACPI IORT.
+//!
+//! [`Iort`] reads the SMMUv3 nodes of an IO Remapping Table, see the Arm DEN 0049 specification,
+//! and the ID mappings of the root complex and named component nodes behind them. The table is
+//! parsed in place, without allocating.
+
+use bitflags::bitflags;
+use memory_addr::PhysAddr;
+
+use crate::hal::PagingHandler;
+use crate::stream_id::{PciBdf, StreamId};
+use crate::SMMUv3;
+
+const IORT_SIGNATURE: &[u8; 4] = b"IORT";
+/// Size of the ACPI table header.
+const ACPI_HEADER_LEN: usize = 36;
+
+const IORT_NODE_NAMED_COMPONENT: u8 = 0x01;
+const IORT_NODE_ROOT_COMPLEX: u8 = 0x02;
+const IORT_NODE_SMMU_V3: u8 = 0x04;
+
+/// Node header fields.
+const NODE_TYPE: usize = 0;
+const NODE_LENGTH: usize = 1;
+const NODE_ID_COUNT: usize = 8;
+const NODE_ID_ARRAY: usize = 12;
+/// Root complex node fields.
+const RC_PCI_SEGMENT: usize = 28;
+/// Named component node fields.
+const NC_OBJECT_NAME: usize = 29;
+/// SMMUv3 node fields.
+const SMMU_BASE: usize = 16;
+const SMMU_FLAGS: usize = 24;
+const SMMU_MODEL: usize = 40;
+const SMMU_EVENT_GSIV: usize = 44;
+const SMMU_PRI_GSIV: usize = 48;
+const SMMU_GERR_GSIV: usize = 52;
+const SMMU_SYNC_GSIV: usize = 56;
+const SMMU_PROXIMITY_DOMAIN: usize = 60;
+
+/// Size of an ID mapping.
+const ID_MAPPING_LEN: usize = 20;
+/// ID mapping flags, the mapping has a single output ID and no input range.
+const ID_MAPPING_SINGLE: u32 = 1 << 0;
+
+/// Reasons a table is not a usable IORT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IortError {
+    /// The table signature is not `IORT`.
+    BadSignature,
+    /// The bytes of the table do not sum to zero.
+    BadChecksum,
+    /// The table is shorter than its header says.
+    Truncated,
+}
+
+bitflags! {
+    /// SMMUv3 node flags.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IortSmmuFlags: u32 {
+        /// The SMMU accesses memory coherently, overriding SMMU_IDR0.COHACC.
+        const COHACC_OVERRIDE = 1 << 0;
+        /// SMMU_IDR0.HTTU is overridden, see [`IortSmmuFlags::httu_override`].
+        const HTTU_OVERRIDE = 0b11 << 1;
+        /// [`IortSmmu::proximity_domain`] is valid.
+        const PROXIMITY_DOMAIN_VALID = 1 << 3;
+        /// The DeviceID mapping index is valid, the SMMU sends MSIs.
+        const DEVICEID_MAPPING_VALID = 1 << 4;
+    }
+}
+
+impl IortSmmuFlags {
+    /// Hardware update of the translation tables the SMMU supports, 0 none, 1 Access flag,
+    /// 2 Access flag and dirty state.
+    pub fn httu_override(&self) -> u32 {
+        (self.bits() & Self::HTTU_OVERRIDE.bits()) >> 1
+    }
+}
+
+fn le32(bytes: &[u8], off: usize) -> Option<u32> {
+    let b = bytes.get(off..off.checked_add(4)?)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn le64(bytes: &[u8], off: usize) -> Option<u64> {
+    Some(le32(bytes, off)? as u64 | (le32(bytes, off + 4)? as u64) << 32)
+}
+
+/// An IO Remapping Table.
+#[derive(Debug, Clone, Copy)]
+pub struct Iort<'a> {
+    table: &'a [u8],
+}
+
+impl<'a> Iort<'a> {
+    /// Check the header of `table`, which must hold the whole IORT.
+    pub fn new(table: &'a [u8]) -> Result<Self, IortError> {
+        if table.get(..4) != Some(IORT_SIGNATURE) {
+            return Err(IortError::BadSignature);
+        }
+        let len = le32(table, 4).ok_or(IortError::Truncated)? as usize;
+        let table = table.get(..len).ok_or(IortError::Truncated)?;
+        if len < ACPI_HEADER_LEN + 12 {
+            return Err(IortError::Truncated);
+        }
+        if table.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(IortError::BadChecksum);
+        }
+        Ok(Self { table })
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = IortNode<'a>> + 'a {
+        let table = self.table;
+        let count = le32(table, ACPI_HEADER_LEN).unwrap_or(0);
+        let mut off = le32(table, ACPI_HEADER_LEN + 4).unwrap_or(0) as usize;
+        (0..count).map_while(move |_| {
+            let node = IortNode::new(table, off)?;
+            off += node.data.len();
+            Some(node)
+        })
+    }
+
+    /// The SMMUv3 nodes, in table order.
+    pub fn smmus(&self) -> impl Iterator<Item = IortSmmu> + 'a {
+        self.nodes().filter_map(|node| IortSmmu::from_node(&node))
+    }
+
+    /// Index in [`Iort::smmus`] of the SMMUv3 node at `offset`.
+    fn smmu_index(&self, offset: u32) -> Option<usize> {
+        self.smmus().position(|smmu| smmu.offset == offset)
+    }
+
+    /// First hop of `id` through the ID mappings of `node`, when it leads to an SMMUv3.
+    fn map_id(&self, node: &IortNode<'a>, id: Option<u32>) -> Option<(usize, StreamId)> {
+        node.id_mappings().find_map(|m| {
+            let sid = match id {
+                Some(id) if m.flags & ID_MAPPING_SINGLE == 0 => {
+                    // The number of IDs is encoded minus one.
+                    let offset = id.checked_sub(m.input_base)?;
+                    m.output_base
+                        .checked_add(offset)
+                        .filter(|_| offset <= m.id_count)?
+                }
+                None if m.flags & ID_MAPPING_SINGLE != 0 => m.output_base,
+                _ => return None,
+            };
+            Some((self.smmu_index(m.output_reference)?, StreamId::new(sid)))
+        })
+    }
+
+    /// The SMMU index in [`Iort::smmus`] and StreamID of PCIe requester `rid` of PCI segment
+    /// `segment`.
+    pub fn resolve_rid(&self, segment: u32, rid: u16) -> Option<(usize, StreamId)> {
+        self.nodes()
+            .filter(|node| node.kind() == IORT_NODE_ROOT_COMPLEX)
+            .filter(|node| le32(node.data, RC_PCI_SEGMENT) == Some(segment))
+            .find_map(|node| self.map_id(&node, Some(rid as u32)))
+    }
+
+    /// The SMMU index in [`Iort::smmus`] and StreamID of the PCIe function `bdf`.
+    pub fn resolve_bdf(&self, bdf: PciBdf) -> Option<(usize, StreamId)> {
+        self.resolve_rid(bdf.segment as u32, bdf.rid())
+    }
+
+    /// The SMMU index in [`Iort::smmus`] and StreamID of the named component `name`, the full
+    /// path of its ACPI device object such as `\_SB.ETH0`.
+    pub fn resolve_named(&self, name: &str) -> Option<(usize, StreamId)> {
+        self.nodes()
+            .filter(|node| node.kind() == IORT_NODE_NAMED_COMPONENT)
+            .filter(|node| node.object_name() == Some(name))
+            .find_map(|node| self.map_id(&node, None))
+    }
+}
+
+/// An ID mapping, from the IDs of a node to those of its output node.
+#[derive(Debug, Clone, Copy)]
+struct IdMapping {
+    input_base: u32,
+    /// Number of IDs in the range, minus one.
+    id_count: u32,
+    output_base: u32,
+    /// Offset of the output node from the start of the IORT.
+    output_reference: u32,
+    flags: u32,
+}
+
+/// A node, with its header.
+#[derive(Debug, Clone, Copy)]
+struct IortNode<'a> {
+    offset: u32,
+    data: &'a [u8],
+}
+
+impl<'a> IortNode<'a> {
+    fn new(table: &'a [u8], off: usize) -> Option<Self> {
+        let len = u16::from_le_bytes([
+            *table.get(off + NODE_LENGTH)?,
+            *table.get(off + NODE_LENGTH + 1)?,
+        ]);
+        let data = table.get(off..off + len as usize)?;
+        if data.len() < NODE_ID_ARRAY + 4 {
+            return None;
+        }
+        Some(Self {
+            offset: off as u32,
+            data,
+        })
+    }
+
+    fn kind(&self) -> u8 {
+        self.data[NODE_TYPE]
+    }
+
+    fn id_mappings(&self) -> impl Iterator<Item = IdMapping> + 'a {
+        let data = self.data;
+        let count = le32(data, NODE_ID_COUNT).unwrap_or(0) as usize;
+        let base = le32(data, NODE_ID_ARRAY).unwrap_or(0) as usize;
+        (0..count).map_while(move |i| {
+            let off = base + i * ID_MAPPING_LEN;
+            Some(IdMapping {
+                input_base: le32(data, off)?,
+                id_count: le32(data, off + 4)?,
+                output_base: le32(data, off + 8)?,
+                output_reference: le32(data, off + 12)?,
+                flags: le32(data, off + 16)?,
+            })
+        })
+    }
+
+    /// Device object name of a named component.
+    fn object_name(&self) -> Option<&'a str> {
+        let name = self.data.get(NC_OBJECT_NAME..)?;
+        let len = name.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&name[..len]).ok()
+    }
+}
+
+/// An SMMUv3 node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IortSmmu {
+    /// Offset of the node in the IORT, referred to by the ID mappings of its masters.
+    pub offset: u32,
+    /// Base of the register frame.
+    pub base: PhysAddr,
+    /// Flags of the node, such as its SMMU_IDR0 overrides.
+    pub flags: IortSmmuFlags,
+    /// Implementation of the SMMU, for its errata.
+    pub model: u32,
+    /// GSIV of the Event queue interrupt, `None` when it is an MSI or not implemented.
+    pub event_gsiv: Option<u32>,
+    /// GSIV of the PRI queue interrupt.
+    pub pri_gsiv: Option<u32>,
+    /// GSIV of the global error interrupt.
+    pub gerr_gsiv: Option<u32>,
+    /// GSIV of the CMD_SYNC interrupt.
+    pub sync_gsiv: Option<u32>,
+    /// NUMA proximity domain of the SMMU, as in the SRAT.
+    pub proximity_domain: Option<u32>,
+}
+
+impl IortSmmu {
+    fn from_node(node: &IortNode) -> Option<Self> {
+        if node.kind() != IORT_NODE_SMMU_V3 {
+            return None;
+        }
+        let data = node.data;
+        let gsiv = |off| le32(data, off).filter(|&gsiv| gsiv != 0);
+        let flags = IortSmmuFlags::from_bits_retain(le32(data, SMMU_FLAGS)?);
+        Some(Self {
+            offset: node.offset,
+            base: PhysAddr::from_usize(le64(data, SMMU_BASE)? as usize),
+            flags,
+            model: le32(data, SMMU_MODEL)?,
+            event_gsiv: gsiv(SMMU_EVENT_GSIV),
+            pri_gsiv: gsiv(SMMU_PRI_GSIV),
+            gerr_gsiv: gsiv(SMMU_GERR_GSIV),
+            sync_gsiv: gsiv(SMMU_SYNC_GSIV),
+            proximity_domain: le32(data, SMMU_PROXIMITY_DOMAIN)
+                .filter(|_| flags.contains(IortSmmuFlags::PROXIMITY_DOMAIN_VALID)),
+        })
+    }
+
+    /// A driver instance for this SMMU, accessing its registers at `H::phys_to_virt(base)`.
+    pub fn smmu<H: PagingHandler>(&self) -> SMMUv3<H> {
+        SMMUv3::new(H::phys_to_virt(self.base).as_mut_ptr())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn id_mapping(input: u32, count: u32, output: u32, reference: u32, flags: u32) -> Vec<u8> {
+        [input, count, output, reference, flags]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect()
+    }
+
+    /// Node with its header, `data` at offset 16 and the ID mappings after it.
+    fn node(kind: u8, data: &[u8], mappings: &[Vec<u8>]) -> Vec<u8> {
+        let id_array = 16 + data.len();
+        let len = id_array + mappings.len() * ID_MAPPING_LEN;
+        let mut node = std::vec![kind];
+        node.extend((len as u16).to_le_bytes());
+        node.push(0);
+        node.extend(0u32.to_le_bytes());
+        node.extend((mappings.len() as u32).to_le_bytes());
+        node.extend((id_array as u32).to_le_bytes());
+        node.extend(data);
+        mappings.iter().for_each(|m| node.extend(m));
+        node
+    }
+
+    #[test]
+    fn test_parse_iort() {
+        const NODES: u32 = 48;
+        let mut smmu = Vec::new();
+        smmu.extend(0x2b40_0000u64.to_le_bytes());
+        smmu.extend(((1 << 3) | (1 << 0) | (2 << 1) as u32).to_le_bytes());
+        smmu.extend([0; 12]);
+        for v in [0u32, 0x6a, 0, 0x6b, 0, 1, 0] {
+            smmu.extend(v.to_le_bytes());
+        }
+        let smmu = node(IORT_NODE_SMMU_V3, &smmu, &[]);
+        let smmu_ref = NODES;
+
+        let mut rc = std::vec![0; 12];
+        rc.extend(1u32.to_le_bytes());
+        rc.extend([0; 4]);
+        let rc = node(
+            IORT_NODE_ROOT_COMPLEX,
+            &rc,
+            &[id_mapping(0, 0xffff, 0x1_0000, smmu_ref, 0)],
+        );
+
+        let mut nc = std::vec![0; 13];
+        nc.extend(b"\\_SB.ETH0\0\0\0");
+        let nc = node(
+            IORT_NODE_NAMED_COMPONENT,
+            &nc,
+            &[id_mapping(0, 0, 0x42, smmu_ref, ID_MAPPING_SINGLE)],
+        );
+
+        let mut table = Vec::new();
+        table.extend(IORT_SIGNATURE);
+        table.extend([0; 32]);
+        table.extend(3u32.to_le_bytes());
+        table.extend(NODES.to_le_bytes());
+        table.extend([0; 4]);
+        for node in [smmu, rc, nc] {
+            table.extend(node);
+        }
+        let len = table.len() as u32;
+        table[4..8].copy_from_slice(&len.to_le_bytes());
+        table[9] = 0u8.wrapping_sub(table.iter().fold(0u8, |s, &b| s.wrapping_add(b)));
+
+        assert_eq!(Iort::new(&table[1..]).unwrap_err(), IortError::BadSignature);
+        assert_eq!(Iort::new(&table[..40]).unwrap_err(), IortError::Truncated);
+        let mut corrupted = table.clone();
+        corrupted[100] ^= 1;
+        assert_eq!(Iort::new(&corrupted).unwrap_err(), IortError::BadChecksum);
+
+        let iort = Iort::new(&table).unwrap();
+        let smmus: Vec<_> = iort.smmus().collect();
+        assert_eq!(smmus.len(), 1);
+        assert_eq!(smmus[0].base, PhysAddr::from_usize(0x2b40_0000));
+        assert_eq!((smmus[0].event_gsiv, smmus[0].pri_gsiv), (Some(0x6a), None));
+        assert_eq!(smmus[0].gerr_gsiv, Some(0x6b));
+        assert_eq!(smmus[0].proximity_domain, Some(1));
+        assert!(smmus[0].flags.contains(IortSmmuFlags::COHACC_OVERRIDE));
+        assert_eq!(smmus[0].flags.httu_override(), 2);
+
+        assert_eq!(
+            iort.resolve_rid(1, 0x0308),
+            Some((0, StreamId::new(0x1_0308)))
+        );
+        assert_eq!(iort.resolve_rid(0, 0x0308), None);
+        assert_eq!(
+            iort.resolve_bdf(PciBdf::new(1, 3, 1, 0)),
+            Some((0, StreamId::new(0x1_0308)))
+        );
+        assert_eq!(
+            iort.resolve_named("\\_SB.ETH0"),
+            Some((0, StreamId::new(0x42)))
+        );
+        assert_eq!(iort.resolve_named("\\_SB.ETH1"), None);
+    }
+}
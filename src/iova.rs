@@ -0,0 +1,114 @@
+//! I/O virtual address space allocation for DMA domains.
+
+use alloc::collections::BTreeMap;
+
+/// Allocator of I/O virtual address ranges.
+///
+/// Free ranges are kept in a B-tree keyed by their start address and merged with their neighbours
+/// when released. Allocations are carved from the top of the highest free range that fits.
+pub struct IovaAllocator {
+    /// Free ranges, start to end (exclusive).
+    free: BTreeMap<usize, usize>,
+    granule: usize,
+}
+
+impl IovaAllocator {
+    /// Create an allocator handing out `granule` aligned ranges of `[base, base + size)`.
+    pub fn new(base: usize, size: usize, granule: usize) -> Self {
+        assert!(
+            granule.is_power_of_two(),
+            "IOVA granule must be a power of two"
+        );
+        assert!(
+            base.is_multiple_of(granule) && size.is_multiple_of(granule),
+            "IOVA aperture must be granule aligned"
+        );
+        let mut free = BTreeMap::new();
+        if size != 0 {
+            free.insert(base, base + size);
+        }
+        Self { free, granule }
+    }
+
+    /// Allocation granule, all sizes are rounded up to it.
+    pub fn granule(&self) -> usize {
+        self.granule
+    }
+
+    /// Allocate `size` bytes aligned to `align`, a power of two, or to the granule if larger.
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        if size == 0 {
+            return None;
+        }
+        let size = size.checked_next_multiple_of(self.granule)?;
+        let mask = align.max(self.granule) - 1;
+        let (start, end, iova) = self.free.iter().rev().find_map(|(&start, &end)| {
+            let iova = end.checked_sub(size)? & !mask;
+            (iova >= start).then_some((start, end, iova))
+        })?;
+
+        self.free.remove(&start);
+        if start < iova {
+            self.free.insert(start, iova);
+        }
+        if iova + size < end {
+            self.free.insert(iova + size, end);
+        }
+        Some(iova)
+    }
+
+    /// Release a range obtained from [`IovaAllocator::alloc`] with the same size.
+    pub fn free(&mut self, iova: usize, size: usize) {
+        let Some(size) = size.checked_next_multiple_of(self.granule) else {
+            return;
+        };
+        let (mut start, mut end) = (iova, iova + size);
+        let overlaps_prev = self
+            .free
+            .range(..=start)
+            .next_back()
+            .is_some_and(|(_, &prev_end)| prev_end > start);
+        if overlaps_prev || self.free.range(start..end).next().is_some() {
+            warn!("Freeing unallocated IOVA range [{:#x}, {:#x})", start, end);
+            return;
+        }
+
+        if let Some((&prev, _)) = self
+            .free
+            .range(..start)
+            .next_back()
+            .filter(|(_, &e)| e == start)
+        {
+            self.free.remove(&prev);
+            start = prev;
+        }
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
+        self.free.insert(start, end);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_iova_alloc_free() {
+        let mut iova = IovaAllocator::new(0x1000, 0xf000, 0x1000);
+
+        // Allocations come from the top, rounded up to the granule.
+        assert_eq!(iova.alloc(0x1800, 0), Some(0xe000));
+        assert_eq!(iova.alloc(0x1000, 0x4000), Some(0xc000));
+        assert_eq!(iova.alloc(0x1000, 0), Some(0xd000));
+        assert_eq!(iova.alloc(0x10000, 0), None);
+
+        // Freed neighbours are merged back into a single range.
+        iova.free(0xd000, 0x1000);
+        iova.free(0xe000, 0x1800);
+        iova.free(0xe000, 0x1000);
+        iova.free(0xc000, 0x1000);
+        assert_eq!(iova.alloc(0xf000, 0), Some(0x1000));
+        assert_eq!(iova.alloc(0x1000, 0), None);
+    }
+}
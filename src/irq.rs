@@ -0,0 +1,373 @@
+//! Interrupt configuration and handling.
+//!
+//! The SMMU signals three interrupt sources: global errors, new Event queue records and new PRI
+//! queue entries. Each one is a wired interrupt, or an MSI when SMMU_IDR0.MSI is set and an MSI
+//! address is configured. The `handle_*_irq` methods are called from the interrupt handlers,
+//! [`SMMUv3::handle_combined_irq`] from the handler of a single combined interrupt. Faults are
+//! reported to the [`FaultHandler`] set with [`SMMUv3::set_fault_handler`].
+
+use bitflags::bitflags;
+use memory_addr::PhysAddr;
+use tock_registers::fields::Field;
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+
+use crate::backend::RegisterBackend;
+use crate::event::{Event, PageRequest, PriResponse};
+use crate::guest_stream::GuestStream;
+use crate::hal::PagingHandler;
+use crate::queue::{Cmd, EVTQ_ENT_DWORDS, PRIQ_ENT_DWORDS};
+use crate::stream_id::StreamId;
+use crate::{
+    SMMUv3, ARM_SMMU_SYNC_TIMEOUT, CMDQ_CONS, EVENTQ_CONS, EVENTQ_PROD, IDR0, IRQ_CFG0, IRQ_CFG1,
+    IRQ_CFG2, IRQ_CTRL, PRIQ_CONS, PRIQ_PROD,
+};
+
+/// Interrupt sources of the SMMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    /// Global errors, see [`SMMUv3::handle_gerror_irq`].
+    GlobalError,
+    /// Event queue, see [`SMMUv3::handle_event_irq`].
+    EventQueue,
+    /// PRI queue, see [`SMMUv3::handle_priq_irq`].
+    PriQueue,
+}
+
+/// MSI write signalling an interrupt source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiConfig {
+    /// Address of the MSI target register, 4-byte aligned.
+    pub addr: PhysAddr,
+    /// Payload written to `addr`.
+    pub data: u32,
+    /// Memory type of the write, SMMU_*_IRQ_CFG2.MemAttr encoding.
+    pub memattr: u8,
+    /// Shareability of the write, SMMU_*_IRQ_CFG2.SH encoding.
+    pub sh: u8,
+}
+
+impl MsiConfig {
+    /// MSI writing `data` to the Device-nGnRE register at `addr`, such as a GIC ITS doorbell.
+    pub const fn new(addr: PhysAddr, data: u32) -> Self {
+        Self {
+            addr,
+            data,
+            memattr: IRQ_CFG2::MEMATTR::DeviceNGnRE.value as u8,
+            sh: IRQ_CFG2::SH::NonShareable.value as u8,
+        }
+    }
+}
+
+/// How an interrupt source is signalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqDelivery {
+    /// Wired interrupt, routed by the platform.
+    Wired,
+    /// Message Signalled Interrupt.
+    Msi(MsiConfig),
+}
+
+/// Reasons an interrupt cannot be configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The SMMU does not implement MSIs, or the PRI queue.
+    Unsupported,
+    /// SMMU_IRQ_CTRLACK did not reflect the update of SMMU_IRQ_CTRL in time.
+    AckTimeout,
+}
+
+bitflags! {
+    /// Active global errors, with the SMMU_GERROR layout.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct GlobalErrors: u32 {
+        /// Command queue error, the reason is in SMMU_CMDQ_CONS.ERR.
+        const CMDQ_ERR = 1 << 0;
+        /// An access to the Event queue was aborted.
+        const EVENTQ_ABT_ERR = 1 << 2;
+        /// An access to the PRI queue was aborted.
+        const PRIQ_ABT_ERR = 1 << 3;
+        /// A CMD_SYNC MSI write was aborted.
+        const MSI_CMDQ_ABT_ERR = 1 << 4;
+        /// An Event queue MSI write was aborted.
+        const MSI_EVENTQ_ABT_ERR = 1 << 5;
+        /// A PRI queue MSI write was aborted.
+        const MSI_PRIQ_ABT_ERR = 1 << 6;
+        /// A GERROR MSI write was aborted.
+        const MSI_GERROR_ABT_ERR = 1 << 7;
+        /// The SMMU entered Service Failure Mode.
+        const SFM_ERR = 1 << 8;
+        /// An error occurred on an Enhanced Command queue.
+        const CMDQP_ERR = 1 << 9;
+        /// An error occurred while looking up a Device Permission Table.
+        const DPT_ERR = 1 << 10;
+    }
+}
+
+/// Receiver of the faults reported by the SMMU interrupts.
+///
+/// Methods are called from the interrupt handlers, so they must not block. The default
+/// implementations log the faults and deny page requests.
+pub trait FaultHandler: Send + Sync {
+    /// An event record was read from the Event queue.
+    fn on_event(&self, event: &Event) {
+        warn!("SMMUv3 event: {:x?}", event);
+    }
+
+    /// A stage 1 fault of a stream assigned to a guest, see [`SMMUv3::set_guest_stream`].
+    ///
+    /// The hypervisor forwards [`Event::guest_record`] to the guest SMMU, with
+    /// [`VSmmu::inject_event`]. Defaults to [`FaultHandler::on_event`].
+    ///
+    /// [`VSmmu::inject_event`]: crate::VSmmu::inject_event
+    fn on_guest_event(&self, event: &Event, _guest: GuestStream) {
+        self.on_event(event)
+    }
+
+    /// A page request was read from the PRI queue.
+    ///
+    /// For the last request of a group, the returned response is sent to the device. `None`
+    /// leaves the group pending, to be answered later with [`SMMUv3::pri_respond`].
+    fn on_page_request(&self, req: &PageRequest) -> Option<PriResponse> {
+        warn!("SMMUv3 unhandled page request: {:x?}", req);
+        Some(PriResponse::Denied)
+    }
+
+    /// The Event queue overflowed and event records were lost, how many is not known.
+    fn on_event_overflow(&self) {
+        warn!("SMMUv3 event queue overflow, events lost");
+    }
+
+    /// `sid` reached its fault threshold with `faults` faults and was quarantined, see
+    /// [`SMMUv3::set_fault_threshold`].
+    fn on_quarantine(&self, sid: u32, faults: u32) {
+        warn!("SMMUv3 sid {:#x} quarantined after {} faults", sid, faults);
+    }
+
+    /// Global errors became active, they are acknowledged once this returns.
+    fn on_global_error(&self, errors: GlobalErrors) {
+        error!("SMMUv3 global errors: {:?}", errors);
+    }
+}
+
+/// Handler used until [`SMMUv3::set_fault_handler`] is called.
+struct LogFaultHandler;
+
+impl FaultHandler for LogFaultHandler {}
+
+const fn irqen(source: IrqSource) -> Field<u32, IRQ_CTRL::Register> {
+    match source {
+        IrqSource::GlobalError => IRQ_CTRL::GERROR_IRQEN,
+        IrqSource::EventQueue => IRQ_CTRL::EVENTQ_IRQEN,
+        IrqSource::PriQueue => IRQ_CTRL::PRIQ_IRQEN,
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Report faults to `handler` from now on.
+    pub fn set_fault_handler(&mut self, handler: &'static dyn FaultHandler) {
+        self.fault_handler = Some(handler);
+    }
+
+    pub(crate) fn fault_handler(&self) -> &'static dyn FaultHandler {
+        self.fault_handler.unwrap_or(&LogFaultHandler)
+    }
+
+    /// Whether the SMMU can signal interrupts with MSIs.
+    pub fn msi_supported(&self) -> bool {
+        self.regs().IDR0.is_set(IDR0::MSI)
+    }
+
+    /// Whether interrupts of `source` are enabled.
+    pub fn irq_enabled(&self, source: IrqSource) -> bool {
+        self.regs().IRQ_CTRL.is_set(irqen(source))
+    }
+
+    /// Enable or disable interrupts of `source`, waiting for SMMU_IRQ_CTRLACK.
+    pub fn set_irq_enabled(&mut self, source: IrqSource, enable: bool) -> Result<(), IrqError> {
+        if source == IrqSource::PriQueue && !self.pri_supported() {
+            return Err(IrqError::Unsupported);
+        }
+        self.regs()
+            .IRQ_CTRL
+            .modify(irqen(source).val(enable as u32));
+
+        let irq_ctrl = self.regs().IRQ_CTRL.get();
+        for _timeout in 0..ARM_SMMU_SYNC_TIMEOUT {
+            if self.regs().IRQ_CTRLACK.get() == irq_ctrl {
+                return Ok(());
+            }
+        }
+        error!("SMMUv3 IRQ_CTRL update timeout");
+        Err(IrqError::AckTimeout)
+    }
+
+    /// Select how `source` is signalled.
+    ///
+    /// The source is disabled while its SMMU_*_IRQ_CFG registers are updated, and enabled again
+    /// if it was before.
+    pub fn configure_irq(
+        &mut self,
+        source: IrqSource,
+        delivery: IrqDelivery,
+    ) -> Result<(), IrqError> {
+        if source == IrqSource::PriQueue && !self.pri_supported() {
+            return Err(IrqError::Unsupported);
+        }
+        let msi_supported = self.msi_supported();
+        if matches!(delivery, IrqDelivery::Msi(_)) && !msi_supported {
+            return Err(IrqError::Unsupported);
+        }
+        if !msi_supported {
+            // Wired only, nothing to configure.
+            return Ok(());
+        }
+
+        let enabled = self.irq_enabled(source);
+        self.set_irq_enabled(source, false)?;
+        let regs = self.regs();
+        let (cfg0, cfg1, cfg2) = match source {
+            IrqSource::GlobalError => (
+                &regs.GERROR_IRQ_CFG0,
+                &regs.GERROR_IRQ_CFG1,
+                &regs.GERROR_IRQ_CFG2,
+            ),
+            IrqSource::EventQueue => (
+                &regs.EVENTQ_IRQ_CFG0,
+                &regs.EVENTQ_IRQ_CFG1,
+                &regs.EVENTQ_IRQ_CFG2,
+            ),
+            IrqSource::PriQueue => (
+                &regs.PRIQ_IRQ_CFG0,
+                &regs.PRIQ_IRQ_CFG1,
+                &regs.PRIQ_IRQ_CFG2,
+            ),
+        };
+        match delivery {
+            // A zero MSI address selects the wired interrupt.
+            IrqDelivery::Wired => cfg0.write(IRQ_CFG0::ADDR.val(0)),
+            IrqDelivery::Msi(msi) => {
+                cfg0.write(IRQ_CFG0::ADDR.val(msi.addr.as_usize() as u64 >> 2));
+                cfg1.write(IRQ_CFG1::DATA.val(msi.data));
+                cfg2.write(
+                    IRQ_CFG2::MEMATTR.val(msi.memattr as u32) + IRQ_CFG2::SH.val(msi.sh as u32),
+                );
+            }
+        }
+        if enabled {
+            self.set_irq_enabled(source, true)?;
+        }
+        Ok(())
+    }
+
+    /// Interrupt handler of a combined interrupt, signalling all the sources.
+    ///
+    /// Handles the active global errors, then the new event records and page requests. Returns
+    /// whether any of them was pending, for interrupt lines shared with other devices.
+    pub fn handle_combined_irq(&mut self) -> bool {
+        let errors = self.handle_gerror_irq();
+        let events = self.handle_event_irq();
+        let requests = self.handle_priq_irq();
+        !errors.is_empty() || events != 0 || requests != 0
+    }
+
+    /// Global error interrupt handler, reports and acknowledges the active global errors.
+    pub fn handle_gerror_irq(&mut self) -> GlobalErrors {
+        let gerror = self.regs().GERROR.get();
+        let errors = GlobalErrors::from_bits_retain(gerror ^ self.regs().GERRORN.get());
+        if errors.is_empty() {
+            return errors;
+        }
+
+        self.fault_handler().on_global_error(errors);
+        if errors.contains(GlobalErrors::CMDQ_ERR) {
+            self.skip_cmdq_err();
+        }
+        // Toggle the acknowledged bits of SMMU_GERRORN back in line with SMMU_GERROR.
+        self.regs().GERRORN.set(gerror);
+        errors
+    }
+
+    /// Replace the command the Command queue stopped on by a CMD_SYNC, before SMMU_GERROR.CMDQ_ERR
+    /// is acknowledged.
+    pub(crate) fn skip_cmdq_err(&self) {
+        // The SMMU stopped on the faulting command and restarts from it once acknowledged.
+        let cons = self.regs().CMDQ_CONS.extract();
+        error!("CMDQ_CONS ERR code {:#x}", cons.read(CMDQ_CONS::ERR));
+        self.cmd_queue.skip_err(cons.read(CMDQ_CONS::RD));
+    }
+
+    /// Event queue interrupt handler, consumes the new event records and reports them.
+    ///
+    /// Faults are counted against their StreamID, see [`SMMUv3::set_fault_threshold`], and records
+    /// of quarantined streams are dropped. Stage 1 faults of the streams assigned to guests are
+    /// reported with [`FaultHandler::on_guest_event`]. An overflow of the queue is reported and
+    /// acknowledged once the records still in the queue are consumed. Returns the number of
+    /// records consumed.
+    pub fn handle_event_irq(&mut self) -> usize {
+        let prod = self.regs().EVENTQ_PROD.extract();
+        self.event_queue.set_prod_value(prod.read(EVENTQ_PROD::WR));
+        let handler = self.fault_handler();
+        let mut count = 0;
+        while let Some(raw) = self.event_queue.entry_pop::<EVTQ_ENT_DWORDS>() {
+            count += 1;
+            let event = Event::from_raw(raw);
+            // Records queued before the quarantine of their stream are dropped.
+            if self.is_quarantined(StreamId::new(event.sid)) {
+                continue;
+            }
+            match self.guest_stream(StreamId::new(event.sid)) {
+                Some(guest) if event.is_stage1_fault() => handler.on_guest_event(&event, guest),
+                _ => handler.on_event(&event),
+            }
+            self.account_fault(&event);
+        }
+
+        // The queue is in the overflow condition while OVSLG differs from OVACKFLG, and leaves it
+        // once OVACKFLG is written with the value of OVSLG.
+        let ovflg = prod.read(EVENTQ_PROD::OVSLG);
+        if ovflg != self.regs().EVENTQ_CONS.read(EVENTQ_CONS::OVACKFLG) {
+            self.event_overflows += 1;
+            handler.on_event_overflow();
+        }
+        self.regs().EVENTQ_CONS.write(
+            EVENTQ_CONS::RD.val(self.event_queue.cons_value()) + EVENTQ_CONS::OVACKFLG.val(ovflg),
+        );
+        count
+    }
+
+    /// Number of Event queue overflows seen by [`SMMUv3::handle_event_irq`].
+    pub fn event_queue_overflows(&self) -> u64 {
+        self.event_overflows
+    }
+
+    /// PRI queue interrupt handler, consumes the new page requests and reports them.
+    ///
+    /// Returns the number of requests consumed.
+    pub fn handle_priq_irq(&mut self) -> usize {
+        if !self.pri_supported() {
+            return 0;
+        }
+        self.pri_queue
+            .set_prod_value(self.regs().PRIQ_PROD.read(PRIQ_PROD::WR));
+        let handler = self.fault_handler();
+        let mut count = 0;
+        while let Some(raw) = self.pri_queue.entry_pop::<PRIQ_ENT_DWORDS>() {
+            let req = PageRequest::from_raw(raw);
+            if let Some(resp) = handler.on_page_request(&req) {
+                if req.last {
+                    self.pri_respond(&req, resp);
+                }
+            }
+            count += 1;
+        }
+        self.regs()
+            .PRIQ_CONS
+            .write(PRIQ_CONS::RD.val(self.pri_queue.cons_value()));
+        count
+    }
+
+    /// Answer the Page Request Group of `req`, which must be the last request of the group.
+    pub fn pri_respond(&mut self, req: &PageRequest, resp: PriResponse) {
+        self.add_cmd(Cmd::cmd_pri_resp(req, resp), false);
+    }
+}
@@ -1,28 +1,108 @@
 //! ARM System Memory Management Unit (SMMU) v3 driver written in Rust.
 
 #![no_std]
+// The register_structs! of SMMUv3Regs, with the Secure registers, needs more than the default.
+#![recursion_limit = "256"]
 
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "dma")]
+extern crate alloc;
+
 use core::panic;
-use core::ptr::NonNull;
 
 use memory_addr::PhysAddr;
 use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::register_structs;
-use tock_registers::registers::{ReadOnly, ReadWrite};
-
+use tock_registers::registers::ReadOnly;
+
+mod atos;
+mod backend;
+mod cmdq;
+mod context_descriptor;
+#[cfg(feature = "dma")]
+mod dma;
+mod domain;
+mod dpt;
+mod ecmdq;
+mod event;
+#[cfg(feature = "fdt")]
+mod fdt;
+mod guest_stream;
 mod hal;
+mod id_alloc;
+#[cfg(feature = "io_pgtable")]
+mod io_pgtable;
+#[cfg(feature = "iort")]
+mod iort;
+#[cfg(feature = "dma")]
+mod iova;
+mod irq;
+mod manager;
+// The model needs std, built for the tests and with the `model` feature on hosted targets.
+#[cfg(any(test, all(feature = "model", not(target_os = "none"))))]
+mod model;
+mod quarantine;
 mod queue;
 mod regs;
+mod secure;
+mod stream_id;
 mod stream_table;
-
+mod suspend;
+#[cfg(test)]
+mod test_utils;
+mod vsmmu;
+mod walk;
+
+pub use atos::{AtosError, AtosFaultReason, AtosKind, AtosStages, AtosTranslation};
+pub use backend::{
+    CmdqControlPageRegs, EcmdqRegs, Mmio, ReadOnlyProxy, ReadWriteProxy, RegisterBackend,
+    RegisterWidth, SmmuRegs,
+};
+pub use context_descriptor::{CdTable, ContextDescriptor};
+#[cfg(feature = "dma")]
+pub use dma::{DmaDirection, DmaDomainConfig, DmaDomainId, DmaError};
+pub use domain::{DomainContext, DomainError, IommuDomain};
+pub use dpt::{DevicePermission, DptError};
+pub use ecmdq::EcmdqError;
+pub use event::{Event, EventType, PageRequest, PriResponse};
+#[cfg(feature = "fdt")]
+pub use fdt::{Fdt, FdtError, FdtSmmu, FdtStreamMap, IrqSpec, SmmuIrqs};
+pub use guest_stream::GuestStream;
 pub use hal::PagingHandler;
+pub use id_alloc::VmidError;
+#[cfg(feature = "io_pgtable")]
+pub use io_pgtable::{
+    Granule, IoPageTable, IoPgtableConfig, IoPgtableError, IoProt, IoTlbFlush, TlbContext,
+};
+#[cfg(feature = "iort")]
+pub use iort::{Iort, IortError, IortSmmu, IortSmmuFlags};
+#[cfg(feature = "dma")]
+pub use iova::IovaAllocator;
+pub use irq::{FaultHandler, GlobalErrors, IrqDelivery, IrqError, IrqSource, MsiConfig};
+pub use manager::{ManagerError, SidRange, SmmuManager};
+#[cfg(any(test, all(feature = "model", not(target_os = "none"))))]
+pub use model::{ModelConfig, SmmuModel};
 pub use regs::*;
-
+pub use stream_id::{PciBdf, PciBridge, RidMap, StreamId};
+pub use stream_table::HttuMode;
+pub use suspend::SuspendError;
+pub use vsmmu::{GuestEventError, VSmmu, VSmmuHooks};
+pub use walk::{Access, Translation, TranslationFault};
+
+#[cfg(feature = "dma")]
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use cmdq::CmdQueue;
+use dpt::DeviceTable;
+use ecmdq::Ecmdqs;
+use guest_stream::GuestStreams;
+use id_alloc::{IdAllocator, VmidAllocator};
+use quarantine::FaultCounters;
 use queue::{Cmd, Queue};
 use stream_table::LinearStreamTable;
+use suspend::SavedRegs;
 
 register_structs! {
     /// Chapter 6. Memory map and registers 6.2.
@@ -30,12 +110,17 @@ register_structs! {
     /// The following registers are accessible from the SMMU page 0 and page 1 region.
     /// - 0x00000-0x0FFFF SMMU registers, Page 0
     /// - 0x10000-0x1FFFF SMMU registers, Page 1
+    ///
+    /// The Secure registers from 0x8000 of page 0 that duplicate Non-secure ones are reached with
+    /// the Non-secure names through [`SmmuRegs::secure`].
+    ///
+    /// Registers added here are accessed by the driver once listed in [`SmmuRegs`] as well.
     #[allow(non_snake_case)]
     pub SMMUv3Regs  {
         (0x0000 => IDR0: IDR0Reg),
         (0x0004 => IDR1: IDR1Reg),
         (0x0008 => IDR2: ReadOnly<u32>),
-        (0x000C => IDR3: ReadOnly<u32>),
+        (0x000C => IDR3: IDR3Reg),
         (0x0010 => IDR4: ReadOnly<u32>),
         (0x0014 => IDR5: ReadOnly<u32>),
         (0x0018 => IIDR: ReadOnly<u32>),
@@ -45,13 +130,17 @@ register_structs! {
         (0x0028 => CR1: Cr1Reg),
         (0x002c => CR2: Cr2Reg),
         (0x0030 => _reserved0),
-        (0x0050 => IRQ_CTRL: ReadWrite<u32>),
-        (0x0054 => IRQ_CTRLACK: ReadOnly<u32>),
+        (0x0044 => GBPA: GbpaReg),
+        (0x0048 => _reserved20),
+        (0x0050 => IRQ_CTRL: IrqCtrlReg),
+        (0x0054 => IRQ_CTRLACK: IrqCtrlAckReg),
         (0x0058 => _reserved1),
-        (0x0060 => GERROR: ReadOnly<u32>),
-        (0x0064 => GERRORN: ReadWrite<u32>),
-        (0x0068 => GERROR_IRQ_CFG0: ReadWrite<u64>),
-        (0x0070 => _reserved2),
+        (0x0060 => GERROR: GerrorReg),
+        (0x0064 => GERRORN: GerrorNReg),
+        (0x0068 => GERROR_IRQ_CFG0: IrqCfg0Reg),
+        (0x0070 => GERROR_IRQ_CFG1: IrqCfg1Reg),
+        (0x0074 => GERROR_IRQ_CFG2: IrqCfg2Reg),
+        (0x0078 => _reserved2),
         (0x0080 => STRTAB_BASE: StrtabBaseReg),
         (0x0088 => STRTAB_BASE_CFG: StrtabBaseCfgReg),
         (0x008c => _reserved3),
@@ -60,45 +149,150 @@ register_structs! {
         (0x009c => CMDQ_CONS: CmdQConsReg),
         (0x00a0 => EVENTQ_BASE: EventQBaseReg),
         (0x00a8 => _reserved4),
-        (0x00b0 => EVENTQ_IRQ_CFG0: ReadWrite<u64>),
-        (0x00b8 => EVENTQ_IRQ_CFG1: ReadWrite<u32>),
-        (0x00bc => EVENTQ_IRQ_CFG2: ReadWrite<u32>),
-        (0x00c0 => _reserved5),
+        (0x00b0 => EVENTQ_IRQ_CFG0: IrqCfg0Reg),
+        (0x00b8 => EVENTQ_IRQ_CFG1: IrqCfg1Reg),
+        (0x00bc => EVENTQ_IRQ_CFG2: IrqCfg2Reg),
+        (0x00c0 => PRIQ_BASE: PriQBaseReg),
+        (0x00c8 => _reserved5),
+        (0x00d0 => PRIQ_IRQ_CFG0: IrqCfg0Reg),
+        (0x00d8 => PRIQ_IRQ_CFG1: IrqCfg1Reg),
+        (0x00dc => PRIQ_IRQ_CFG2: IrqCfg2Reg),
+        (0x00e0 => _reserved6),
+        (0x0100 => GATOS_CTRL: GatosCtrlReg),
+        (0x0104 => _reserved7),
+        (0x0108 => GATOS_SID: GatosSidReg),
+        (0x0110 => GATOS_ADDR: GatosAddrReg),
+        (0x0118 => GATOS_PAR: GatosParReg),
+        (0x0120 => _reserved8),
+        (0x0190 => IDR6: IDR6Reg),
+        (0x0194 => _reserved11),
+        (0x0200 => DPT_BASE: DptBaseReg),
+        (0x0208 => DPT_BASE_CFG: DptBaseCfgReg),
+        (0x020c => _reserved19),
+        (0x8000 => S_IDR0: ReadOnly<u32>),
+        (0x8004 => S_IDR1: SIdr1Reg),
+        (0x8008 => S_IDR2: ReadOnly<u32>),
+        (0x800c => S_IDR3: ReadOnly<u32>),
+        (0x8010 => S_IDR4: ReadOnly<u32>),
+        (0x8014 => _reserved12),
+        (0x8020 => S_CR0: Cr0Reg),
+        (0x8024 => S_CR0ACK: Cr0AckReg),
+        (0x8028 => S_CR1: Cr1Reg),
+        (0x802c => S_CR2: Cr2Reg),
+        (0x8030 => _reserved13),
+        (0x803c => S_INIT: SInitReg),
+        (0x8040 => _reserved14),
+        (0x8044 => S_GBPA: GbpaReg),
+        (0x8048 => _reserved21),
+        (0x8050 => S_IRQ_CTRL: IrqCtrlReg),
+        (0x8054 => S_IRQ_CTRLACK: IrqCtrlAckReg),
+        (0x8058 => _reserved15),
+        (0x8060 => S_GERROR: GerrorReg),
+        (0x8064 => S_GERRORN: GerrorNReg),
+        (0x8068 => S_GERROR_IRQ_CFG0: IrqCfg0Reg),
+        (0x8070 => S_GERROR_IRQ_CFG1: IrqCfg1Reg),
+        (0x8074 => S_GERROR_IRQ_CFG2: IrqCfg2Reg),
+        (0x8078 => _reserved16),
+        (0x8080 => S_STRTAB_BASE: StrtabBaseReg),
+        (0x8088 => S_STRTAB_BASE_CFG: StrtabBaseCfgReg),
+        (0x808c => _reserved17),
+        (0x8090 => S_CMDQ_BASE: CmdQBaseReg),
+        (0x8098 => S_CMDQ_PROD: CmdQProdReg),
+        (0x809c => S_CMDQ_CONS: CmdQConsReg),
+        (0x80a0 => S_EVENTQ_BASE: EventQBaseReg),
+        (0x80a8 => S_EVENTQ_PROD: EventQProdReg),
+        (0x80ac => S_EVENTQ_CONS: EventQConsReg),
+        (0x80b0 => S_EVENTQ_IRQ_CFG0: IrqCfg0Reg),
+        (0x80b8 => S_EVENTQ_IRQ_CFG1: IrqCfg1Reg),
+        (0x80bc => S_EVENTQ_IRQ_CFG2: IrqCfg2Reg),
+        (0x80c0 => _reserved18),
         (0x100a8 => EVENTQ_PROD: EventQProdReg),
         (0x100ac => EVENTQ_CONS: EventQConsReg),
-        (0x100b0 => _reserved6),
+        (0x100b0 => _reserved9),
+        (0x100c8 => PRIQ_PROD: PriQProdReg),
+        (0x100cc => PRIQ_CONS: PriQConsReg),
+        (0x100d0 => _reserved10),
         (0x20000 => @END),
     }
 }
 
 /// SMMUv3 driver with a linear stream table and cmd queue.
-pub struct SMMUv3<H: PagingHandler> {
-    base: NonNull<SMMUv3Regs>,
+///
+/// Registers are accessed through `B`, memory mapped by default.
+pub struct SMMUv3<H: PagingHandler, B: RegisterBackend = Mmio> {
+    backend: B,
+    secure: bool,
     stream_table: LinearStreamTable<H>,
-    cmd_queue: Queue<H>,
+    cmd_queue: CmdQueue<H>,
+    ecmdqs: Ecmdqs<H>,
     event_queue: Queue<H>,
+    pri_queue: Queue<H>,
+    asid_alloc: IdAllocator<H>,
+    vmid_alloc: VmidAllocator<H>,
+    httu: HttuMode,
+    fault_handler: Option<&'static dyn FaultHandler>,
+    event_overflows: u64,
+    fault_counters: FaultCounters<H>,
+    guest_streams: GuestStreams<H>,
+    dpt: DeviceTable<H>,
+    suspended: Option<SavedRegs>,
+    #[cfg(feature = "dma")]
+    dma_domains: Vec<Option<dma::DmaDomain<H>>>,
+    #[cfg(feature = "dma")]
+    dma_sids: BTreeMap<StreamId, DmaDomainId>,
 }
 
-unsafe impl<H: PagingHandler> Send for SMMUv3<H> {}
-unsafe impl<H: PagingHandler> Sync for SMMUv3<H> {}
+unsafe impl<H: PagingHandler, B: RegisterBackend + Send> Send for SMMUv3<H, B> {}
+unsafe impl<H: PagingHandler, B: RegisterBackend + Sync> Sync for SMMUv3<H, B> {}
 
 const ARM_SMMU_SYNC_TIMEOUT: usize = 0x1000000;
+/// Above this many TLBI_NH_VA or TLBI_S2_IPA commands, a range invalidation falls back to
+/// invalidating the whole ASID or VMID.
+#[cfg(feature = "io_pgtable")]
+const TLBI_RANGE_MAX_CMDS: usize = 256;
 
 impl<H: PagingHandler> SMMUv3<H> {
     /// Construct a new SMMUv3 instance from the base address.
     pub const fn new(base: *mut u8) -> Self {
+        Self::with_backend(Mmio::new(base))
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Construct a new SMMUv3 instance accessing its registers through `backend`.
+    pub const fn with_backend(backend: B) -> Self {
         Self {
-            base: NonNull::new(base).unwrap().cast(),
+            backend,
+            secure: false,
             stream_table: LinearStreamTable::uninit(),
-            cmd_queue: Queue::uninit(),
+            cmd_queue: CmdQueue::uninit(),
+            ecmdqs: Ecmdqs::uninit(),
             event_queue: Queue::uninit(),
+            pri_queue: Queue::uninit(),
+            asid_alloc: IdAllocator::uninit(),
+            vmid_alloc: VmidAllocator::uninit(),
+            httu: HttuMode::Disabled,
+            fault_handler: None,
+            event_overflows: 0,
+            fault_counters: FaultCounters::uninit(),
+            guest_streams: GuestStreams::uninit(),
+            dpt: DeviceTable::uninit(),
+            suspended: None,
+            #[cfg(feature = "dma")]
+            dma_domains: Vec::new(),
+            #[cfg(feature = "dma")]
+            dma_sids: BTreeMap::new(),
         }
     }
 
     /// Initialize the SMMUv3 instance.
     pub fn init(&mut self) {
-        let sid_max_bits = self.regs().IDR1.read(IDR1::SIDSIZE);
-        info!("Max SID bits: {}, max SIE count {}", sid_max_bits, 1 << sid_max_bits);
+        let sid_max_bits = self.sid_bits();
+        info!(
+            "Max SID bits: {}, max SIE count {}",
+            sid_max_bits,
+            1 << sid_max_bits
+        );
 
         if sid_max_bits >= 7
             && self.regs().IDR0.read(IDR0::ST_LEVEL) == IDR0::ST_LEVEL::LinearStreamTable.into()
@@ -107,6 +301,10 @@ impl<H: PagingHandler> SMMUv3<H> {
             panic!("Smmuv3 the system must support for 2-level table");
         }
 
+        if self.secure {
+            self.secure_invalidate_all();
+        }
+
         let cmdqs_log2 = H::CMDQ_EVENTQ_BITS_SET;
         self.cmd_queue.init(cmdqs_log2);
         self.regs().CMDQ_BASE.write(
@@ -120,12 +318,54 @@ impl<H: PagingHandler> SMMUv3<H> {
             .write(CMDQ_PROD::WR.val(self.cmd_queue.prod_value()));
         self.regs()
             .CMDQ_CONS
-            .write(CMDQ_CONS::RD.val(self.cmd_queue.cons_value()));
+            .write(CMDQ_CONS::RD.val(self.cmd_queue.prod_value()));
+
+        let eventqs_log2 = H::CMDQ_EVENTQ_BITS_SET;
+        self.event_queue.init_event(eventqs_log2);
+        self.regs().EVENTQ_BASE.write(
+            EVENTQ_BASE::WA::ReadAllocate
+                + EVENTQ_BASE::ADDR.val(self.event_queue.base_addr().as_usize() as u64 >> 5)
+                + EVENTQ_BASE::LOG2SIZE.val(eventqs_log2 as _),
+        );
+        self.regs()
+            .EVENTQ_PROD
+            .write(EVENTQ_PROD::WR.val(self.event_queue.prod_value()));
+        self.regs()
+            .EVENTQ_CONS
+            .write(EVENTQ_CONS::RD.val(self.event_queue.cons_value()));
+
+        if self.pri_supported() {
+            let priqs_log2 = H::CMDQ_EVENTQ_BITS_SET;
+            self.pri_queue.init_pri(priqs_log2);
+            self.regs().PRIQ_BASE.write(
+                PRIQ_BASE::WA::WriteAllocate
+                    + PRIQ_BASE::ADDR.val(self.pri_queue.base_addr().as_usize() as u64 >> 5)
+                    + PRIQ_BASE::LOG2SIZE.val(priqs_log2 as _),
+            );
+            self.regs()
+                .PRIQ_PROD
+                .write(PRIQ_PROD::WR.val(self.pri_queue.prod_value()));
+            self.regs()
+                .PRIQ_CONS
+                .write(PRIQ_CONS::RD.val(self.pri_queue.cons_value()));
+        }
 
         self.stream_table_init();
 
-        self.enable();
+        let asid_bits = if self.regs().IDR0.is_set(IDR0::ASID16) {
+            16
+        } else {
+            8
+        };
+        self.asid_alloc.init(asid_bits);
+        let vmid_bits = if self.regs().IDR0.is_set(IDR0::VMID16) {
+            16
+        } else {
+            8
+        };
+        self.vmid_alloc.init(vmid_bits);
 
+        self.enable();
     }
 
     fn enable(&mut self) {
@@ -139,13 +379,16 @@ impl<H: PagingHandler> SMMUv3<H> {
         );
 
         self.regs().CR2.write(CR2::VALID::defaul);
+        let priqen = CR0::PRIQEN.val(self.pri_supported() as u32);
         self.regs()
             .CR0
-            .write(CR0::SMMUEN::Enable + CR0::CMDQEN::Enable);
+            .write(CR0::SMMUEN::Enable + CR0::CMDQEN::Enable + CR0::EVENTQEN::Enable + priqen);
 
         for _timeout in 0..ARM_SMMU_SYNC_TIMEOUT {
             if self.regs().CR0ACK.is_set(CR0ACK::SMMUEN)
                 && self.regs().CR0ACK.is_set(CR0ACK::CMDQEN)
+                && self.regs().CR0ACK.is_set(CR0ACK::EVENTQEN)
+                && self.regs().CR0ACK.read(CR0ACK::PRIQEN) == self.pri_supported() as u32
             {
                 info!("SMMUv3 enabled");
                 return;
@@ -157,18 +400,34 @@ impl<H: PagingHandler> SMMUv3<H> {
     pub fn stream_table_init(&mut self) {
         self.stream_table.init(H::SID_BITS_SET);
 
-        self.regs().STRTAB_BASE_CFG.write(
-            STRTAB_BASE_CFG::FMT::Linear + STRTAB_BASE_CFG::LOG2SIZE.val(H::SID_BITS_SET),
-        );
+        self.regs()
+            .STRTAB_BASE_CFG
+            .write(STRTAB_BASE_CFG::FMT::Linear + STRTAB_BASE_CFG::LOG2SIZE.val(H::SID_BITS_SET));
         self.regs().STRTAB_BASE.write(
             STRTAB_BASE::RA::Enable
                 + STRTAB_BASE::ADDR.val(self.stream_table.base_addr().as_usize() as u64 >> 6),
         );
     }
 
-    /// Get the SMMUv3 registers.
-    pub const fn regs(&self) -> &SMMUv3Regs {
-        unsafe { self.base.as_ref() }
+    /// Get the SMMUv3 registers, those of the Secure programming interface once
+    /// [`SMMUv3::set_secure`] succeeded.
+    pub const fn regs(&self) -> SmmuRegs<'_, B> {
+        if self.secure {
+            SmmuRegs::secure(&self.backend)
+        } else {
+            SmmuRegs::new(&self.backend)
+        }
+    }
+
+    /// Get the register access backend.
+    pub const fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Whether the SMMU implements the PRI queue, to receive PCIe Page Requests.
+    pub fn pri_supported(&self) -> bool {
+        // The PRI queue has no Secure counterpart.
+        !self.secure && self.regs().IDR0.is_set(IDR0::PRI)
     }
 
     /// Get the SMMUv3 version.
@@ -184,60 +443,366 @@ impl<H: PagingHandler> SMMUv3<H> {
     }
 
     /// Add a command to the command queue.
-    pub fn add_cmd(&mut self, cmd: Cmd, sync: bool) {
-        while self.cmd_queue.full() {
-            warn!("Command queue is full, try consuming");
-            let cmdq_cons = self.regs().CMDQ_CONS.get();
-            if cmdq_cons & (CMDQ_CONS::ERR.mask << CMDQ_CONS::ERR.shift) != 0 {
-                warn!(
-                    "CMDQ_CONS ERR code {}",
-                    (cmdq_cons & (CMDQ_CONS::ERR.mask << CMDQ_CONS::ERR.shift)) >> CMDQ_CONS::ERR.shift
-                );
-            }
+    ///
+    /// With `sync`, returns once the SMMU consumed it. Several CPUs may add commands concurrently,
+    /// their commands being gathered into the same update of SMMU_CMDQ_PROD.
+    pub fn add_cmd(&self, cmd: Cmd, sync: bool) {
+        self.add_cmds(core::iter::once(cmd), sync);
+    }
 
-            let cons_value = cmdq_cons & (CMDQ_CONS::RD.mask << CMDQ_CONS::RD.shift);
-            self.cmd_queue.set_cons_value(cons_value);
+    /// Add a batch of commands, updating SMMU_CMDQ_PROD once per batch rather than once per command.
+    ///
+    /// With `sync`, returns once the SMMU consumed all of them and the trailing CMD_SYNC. Commands
+    /// added concurrently by other CPUs are gathered into the same update of SMMU_CMDQ_PROD, their
+    /// CMD_SYNCs completing together. Commands go to the Enhanced Command queue of the current CPU
+    /// once [`SMMUv3::enable_ecmdqs`] succeeded.
+    fn add_cmds<I: IntoIterator<Item = Cmd>>(&self, cmds: I, sync: bool) {
+        if self.ecmdqs.count() != 0 {
+            self.add_ecmdq_cmds(cmds, sync);
+            return;
         }
+        self.cmd_queue.issue(
+            cmds,
+            sync,
+            |prod| self.regs().CMDQ_PROD.write(CMDQ_PROD::WR.val(prod)),
+            || self.read_cmdq_cons(),
+        );
+    }
 
-        self.cmd_queue.cmd_insert(cmd.clone());
-
-        self.regs()
-            .CMDQ_PROD
-            .write(CMDQ_PROD::WR.val(self.cmd_queue.prod_value()));
+    /// SMMU_CMDQ_CONS.RD, skipping the command the queue stopped on if SMMU_GERROR.CMDQ_ERR is
+    /// active, so that CMD_SYNCs complete without [`SMMUv3::handle_gerror_irq`].
+    fn read_cmdq_cons(&self) -> u32 {
+        let active = |gerrorn: u32| {
+            (self.regs().GERROR.get() ^ gerrorn) & GlobalErrors::CMDQ_ERR.bits() != 0
+        };
+        if active(self.regs().GERRORN.get()) {
+            self.cmd_queue.handle_err(|| {
+                // Another CPU may have acknowledged the error meanwhile.
+                let gerrorn = self.regs().GERRORN.get();
+                if active(gerrorn) {
+                    self.fault_handler().on_global_error(GlobalErrors::CMDQ_ERR);
+                    self.skip_cmdq_err();
+                    self.regs()
+                        .GERRORN
+                        .set(gerrorn ^ GlobalErrors::CMDQ_ERR.bits());
+                }
+            });
+        }
+        self.regs().CMDQ_CONS.read(CMDQ_CONS::RD)
+    }
 
-        while !self.cmd_queue.empty() {
-            trace!("Command queue is not empty, consuming");
-            let cmdq_cons = self.regs().CMDQ_CONS.get();
-            if cmdq_cons & (CMDQ_CONS::ERR.mask << CMDQ_CONS::ERR.shift) != 0 {
-                warn!(
-                    "CMDQ_CONS ERR code {}",
-                    (cmdq_cons & (CMDQ_CONS::ERR.mask << CMDQ_CONS::ERR.shift)) >> CMDQ_CONS::ERR.shift
-                );
+    /// Hardware Access flag and Dirty state update support reported by SMMU_IDR0.HTTU.
+    pub fn httu_support(&self) -> HttuMode {
+        match self.regs().IDR0.read_as_enum(IDR0::HTTU) {
+            Some(IDR0::HTTU::Value::AccessFlag) => HttuMode::AccessFlag,
+            Some(IDR0::HTTU::Value::AccessFlagDirtyState)
+            | Some(IDR0::HTTU::Value::AccessFlagDirtyStateAccessFlagTableDescriptors) => {
+                HttuMode::AccessFlagDirtyState
             }
-            let cons_value = cmdq_cons & (CMDQ_CONS::RD.mask << CMDQ_CONS::RD.shift);
-            self.cmd_queue.set_cons_value(cons_value);
+            _ => HttuMode::Disabled,
         }
+    }
 
-        if sync {
-            self.add_cmd(Cmd::cmd_sync(), false);
+    /// Opt in to hardware Access flag and Dirty state updates for devices added afterwards.
+    ///
+    /// The requested mode is limited to what SMMU_IDR0.HTTU advertises, the mode actually in use is returned.
+    /// With [`HttuMode::AccessFlagDirtyState`], device writes mark stage 2 pages dirty, which is what
+    /// live migration of VMs with passthrough devices relies on.
+    pub fn set_httu(&mut self, mode: HttuMode) -> HttuMode {
+        let supported = self.httu_support();
+        if mode > supported {
+            warn!(
+                "HTTU mode {:?} not supported, falling back to {:?}",
+                mode, supported
+            );
         }
+        self.httu = mode.min(supported);
+        self.httu
     }
 
-    /// Add a passthrough device, updating the stream table.
-    pub fn add_device(&mut self, sid: usize, vmid: usize, s2pt_base: PhysAddr) {
-        let cmd = Cmd::cmd_cfgi_ste(sid as u32);
+    /// Make hardware dirty state updates visible and restart dirty tracking for `vmid`.
+    ///
+    /// Call this after clearing the dirty state of harvested pages in the stage 2 tables, so that
+    /// cached translations are dropped and the next device write marks the page dirty again.
+    /// The trailing CMD_SYNC also guarantees that HTTU updates caused by completed translations
+    /// have been written to memory.
+    pub fn sync_dirty_log(&self, vmid: usize) {
+        self.add_cmd(Cmd::cmd_tlbi_s12_vmall(vmid as u16), true);
+    }
 
-        self.stream_table
-            .set_s2_translated_ste(sid, vmid, s2pt_base);
+    /// Width of the StreamIDs, SMMU_IDR1.SIDSIZE, or SMMU_S_IDR1.S_SIDSIZE for Secure streams.
+    pub fn sid_bits(&self) -> u32 {
+        if self.secure {
+            self.regs().S_IDR1.read(S_IDR1::S_SIDSIZE)
+        } else {
+            self.regs().IDR1.read(IDR1::SIDSIZE)
+        }
+    }
 
-        self.add_cmd(cmd, true);
+    /// Width of the VMIDs in use, 8 or 16 bits according to SMMU_IDR0.VMID16.
+    pub fn vmid_bits(&self) -> u32 {
+        self.vmid_alloc.vmid_bits()
+    }
+
+    /// Allocate a VMID for stage 2 tables private to the SMMU.
+    ///
+    /// VMIDs of VMs whose stage 2 tables are shared with the CPU are passed to
+    /// [`SMMUv3::add_device`] directly instead, and are never returned here while in use.
+    pub fn alloc_vmid(&mut self) -> Result<u16, VmidError> {
+        self.check_stage2()?;
+        self.vmid_alloc.alloc()
+    }
+
+    /// Release a VMID obtained from [`SMMUv3::alloc_vmid`].
+    pub fn free_vmid(&mut self, vmid: u16) {
+        if self.vmid_alloc.free(vmid) {
+            self.add_cmd(Cmd::cmd_tlbi_s12_vmall(vmid), true);
+        }
+    }
+
+    /// Number of devices currently translated with `vmid`.
+    pub fn vmid_devices(&self, vmid: u16) -> usize {
+        self.vmid_alloc.devices(vmid)
+    }
+
+    /// Add a passthrough device, updating the stream table.
+    ///
+    /// `vmid` is usually the VMID the CPU uses for the VM owning `s2pt_base`, so both share TLB
+    /// tags along with the tables. It must fit the width reported by [`SMMUv3::vmid_bits`], and
+    /// fails with [`VmidError::InUse`] if it was obtained from [`SMMUv3::alloc_vmid`].
+    /// The StreamID of a PCIe function is obtained with [`StreamId::from_bdf`] or [`RidMap`].
+    pub fn add_device(
+        &mut self,
+        sid: StreamId,
+        vmid: usize,
+        s2pt_base: PhysAddr,
+    ) -> Result<(), VmidError> {
+        let sid = sid.as_usize();
+        self.check_stage2()?;
+        self.vmid_alloc.get(vmid)?;
+        let old_vmid = self.stream_table.ste(sid).s2_vmid();
+
+        self.stream_table.set_s2_translated_ste(
+            sid,
+            vmid,
+            s2pt_base,
+            stream_table::DEFAULT_S2VTCR,
+            self.httu,
+        );
+        self.update_ste(sid, old_vmid);
 
         //prefetch can optimize the initial use STE lookup time
         self.cmd_prefetch(sid);
+        Ok(())
     }
 
-    pub fn cmd_prefetch(&mut self, sid: usize) {
+    /// Detach a device, returning its stream to the default bypass configuration.
+    ///
+    /// TLB entries of the device VMID are invalidated once no other device uses it.
+    pub fn remove_device(&mut self, sid: StreamId) {
+        let sid = sid.as_usize();
+        let old_vmid = self.stream_table.ste(sid).s2_vmid();
+        self.stream_table.set_bypass_ste(sid);
+        self.update_ste(sid, old_vmid);
+    }
+
+    /// Walk the configuration of `sid` in software, to tell what the SMMU does with a
+    /// transaction to `addr`.
+    ///
+    /// `ssid` is the SubstreamID of the transaction, if it carries one. The walk reads the STE,
+    /// CD and translation tables as they are in memory, so it ignores stale TLB or configuration
+    /// cache entries the SMMU may still hold. Returns the output PA and its permissions, or the
+    /// fault the SMMU would raise.
+    pub fn translate(
+        &self,
+        sid: StreamId,
+        ssid: Option<u32>,
+        addr: u64,
+        access: Access,
+    ) -> Result<Translation, TranslationFault> {
+        let sid = sid.as_usize();
+        if sid >= self.stream_table.entry_count() {
+            return Err(TranslationFault::BadStreamId);
+        }
+        walk::translate::<H>(self.stream_table.ste(sid), ssid, addr, access)
+    }
+
+    /// Take a device reference on the VMID of a stage 2 table, allocated with
+    /// [`SMMUv3::alloc_vmid`] or shared with the CPU.
+    fn get_table_vmid(&mut self, vmid: u16) -> Result<(), VmidError> {
+        self.check_stage2()?;
+        if self.vmid_alloc.is_allocated(vmid as usize) {
+            self.vmid_alloc.get_allocated(vmid as usize)
+        } else {
+            self.vmid_alloc.get(vmid as usize)
+        }
+    }
+
+    /// Invalidate the rewritten STE of `sid`, then drop the reference on the VMID it used before.
+    fn update_ste(&mut self, sid: usize, old_vmid: Option<u16>) {
+        self.add_cmd(Cmd::cmd_cfgi_ste(sid as u32), true);
+
+        if let Some(vmid) = old_vmid {
+            if self.vmid_alloc.put(vmid as usize) {
+                self.add_cmd(Cmd::cmd_tlbi_s12_vmall(vmid), true);
+            }
+        }
+    }
+
+    pub fn cmd_prefetch(&self, sid: usize) {
         let cmd = Cmd::cmd_prefetch_config(sid as u32);
         self.add_cmd(cmd, true);
     }
+
+    /// Width of the ASIDs in use, 8 or 16 bits according to SMMU_IDR0.ASID16.
+    pub fn asid_bits(&self) -> u32 {
+        self.asid_alloc.id_bits()
+    }
+
+    /// Allocate an ASID for a stage 1 context, ASID 0 is never returned.
+    pub fn alloc_asid(&mut self) -> Option<u16> {
+        self.asid_alloc.alloc()
+    }
+
+    /// Release an ASID, dropping any TLB entries still tagged with it.
+    pub fn free_asid(&mut self, asid: u16) {
+        self.add_cmd(Cmd::cmd_tlbi_nh_asid(0, asid), true);
+        self.asid_alloc.free(asid);
+    }
+
+    /// Add a device translated at stage 1 by the Context Descriptors in `cd_table`.
+    ///
+    /// The CD table must outlive the attachment, CDs can be installed before or after with [`SMMUv3::write_cd`].
+    pub fn add_device_s1(&mut self, sid: StreamId, cd_table: &CdTable<H>) {
+        let sid = sid.as_usize();
+        if !self.regs().IDR0.is_set(IDR0::S1P) {
+            warn!(
+                "Stage 1 translation not supported, sid 0x{:x} will fault",
+                sid
+            );
+        }
+        let old_vmid = self.stream_table.ste(sid).s2_vmid();
+        self.stream_table
+            .set_s1_translated_ste(sid, cd_table.base_addr(), cd_table.ssid_bits());
+        self.update_ste(sid, old_vmid);
+
+        self.cmd_prefetch(sid);
+    }
+
+    /// Install `cd` for SubstreamID `ssid` of the device `sid` and invalidate cached copies.
+    pub fn write_cd(
+        &mut self,
+        sid: StreamId,
+        cd_table: &CdTable<H>,
+        ssid: usize,
+        cd: &ContextDescriptor,
+    ) {
+        cd_table.set_cd(ssid, cd);
+        self.add_cmd(Cmd::cmd_cfgi_cd(sid.as_u32(), ssid as u32), true);
+    }
+}
+
+#[cfg(feature = "io_pgtable")]
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Add a passthrough device translated by a driver managed stage 2 table.
+    ///
+    /// Unlike [`SMMUv3::add_device`], the STE stage 2 configuration follows the geometry of `pgtable`.
+    /// Stage 1 tables are installed in a CD table with [`SMMUv3::write_s1_pgtable`] instead.
+    pub fn add_device_with_pgtable(
+        &mut self,
+        sid: StreamId,
+        pgtable: &IoPageTable<H>,
+    ) -> Result<(), VmidError> {
+        let sid = sid.as_usize();
+        let TlbContext::Stage2 { vmid } = pgtable.tlb_context() else {
+            panic!("stage 1 table passed to add_device_with_pgtable");
+        };
+        self.get_table_vmid(vmid)?;
+        let old_vmid = self.stream_table.ste(sid).s2_vmid();
+
+        self.stream_table.set_s2_translated_ste(
+            sid,
+            vmid as usize,
+            pgtable.root_paddr(),
+            pgtable.vtcr(),
+            self.httu,
+        );
+        self.update_ste(sid, old_vmid);
+
+        self.cmd_prefetch(sid);
+        Ok(())
+    }
+
+    /// A domain translating through `pgtable`, at the stage the table was built for.
+    ///
+    /// Stage 1 domains use the HTTU mode selected by [`SMMUv3::set_httu`].
+    pub fn pgtable_domain(&self, pgtable: &IoPageTable<H>) -> IommuDomain<H> {
+        match pgtable.tlb_context() {
+            TlbContext::Stage1 { .. } => {
+                IommuDomain::new_s1(&pgtable.context_descriptor(self.httu).unwrap())
+            }
+            TlbContext::Stage2 { vmid } => {
+                IommuDomain::new_s2_with_vtcr(vmid, pgtable.root_paddr(), pgtable.vtcr())
+            }
+        }
+    }
+
+    /// Point SubstreamID `ssid` of the device `sid` at the stage 1 table `pgtable`.
+    ///
+    /// The CD is built with the HTTU mode selected by [`SMMUv3::set_httu`].
+    pub fn write_s1_pgtable(
+        &mut self,
+        sid: StreamId,
+        cd_table: &CdTable<H>,
+        ssid: usize,
+        pgtable: &IoPageTable<H>,
+    ) {
+        let cd = pgtable
+            .context_descriptor(self.httu)
+            .expect("stage 2 table passed to write_s1_pgtable");
+        self.write_cd(sid, cd_table, ssid, &cd);
+    }
+}
+
+/// TLBI command dropping every cached translation of `ctx`.
+#[cfg(feature = "io_pgtable")]
+fn cmd_tlbi_context(ctx: TlbContext) -> Cmd {
+    match ctx {
+        TlbContext::Stage1 { asid } => Cmd::cmd_tlbi_nh_asid(0, asid),
+        TlbContext::Stage2 { vmid } => Cmd::cmd_tlbi_s12_vmall(vmid),
+    }
+}
+
+/// TLBI command dropping cached translations of `ctx` for the granule holding `addr`.
+#[cfg(feature = "io_pgtable")]
+fn cmd_tlbi_addr(ctx: TlbContext, addr: usize, leaf: bool) -> Cmd {
+    match ctx {
+        TlbContext::Stage1 { asid } => Cmd::cmd_tlbi_nh_va(0, asid, addr as u64, leaf),
+        TlbContext::Stage2 { vmid } => Cmd::cmd_tlbi_s2_ipa(vmid, addr as u64, leaf),
+    }
+}
+
+#[cfg(feature = "io_pgtable")]
+impl<H: PagingHandler, B: RegisterBackend> IoTlbFlush for SMMUv3<H, B> {
+    fn tlb_inv_range(
+        &mut self,
+        ctx: TlbContext,
+        iova: usize,
+        size: usize,
+        granule: usize,
+        leaf: bool,
+    ) {
+        if size / granule > TLBI_RANGE_MAX_CMDS {
+            self.add_cmd(cmd_tlbi_context(ctx), false);
+            return;
+        }
+        let cmds = (iova..iova + size)
+            .step_by(granule)
+            .map(|addr| cmd_tlbi_addr(ctx, addr, leaf));
+        self.add_cmds(cmds, false);
+    }
+
+    fn tlb_sync(&mut self) {
+        self.add_cmds(core::iter::empty(), true);
+    }
 }
@@ -0,0 +1,231 @@
+//! Several SMMUs of an SoC behind a single interface.
+//!
+//! Each SMMU serves the devices of some requester IDs, such as the PCIe requester IDs of a root
+//! complex. [`SmmuManager`] owns the [`SMMUv3`] instances and a map of requester ID ranges to the
+//! SMMU and StreamIDs serving them, and forwards device operations to the right instance.
+//! Requester IDs are only unique within a PCI segment, so the ranges are keyed by both.
+
+use memory_addr::PhysAddr;
+
+use crate::backend::{Mmio, RegisterBackend};
+use crate::hal::PagingHandler;
+use crate::id_alloc::VmidError;
+use crate::stream_id::{RidMap, StreamId};
+use crate::SMMUv3;
+
+/// Requester IDs `rid_base..rid_base + len` of PCI segment `segment` served by SMMU `smmu`, with
+/// the StreamIDs `sid_base..sid_base + len`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SidRange {
+    /// Index of the SMMU, as returned by [`SmmuManager::add_smmu`].
+    pub smmu: usize,
+    /// PCI segment of the requesters, 0 for platform devices.
+    pub segment: u16,
+    /// First requester ID of the range.
+    pub rid_base: u32,
+    /// StreamID of the first requester ID.
+    pub sid_base: u32,
+    /// Number of requester IDs in the range.
+    pub len: u32,
+}
+
+impl SidRange {
+    /// The mapping of the requester IDs to the StreamIDs.
+    pub fn rid_map(&self) -> RidMap {
+        RidMap {
+            rid_base: self.rid_base,
+            sid_base: self.sid_base,
+            len: self.len,
+        }
+    }
+
+    fn overlaps(&self, other: &SidRange) -> bool {
+        self.segment == other.segment
+            && self.rid_base < other.rid_base.saturating_add(other.len)
+            && other.rid_base < self.rid_base.saturating_add(self.len)
+    }
+}
+
+/// Reasons a [`SmmuManager`] operation fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManagerError {
+    /// No room left for another SMMU or range.
+    Full,
+    /// The range refers to an SMMU that was not added.
+    NoSmmu,
+    /// The range overlaps an existing one, or is empty.
+    BadRange,
+    /// No range covers the requester ID.
+    NoRoute,
+    /// The SMMU serving the device refused the VMID.
+    Vmid(VmidError),
+}
+
+impl From<VmidError> for ManagerError {
+    fn from(err: VmidError) -> Self {
+        Self::Vmid(err)
+    }
+}
+
+/// Up to `N` SMMUs, and up to `R` ranges routing requester IDs to them.
+pub struct SmmuManager<
+    H: PagingHandler,
+    B: RegisterBackend = Mmio,
+    const N: usize = 8,
+    const R: usize = 32,
+> {
+    smmus: [Option<SMMUv3<H, B>>; N],
+    ranges: [Option<SidRange>; R],
+}
+
+impl<H: PagingHandler, B: RegisterBackend, const N: usize, const R: usize> Default
+    for SmmuManager<H, B, N, R>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend, const N: usize, const R: usize> SmmuManager<H, B, N, R> {
+    /// An empty manager.
+    pub const fn new() -> Self {
+        Self {
+            smmus: [const { None }; N],
+            ranges: [None; R],
+        }
+    }
+
+    /// Take ownership of `smmu`, returning its index.
+    ///
+    /// The instance is used as is, [`SMMUv3::init`] is called before or after adding it.
+    pub fn add_smmu(&mut self, smmu: SMMUv3<H, B>) -> Result<usize, ManagerError> {
+        let (index, slot) = self
+            .smmus
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())
+            .ok_or(ManagerError::Full)?;
+        *slot = Some(smmu);
+        Ok(index)
+    }
+
+    /// Initialize all the SMMUs.
+    pub fn init(&mut self) {
+        for smmu in self.smmus.iter_mut().flatten() {
+            smmu.init();
+        }
+    }
+
+    /// The SMMU at `index`.
+    pub fn smmu(&self, index: usize) -> Option<&SMMUv3<H, B>> {
+        self.smmus.get(index)?.as_ref()
+    }
+
+    /// The SMMU at `index`, mutably.
+    pub fn smmu_mut(&mut self, index: usize) -> Option<&mut SMMUv3<H, B>> {
+        self.smmus.get_mut(index)?.as_mut()
+    }
+
+    /// All the SMMUs with their index, for example to poll their interrupts.
+    pub fn smmus_mut(&mut self) -> impl Iterator<Item = (usize, &mut SMMUv3<H, B>)> {
+        self.smmus
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, smmu)| Some((index, smmu.as_mut()?)))
+    }
+
+    /// Route the requester IDs of `range` to its SMMU.
+    pub fn add_range(&mut self, range: SidRange) -> Result<(), ManagerError> {
+        if self.smmu(range.smmu).is_none() {
+            return Err(ManagerError::NoSmmu);
+        }
+        if range.len == 0 || self.ranges.iter().flatten().any(|r| r.overlaps(&range)) {
+            return Err(ManagerError::BadRange);
+        }
+        let slot = self
+            .ranges
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ManagerError::Full)?;
+        *slot = Some(range);
+        Ok(())
+    }
+
+    /// The SMMU index and StreamID serving requester `rid` of PCI segment `segment`.
+    pub fn route(&self, segment: u16, rid: u32) -> Option<(usize, StreamId)> {
+        self.ranges
+            .iter()
+            .flatten()
+            .filter(|range| range.segment == segment)
+            .find_map(|range| Some((range.smmu, range.rid_map().map(rid)?)))
+    }
+
+    fn route_mut(
+        &mut self,
+        segment: u16,
+        rid: u32,
+    ) -> Result<(&mut SMMUv3<H, B>, StreamId), ManagerError> {
+        let (index, sid) = self.route(segment, rid).ok_or(ManagerError::NoRoute)?;
+        let smmu = self.smmu_mut(index).ok_or(ManagerError::NoSmmu)?;
+        Ok((smmu, sid))
+    }
+
+    /// Add the passthrough device `rid` of PCI segment `segment` on the SMMU serving it, see
+    /// [`SMMUv3::add_device`].
+    pub fn add_device(
+        &mut self,
+        segment: u16,
+        rid: u32,
+        vmid: usize,
+        s2pt_base: PhysAddr,
+    ) -> Result<(), ManagerError> {
+        let (smmu, sid) = self.route_mut(segment, rid)?;
+        smmu.add_device(sid, vmid, s2pt_base)?;
+        Ok(())
+    }
+
+    /// Detach device `rid` of PCI segment `segment` from the SMMU serving it, see
+    /// [`SMMUv3::remove_device`].
+    pub fn remove_device(&mut self, segment: u16, rid: u32) -> Result<(), ManagerError> {
+        let (smmu, sid) = self.route_mut(segment, rid)?;
+        smmu.remove_device(sid);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::HostPagingHandler;
+
+    #[test]
+    fn test_route_segments() {
+        let mut manager = SmmuManager::<HostPagingHandler, Mmio, 2, 4>::new();
+        // Routing never accesses the registers of the SMMUs.
+        for _ in 0..2 {
+            manager
+                .add_smmu(SMMUv3::new(core::ptr::NonNull::dangling().as_ptr()))
+                .unwrap();
+        }
+        let range = |smmu, segment, sid_base| SidRange {
+            smmu,
+            segment,
+            rid_base: 0,
+            sid_base,
+            len: 0x100,
+        };
+        // The same requester IDs of two root complexes go to different SMMUs.
+        manager.add_range(range(0, 0, 0)).unwrap();
+        manager.add_range(range(1, 1, 0x100)).unwrap();
+        assert_eq!(
+            manager.add_range(range(1, 1, 0)),
+            Err(ManagerError::BadRange)
+        );
+
+        assert_eq!(manager.route(0, 0x10), Some((0, StreamId::new(0x10))));
+        assert_eq!(manager.route(1, 0x10), Some((1, StreamId::new(0x110))));
+        assert_eq!(manager.route(2, 0x10), None);
+        assert_eq!(manager.route(1, 0x100), None);
+        assert_eq!(manager.remove_device(2, 0x10), Err(ManagerError::NoRoute));
+    }
+}
@@ -0,0 +1,1472 @@
+//! Software model of an SMMUv3, to exercise the driver on a host.
+//!
+//! [`SmmuModel`] backs [`SMMUv3Regs`] with memory that [`SMMUv3::new`] is pointed at, and plays
+//! the part of the hardware behind it:
+//! - CR0 and IRQ_CTRL updates are acknowledged in CR0ACK and IRQ_CTRLACK, GBPA updates by
+//!   clearing GBPA.Update.
+//! - Commands are consumed from the Command queue while CR0ACK.CMDQEN is set, illegal commands
+//!   stop the queue with CMDQ_CONS.ERR and SMMU_GERROR.CMDQ_ERR, until SMMU_GERRORN acknowledges
+//!   the error and the queue restarts from the same entry.
+//! - With [`ModelConfig::ecmdqs`], commands are also consumed from the enabled Enhanced Command
+//!   queues, illegal commands stop the queue with ECMDQ_CONS.ERR.
+//! - Transactions issued with [`SmmuModel::dma`] are translated through the stream table, faults
+//!   are recorded in the Event queue while CR0ACK.EVENTQEN is set.
+//! - ATOS requests perform the same walk, whatever SMMU_GATOS_ADDR.TYPE asks for.
+//! - With [`ModelConfig::dpt`], the PA of transactions is checked against the Device Permission
+//!   Table while CR0ACK.DPT_WALK_EN is set, denied accesses being aborted without an event and
+//!   invalid descriptors raising SMMU_GERROR.DPT_ERR.
+//!
+//! Nothing is cached, so the model does not catch missing invalidations. It runs on a
+//! background thread started by [`SmmuModel::spawn`], or is driven with [`SmmuModel::step`].
+//!
+//! [`SMMUv3::new`]: crate::SMMUv3::new
+
+extern crate std;
+
+use core::marker::PhantomData;
+use core::mem::offset_of;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+use memory_addr::{PhysAddr, PAGE_SIZE_4K};
+
+use crate::hal::PagingHandler;
+use crate::queue::EVTQ_ENT_DWORDS;
+use crate::stream_table::StreamTableEntry;
+use crate::walk::{self, Access, TranslationFault};
+use crate::{
+    HttuMode, SMMUv3Regs, AIDR, CR0, DPT_BASE, DPT_BASE_CFG, GATOS_ADDR, GATOS_SID, IDR0, IDR1,
+    IDR3, IDR6,
+};
+
+/// Size and alignment of the register frame, SMMU pages 0 and 1 and the ECMDQ control page.
+const REGS_SIZE: usize = 0x30000;
+const REGS_ALIGN: usize = 0x10000;
+
+/// CR0 fields reflected in CR0ACK.
+const CR0_ACK_MASK: u32 = 0x5df;
+/// IRQ_CTRL.{GERROR_IRQEN, PRIQ_IRQEN, EVENTQ_IRQEN}.
+const IRQ_CTRL_ACK_MASK: u32 = 0b111;
+/// Queue index and wrap bits of the PROD and CONS registers.
+const QUEUE_INDEX_MASK: u32 = (1 << 20) - 1;
+/// Queue base address, bits [55:5].
+const QUEUE_BASE_ADDR_MASK: u64 = ((1 << 56) - 1) & !((1 << 5) - 1);
+/// CMDQ_CONS.ERR, bits [30:24].
+const CMDQ_CONS_ERR_OFFSET: u32 = 24;
+/// CERROR_ILL, the command opcode is not supported.
+const CERROR_ILL: u32 = 0x01;
+/// SMMU_GERROR.CMDQ_ERR, bit [0].
+const GERROR_CMDQ_ERR: u32 = 1 << 0;
+/// SMMU_GERROR.DPT_ERR, bit [10].
+const GERROR_DPT_ERR: u32 = 1 << 10;
+/// EVENTQ_PROD.OVSLG, bit [31].
+const EVENTQ_PROD_OVSLG: u32 = 1 << 31;
+/// SMMU_GBPA.Update, bit [31].
+const GBPA_UPDATE: u32 = 1 << 31;
+/// SMMU_GBPA.ABORT, bit [20].
+const GBPA_ABORT: u32 = 1 << 20;
+/// SMMU_CMDQ_CONTROL_PAGE_{BASE,CFG,STATUS}0, the registers of the only control page.
+const CMDQ_CONTROL_PAGE_BASE0: usize = 0x4000;
+const CMDQ_CONTROL_PAGE_CFG0: usize = 0x4008;
+const CMDQ_CONTROL_PAGE_STATUS0: usize = 0x400c;
+/// Offset of the ECMDQ control page in the register frame.
+const ECMDQ_CONTROL_PAGE: usize = 0x20000;
+/// Log2 of the number of Enhanced Command queues in the control page.
+const ECMDQ_LOG2NUMQ: u32 = 2;
+/// Distance between the registers of successive Enhanced Command queues.
+const ECMDQ_STRIDE: usize = 0x80;
+/// ECMDQ_PROD.EN and ECMDQ_CONS.ENACK, bit [31].
+const ECMDQ_EN: u32 = 1 << 31;
+/// ECMDQ_PROD.ERRACK and ECMDQ_CONS.ERR, bit [23].
+const ECMDQ_ERR: u32 = 1 << 23;
+/// ECMDQ_CONS.ERR_REASON, bits [26:24].
+const ECMDQ_CONS_ERR_REASON_MASK: u32 = 0b111 << 24;
+/// STRTAB_BASE.ADDR, bits [51:6].
+const STRTAB_BASE_ADDR_MASK: u64 = ((1 << 52) - 1) & !((1 << 6) - 1);
+/// Physical address sizes of SMMU_DPT_BASE_CFG.DPTPS.
+const DPTPS_BITS: [u32; 7] = [32, 36, 40, 42, 44, 48, 52];
+/// DPT level 0 block and table descriptors, bits [3:0].
+const DPT_L0_BLOCK: u64 = 0b0001;
+const DPT_L0_TABLE: u64 = 0b0011;
+/// Address of a DPT level 1 table, bits [51:12] of its level 0 descriptor.
+const DPT_L0_TABLE_ADDR_MASK: u64 = ((1 << 52) - 1) & !((1 << 12) - 1);
+
+/// CMD_SYNC.CS, bits [13:12], 0b01 signals completion with an MSI write.
+const CMD_SYNC_CS_SIG_IRQ: u64 = 0b01;
+const CMD_SYNC: u64 = 0x46;
+/// CMD_SYNC.MSIAddress, bits [51:2] of the second word.
+const CMD_SYNC_1_MSIADDR_MASK: u64 = ((1 << 52) - 1) & !0b11;
+
+/// Opcodes the model accepts, see 4.1.1 Command opcodes.
+const SUPPORTED_OPCODES: &[u64] = &[
+    0x01, // CMD_PREFETCH_CONFIG
+    0x02, // CMD_PREFETCH_ADDR
+    0x03, // CMD_CFGI_STE
+    0x04, // CMD_CFGI_STE_RANGE
+    0x05, // CMD_CFGI_CD
+    0x06, // CMD_CFGI_CD_ALL
+    0x10, // CMD_TLBI_NH_ALL
+    0x11, // CMD_TLBI_NH_ASID
+    0x12, // CMD_TLBI_NH_VA
+    0x13, // CMD_TLBI_NH_VAA
+    0x20, // CMD_TLBI_EL2_ALL
+    0x28, // CMD_TLBI_S12_VMALL
+    0x2a, // CMD_TLBI_S2_IPA
+    0x30, // CMD_TLBI_NSNH_ALL
+    0x40, // CMD_ATC_INV
+    0x41, // CMD_PRI_RESP
+    0x44, // CMD_RESUME
+    0x45, // CMD_STALL_TERM
+    0x70, // CMD_DPTI_ALL
+    0x73, // CMD_DPTI_PA
+    CMD_SYNC,
+];
+
+/// Features the model advertises in its ID registers.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelConfig {
+    /// SMMU_IDR1.SIDSIZE.
+    pub sid_bits: u32,
+    /// SMMU_IDR1.SSIDSIZE.
+    pub ssid_bits: u32,
+    /// SMMU_IDR1.CMDQS.
+    pub cmdq_bits: u32,
+    /// SMMU_IDR1.EVENTQS.
+    pub eventq_bits: u32,
+    /// SMMU_IDR0.S1P.
+    pub stage1: bool,
+    /// SMMU_IDR0.S2P.
+    pub stage2: bool,
+    /// SMMU_IDR0.ASID16.
+    pub asid16: bool,
+    /// SMMU_IDR0.VMID16.
+    pub vmid16: bool,
+    /// SMMU_IDR0.HTTU.
+    pub httu: HttuMode,
+    /// SMMU_IDR0.ATOS.
+    pub atos: bool,
+    /// SMMU_IDR0.MSI.
+    pub msi: bool,
+    /// SMMU_IDR1.ECMDQ, with one control page of four Enhanced Command queues located at offset
+    /// 0x20000 of the register frame.
+    pub ecmdqs: bool,
+    /// SMMU_IDR3.DPT, with 4KB granules and 1GB level 0 descriptors only.
+    pub dpt: bool,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            sid_bits: 8,
+            ssid_bits: 8,
+            cmdq_bits: 8,
+            eventq_bits: 8,
+            stage1: true,
+            stage2: true,
+            asid16: true,
+            vmid16: true,
+            httu: HttuMode::Disabled,
+            atos: true,
+            msi: true,
+            ecmdqs: false,
+            dpt: false,
+        }
+    }
+}
+
+/// Register frame and state shared with the model thread.
+struct Inner<H: PagingHandler> {
+    regs: NonNull<u8>,
+    commands: Mutex<Vec<[u64; 2]>>,
+    events: Mutex<Vec<[u64; EVTQ_ENT_DWORDS]>>,
+    stop: AtomicBool,
+    _phantom: PhantomData<H>,
+}
+
+// SAFETY: the register frame is only accessed through atomics, or volatile accesses by the driver.
+unsafe impl<H: PagingHandler> Send for Inner<H> {}
+unsafe impl<H: PagingHandler> Sync for Inner<H> {}
+
+/// A software SMMUv3 playing the hardware behind the [`SMMUv3Regs`] frame handed to
+/// [`SMMUv3::new`](crate::SMMUv3::new), run by [`SmmuModel::spawn`] or [`SmmuModel::step`].
+///
+/// Commands, transactions issued with [`SmmuModel::dma`] and ATOS requests are serviced without
+/// caching anything, so missing invalidations go unnoticed.
+pub struct SmmuModel<H: PagingHandler> {
+    inner: Arc<Inner<H>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<H: PagingHandler + 'static> SmmuModel<H> {
+    /// Create a model in its reset state, with the ID registers describing `cfg`.
+    pub fn new(cfg: ModelConfig) -> Self {
+        let layout = Layout::from_size_align(REGS_SIZE, REGS_ALIGN).unwrap();
+        let regs =
+            NonNull::new(unsafe { alloc_zeroed(layout) }).expect("Failed to allocate registers");
+        let inner = Inner {
+            regs,
+            commands: Mutex::new(Vec::new()),
+            events: Mutex::new(Vec::new()),
+            stop: AtomicBool::new(false),
+            _phantom: PhantomData,
+        };
+
+        let httu = match cfg.httu {
+            HttuMode::Disabled => 0,
+            HttuMode::AccessFlag => 1,
+            HttuMode::AccessFlagDirtyState => 2,
+        };
+        let idr0 = IDR0::ST_LEVEL.val(0b01)
+            + IDR0::VMID16.val(cfg.vmid16 as u32)
+            + IDR0::ASID16.val(cfg.asid16 as u32)
+            + IDR0::ATOS.val(cfg.atos as u32)
+            + IDR0::MSI.val(cfg.msi as u32)
+            + IDR0::HTTU.val(httu)
+            + IDR0::TTF.val(0b10)
+            + IDR0::S1P.val(cfg.stage1 as u32)
+            + IDR0::S2P.val(cfg.stage2 as u32);
+        let idr1 = IDR1::CMDQS.val(cfg.cmdq_bits)
+            + IDR1::EVENTQS.val(cfg.eventq_bits)
+            + IDR1::SSIDSIZE.val(cfg.ssid_bits)
+            + IDR1::SIDSIZE.val(cfg.sid_bits)
+            + IDR1::ECMDQS.val(cfg.ecmdqs as u32);
+        inner
+            .reg32(offset_of!(SMMUv3Regs, IDR0))
+            .store(idr0.value, Ordering::Relaxed);
+        inner
+            .reg32(offset_of!(SMMUv3Regs, IDR1))
+            .store(idr1.value, Ordering::Relaxed);
+        inner
+            .reg32(offset_of!(SMMUv3Regs, IDR3))
+            .store(IDR3::DPT.val(cfg.dpt as u32).value, Ordering::Relaxed);
+        if cfg.ecmdqs {
+            inner
+                .reg32(offset_of!(SMMUv3Regs, IDR6))
+                .store(IDR6::LOG2NUMQ.val(ECMDQ_LOG2NUMQ).value, Ordering::Relaxed);
+            let page = regs.as_ptr() as u64 + ECMDQ_CONTROL_PAGE as u64;
+            inner
+                .reg64(CMDQ_CONTROL_PAGE_BASE0)
+                .store(page, Ordering::Relaxed);
+        }
+        inner
+            .reg32(offset_of!(SMMUv3Regs, AIDR))
+            .store(AIDR::ArchMinorRev::SMMUv3_2.value, Ordering::Relaxed);
+
+        Self {
+            inner: Arc::new(inner),
+            thread: None,
+        }
+    }
+
+    /// Base of the register frame, to be passed to [`crate::SMMUv3::new`].
+    pub fn base(&self) -> *mut u8 {
+        self.inner.regs.as_ptr()
+    }
+
+    /// Run the model on a background thread until it is dropped.
+    pub fn spawn(&mut self) {
+        if self.thread.is_some() {
+            return;
+        }
+        let inner = self.inner.clone();
+        self.thread = Some(std::thread::spawn(move || {
+            while !inner.stop.load(Ordering::Acquire) {
+                inner.step();
+                std::thread::yield_now();
+            }
+        }));
+    }
+
+    /// Process pending register updates and commands once.
+    pub fn step(&self) {
+        self.inner.step();
+    }
+
+    /// Commands consumed so far, in order.
+    pub fn commands(&self) -> Vec<[u64; 2]> {
+        self.inner.commands.lock().unwrap().clone()
+    }
+
+    /// Event records written to the Event queue so far, in order.
+    pub fn events(&self) -> Vec<[u64; EVTQ_ENT_DWORDS]> {
+        self.inner.events.lock().unwrap().clone()
+    }
+
+    /// Issue a transaction of device `sid` to `addr`, returning the PA it reaches.
+    ///
+    /// Faults are recorded in the Event queue like the hardware would, with `ssid` as the
+    /// SubstreamID of the transaction if it carries one.
+    pub fn dma(
+        &self,
+        sid: u32,
+        ssid: Option<u32>,
+        addr: u64,
+        access: Access,
+    ) -> Result<PhysAddr, TranslationFault> {
+        let res = self.inner.translate(sid, ssid, addr, access);
+        if let Err(fault) = res {
+            self.inner.record_event(sid, ssid, addr, access, fault);
+        }
+        res.map(|t| t.pa)
+    }
+}
+
+impl<H: PagingHandler> Drop for SmmuModel<H> {
+    fn drop(&mut self) {
+        self.inner.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+        let layout = Layout::from_size_align(REGS_SIZE, REGS_ALIGN).unwrap();
+        unsafe { dealloc(self.inner.regs.as_ptr(), layout) };
+    }
+}
+
+impl<H: PagingHandler> Inner<H> {
+    fn reg32(&self, offset: usize) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(self.regs.as_ptr().add(offset) as *mut u32) }
+    }
+
+    fn reg64(&self, offset: usize) -> &AtomicU64 {
+        unsafe { AtomicU64::from_ptr(self.regs.as_ptr().add(offset) as *mut u64) }
+    }
+
+    fn cr0ack(&self) -> u32 {
+        self.reg32(offset_of!(SMMUv3Regs, CR0ACK))
+            .load(Ordering::Acquire)
+    }
+
+    fn step(&self) {
+        let cr0 = self
+            .reg32(offset_of!(SMMUv3Regs, CR0))
+            .load(Ordering::Acquire);
+        self.reg32(offset_of!(SMMUv3Regs, CR0ACK))
+            .store(cr0 & CR0_ACK_MASK, Ordering::Release);
+        let irq_ctrl = self
+            .reg32(offset_of!(SMMUv3Regs, IRQ_CTRL))
+            .load(Ordering::Acquire);
+        self.reg32(offset_of!(SMMUv3Regs, IRQ_CTRLACK))
+            .store(irq_ctrl & IRQ_CTRL_ACK_MASK, Ordering::Release);
+        self.reg32(offset_of!(SMMUv3Regs, GBPA))
+            .fetch_and(!GBPA_UPDATE, Ordering::AcqRel);
+
+        if cr0 & CR0::CMDQEN::SET.value != 0 {
+            self.consume_commands(
+                offset_of!(SMMUv3Regs, CMDQ_BASE),
+                offset_of!(SMMUv3Regs, CMDQ_PROD),
+                offset_of!(SMMUv3Regs, CMDQ_CONS),
+                false,
+            );
+        }
+        let cp_cfg = self.reg32(CMDQ_CONTROL_PAGE_CFG0).load(Ordering::Acquire);
+        self.reg32(CMDQ_CONTROL_PAGE_STATUS0)
+            .store(cp_cfg & 1, Ordering::Release);
+        if cp_cfg & 1 != 0 {
+            for q in 0..1 << ECMDQ_LOG2NUMQ {
+                self.step_ecmdq(ECMDQ_CONTROL_PAGE + q * ECMDQ_STRIDE);
+            }
+        }
+        if self
+            .reg32(offset_of!(SMMUv3Regs, GATOS_CTRL))
+            .load(Ordering::Acquire)
+            & 1
+            != 0
+        {
+            self.run_atos();
+        }
+    }
+
+    /// Perform the pending ATOS request and write its result to SMMU_GATOS_PAR.
+    fn run_atos(&self) {
+        let sid = self
+            .reg64(offset_of!(SMMUv3Regs, GATOS_SID))
+            .load(Ordering::Acquire);
+        let req = self
+            .reg64(offset_of!(SMMUv3Regs, GATOS_ADDR))
+            .load(Ordering::Acquire);
+        let ssid =
+            (GATOS_SID::SSID_VALID.read(sid) != 0).then(|| GATOS_SID::SUBSTREAMID.read(sid) as u32);
+        let access = if GATOS_ADDR::RnW.read(req) == 0 {
+            Access::Write
+        } else if GATOS_ADDR::InD.read(req) != 0 {
+            Access::Exec
+        } else {
+            Access::Read
+        };
+        let addr = GATOS_ADDR::ADDR.read(req) << 12;
+
+        let par = match self.translate(GATOS_SID::STREAMID.read(sid) as u32, ssid, addr, access) {
+            Ok(t) => {
+                // Bits of ADDR below the translation size are set to encode it.
+                let size_bits = (t.size as u64 - 1) >> 1 & !(PAGE_SIZE_4K as u64 - 1);
+                let size = (t.size > PAGE_SIZE_4K) as u64;
+                (t.pa.as_usize() as u64 & !(t.size as u64 - 1)) | size_bits | size << 11
+            }
+            Err(fault) => {
+                let reason = if fault.is_stage2() { 0b11 } else { 0b00 };
+                reason << 12 | (fault.event_id().unwrap_or(0) as u64) << 4 | 1
+            }
+        };
+        self.reg64(offset_of!(SMMUv3Regs, GATOS_PAR))
+            .store(par, Ordering::Release);
+        self.reg32(offset_of!(SMMUv3Regs, GATOS_CTRL))
+            .store(0, Ordering::Release);
+    }
+
+    /// Acknowledge ECMDQ_PROD.EN of the Enhanced Command queue at `offset`, and consume its
+    /// commands while enabled.
+    fn step_ecmdq(&self, offset: usize) {
+        let prod = self.reg32(offset + 0x8).load(Ordering::Acquire);
+        let cons_reg = self.reg32(offset + 0xc);
+        let cons = cons_reg.load(Ordering::Acquire);
+        cons_reg.store(cons & !ECMDQ_EN | prod & ECMDQ_EN, Ordering::Release);
+        if prod & ECMDQ_EN != 0 {
+            self.consume_commands(offset, offset + 0x8, offset + 0xc, true);
+        }
+    }
+
+    /// Consume the commands of the queue whose BASE, PROD and CONS registers are at `base_off`,
+    /// `prod_off` and `cons_off`, an Enhanced Command queue if `ecmdq`.
+    fn consume_commands(&self, base_off: usize, prod_off: usize, cons_off: usize, ecmdq: bool) {
+        let base = self.reg64(base_off).load(Ordering::Acquire);
+        let prod_val = self.reg32(prod_off).load(Ordering::Acquire);
+        let prod = prod_val & QUEUE_INDEX_MASK;
+        let cons_reg = self.reg32(cons_off);
+        let cons_val = cons_reg.load(Ordering::Acquire);
+        let stopped = if ecmdq {
+            (cons_val ^ prod_val) & ECMDQ_ERR != 0
+        } else {
+            self.gerror_active(GERROR_CMDQ_ERR)
+        };
+        if stopped {
+            // Stopped on an error until software acknowledges it.
+            return;
+        }
+        // ECMDQ_CONS.{ENACK, ERR, ERR_REASON}, kept as is.
+        let status = cons_val & !QUEUE_INDEX_MASK;
+
+        let qs = (base & 0x1f) as u32;
+        let wrap_mask = (1 << (qs + 1)) - 1;
+        let queue = H::phys_to_virt(PhysAddr::from_usize((base & QUEUE_BASE_ADDR_MASK) as usize));
+        let mut cons = cons_val & wrap_mask;
+        while cons != prod & wrap_mask {
+            let idx = (cons & ((1 << qs) - 1)) as usize;
+            let cmd = unsafe { (queue.as_ptr() as *const [u64; 2]).add(idx).read_volatile() };
+            let opcode = cmd[0] & 0xff;
+            if !SUPPORTED_OPCODES.contains(&opcode) {
+                warn!("model: illegal command {:#x}", opcode);
+                if ecmdq {
+                    let status = (status & !ECMDQ_CONS_ERR_REASON_MASK) ^ ECMDQ_ERR;
+                    cons_reg.store(
+                        status | CERROR_ILL << CMDQ_CONS_ERR_OFFSET | cons,
+                        Ordering::Release,
+                    );
+                } else {
+                    cons_reg.store(cons | CERROR_ILL << CMDQ_CONS_ERR_OFFSET, Ordering::Release);
+                    self.raise_gerror(GERROR_CMDQ_ERR);
+                }
+                return;
+            }
+            if opcode == CMD_SYNC && (cmd[0] >> 12) & 0b11 == CMD_SYNC_CS_SIG_IRQ {
+                let msi_addr = cmd[1] & CMD_SYNC_1_MSIADDR_MASK;
+                let msi = H::phys_to_virt(PhysAddr::from_usize(msi_addr as usize));
+                unsafe { (msi.as_mut_ptr() as *mut u32).write_volatile((cmd[0] >> 32) as u32) };
+            }
+            self.commands.lock().unwrap().push(cmd);
+            cons = (cons + 1) & wrap_mask;
+            cons_reg.store(status | cons, Ordering::Release);
+        }
+    }
+
+    /// Whether the global error `bit` is active, SMMU_GERROR differing from SMMU_GERRORN.
+    fn gerror_active(&self, bit: u32) -> bool {
+        let gerror = self
+            .reg32(offset_of!(SMMUv3Regs, GERROR))
+            .load(Ordering::Acquire);
+        let gerrorn = self
+            .reg32(offset_of!(SMMUv3Regs, GERRORN))
+            .load(Ordering::Acquire);
+        (gerror ^ gerrorn) & bit != 0
+    }
+
+    /// Activate a global error by making SMMU_GERROR differ from SMMU_GERRORN.
+    fn raise_gerror(&self, bit: u32) {
+        let gerrorn = self
+            .reg32(offset_of!(SMMUv3Regs, GERRORN))
+            .load(Ordering::Acquire);
+        let gerror = self.reg32(offset_of!(SMMUv3Regs, GERROR));
+        gerror.store(
+            (gerror.load(Ordering::Acquire) & !bit) | (!gerrorn & bit),
+            Ordering::Release,
+        );
+    }
+
+    fn translate(
+        &self,
+        sid: u32,
+        ssid: Option<u32>,
+        addr: u64,
+        access: Access,
+    ) -> Result<walk::Translation, TranslationFault> {
+        let t = self.walk_stream(sid, ssid, addr, access)?;
+        if self.cr0ack() & CR0::DPT_WALK_EN::SET.value != 0 {
+            self.check_dpt(t.pa.as_usize() as u64, access)?;
+        }
+        Ok(t)
+    }
+
+    /// Translate `addr` through the Stream table, or bypass it while the SMMU is disabled.
+    fn walk_stream(
+        &self,
+        sid: u32,
+        ssid: Option<u32>,
+        addr: u64,
+        access: Access,
+    ) -> Result<walk::Translation, TranslationFault> {
+        if self.cr0ack() & CR0::SMMUEN::SET.value == 0 {
+            if self
+                .reg32(offset_of!(SMMUv3Regs, GBPA))
+                .load(Ordering::Acquire)
+                & GBPA_ABORT
+                != 0
+            {
+                return Err(TranslationFault::Abort);
+            }
+            // The attributes of SMMU_GBPA are not modelled.
+            return walk::translate::<H>(&StreamTableEntry::bypass_entry(), ssid, addr, access);
+        }
+        let strtab_cfg = self
+            .reg32(offset_of!(SMMUv3Regs, STRTAB_BASE_CFG))
+            .load(Ordering::Acquire);
+        let strtab_base = self
+            .reg64(offset_of!(SMMUv3Regs, STRTAB_BASE))
+            .load(Ordering::Acquire);
+        // Only linear stream tables are modelled, STRTAB_BASE_CFG.FMT is assumed to be 0b00.
+        let log2size = strtab_cfg & 0x3f;
+        if sid >> log2size != 0 {
+            return Err(TranslationFault::BadStreamId);
+        }
+        let ste = H::phys_to_virt(PhysAddr::from_usize(
+            (strtab_base & STRTAB_BASE_ADDR_MASK) as usize,
+        ));
+        let ste = unsafe { &*(ste.as_ptr() as *const StreamTableEntry).add(sid as usize) };
+        walk::translate::<H>(ste, ssid, addr, access)
+    }
+
+    /// Check `access` to `pa` against the Device Permission Table, aborting it when denied.
+    fn check_dpt(&self, pa: u64, access: Access) -> Result<(), TranslationFault> {
+        let base = self
+            .reg64(offset_of!(SMMUv3Regs, DPT_BASE))
+            .load(Ordering::Acquire);
+        let cfg = self
+            .reg32(offset_of!(SMMUv3Regs, DPT_BASE_CFG))
+            .load(Ordering::Acquire);
+        let pa_bits =
+            DPTPS_BITS[(DPT_BASE_CFG::DPTPS.read(cfg) as usize).min(DPTPS_BITS.len() - 1)];
+        if pa >> pa_bits != 0 {
+            return Err(TranslationFault::Abort);
+        }
+        // Only 4KB granules and 1GB level 0 descriptors are modelled.
+        let l0 = H::phys_to_virt(PhysAddr::from_usize(
+            (DPT_BASE::ADDR.read(base) << 12) as usize,
+        ));
+        let desc = unsafe {
+            (l0.as_ptr() as *const u64)
+                .add((pa >> 30) as usize)
+                .read_volatile()
+        };
+        let dpi = match desc & 0b1111 {
+            DPT_L0_BLOCK => desc >> 4 & 0b1111,
+            DPT_L0_TABLE => {
+                let granule = (pa & ((1 << 30) - 1)) >> 12;
+                let l1 = H::phys_to_virt(PhysAddr::from_usize(
+                    (desc & DPT_L0_TABLE_ADDR_MASK) as usize,
+                ));
+                let entry = unsafe {
+                    (l1.as_ptr() as *const u64)
+                        .add(granule as usize / 16)
+                        .read_volatile()
+                };
+                entry >> (granule % 16 * 4) & 0b1111
+            }
+            _ => {
+                self.raise_gerror(GERROR_DPT_ERR);
+                return Err(TranslationFault::Abort);
+            }
+        };
+        let allowed = match access {
+            Access::Write => dpi == 0b0011,
+            _ => dpi == 0b0001 || dpi == 0b0011,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(TranslationFault::Abort)
+        }
+    }
+
+    /// Write the event record of `fault` to the Event queue, if enabled and not full.
+    fn record_event(
+        &self,
+        sid: u32,
+        ssid: Option<u32>,
+        addr: u64,
+        access: Access,
+        fault: TranslationFault,
+    ) {
+        let Some(id) = fault.event_id() else {
+            return;
+        };
+        if self.cr0ack() & CR0::EVENTQEN::SET.value == 0 {
+            return;
+        }
+
+        let mut record = [0u64; EVTQ_ENT_DWORDS];
+        record[0] = id as u64 | (sid as u64) << 32;
+        if let Some(ssid) = ssid {
+            record[0] |= 1 << 11 | (ssid as u64 & 0xf_ffff) << 12;
+        }
+        if access != Access::Write {
+            record[1] |= 1 << 35;
+        }
+        if access == Access::Exec {
+            record[1] |= 1 << 34;
+        }
+        if fault.is_stage2() {
+            record[1] |= 1 << 39;
+        }
+        record[2] = addr;
+
+        let base = self
+            .reg64(offset_of!(SMMUv3Regs, EVENTQ_BASE))
+            .load(Ordering::Acquire);
+        let prod_reg = self.reg32(offset_of!(SMMUv3Regs, EVENTQ_PROD));
+        let prod_val = prod_reg.load(Ordering::Acquire);
+        let cons = self
+            .reg32(offset_of!(SMMUv3Regs, EVENTQ_CONS))
+            .load(Ordering::Acquire);
+        let qs = (base & 0x1f) as u32;
+        let wrap_mask = (1 << (qs + 1)) - 1;
+        let prod = prod_val & wrap_mask;
+        if prod ^ (cons & wrap_mask) == 1 << qs {
+            // Full, the record is lost. The overflow flag toggles unless the previous overflow is
+            // not acknowledged yet.
+            if (prod_val ^ cons) & EVENTQ_PROD_OVSLG == 0 {
+                prod_reg.store(prod_val ^ EVENTQ_PROD_OVSLG, Ordering::Release);
+            }
+            return;
+        }
+
+        let queue = H::phys_to_virt(PhysAddr::from_usize((base & QUEUE_BASE_ADDR_MASK) as usize));
+        let idx = (prod & ((1 << qs) - 1)) as usize;
+        unsafe {
+            (queue.as_mut_ptr() as *mut [u64; EVTQ_ENT_DWORDS])
+                .add(idx)
+                .write_volatile(record)
+        };
+        prod_reg.store(
+            (prod_val & EVENTQ_PROD_OVSLG) | ((prod + 1) & wrap_mask),
+            Ordering::Release,
+        );
+        self.events.lock().unwrap().push(record);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tock_registers::interfaces::Readable;
+
+    use super::*;
+    use crate::test_utils::HostPagingHandler;
+    use crate::{
+        Event, EventType, FaultHandler, IrqDelivery, IrqSource, MsiConfig, SMMUv3, StreamId,
+        CMDQ_CONS, CR0ACK, EVENTQ_CONS, EVENTQ_PROD, IRQ_CTRL,
+    };
+
+    fn model_and_driver() -> (SmmuModel<HostPagingHandler>, SMMUv3<HostPagingHandler>) {
+        let mut model = SmmuModel::new(ModelConfig::default());
+        model.spawn();
+        let mut smmu = SMMUv3::new(model.base());
+        smmu.init();
+        (model, smmu)
+    }
+
+    #[test]
+    fn test_init_and_faults() {
+        let (model, mut smmu) = model_and_driver();
+        assert!(smmu.regs().CR0ACK.is_set(CR0ACK::SMMUEN));
+        assert!(smmu.regs().CR0ACK.is_set(CR0ACK::CMDQEN));
+        assert!(smmu.regs().CR0ACK.is_set(CR0ACK::EVENTQEN));
+        assert_eq!(smmu.vmid_bits(), 16);
+
+        // Streams bypass until a device is added.
+        assert_eq!(
+            model.dma(5, None, 0x1234, Access::Write),
+            Ok(PhysAddr::from_usize(0x1234))
+        );
+        assert_eq!(
+            model.dma(0x100, None, 0x1234, Access::Read),
+            Err(TranslationFault::BadStreamId)
+        );
+        let events = model.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0][0], 0x02 | 0x100 << 32);
+        assert_eq!(smmu.regs().EVENTQ_PROD.read(EVENTQ_PROD::WR), 1);
+
+        let msi = MsiConfig::new(PhysAddr::from_usize(0x800_0040), 0x42);
+        smmu.configure_irq(IrqSource::EventQueue, IrqDelivery::Msi(msi))
+            .unwrap();
+        smmu.set_irq_enabled(IrqSource::EventQueue, true).unwrap();
+        assert_eq!(smmu.regs().EVENTQ_IRQ_CFG0.get(), 0x800_0040);
+        assert!(smmu.regs().IRQ_CTRLACK.is_set(IRQ_CTRL::EVENTQ_IRQEN));
+        assert_eq!(smmu.handle_event_irq(), 1);
+        assert_eq!(smmu.regs().EVENTQ_CONS.read(EVENTQ_CONS::RD), 1);
+        assert_eq!(smmu.handle_event_irq(), 0);
+    }
+
+    #[test]
+    fn test_combined_irq() {
+        struct Recorder(Mutex<Vec<Event>>);
+
+        impl FaultHandler for Recorder {
+            fn on_event(&self, event: &Event) {
+                self.0.lock().unwrap().push(*event);
+            }
+        }
+
+        static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+
+        let (model, mut smmu) = model_and_driver();
+        smmu.set_fault_handler(&RECORDER);
+        assert!(!smmu.handle_combined_irq());
+
+        model.dma(0x100, Some(3), 0x1234, Access::Read).unwrap_err();
+        model.dma(0x200, None, 0x5678, Access::Write).unwrap_err();
+        assert!(smmu.handle_combined_irq());
+        let events = RECORDER.0.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[0].kind, events[0].sid),
+            (EventType::BadStreamId, 0x100)
+        );
+        assert_eq!(
+            (events[1].kind, events[1].sid),
+            (EventType::BadStreamId, 0x200)
+        );
+        assert!(!smmu.handle_combined_irq());
+    }
+
+    #[test]
+    fn test_event_queue_overflow() {
+        let (model, mut smmu) = model_and_driver();
+        // 256 records fill the queue, the next ones are lost.
+        for i in 0..300 {
+            model.dma(0x100 + i, None, 0, Access::Read).unwrap_err();
+        }
+        assert!(smmu.regs().EVENTQ_PROD.is_set(EVENTQ_PROD::OVSLG));
+        assert_eq!(smmu.handle_event_irq(), 256);
+        assert_eq!(smmu.event_queue_overflows(), 1);
+        assert!(smmu.regs().EVENTQ_CONS.is_set(EVENTQ_CONS::OVACKFLG));
+
+        model.dma(0x100, None, 0, Access::Read).unwrap_err();
+        assert_eq!(smmu.handle_event_irq(), 1);
+        assert_eq!(smmu.event_queue_overflows(), 1);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let (model, mut smmu) = model_and_driver();
+        smmu.set_fault_threshold(Some(3));
+        smmu.set_sid_fault_threshold(StreamId::new(6), Some(u32::MAX));
+        // Empty stage 2 tables, every access faults.
+        let s2pt = HostPagingHandler::alloc_pages(1).unwrap();
+        smmu.add_device(StreamId::new(5), 1, s2pt).unwrap();
+        smmu.add_device(StreamId::new(6), 2, s2pt).unwrap();
+
+        for _ in 0..2 {
+            model.dma(5, None, 0x1000, Access::Read).unwrap_err();
+        }
+        assert_eq!(smmu.handle_event_irq(), 2);
+        assert_eq!(smmu.sid_faults(StreamId::new(5)), 2);
+        smmu.reset_fault_counts();
+        assert_eq!(smmu.sid_faults(StreamId::new(5)), 0);
+
+        for _ in 0..4 {
+            model.dma(5, None, 0x1000, Access::Read).unwrap_err();
+            model.dma(6, None, 0x1000, Access::Read).unwrap_err();
+        }
+        assert_eq!(smmu.handle_event_irq(), 8);
+        assert!(smmu.is_quarantined(StreamId::new(5)));
+        assert!(!smmu.is_quarantined(StreamId::new(6)));
+        assert_eq!(smmu.sid_faults(StreamId::new(6)), 4);
+        assert_eq!(smmu.vmid_devices(1), 0);
+
+        // Traffic of the quarantined stream is aborted without events.
+        assert_eq!(
+            model.dma(5, None, 0x1000, Access::Read),
+            Err(TranslationFault::Abort)
+        );
+        assert_eq!(smmu.handle_event_irq(), 0);
+
+        // C_BAD_STE records, from a reserved VTCR.TG0, do not count against the stream.
+        let vmid = smmu.alloc_vmid().unwrap();
+        let vtcr = crate::stream_table::DEFAULT_S2VTCR | 0b11 << 14;
+        let mut domain = crate::IommuDomain::new_s2_with_vtcr(vmid, s2pt, vtcr);
+        smmu.attach_domain(&mut domain, &[StreamId::new(7)])
+            .unwrap();
+        for _ in 0..4 {
+            assert_eq!(
+                model.dma(7, None, 0x1000, Access::Read),
+                Err(TranslationFault::BadSte)
+            );
+        }
+        assert_eq!(smmu.handle_event_irq(), 4);
+        assert_eq!(smmu.sid_faults(StreamId::new(7)), 0);
+        assert!(!smmu.is_quarantined(StreamId::new(7)));
+        smmu.detach_all(&mut domain);
+    }
+
+    #[test]
+    fn test_attach_domain() {
+        use crate::{DomainError, IommuDomain, VmidError};
+
+        let (model, mut smmu) = model_and_driver();
+        let s2pt = HostPagingHandler::alloc_pages(1).unwrap();
+        let vmid = smmu.alloc_vmid().unwrap();
+        let mut domain = IommuDomain::new_s2(vmid, s2pt);
+
+        // A device and the alias of its bridge, one listed twice, take a VMID reference each.
+        let sids = [
+            StreamId::new(0x48),
+            StreamId::new(0x08),
+            StreamId::new(0x48),
+        ];
+        smmu.attach_domain(&mut domain, &sids).unwrap();
+        assert!(domain.sids().eq([StreamId::new(0x08), StreamId::new(0x48)]));
+        assert_eq!(smmu.vmid_devices(vmid), 2);
+        smmu.attach_domain(&mut domain, &sids[..1]).unwrap();
+        assert_eq!(smmu.vmid_devices(vmid), 2);
+        assert_eq!(
+            model.dma(0x08, None, 0x1000, Access::Read),
+            Err(TranslationFault::Translation { s2: true })
+        );
+
+        // Streams translated elsewhere or quarantined are refused, leaving the others unattached.
+        smmu.add_device(StreamId::new(5), 0x20, s2pt).unwrap();
+        smmu.quarantine(StreamId::new(6));
+        let mut other = IommuDomain::new_s2(vmid, s2pt);
+        for (sid, err) in [(5, DomainError::InUse), (6, DomainError::Quarantined)] {
+            assert_eq!(
+                smmu.attach_domain(&mut other, &[StreamId::new(7), StreamId::new(sid)]),
+                Err(err)
+            );
+        }
+        assert_eq!(
+            smmu.attach_domain(&mut other, &[StreamId::new(0x100)]),
+            Err(DomainError::OutOfRange)
+        );
+        assert_eq!(other.sid_count(), 0);
+        assert_eq!(smmu.vmid_devices(vmid), 2);
+
+        // Running out of VMID references on the second StreamID leaves the first one unattached.
+        let free = 0x7fff - smmu.vmid_devices(vmid);
+        for _ in 1..free {
+            smmu.vmid_alloc.get_allocated(vmid as usize).unwrap();
+        }
+        assert_eq!(
+            smmu.attach_domain(&mut other, &[StreamId::new(7), StreamId::new(9)]),
+            Err(DomainError::Vmid(VmidError::Exhausted))
+        );
+        assert_eq!(other.sid_count(), 0);
+        assert_eq!(smmu.vmid_devices(vmid), 0x7ffe);
+        assert!(!smmu.stream_table.ste(7).is_translated());
+        for _ in 1..free {
+            smmu.vmid_alloc.put(vmid as usize);
+        }
+
+        // Detaching returns the streams to bypass and drops the VMID references.
+        smmu.detach_domain(&mut domain, &[StreamId::new(0x08), StreamId::new(0x10)]);
+        assert_eq!(smmu.vmid_devices(vmid), 1);
+        assert_eq!(
+            model.dma(0x08, None, 0x1234, Access::Read),
+            Ok(PhysAddr::from_usize(0x1234))
+        );
+        smmu.detach_all(&mut domain);
+        assert_eq!(smmu.vmid_devices(vmid), 0);
+        assert_eq!(domain.sid_count(), 0);
+    }
+
+    #[test]
+    fn test_quarantine_domain() {
+        use crate::IommuDomain;
+
+        let (model, mut smmu) = model_and_driver();
+        let vmid = smmu.alloc_vmid().unwrap();
+        let s2pt = HostPagingHandler::alloc_pages(1).unwrap();
+        let mut domain = IommuDomain::new_s2(vmid, s2pt);
+        let sids = [StreamId::new(5), StreamId::new(6)];
+        smmu.attach_domain(&mut domain, &sids).unwrap();
+        assert_eq!(smmu.vmid_devices(vmid), 2);
+
+        smmu.quarantine(StreamId::new(5));
+        assert_eq!(smmu.vmid_devices(vmid), 1);
+
+        // Moving the domain to new tables does not lift the quarantine.
+        let s2pt = HostPagingHandler::alloc_pages(1).unwrap();
+        smmu.set_domain_root(&mut domain, s2pt);
+        assert!(smmu.is_quarantined(StreamId::new(5)));
+        assert_eq!(smmu.vmid_devices(vmid), 1);
+        assert_eq!(
+            model.dma(5, None, 0x1000, Access::Read),
+            Err(TranslationFault::Abort)
+        );
+
+        // Detaching drops the VMID reference of the translated stream only.
+        smmu.detach_all(&mut domain);
+        assert_eq!(smmu.vmid_devices(vmid), 0);
+        assert!(!smmu.is_quarantined(StreamId::new(5)));
+        smmu.free_vmid(vmid);
+    }
+
+    #[test]
+    fn test_cmdq_error() {
+        use crate::queue::Cmd;
+        use crate::GlobalErrors;
+
+        let (model, mut smmu) = model_and_driver();
+        smmu.add_cmd(Cmd::from_raw([0xff, 0]), false);
+        while smmu.regs().GERROR.get() == smmu.regs().GERRORN.get() {
+            std::thread::yield_now();
+        }
+        assert_eq!(smmu.regs().CMDQ_CONS.read(CMDQ_CONS::ERR), CERROR_ILL);
+        assert_eq!(smmu.handle_gerror_irq(), GlobalErrors::CMDQ_ERR);
+
+        // The illegal command was replaced by a CMD_SYNC, and the queue goes on.
+        smmu.add_cmd(Cmd::cmd_cfgi_all(), true);
+        let opcodes: Vec<u64> = model.commands().iter().map(|c| c[0] & 0xff).collect();
+        assert!(opcodes.ends_with(&[CMD_SYNC, 0x04, CMD_SYNC]));
+        assert!(!opcodes.contains(&0xff));
+        assert!(smmu.handle_gerror_irq().is_empty());
+    }
+
+    #[test]
+    fn test_cmdq_error_sync() {
+        use crate::queue::Cmd;
+
+        let (model, mut smmu) = model_and_driver();
+        // The CMD_SYNC wait skips the illegal command itself instead of hanging.
+        smmu.add_cmd(Cmd::from_raw([0xff, 0]), true);
+        let opcodes: Vec<u64> = model.commands().iter().map(|c| c[0] & 0xff).collect();
+        assert!(opcodes.ends_with(&[CMD_SYNC, CMD_SYNC]));
+        assert!(!opcodes.contains(&0xff));
+        assert!(smmu.handle_gerror_irq().is_empty());
+
+        smmu.add_cmd(Cmd::cmd_cfgi_all(), true);
+        let opcodes: Vec<u64> = model.commands().iter().map(|c| c[0] & 0xff).collect();
+        assert!(opcodes.ends_with(&[0x04, CMD_SYNC]));
+    }
+
+    #[test]
+    fn test_guest_event() {
+        use crate::{ContextDescriptor, GuestStream, IommuDomain};
+
+        struct Forwarder(Mutex<Vec<(GuestStream, [u64; EVTQ_ENT_DWORDS])>>, AtomicU32);
+
+        impl FaultHandler for Forwarder {
+            fn on_event(&self, _event: &Event) {
+                self.1.fetch_add(1, Ordering::Relaxed);
+            }
+
+            fn on_guest_event(&self, event: &Event, guest: GuestStream) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((guest, event.guest_record(guest.vsid.as_u32())));
+            }
+        }
+
+        static FORWARDER: Forwarder = Forwarder(Mutex::new(Vec::new()), AtomicU32::new(0));
+
+        let (model, mut smmu) = model_and_driver();
+        smmu.set_fault_handler(&FORWARDER);
+        // Empty stage 1 tables for stream 5, empty stage 2 tables for stream 6.
+        let pt = HostPagingHandler::alloc_pages(1).unwrap();
+        let cd = ContextDescriptor::s1_entry(1, pt, 25, 0, 0b101, 0, HttuMode::Disabled);
+        let mut domain = IommuDomain::new_s1(&cd);
+        smmu.attach_domain(&mut domain, &[StreamId::new(5)])
+            .unwrap();
+        smmu.add_device(StreamId::new(6), 1, pt).unwrap();
+        let guest = GuestStream {
+            vm: 2,
+            vsid: StreamId::new(0x10),
+        };
+        smmu.set_guest_stream(StreamId::new(5), guest);
+        smmu.set_guest_stream(StreamId::new(6), guest);
+        assert_eq!(smmu.guest_stream(StreamId::new(5)), Some(guest));
+
+        assert_eq!(
+            model.dma(5, None, 0x1000, Access::Write),
+            Err(TranslationFault::Translation { s2: false })
+        );
+        // Stage 2 faults are the hypervisor's.
+        model.dma(6, None, 0x1000, Access::Read).unwrap_err();
+        assert_eq!(smmu.handle_event_irq(), 2);
+        let forwarded = FORWARDER.0.lock().unwrap().clone();
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0].0, guest);
+        assert_eq!(Event::from_raw(forwarded[0].1).sid, 0x10);
+        assert_eq!(forwarded[0].1[2], 0x1000);
+        assert_eq!(FORWARDER.1.load(Ordering::Relaxed), 1);
+
+        smmu.clear_guest_stream(StreamId::new(5));
+        assert_eq!(smmu.guest_stream(StreamId::new(5)), None);
+        model.dma(5, None, 0x1000, Access::Write).unwrap_err();
+        assert_eq!(smmu.handle_event_irq(), 1);
+        assert_eq!(FORWARDER.1.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_concurrent_cmds() {
+        let (model, smmu) = model_and_driver();
+        let commands = model.commands().len();
+
+        // CPUs invalidate through a shared reference, each returning once its CMD_SYNC completed.
+        std::thread::scope(|s| {
+            for vmid in 1..=4usize {
+                let smmu = &smmu;
+                s.spawn(move || {
+                    for _ in 0..25 {
+                        smmu.sync_dirty_log(vmid);
+                    }
+                });
+            }
+        });
+        let issued = &model.commands()[commands..];
+        assert_eq!(issued.len(), 200);
+        for vmid in 1..=4u64 {
+            assert_eq!(
+                issued
+                    .iter()
+                    .filter(|cmd| cmd[0] == 0x28 | vmid << 32)
+                    .count(),
+                25
+            );
+        }
+        assert_eq!(issued.iter().filter(|cmd| cmd[0] == 0x46).count(), 100);
+        assert_eq!(smmu.regs().CMDQ_CONS.get(), smmu.regs().CMDQ_PROD.get());
+    }
+
+    #[test]
+    fn test_ecmdq() {
+        use crate::queue::Cmd;
+        use crate::{EcmdqError, EcmdqRegs, ECMDQ_CONS};
+
+        let (_model, mut smmu) = model_and_driver();
+        let base = PhysAddr::from_usize(smmu.backend().regs() as *const _ as usize);
+        assert_eq!(smmu.enable_ecmdqs(base, 8), Err(EcmdqError::NotSupported));
+
+        let mut model = SmmuModel::<HostPagingHandler>::new(ModelConfig {
+            ecmdqs: true,
+            ..Default::default()
+        });
+        model.spawn();
+        let mut smmu = SMMUv3::<HostPagingHandler>::new(model.base());
+        smmu.init();
+        assert_eq!(smmu.ecmdq_capacity(), 4);
+        let base = PhysAddr::from_usize(model.base() as usize);
+        assert_eq!(smmu.enable_ecmdqs(base, 8), Ok(4));
+        assert_eq!(smmu.ecmdqs(), 4);
+
+        // Commands of CPU 0 go to the first queue, the Command queue is left alone.
+        let cmdq_prod = smmu.regs().CMDQ_PROD.get();
+        let commands = model.commands().len();
+        let s2pt = HostPagingHandler::alloc_pages(1).unwrap();
+        smmu.add_device(StreamId::new(3), 1, s2pt).unwrap();
+        assert_eq!(smmu.regs().CMDQ_PROD.get(), cmdq_prod);
+        assert!(model.commands().len() > commands);
+        let ecmdq = EcmdqRegs::new(smmu.backend(), ECMDQ_CONTROL_PAGE);
+        assert!(ecmdq.CONS.is_set(ECMDQ_CONS::ENACK));
+        assert_eq!(
+            ecmdq.CONS.read(ECMDQ_CONS::RD) as usize,
+            model.commands().len() - commands
+        );
+        assert_eq!(
+            model.dma(3, None, 0, Access::Read),
+            Err(TranslationFault::Translation { s2: true })
+        );
+
+        // Illegal commands are skipped, the second one toggling ECMDQ_CONS.ERR back.
+        for _ in 0..2 {
+            smmu.add_cmd(Cmd::from_raw([0xff, 0]), true);
+        }
+        smmu.add_cmd(Cmd::cmd_cfgi_all(), true);
+        assert!(!ecmdq.CONS.is_set(ECMDQ_CONS::ERR));
+        let opcodes: Vec<u64> = model.commands().iter().map(|c| c[0] & 0xff).collect();
+        assert!(opcodes.ends_with(&[CMD_SYNC, CMD_SYNC, CMD_SYNC, CMD_SYNC, 0x04, CMD_SYNC]));
+        assert!(!opcodes.contains(&0xff));
+    }
+
+    #[test]
+    fn test_manager_routing() {
+        use crate::{ManagerError, SidRange, SmmuManager};
+
+        let (model0, smmu0) = model_and_driver();
+        let (model1, smmu1) = model_and_driver();
+        let mut manager = SmmuManager::<HostPagingHandler, _, 2, 4>::new();
+        assert_eq!(manager.add_smmu(smmu0), Ok(0));
+        assert_eq!(manager.add_smmu(smmu1), Ok(1));
+        manager
+            .add_range(SidRange {
+                smmu: 0,
+                segment: 0,
+                rid_base: 0,
+                sid_base: 0,
+                len: 0x100,
+            })
+            .unwrap();
+        manager
+            .add_range(SidRange {
+                smmu: 1,
+                segment: 0,
+                rid_base: 0x1000,
+                sid_base: 0x10,
+                len: 0x20,
+            })
+            .unwrap();
+        assert_eq!(
+            manager.add_range(SidRange {
+                smmu: 1,
+                segment: 0,
+                rid_base: 0xff,
+                sid_base: 0,
+                len: 2
+            }),
+            Err(ManagerError::BadRange)
+        );
+        assert_eq!(
+            manager.add_range(SidRange {
+                smmu: 2,
+                segment: 0,
+                rid_base: 0x2000,
+                sid_base: 0,
+                len: 1
+            }),
+            Err(ManagerError::NoSmmu)
+        );
+        assert_eq!(manager.route(0, 0x1005), Some((1, StreamId::new(0x15))));
+        assert_eq!(manager.route(0, 0x1020), None);
+
+        let s2pt = HostPagingHandler::alloc_pages(1).unwrap();
+        manager.add_device(0, 0x1005, 1, s2pt).unwrap();
+        assert_eq!(
+            model1.dma(0x15, None, 0, Access::Read),
+            Err(TranslationFault::Translation { s2: true })
+        );
+        assert_eq!(
+            model0.dma(0x15, None, 0, Access::Read),
+            Ok(PhysAddr::from_usize(0))
+        );
+        assert_eq!(
+            manager.add_device(0, 0x3000, 1, s2pt),
+            Err(ManagerError::NoRoute)
+        );
+        manager.remove_device(0, 0x1005).unwrap();
+        assert_eq!(manager.smmu(1).unwrap().vmid_devices(1), 0);
+    }
+
+    #[cfg(feature = "io_pgtable")]
+    #[test]
+    fn test_add_device_s2() {
+        use crate::{AtosError, AtosFaultReason, AtosKind, AtosTranslation};
+        use crate::{IoPageTable, IoPgtableConfig, IoProt};
+
+        let (model, mut smmu) = model_and_driver();
+        let vmid = smmu.alloc_vmid().unwrap();
+        let mut pt =
+            IoPageTable::<HostPagingHandler>::new_s2(IoPgtableConfig::default(), vmid).unwrap();
+        pt.map(
+            0x1000,
+            PhysAddr::from_usize(0x8000_0000),
+            0x1000,
+            IoProt::READ,
+        )
+        .unwrap();
+        smmu.add_device_with_pgtable(StreamId::new(3), &pt).unwrap();
+
+        let opcodes: Vec<u64> = model.commands().iter().map(|c| c[0] & 0xff).collect();
+        assert!(opcodes.ends_with(&[0x03, CMD_SYNC, 0x01, CMD_SYNC]));
+
+        assert_eq!(
+            model.dma(3, None, 0x1008, Access::Read),
+            Ok(PhysAddr::from_usize(0x8000_0008))
+        );
+        assert_eq!(
+            model.dma(3, None, 0x1008, Access::Write),
+            Err(TranslationFault::Permission { s2: true })
+        );
+        assert_eq!(
+            model.dma(3, None, 0x5000, Access::Read),
+            Err(TranslationFault::Translation { s2: true })
+        );
+        let events = model.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1][0], 0x10 | 3 << 32);
+        assert_ne!(events[1][1] & 1 << 39, 0);
+        assert_eq!(events[1][2], 0x5000);
+
+        assert_eq!(
+            smmu.hw_translate(StreamId::new(3), None, 0x1008, AtosKind::new(Access::Read)),
+            Ok(AtosTranslation {
+                pa: PhysAddr::from_usize(0x8000_0008),
+                size: 0x1000,
+                attr: 0,
+                sh: 0,
+            })
+        );
+        assert_eq!(
+            smmu.hw_translate(StreamId::new(3), None, 0x1008, AtosKind::new(Access::Write)),
+            Err(AtosError::Fault {
+                code: 0x13,
+                reason: AtosFaultReason::Stage2,
+            })
+        );
+
+        smmu.remove_device(StreamId::new(3));
+        assert!(model.dma(3, None, 0x5000, Access::Read).is_ok());
+    }
+
+    #[cfg(feature = "dma")]
+    #[test]
+    fn test_dma() {
+        use crate::{DmaDirection, DmaDomainConfig, DmaError, IoPgtableConfig};
+
+        const CMD_TLBI_S2_IPA: u64 = 0x2a;
+
+        let (model, mut smmu) = model_and_driver();
+        // Four pages of IOVA space, allocated from the top.
+        let cfg = DmaDomainConfig {
+            pgtable: IoPgtableConfig::default(),
+            iova_base: 0x1000,
+            iova_size: 0x4000,
+        };
+        let id = smmu.create_dma_domain(cfg).unwrap();
+        let sid = StreamId::new(5);
+        let buf = PhysAddr::from_usize(0x8000_0000);
+        assert_eq!(
+            smmu.dma_map(sid, buf, 0x10, DmaDirection::ToDevice),
+            Err(DmaError::NoDomain)
+        );
+        smmu.attach_dma_domain(sid, id).unwrap();
+        // Keeps the tables of the aperture in use, unmaps then leave them in place.
+        let pinned = smmu
+            .dma_map(sid, buf, 0x1000, DmaDirection::ToDevice)
+            .unwrap();
+        assert_eq!(pinned, 0x4000);
+
+        let a = smmu
+            .dma_map(sid, buf + 0x18, 0x10, DmaDirection::ToDevice)
+            .unwrap();
+        assert_eq!(a, 0x3018);
+        assert_eq!(model.dma(5, None, a as u64, Access::Read), Ok(buf + 0x18));
+        assert_eq!(
+            model.dma(5, None, a as u64, Access::Write),
+            Err(TranslationFault::Permission { s2: true })
+        );
+
+        // The unmapped IOVA is not handed out again before its invalidation completed.
+        let commands = model.commands().len();
+        smmu.dma_unmap(sid, a, 0x10).unwrap();
+        assert_eq!(model.commands().len(), commands);
+        let b = smmu
+            .dma_map(sid, buf, 0x1000, DmaDirection::Bidirectional)
+            .unwrap();
+        assert_eq!(b, 0x2000);
+
+        smmu.flush_dma_domain(id).unwrap();
+        let commands = model.commands();
+        let tlbi = commands[commands.len() - 2];
+        assert_eq!(tlbi[0] & 0xff, CMD_TLBI_S2_IPA);
+        assert_eq!(tlbi[1] & !0xfff, 0x3000);
+        assert_eq!(commands.last().unwrap()[0] & 0xff, CMD_SYNC);
+        let c = smmu
+            .dma_map(sid, buf, 0x1000, DmaDirection::FromDevice)
+            .unwrap();
+        assert_eq!(c, 0x3000);
+
+        // Running out of IOVA space flushes the pending ranges to reuse them.
+        let d = smmu
+            .dma_map(sid, buf, 0x1000, DmaDirection::FromDevice)
+            .unwrap();
+        assert_eq!(d, 0x1000);
+        smmu.dma_unmap(sid, b, 0x1000).unwrap();
+        smmu.dma_unmap(sid, d, 0x1000).unwrap();
+        let e = smmu
+            .dma_map(sid, buf, 0x2000, DmaDirection::FromDevice)
+            .unwrap();
+        assert_eq!(e, 0x1000);
+        assert_eq!(
+            smmu.dma_map(sid, buf, 0x1000, DmaDirection::FromDevice),
+            Err(DmaError::IovaExhausted)
+        );
+
+        assert_eq!(smmu.destroy_dma_domain(id), Err(DmaError::Busy));
+        smmu.detach_dma_domain(sid).unwrap();
+        assert_eq!(
+            model.dma(5, None, 0x1234, Access::Read),
+            Ok(PhysAddr::from_usize(0x1234))
+        );
+        smmu.destroy_dma_domain(id).unwrap();
+
+        // A block reuses the IOVA of an unmapped page, whose tables are gone.
+        let cfg = DmaDomainConfig {
+            pgtable: IoPgtableConfig::default(),
+            iova_base: 0x20_0000,
+            iova_size: 0x20_0000,
+        };
+        let id = smmu.create_dma_domain(cfg).unwrap();
+        smmu.attach_dma_domain(sid, id).unwrap();
+        let a = smmu
+            .dma_map(sid, buf, 0x1000, DmaDirection::ToDevice)
+            .unwrap();
+        smmu.dma_unmap(sid, a, 0x1000).unwrap();
+        smmu.flush_dma_domain(id).unwrap();
+        let block = PhysAddr::from_usize(0x8020_0000);
+        let b = smmu
+            .dma_map(sid, block, 0x20_0000, DmaDirection::Bidirectional)
+            .unwrap();
+        assert_eq!(b, 0x20_0000);
+        assert_eq!(
+            model.dma(5, None, 0x21_2345, Access::Write),
+            Ok(block + 0x1_2345)
+        );
+        smmu.detach_dma_domain(sid).unwrap();
+        smmu.destroy_dma_domain(id).unwrap();
+    }
+
+    #[test]
+    fn test_suspend_resume() {
+        use crate::SuspendError;
+
+        let (model, mut smmu) = model_and_driver();
+        // Empty stage 2 tables for stream 6, other streams bypass.
+        let pt = HostPagingHandler::alloc_pages(1).unwrap();
+        smmu.add_device(StreamId::new(6), 1, pt).unwrap();
+        smmu.set_irq_enabled(IrqSource::EventQueue, true).unwrap();
+        model.dma(6, None, 0x1000, Access::Read).unwrap_err();
+        assert_eq!(smmu.resume(), Err(SuspendError::NotSuspended));
+
+        smmu.suspend().unwrap();
+        assert!(smmu.is_suspended());
+        assert_eq!(smmu.suspend(), Err(SuspendError::AlreadySuspended));
+        assert_eq!(smmu.regs().CR0ACK.get(), 0);
+        assert_eq!(
+            model.dma(1, None, 0x1000, Access::Read),
+            Err(TranslationFault::Abort)
+        );
+        // The SMMU loses its registers, the ID registers aside.
+        unsafe { core::ptr::write_bytes(model.base().add(0x20), 0, 0xe0) };
+
+        smmu.resume().unwrap();
+        assert!(!smmu.is_suspended());
+        let opcodes: Vec<u64> = model.commands().iter().map(|c| c[0] & 0xff).collect();
+        assert!(opcodes.ends_with(&[0x04, 0x30, CMD_SYNC]));
+        assert!(smmu.irq_enabled(IrqSource::EventQueue));
+        assert_eq!(
+            model.dma(1, None, 0x1000, Access::Read),
+            Ok(PhysAddr::from_usize(0x1000))
+        );
+        assert_eq!(
+            model.dma(6, None, 0x1000, Access::Read),
+            Err(TranslationFault::Translation { s2: true })
+        );
+        assert_eq!(smmu.handle_event_irq(), 1);
+    }
+
+    #[test]
+    fn test_dpt() {
+        use crate::{DevicePermission, DptError};
+
+        let (_model, mut smmu) = model_and_driver();
+        assert!(!smmu.dpt_supported());
+        assert_eq!(
+            smmu.enable_dpt(32, DevicePermission::ReadWrite),
+            Err(DptError::NotSupported)
+        );
+
+        let mut model = SmmuModel::<HostPagingHandler>::new(ModelConfig {
+            dpt: true,
+            ..Default::default()
+        });
+        model.spawn();
+        let mut smmu = SMMUv3::<HostPagingHandler>::new(model.base());
+        smmu.init();
+        let pa = PhysAddr::from_usize(0x4000_2000);
+        assert_eq!(
+            smmu.revoke_device_access(pa, 0x1000),
+            Err(DptError::NotEnabled)
+        );
+        smmu.enable_dpt(30, DevicePermission::ReadWrite).unwrap();
+        assert_eq!(
+            smmu.device_permission(pa),
+            Some(DevicePermission::ReadWrite)
+        );
+        assert_eq!(smmu.device_permission(PhysAddr::from_usize(1 << 32)), None);
+        assert!(smmu.regs().CR0ACK.is_set(CR0ACK::DPT_WALK_EN));
+
+        // Streams bypass, only the DPT restricts them.
+        assert_eq!(
+            model.dma(1, None, 0x4000_2008, Access::Write),
+            Ok(PhysAddr::from_usize(0x4000_2008))
+        );
+        smmu.revoke_device_access(pa, 0x1000).unwrap();
+        smmu.grant_device_access(pa + 0x1000, 1, DevicePermission::Read)
+            .unwrap();
+        assert_eq!(smmu.device_permission(pa), Some(DevicePermission::None));
+        assert_eq!(
+            smmu.device_permission(pa - 0x1000),
+            Some(DevicePermission::ReadWrite)
+        );
+        assert_eq!(
+            model.dma(1, None, 0x4000_2008, Access::Read),
+            Err(TranslationFault::Abort)
+        );
+        assert_eq!(
+            model.dma(1, None, 0x4000_3000, Access::Read),
+            Ok(PhysAddr::from_usize(0x4000_3000))
+        );
+        assert_eq!(
+            model.dma(1, None, 0x4000_3000, Access::Write),
+            Err(TranslationFault::Abort)
+        );
+        assert_eq!(
+            model.dma(1, None, 0x4000_1000, Access::Write),
+            Ok(PhysAddr::from_usize(0x4000_1000))
+        );
+        assert_eq!(
+            model.dma(1, None, 1 << 32, Access::Read),
+            Err(TranslationFault::Abort)
+        );
+        assert!(model.events().is_empty());
+
+        let dpti: Vec<u64> = model
+            .commands()
+            .iter()
+            .map(|cmd| cmd[0] & 0xff)
+            .filter(|&op| op != CMD_SYNC)
+            .collect();
+        assert_eq!(dpti, [0x70, 0x73, 0x73]);
+        assert_eq!(
+            smmu.grant_device_access(
+                PhysAddr::from_usize(0xffff_f000),
+                0x2000,
+                DevicePermission::Read
+            ),
+            Err(DptError::OutOfRange)
+        );
+    }
+}
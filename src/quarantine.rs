@@ -0,0 +1,181 @@
+//! Per-StreamID fault accounting and quarantine.
+//!
+//! Translation faults, F_TRANSLATION to F_PERMISSION, read from the Event queue are counted per
+//! StreamID. Once a stream reaches its fault threshold, its STE is switched to abort: the SMMU
+//! then terminates its traffic without recording events, and the quarantine is reported to the
+//! [`FaultHandler`]. Thresholds count the faults since the last [`SMMUv3::reset_fault_counts`],
+//! calling it periodically turns them into rates.
+//!
+//! [`FaultHandler`]: crate::FaultHandler
+
+use core::marker::PhantomData;
+
+use memory_addr::{align_up_4k, va, VirtAddr, PAGE_SIZE_4K};
+
+use crate::backend::RegisterBackend;
+use crate::event::Event;
+use crate::hal::PagingHandler;
+use crate::stream_id::StreamId;
+use crate::stream_table::SteConfig;
+use crate::SMMUv3;
+
+/// Fault count of a StreamID, and its threshold.
+#[repr(C)]
+struct FaultCounter {
+    faults: u32,
+    /// Threshold overriding the default one, or [`THRESHOLD_DEFAULT`].
+    threshold: u32,
+}
+
+/// [`FaultCounter::threshold`] value selecting the default threshold.
+const THRESHOLD_DEFAULT: u32 = 0;
+
+/// Fault counters of all the StreamIDs.
+///
+/// The counters live in pages obtained from [`PagingHandler::alloc_pages`], allocated the first
+/// time a threshold is set, so that streams are not accounted for until then.
+pub(crate) struct FaultCounters<H: PagingHandler> {
+    base: VirtAddr,
+    entry_count: usize,
+    default_threshold: Option<u32>,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> FaultCounters<H> {
+    pub const fn uninit() -> Self {
+        Self {
+            base: va!(0xdead_beef),
+            entry_count: 0,
+            default_threshold: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn is_init(&self) -> bool {
+        self.entry_count != 0
+    }
+
+    /// Allocate zeroed counters for `entry_count` StreamIDs, unless already done.
+    fn init(&mut self, entry_count: usize) {
+        if self.is_init() {
+            return;
+        }
+        let size = align_up_4k(entry_count * size_of::<FaultCounter>());
+        let base = H::alloc_pages(size / PAGE_SIZE_4K).expect("Failed to allocate fault counters");
+        self.base = H::phys_to_virt(base);
+        unsafe { core::ptr::write_bytes(self.base.as_mut_ptr(), 0, size) };
+        self.entry_count = entry_count;
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn counter(&self, sid: usize) -> &mut FaultCounter {
+        debug_assert!(sid < self.entry_count);
+        unsafe { &mut *(self.base.as_mut_ptr() as *mut FaultCounter).add(sid) }
+    }
+
+    fn threshold(&self, sid: usize) -> Option<u32> {
+        match self.counter(sid).threshold {
+            THRESHOLD_DEFAULT => self.default_threshold,
+            threshold => Some(threshold),
+        }
+    }
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Quarantine the StreamIDs reaching `threshold` faults, `None` disables the default
+    /// threshold.
+    ///
+    /// Applies to the StreamIDs without a threshold of their own, see
+    /// [`SMMUv3::set_sid_fault_threshold`].
+    pub fn set_fault_threshold(&mut self, threshold: Option<u32>) {
+        self.fault_counters.init(self.stream_table.entry_count());
+        self.fault_counters.default_threshold = threshold.map(|t| t.max(1));
+    }
+
+    /// Quarantine `sid` once it reaches `threshold` faults, or with `None` once it reaches the
+    /// default threshold.
+    ///
+    /// `u32::MAX` exempts the stream from quarantine.
+    pub fn set_sid_fault_threshold(&mut self, sid: StreamId, threshold: Option<u32>) {
+        let sid = sid.as_usize();
+        if sid >= self.stream_table.entry_count() {
+            warn!("Fault threshold of out of range sid {:#x}", sid);
+            return;
+        }
+        self.fault_counters.init(self.stream_table.entry_count());
+        self.fault_counters.counter(sid).threshold =
+            threshold.map_or(THRESHOLD_DEFAULT, |t| t.max(1));
+    }
+
+    /// Number of faults counted for `sid` since the last reset.
+    pub fn sid_faults(&self, sid: StreamId) -> u32 {
+        let sid = sid.as_usize();
+        if !self.fault_counters.is_init() || sid >= self.fault_counters.entry_count {
+            return 0;
+        }
+        self.fault_counters.counter(sid).faults
+    }
+
+    /// Clear the fault counts of all the StreamIDs.
+    pub fn reset_fault_counts(&mut self) {
+        if !self.fault_counters.is_init() {
+            return;
+        }
+        for sid in 0..self.fault_counters.entry_count {
+            self.fault_counters.counter(sid).faults = 0;
+        }
+    }
+
+    /// Whether the STE of `sid` aborts its traffic, after [`SMMUv3::quarantine`].
+    pub fn is_quarantined(&self, sid: StreamId) -> bool {
+        let sid = sid.as_usize();
+        sid < self.stream_table.entry_count()
+            && matches!(self.stream_table.ste(sid).config(), SteConfig::Abort)
+    }
+
+    /// Terminate the traffic of `sid` without recording events, until the device is added or
+    /// removed again.
+    ///
+    /// A StreamID attached to an [`IommuDomain`] stays attached, [`SMMUv3::set_domain_root`]
+    /// leaves it aborting and [`SMMUv3::detach_domain`] returns it to bypass.
+    ///
+    /// [`IommuDomain`]: crate::IommuDomain
+    pub fn quarantine(&mut self, sid: StreamId) {
+        let sid = sid.as_usize();
+        if sid >= self.stream_table.entry_count() {
+            warn!("Quarantine of out of range sid {:#x}", sid);
+            return;
+        }
+        let old_vmid = self.stream_table.ste(sid).s2_vmid();
+        self.stream_table.set_abort_ste(sid);
+        self.update_ste(sid, old_vmid);
+        if self.fault_counters.is_init() {
+            self.fault_counters.counter(sid).faults = 0;
+        }
+    }
+
+    /// Count the fault of `event` against its StreamID, and quarantine the stream once it reaches
+    /// its threshold.
+    ///
+    /// Only translation faults are counted, configuration errors such as C_BAD_STE are not the
+    /// device's doing.
+    pub(crate) fn account_fault(&mut self, event: &Event) {
+        let sid = event.sid as usize;
+        if !event.kind.is_translation_fault()
+            || !self.fault_counters.is_init()
+            || sid >= self.fault_counters.entry_count
+        {
+            return;
+        }
+        let Some(threshold) = self.fault_counters.threshold(sid) else {
+            return;
+        };
+        let counter = self.fault_counters.counter(sid);
+        counter.faults = counter.faults.saturating_add(1);
+        let faults = counter.faults;
+        if faults >= threshold && threshold != u32::MAX {
+            self.quarantine(StreamId::new(event.sid));
+            self.fault_handler().on_quarantine(event.sid, faults);
+        }
+    }
+}
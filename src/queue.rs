@@ -1,6 +1,7 @@
-use core::sync::atomic::{Ordering, fence};
+use core::sync::atomic::{fence, Ordering};
 use memory_addr::{align_up_4k, va, VirtAddr, PAGE_SIZE_4K};
 
+use crate::event::{PageRequest, PriResponse};
 use crate::hal::PagingHandler;
 
 /// According to the SMMUv3 spec, Chapter 3. Operation 3.5. Command and Event queues.
@@ -16,15 +17,33 @@ pub const MAX_CMD_EVENT_QS: u32 = 19;
 /// 4.1.1 Command opcodes
 const CMD_PREFETCH_CONFIG: u64 = 0x01;
 const CMD_CFGI_STE: u64 = 0x03;
+const CMD_CFGI_CD: u64 = 0x05;
+const CMD_TLBI_NH_ASID: u64 = 0x11;
+const CMD_TLBI_NH_VA: u64 = 0x12;
+const CMD_TLBI_S12_VMALL: u64 = 0x28;
+const CMD_TLBI_S2_IPA: u64 = 0x2a;
+const CMD_TLBI_NSNH_ALL: u64 = 0x30;
 const CMD_SYNC: u64 = 0x46;
+const CMD_DPTI_ALL: u64 = 0x70;
+const CMD_DPTI_PA: u64 = 0x73;
 
 const CMDQ_ENT_DWORDS: usize = 2;
+/// 7.1 Event records are 32 bytes.
+pub const EVTQ_ENT_DWORDS: usize = 4;
+/// 8.1 PRI queue entries are 16 bytes.
+pub const PRIQ_ENT_DWORDS: usize = 2;
 
 #[derive(Default, Debug, Clone)]
 #[repr(C)]
 pub struct Cmd([u64; CMDQ_ENT_DWORDS]);
 
 impl Cmd {
+    /// A command made of `raw`, to issue commands the driver does not build.
+    #[cfg(test)]
+    pub(crate) fn from_raw(raw: [u64; CMDQ_ENT_DWORDS]) -> Self {
+        Self(raw)
+    }
+
     /// 4.3.1 CMD_CFGI_STE(StreamID, SSec, Leaf)
     ///
     /// Invalidate the STE indicated by StreamID and SSec.
@@ -40,6 +59,55 @@ impl Cmd {
         cmd
     }
 
+    /// 4.3.3 CMD_CFGI_CD(StreamID, SSec, SubstreamID, Leaf)
+    ///
+    /// Invalidate the CD indicated by StreamID and SubstreamID.
+    pub fn cmd_cfgi_cd(stream_id: u32, substream_id: u32) -> Self {
+        const CMD_CFGI_CD_SSID_OFFSET: u64 = 12;
+        const CMD_CFGI_CD_SID_OFFSET: u64 = 32;
+        const CMDQ_CFGI_1_LEAF: u64 = 1;
+
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_CFGI_CD;
+        cmd.0[0] |= ((substream_id & 0xf_ffff) as u64) << CMD_CFGI_CD_SSID_OFFSET;
+        cmd.0[0] |= (stream_id as u64) << CMD_CFGI_CD_SID_OFFSET;
+        cmd.0[1] |= CMDQ_CFGI_1_LEAF;
+        cmd
+    }
+
+    /// 4.4.2 CMD_TLBI_NH_ASID(VMID, ASID)
+    ///
+    /// Invalidate all stage 1 TLB entries of the ASID, for stage 1 only streams VMID is 0.
+    pub fn cmd_tlbi_nh_asid(vmid: u16, asid: u16) -> Self {
+        const CMD_TLBI_VMID_OFFSET: u64 = 32;
+        const CMD_TLBI_ASID_OFFSET: u64 = 48;
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_TLBI_NH_ASID;
+        cmd.0[0] |= (vmid as u64) << CMD_TLBI_VMID_OFFSET;
+        cmd.0[0] |= (asid as u64) << CMD_TLBI_ASID_OFFSET;
+        cmd
+    }
+
+    /// 4.4.1 CMD_TLBI_NH_VA(VMID, ASID, Address, Leaf)
+    ///
+    /// Invalidate stage 1 TLB entries of the ASID for the VA. When `leaf` is false,
+    /// cached intermediate table entries for the address are invalidated too.
+    pub fn cmd_tlbi_nh_va(vmid: u16, asid: u16, va: u64, leaf: bool) -> Self {
+        const CMD_TLBI_VMID_OFFSET: u64 = 32;
+        const CMD_TLBI_ASID_OFFSET: u64 = 48;
+        const CMD_TLBI_1_LEAF: u64 = 1;
+        const CMD_TLBI_1_VA_MASK: u64 = !((1 << 12) - 1);
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_TLBI_NH_VA;
+        cmd.0[0] |= (vmid as u64) << CMD_TLBI_VMID_OFFSET;
+        cmd.0[0] |= (asid as u64) << CMD_TLBI_ASID_OFFSET;
+        cmd.0[1] |= va & CMD_TLBI_1_VA_MASK;
+        if leaf {
+            cmd.0[1] |= CMD_TLBI_1_LEAF;
+        }
+        cmd
+    }
+
     /// 4.7.3 CMD_SYNC(ComplSignal, MSIAddress, MSIData, MSIWriteAttributes)
     ///
     /// This command provides a synchronization mechanism for the following:
@@ -52,10 +120,39 @@ impl Cmd {
         cmd
     }
 
+    /// 4.4.3 CMD_TLBI_S12_VMALL(VMID)
+    ///
+    /// Invalidate all stage 1 and stage 2 TLB entries tagged with the given VMID.
+    pub fn cmd_tlbi_s12_vmall(vmid: u16) -> Self {
+        const CMD_TLBI_VMID_OFFSET: u64 = 32;
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_TLBI_S12_VMALL;
+        cmd.0[0] |= (vmid as u64) << CMD_TLBI_VMID_OFFSET;
+        cmd
+    }
+
+    /// 4.4.4 CMD_TLBI_S2_IPA(VMID, Address, Leaf)
+    ///
+    /// Invalidate stage 2 TLB entries of the given VMID for the IPA. When `leaf` is false,
+    /// cached intermediate table entries for the address are invalidated too.
+    pub fn cmd_tlbi_s2_ipa(vmid: u16, ipa: u64, leaf: bool) -> Self {
+        const CMD_TLBI_VMID_OFFSET: u64 = 32;
+        const CMD_TLBI_1_LEAF: u64 = 1;
+        const CMD_TLBI_1_IPA_MASK: u64 = ((1 << 52) - 1) & !((1 << 12) - 1);
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_TLBI_S2_IPA;
+        cmd.0[0] |= (vmid as u64) << CMD_TLBI_VMID_OFFSET;
+        cmd.0[1] |= ipa & CMD_TLBI_1_IPA_MASK;
+        if leaf {
+            cmd.0[1] |= CMD_TLBI_1_LEAF;
+        }
+        cmd
+    }
+
     pub fn cmd_prefetch_config(stream_id: u32) -> Self {
         const CMD_PREFETCH_CONFIG_SID_OFFSET: u64 = 32;
         let mut cmd = Self::default();
-        cmd.0[0] |= CMD_PREFETCH_CONFIG; 
+        cmd.0[0] |= CMD_PREFETCH_CONFIG;
         cmd.0[0] |= (stream_id as u64) << CMD_PREFETCH_CONFIG_SID_OFFSET;
         cmd
     }
@@ -67,13 +164,62 @@ impl Cmd {
         cmd.0[1] = 31;
         cmd
     }
+
+    /// CMD_TLBI_NSNH_ALL
+    ///
+    /// Invalidate all the Non-secure TLB entries, of all VMIDs and ASIDs.
+    pub fn cmd_tlbi_nsnh_all() -> Self {
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_TLBI_NSNH_ALL;
+        cmd
+    }
+
+    /// CMD_DPTI_ALL
+    ///
+    /// Invalidate all the Device Permission Table entries cached by the SMMU.
+    pub fn cmd_dpti_all() -> Self {
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_DPTI_ALL;
+        cmd
+    }
+
+    /// CMD_DPTI_PA(Address)
+    ///
+    /// Invalidate the cached Device Permission Table entries of the granule at `pa`.
+    pub fn cmd_dpti_pa(pa: u64) -> Self {
+        const CMD_DPTI_1_PA_MASK: u64 = ((1 << 52) - 1) & !((1 << 12) - 1);
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_DPTI_PA;
+        cmd.0[1] |= pa & CMD_DPTI_1_PA_MASK;
+        cmd
+    }
+
+    /// 4.7.1 CMD_PRI_RESP(StreamID, SSV, SubstreamID, PRGIndex, Resp)
+    ///
+    /// Respond to the Page Request Group `req` belongs to.
+    pub fn cmd_pri_resp(req: &PageRequest, resp: PriResponse) -> Self {
+        const CMD_PRI_RESP: u64 = 0x41;
+        const CMD_PRI_0_SSV: u64 = 1 << 11;
+        const CMD_PRI_0_SSID_OFFSET: u64 = 12;
+        const CMD_PRI_0_SID_OFFSET: u64 = 32;
+        const CMD_PRI_1_RESP_OFFSET: u64 = 12;
+        let mut cmd = Self::default();
+        cmd.0[0] |= CMD_PRI_RESP;
+        cmd.0[0] |= (req.sid as u64) << CMD_PRI_0_SID_OFFSET;
+        if let Some(ssid) = req.ssid {
+            cmd.0[0] |= CMD_PRI_0_SSV | ((ssid & 0xf_ffff) as u64) << CMD_PRI_0_SSID_OFFSET;
+        }
+        cmd.0[1] |= (req.prg_index & 0x1ff) as u64;
+        cmd.0[1] |= (resp as u64) << CMD_PRI_1_RESP_OFFSET;
+        cmd
+    }
 }
 
 /// 3.5 Command and Event queues
 pub struct Queue<H: PagingHandler> {
     base: VirtAddr,
     queue_size: u32,
-    qs: u32,//log2(queue_size),
+    qs: u32, //log2(queue_size),
     prod: u32,
     cons: u32,
     _marker: core::marker::PhantomData<H>,
@@ -91,21 +237,26 @@ impl<H: PagingHandler> Queue<H> {
         }
     }
 
-    pub fn init(&mut self, qs: u32) {
-        assert_eq!(size_of::<Cmd>(), CMDQ_ENT_DWORDS << 3);
+    /// Set up the queue for event records.
+    pub fn init_event(&mut self, qs: u32) {
+        self.init_entries(qs, EVTQ_ENT_DWORDS << 3);
+    }
 
+    /// Set up the queue for page requests.
+    pub fn init_pri(&mut self, qs: u32) {
+        self.init_entries(qs, PRIQ_ENT_DWORDS << 3);
+    }
+
+    fn init_entries(&mut self, qs: u32, entry_size: usize) {
         let qs = u32::min(qs, MAX_CMD_EVENT_QS);
         self.qs = qs;
         self.queue_size = 1 << qs;
 
-        let num_pages = align_up_4k(self.queue_size as usize * size_of::<Cmd>()) / PAGE_SIZE_4K;
+        let num_pages = align_up_4k(self.queue_size as usize * entry_size) / PAGE_SIZE_4K;
         self.base = H::phys_to_virt(H::alloc_pages(num_pages).expect("Failed to allocate queue"));
         debug!(
             "Queue base address: {:?}, size: {}, qs: {}, num_pages: {}",
-            self.base,
-            self.queue_size,
-            self.qs,
-            num_pages
+            self.base, self.queue_size, self.qs, num_pages
         );
     }
 
@@ -121,13 +272,12 @@ impl<H: PagingHandler> Queue<H> {
         self.cons
     }
 
-    pub fn set_cons_value(&mut self, cons: u32) {
-        //Bit [QS]: WR_WRAP - Command queue write index wrap flag.
-        //Bits [QS-1:0]: WR - Command queue write index
-        if cons >= 1 << (self.qs + 1) {
-            panic!("cons value {} exceeds queue size {}", cons, self.queue_size);
+    /// Update the write index of a queue produced by the SMMU.
+    pub fn set_prod_value(&mut self, prod: u32) {
+        if prod >= 1 << (self.qs + 1) {
+            panic!("prod value {} exceeds queue size {}", prod, self.queue_size);
         }
-        self.cons = cons;
+        self.prod = prod;
     }
 
     fn prod_wr_wrap(&self) -> bool {
@@ -146,51 +296,24 @@ impl<H: PagingHandler> Queue<H> {
         self.cons & (self.queue_size - 1)
     }
 
-    fn inc_proc_wq(&mut self) {
-        let mut current_proc_wq = self.prod_wr();
-        let mut current_proc_wrap = self.prod_wr_wrap();
-        current_proc_wq += 1;
-        // Check overflow, update wrap bit.
-        if (current_proc_wq & (self.queue_size - 1)) == 0 {
-            current_proc_wq %= self.queue_size;
-            current_proc_wrap = !current_proc_wrap;
-        }
-
-        assert!(current_proc_wq & !((1 << self.qs) - 1) == 0);
-        let current_proc_wrap_bit = if current_proc_wrap {
-            1 << self.qs
-        } else {
-            0
-        };
-
-        self.prod = current_proc_wrap_bit | current_proc_wq;
-    }
-
-    pub fn full(&self) -> bool {
-        // PROD.WR == CONS.RD and PROD.WR_WRAP != CONS.RD_WRAP,
-        // representing a full queue.
-        self.prod_wr() == self.cons_rd() && self.prod_wr_wrap() != self.cons_rd_wrap()
-    }
-
     pub fn empty(&self) -> bool {
         // PROD.WR == CONS.RD and PROD.WR_WRAP == CONS.RD_WRAP,
         // representing an empty queue.
         self.prod_wr() == self.cons_rd() && self.prod_wr_wrap() == self.cons_rd_wrap()
     }
 
-    pub fn cmd_insert(&mut self, cmd: Cmd) {
-        let idx = self.prod_wr() as usize;
-        let base = self.base.as_mut_ptr() as *mut Cmd;
-        let cmdq_addr = unsafe { base.add(idx) };
-
-        unsafe {
-            cmdq_addr.write(cmd);
+    /// Remove the entry at the read index of a queue produced by the SMMU, `N` being the entry
+    /// size in double words.
+    pub fn entry_pop<const N: usize>(&mut self) -> Option<[u64; N]> {
+        if self.empty() {
+            return None;
         }
-        fence(Ordering::Release);//强制保序，强度较弱，可更换成mbarrier库
-
-        H::flush(cmdq_addr as usize, size_of::<Cmd>());
-        
-        self.inc_proc_wq();
+        // Entries are written before the SMMU updates PROD.
+        fence(Ordering::Acquire);
+        let base = self.base.as_ptr() as *const [u64; N];
+        let entry = unsafe { base.add(self.cons_rd() as usize).read_volatile() };
+        self.cons = (self.cons + 1) & ((2 << self.qs) - 1);
+        Some(entry)
     }
 }
 
@@ -210,7 +333,7 @@ mod test {
 
         fn alloc_pages(pages: usize) -> Option<PhysAddr> {
             assert!(pages == 1);
-            Some(pa!(unsafe { DUMMY_PAGE.as_mut_ptr() } as usize))
+            Some(pa!(core::ptr::addr_of_mut!(DUMMY_PAGE) as usize))
         }
 
         fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
@@ -218,7 +341,7 @@ mod test {
         }
 
         fn dealloc_pages(paddr: PhysAddr, _num_pages: usize) {
-            assert!(paddr == pa!(unsafe { DUMMY_PAGE.as_mut_ptr() } as usize));
+            assert!(paddr == pa!(core::ptr::addr_of_mut!(DUMMY_PAGE) as usize));
         }
 
         fn flush(start: usize, len: usize) {
@@ -227,13 +350,14 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_queue() {
         let mut queue = Queue::<DummyPagingHandler>::uninit();
-        queue.init(7);
+        queue.init_event(7);
 
         assert_eq!(
             queue.base_addr(),
-            va!(unsafe { DUMMY_PAGE.as_mut_ptr() } as usize)
+            va!(core::ptr::addr_of_mut!(DUMMY_PAGE) as usize)
         );
         assert_eq!(queue.prod_value(), 0);
         assert_eq!(queue.cons_value(), 0);
@@ -242,25 +366,16 @@ mod test {
         assert_eq!(queue.cons_rd(), 0);
         assert_eq!(queue.cons_rd_wrap(), false);
 
-        assert_eq!(queue.full(), false);
         assert_eq!(queue.empty(), true);
 
-        for i in 0..64 {
-            queue.cmd_insert(crate::queue::Cmd::cmd_cfgi_ste(i));
-        }
-
-        assert_eq!(queue.full(), false);
+        queue.set_prod_value(64);
         assert_eq!(queue.empty(), false);
         assert_eq!(queue.prod_wr(), 64);
         assert_eq!(queue.prod_wr_wrap(), false);
         assert_eq!(queue.cons_rd(), 0);
         assert_eq!(queue.cons_rd_wrap(), false);
 
-        for i in 64..128 {
-            queue.cmd_insert(crate::queue::Cmd::cmd_cfgi_ste(i));
-        }
-
-        assert_eq!(queue.full(), true);
+        queue.set_prod_value(1 << 7);
         assert_eq!(queue.empty(), false);
         assert_eq!(queue.prod_wr(), 0);
         assert_eq!(queue.prod_wr_wrap(), true);
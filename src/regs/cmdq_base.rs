@@ -115,4 +115,36 @@ register_bitfields! {u64,
 /// Access attributes of the event queue are set using the SMMU_CR1.QUEUE_* fields. A Read-Allocate hint is provided for event queue accesses with the WA field.
 ///
 /// SMMU_EVENTQ_BASE is Guarded by SMMU_CR0.EVENTQEN and must only be modified when SMMU_CR0.EVENTQEN == 0
-pub type EventQBaseReg = ReadWrite<u64, EVENTQ_BASE::Register>;
\ No newline at end of file
+pub type EventQBaseReg = ReadWrite<u64, EVENTQ_BASE::Register>;
+register_bitfields! {u64,
+    /// SMMU_PRIQ_BASE fields.
+    pub PRIQ_BASE [
+        /// Bit [63] Reserved, RES0.
+        Reserved63 OFFSET(63) NUMBITS(1) [],
+        /// WA, bit [62] Write-Allocate hint.
+        ///
+        /// - 0b0 No Write-Allocate.
+        /// - 0b1 Write-Allocate.
+        WA OFFSET(62) NUMBITS(1) [
+            /// No Write-Allocate.
+            NoWriteAllocate = 0,
+            /// Write-Allocate.
+            WriteAllocate = 1
+        ],
+        /// Bits [61:56] Reserved, RES0.
+        Reserved56 OFFSET(56) NUMBITS(6) [],
+        /// ADDR, bits [55:5] PA of PRI queue base, bits [55:5].
+        ///
+        /// Aligned like SMMU_EVENTQ_BASE.ADDR.
+        ADDR OFFSET(5) NUMBITS(51) [],
+        /// LOG2SIZE, bits [4:0] Queue size as log2(entries).
+        ///
+        /// LOG2SIZE must be less than or equal to SMMU_IDR1.PRIQS.
+        LOG2SIZE OFFSET(0) NUMBITS(5) []
+    ]
+}
+
+/// SMMU_PRIQ_BASE, present when SMMU_IDR0.PRI == 1.
+///
+/// Initialized like SMMU_EVENTQ_BASE, and Guarded by SMMU_CR0.PRIQEN.
+pub type PriQBaseReg = ReadWrite<u64, PRIQ_BASE::Register>;
@@ -49,7 +49,6 @@ register_bitfields! {u32,
 /// When SMMU_CMDQ_BASE.LOG2SIZE is increased within its valid range, the value of the bits of this register that were previously above the old wrap flag position are UNKNOWN and when it is decreased, the value of the bits from the wrap flag downward are the effective truncation of the value in the old field.
 pub type CmdQConsReg = ReadWrite<u32, CMDQ_CONS::Register>;
 
-
 register_bitfields! {u32,
     pub EVENTQ_CONS [
         /// Bit [31] OVACKFLG.
@@ -69,3 +68,19 @@ register_bitfields! {u32,
 }
 
 pub type EventQConsReg = ReadWrite<u32, EVENTQ_CONS::Register>;
+
+register_bitfields! {u32,
+    /// SMMU_PRIQ_CONS fields.
+    pub PRIQ_CONS [
+        /// Bit [31] OVACKFLG, overflow acknowledge flag.
+        OVACKFLG OFFSET(31) NUMBITS(1) [],
+        /// Bits [30:20] Reserved, RES0.
+        Reserved20 OFFSET(20) NUMBITS(11) [],
+        /// RD, bits [19:0]
+        /// PRI queue read index, with the wrap flag at bit [QS].
+        RD OFFSET(0) NUMBITS(20) []
+    ]
+}
+
+/// SMMU_PRIQ_CONS, the PRI queue read index, written by software.
+pub type PriQConsReg = ReadWrite<u32, PRIQ_CONS::Register>;
@@ -48,7 +48,6 @@ register_bitfields! {u32,
 /// `SMMU_CMDQ_CONS.RD != SMMU_CMDQ_PROD.WR || SMMU_CMDQ_CONS.RD_WRAP == SMMU_CMDQ_PROD.WR_WRAP`
 pub type CmdQProdReg = ReadWrite<u32, CMDQ_PROD::Register>;
 
-
 register_bitfields! {u32,
     pub EVENTQ_PROD [
         /// OVSLG, bit [31] Overflow flag.
@@ -69,4 +68,19 @@ register_bitfields! {u32,
     ]
 }
 
-pub type EventQProdReg = ReadWrite<u32, EVENTQ_PROD::Register>;
\ No newline at end of file
+pub type EventQProdReg = ReadWrite<u32, EVENTQ_PROD::Register>;
+register_bitfields! {u32,
+    /// SMMU_PRIQ_PROD fields.
+    pub PRIQ_PROD [
+        /// OVFLG, bit [31] Overflow flag, toggled when page requests are discarded.
+        OVFLG OFFSET(31) NUMBITS(1) [],
+        /// Bits [30:20] Reserved, RES0.
+        Reserved20 OFFSET(20) NUMBITS(11) [],
+        /// WR, bits [19:0]
+        /// PRI queue write index, with the wrap flag at bit [QS].
+        WR OFFSET(0) NUMBITS(20) []
+    ]
+}
+
+/// SMMU_PRIQ_PROD, the PRI queue write index, updated by the SMMU.
+pub type PriQProdReg = ReadWrite<u32, PRIQ_PROD::Register>;
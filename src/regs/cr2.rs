@@ -1,5 +1,3 @@
-
-
 use tock_registers::register_bitfields;
 use tock_registers::registers::ReadWrite;
 
@@ -7,7 +5,7 @@ register_bitfields! {u32,
     pub CR2 [
         /// Bits [31:12] Reserved, RES0.
         Reserved12 OFFSET(4) NUMBITS(27) [],
-  
+
         VALID OFFSET(0) NUMBITS(4) [
             defaul = 0b0111,
         ],
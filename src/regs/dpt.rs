@@ -0,0 +1,76 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! SMMU_DPT_BASE, SMMU_DPT_BASE_CFG
+//!
+//! ## Purpose
+//! Base address and configuration of the Non-secure Device Permission Table, present when
+//! SMMU_IDR3.DPT == 1. Only written while SMMU_CR0.DPT_WALK_EN is 0.
+//!
+//! ## Attributes
+//! SMMU_DPT_BASE is a 64-bit register, SMMU_DPT_BASE_CFG is a 32-bit register.
+//!
+//! These registers are part of the SMMUv3_PAGE_0 block.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::ReadWrite;
+
+register_bitfields! {u64,
+    /// SMMU_DPT_BASE fields.
+    pub DPT_BASE [
+        /// Bits [63:52] Reserved, RES0.
+        Reserved52 OFFSET(52) NUMBITS(12) [],
+        /// ADDR, bits [51:12]
+        ///
+        /// Physical address of the level 0 DPT, aligned to its size.
+        ADDR OFFSET(12) NUMBITS(40) [],
+        /// Bits [11:0] Reserved, RES0.
+        Reserved0 OFFSET(0) NUMBITS(12) []
+    ]
+}
+
+register_bitfields! {u32,
+    /// SMMU_DPT_BASE_CFG fields.
+    pub DPT_BASE_CFG [
+        /// Bits [31:24] Reserved, RES0.
+        Reserved24 OFFSET(24) NUMBITS(8) [],
+        /// L0DPTSZ, bits [23:20]
+        ///
+        /// Size of the memory covered by a level 0 descriptor.
+        L0DPTSZ OFFSET(20) NUMBITS(4) [
+            /// 1GB.
+            Size1GB = 0b0000,
+            /// 16GB.
+            Size16GB = 0b0100,
+            /// 64GB.
+            Size64GB = 0b0110,
+            /// 512GB.
+            Size512GB = 0b1001
+        ],
+        /// Bits [19:16] Reserved, RES0.
+        Reserved16 OFFSET(16) NUMBITS(4) [],
+        /// DPTGS, bits [15:14]
+        ///
+        /// Size of the granules of the level 1 tables.
+        DPTGS OFFSET(14) NUMBITS(2) [
+            /// 4KB.
+            Granule4KB = 0b00,
+            /// 64KB.
+            Granule64KB = 0b01,
+            /// 16KB.
+            Granule16KB = 0b10
+        ],
+        /// Bits [13:3] Reserved, RES0.
+        Reserved3 OFFSET(3) NUMBITS(11) [],
+        /// DPTPS, bits [2:0]
+        ///
+        /// Size of the physical address space protected by the DPT, with the encoding of
+        /// SMMU_IDR5.OAS: 32, 36, 40, 42, 44, 48 and 52 bits.
+        DPTPS OFFSET(0) NUMBITS(3) []
+    ]
+}
+
+/// DPT_BASE Register, read-write.
+pub type DptBaseReg = ReadWrite<u64, DPT_BASE::Register>;
+
+/// DPT_BASE_CFG Register, read-write.
+pub type DptBaseCfgReg = ReadWrite<u32, DPT_BASE_CFG::Register>;
@@ -0,0 +1,102 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! `SMMU_CMDQ_CONTROL_PAGE_BASE<n>`, `SMMU_CMDQ_CONTROL_PAGE_CFG<n>`,
+//! `SMMU_CMDQ_CONTROL_PAGE_STATUS<n>`, `SMMU_ECMDQ_PROD<n>`, `SMMU_ECMDQ_CONS<n>`
+//!
+//! ## Purpose
+//! Enhanced Command queues, present when SMMU_IDR1.ECMDQ == 1. The queues are grouped in Command
+//! queue control pages, each page being enabled through the SMMU_CMDQ_CONTROL_PAGE_* registers of
+//! page 0 at `0x4000 + 32 * n`. Each queue has its own SMMU_ECMDQ_BASE, with the SMMU_CMDQ_BASE
+//! layout, SMMU_ECMDQ_PROD and SMMU_ECMDQ_CONS registers in its control page.
+//!
+//! ## Attributes
+//! `SMMU_CMDQ_CONTROL_PAGE_BASE<n>` and `SMMU_ECMDQ_BASE<n>` are 64-bit registers, the others
+//! are 32-bit registers.
+
+use tock_registers::register_bitfields;
+
+register_bitfields! {u64,
+    /// `SMMU_CMDQ_CONTROL_PAGE_BASE<n>` fields.
+    pub CMDQ_CONTROL_PAGE_BASE [
+        /// Bits [63:52] Reserved, RES0.
+        Reserved52 OFFSET(52) NUMBITS(12) [],
+        /// ADDR, bits [51:16]
+        ///
+        /// Physical address of the 64KB control page, in the SMMU register space.
+        ADDR OFFSET(16) NUMBITS(36) [],
+        /// Bits [15:0] Reserved, RES0.
+        Reserved0 OFFSET(0) NUMBITS(16) []
+    ]
+}
+
+register_bitfields! {u32,
+    /// `SMMU_CMDQ_CONTROL_PAGE_CFG<n>` fields.
+    pub CMDQ_CONTROL_PAGE_CFG [
+        /// Bits [31:1] Reserved, RES0.
+        Reserved1 OFFSET(1) NUMBITS(31) [],
+        /// EN, bit [0]
+        ///
+        /// Enable of the Enhanced Command queues of the control page.
+        EN OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+register_bitfields! {u32,
+    /// `SMMU_CMDQ_CONTROL_PAGE_STATUS<n>` fields.
+    pub CMDQ_CONTROL_PAGE_STATUS [
+        /// Bits [31:1] Reserved, RES0.
+        Reserved1 OFFSET(1) NUMBITS(31) [],
+        /// ENACK, bit [0]
+        ///
+        /// Acknowledgment of SMMU_CMDQ_CONTROL_PAGE_CFG.EN.
+        ENACK OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+register_bitfields! {u32,
+    /// `SMMU_ECMDQ_PROD<n>` fields.
+    pub ECMDQ_PROD [
+        /// EN, bit [31]
+        ///
+        /// Queue enable, acknowledged in SMMU_ECMDQ_CONS.ENACK.
+        EN OFFSET(31) NUMBITS(1) [],
+        /// Bits [30:24] Reserved, RES0.
+        Reserved24 OFFSET(24) NUMBITS(7) [],
+        /// ERRACK, bit [23]
+        ///
+        /// Error acknowledgment, the queue resumes once it equals SMMU_ECMDQ_CONS.ERR.
+        ERRACK OFFSET(23) NUMBITS(1) [],
+        /// Bits [22:20] Reserved, RES0.
+        Reserved20 OFFSET(20) NUMBITS(3) [],
+        /// WR, bits [19:0]
+        ///
+        /// Queue write index, with the wrap flag at bit [QS].
+        WR OFFSET(0) NUMBITS(20) []
+    ]
+}
+
+register_bitfields! {u32,
+    /// `SMMU_ECMDQ_CONS<n>` fields.
+    pub ECMDQ_CONS [
+        /// ENACK, bit [31]
+        ///
+        /// The queue is enabled.
+        ENACK OFFSET(31) NUMBITS(1) [],
+        /// Bits [30:27] Reserved, RES0.
+        Reserved27 OFFSET(27) NUMBITS(4) [],
+        /// ERR_REASON, bits [26:24]
+        ///
+        /// Reason of the last command error, a CMDQ_CONS.ERR code.
+        ERR_REASON OFFSET(24) NUMBITS(3) [],
+        /// ERR, bit [23]
+        ///
+        /// Toggled when the queue stops on a command error.
+        ERR OFFSET(23) NUMBITS(1) [],
+        /// Bits [22:20] Reserved, RES0.
+        Reserved20 OFFSET(20) NUMBITS(3) [],
+        /// RD, bits [19:0]
+        ///
+        /// Queue read index, with the wrap flag at bit [QS]. Writable while the queue is disabled.
+        RD OFFSET(0) NUMBITS(20) []
+    ]
+}
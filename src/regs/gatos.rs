@@ -0,0 +1,161 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! 6.3.36 SMMU_GATOS_CTRL, 6.3.37 SMMU_GATOS_SID, 6.3.38 SMMU_GATOS_ADDR, 6.3.39 SMMU_GATOS_PAR
+//!
+//! ## Purpose
+//! Global Address Translation Operations (ATOS) interface: software writes a StreamID, an input
+//! address and the kind of lookup, sets SMMU_GATOS_CTRL.RUN, and reads the result from
+//! SMMU_GATOS_PAR once the SMMU clears RUN.
+//!
+//! ## Attributes
+//! SMMU_GATOS_CTRL is a 32-bit register, SMMU_GATOS_SID, SMMU_GATOS_ADDR and SMMU_GATOS_PAR are
+//! 64-bit registers.
+//! These registers are part of the SMMUv3_PAGE_0 block, and are Reserved when SMMU_IDR0.ATOS == 0.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::ReadWrite;
+
+register_bitfields! {u32,
+    /// SMMU_GATOS_CTRL fields.
+    pub GATOS_CTRL [
+        /// Bits [31:1] Reserved, RES0.
+        Reserved1 OFFSET(1) NUMBITS(31) [],
+        /// RUN, bit [0]
+        ///
+        /// - Software writes 1 to request a translation, the SMMU clears it once SMMU_GATOS_PAR holds
+        ///   the result.
+        /// - Writes of 0 are IGNORED, and the SMMU_GATOS_{SID, ADDR} registers must not be written
+        ///   while RUN is 1.
+        ///
+        /// The reset behavior of this field is:
+        /// - This field resets to 0b0.
+        RUN OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+register_bitfields! {u64,
+    /// SMMU_GATOS_SID fields.
+    pub GATOS_SID [
+        /// Bits [63:53] Reserved, RES0.
+        Reserved53 OFFSET(53) NUMBITS(11) [],
+        /// SSID_VALID, bit [52]
+        ///
+        /// - 0b0 The request has no SubstreamID.
+        /// - 0b1 The request carries SUBSTREAMID.
+        SSID_VALID OFFSET(52) NUMBITS(1) [],
+        /// SUBSTREAMID, bits [51:32]
+        ///
+        /// SubstreamID of the request, bits above SMMU_IDR1.SSIDSIZE are RES0.
+        SUBSTREAMID OFFSET(32) NUMBITS(20) [],
+        /// STREAMID, bits [31:0]
+        ///
+        /// StreamID of the request, bits above SMMU_IDR1.SIDSIZE are RES0.
+        STREAMID OFFSET(0) NUMBITS(32) []
+    ]
+}
+
+register_bitfields! {u64,
+    /// SMMU_GATOS_ADDR fields.
+    pub GATOS_ADDR [
+        /// ADDR, bits [63:12]
+        ///
+        /// Input address of the request, VA for stage 1 lookups and IPA for stage 2 only lookups.
+        ADDR OFFSET(12) NUMBITS(52) [],
+        /// TYPE, bits [11:10]
+        ///
+        /// - 0b00 Reserved, the request completes with a fault.
+        /// - 0b01 Stage 1 lookup, the result is an IPA when stage 2 is enabled.
+        /// - 0b10 Stage 2 lookup of an IPA.
+        /// - 0b11 Stage 1 and stage 2 lookup.
+        TYPE OFFSET(10) NUMBITS(2) [
+            /// Stage 1 lookup.
+            Stage1 = 0b01,
+            /// Stage 2 lookup of an IPA.
+            Stage2 = 0b10,
+            /// Stage 1 and stage 2 lookup.
+            Stage12 = 0b11
+        ],
+        /// PnU, bit [9]
+        ///
+        /// - 0b0 Unprivileged request.
+        /// - 0b1 Privileged request.
+        PnU OFFSET(9) NUMBITS(1) [],
+        /// RnW, bit [8]
+        ///
+        /// - 0b0 Write request.
+        /// - 0b1 Read request.
+        RnW OFFSET(8) NUMBITS(1) [],
+        /// InD, bit [7]
+        ///
+        /// - 0b0 Data request.
+        /// - 0b1 Instruction request, only valid with RnW == 1.
+        InD OFFSET(7) NUMBITS(1) [],
+        /// HTTUI, bit [6]
+        ///
+        /// - 0b0 The lookup may update the Access flag and Dirty state of the descriptors.
+        /// - 0b1 The lookup does not update the descriptors.
+        HTTUI OFFSET(6) NUMBITS(1) [],
+        /// Bits [5:0] Reserved, RES0.
+        Reserved0 OFFSET(0) NUMBITS(6) []
+    ]
+}
+
+register_bitfields! {u64,
+    /// SMMU_GATOS_PAR fields.
+    pub GATOS_PAR [
+        /// ATTR, bits [63:56], when FAULT == 0
+        ///
+        /// Memory attributes of the translation, in MAIR_ELx format.
+        ATTR OFFSET(56) NUMBITS(8) [],
+        /// ADDR, bits [51:12], when FAULT == 0
+        ///
+        /// Output address of the translation. When SIZE == 1 the lowest bits of ADDR are set to
+        /// encode the translation size, see SIZE.
+        ADDR OFFSET(12) NUMBITS(40) [],
+        /// REASON, bits [13:12], when FAULT == 1
+        ///
+        /// - 0b00 Stage 1 fault, or a fault not attributed to a stage of translation.
+        /// - 0b01 Stage 2 fault on the fetch of a CD.
+        /// - 0b10 Stage 2 fault on a stage 1 translation table walk.
+        /// - 0b11 Stage 2 fault on the translation of the stage 1 output.
+        REASON OFFSET(12) NUMBITS(2) [
+            /// Stage 1 fault, or a fault not attributed to a stage.
+            Stage1 = 0b00,
+            /// Stage 2 fault on the fetch of a CD.
+            Stage2CdFetch = 0b01,
+            /// Stage 2 fault on a stage 1 table walk.
+            Stage2Walk = 0b10,
+            /// Stage 2 fault on the stage 1 output.
+            Stage2 = 0b11
+        ],
+        /// SIZE, bit [11], when FAULT == 0
+        ///
+        /// - 0b0 The translation covers 4KB.
+        /// - 0b1 The translation covers 2^(N + 13) bytes, N being the position of the lowest 0 bit
+        ///   of ADDR, all bits of ADDR below it read as 1.
+        SIZE OFFSET(11) NUMBITS(1) [],
+        /// FAULTCODE, bits [11:4], when FAULT == 1
+        ///
+        /// Event type number of the fault that terminated the lookup, for example 0x10 for
+        /// F_TRANSLATION.
+        FAULTCODE OFFSET(4) NUMBITS(8) [],
+        /// SH, bits [9:8], when FAULT == 0
+        ///
+        /// Shareability of the translation.
+        SH OFFSET(8) NUMBITS(2) [],
+        /// FAULT, bit [0]
+        ///
+        /// - 0b0 The lookup succeeded.
+        /// - 0b1 The lookup faulted.
+        FAULT OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+/// SMMU Global ATOS control register, Read-Write.
+pub type GatosCtrlReg = ReadWrite<u32, GATOS_CTRL::Register>;
+/// SMMU Global ATOS StreamID register, Read-Write.
+pub type GatosSidReg = ReadWrite<u64, GATOS_SID::Register>;
+/// SMMU Global ATOS input address register, Read-Write.
+pub type GatosAddrReg = ReadWrite<u64, GATOS_ADDR::Register>;
+/// SMMU Global ATOS Physical Address Register, Read-Write.
+pub type GatosParReg = ReadWrite<u64, GATOS_PAR::Register>;
@@ -0,0 +1,67 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! 6.3.14 SMMU_GBPA
+//!
+//! ## Purpose
+//! Global ByPass Attribute register, giving the attributes of the transactions bypassing the SMMU
+//! while SMMU_CR0.SMMUEN == 0, or aborting them.
+//!
+//! ## Attributes
+//! SMMU_GBPA is a 32-bit register.
+//! This register is part of the SMMUv3_PAGE_0 block, its Secure counterpart being SMMU_S_GBPA.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::ReadWrite;
+
+register_bitfields! {u32,
+    /// SMMU_GBPA fields.
+    pub GBPA [
+        /// Update, bit [31]
+        ///
+        /// - Software writes 1 together with the new values of the other fields, the SMMU clears
+        ///   it once the update completed.
+        /// - The other fields must not be written while Update is 1.
+        ///
+        /// The reset behavior of this field is:
+        /// - This field resets to 0b0.
+        UPDATE OFFSET(31) NUMBITS(1) [],
+        /// Bits [30:21] Reserved, RES0.
+        Reserved21 OFFSET(21) NUMBITS(10) [],
+        /// ABORT, bit [20]
+        ///
+        /// - 0b0 Transactions bypass the SMMU with the attributes of this register.
+        /// - 0b1 Transactions are aborted.
+        ABORT OFFSET(20) NUMBITS(1) [],
+        /// INSTCFG, bits [19:18]
+        ///
+        /// Instruction/data override of bypassing transactions.
+        INSTCFG OFFSET(18) NUMBITS(2) [],
+        /// PRIVCFG, bits [17:16]
+        ///
+        /// User/privileged override of bypassing transactions.
+        PRIVCFG OFFSET(16) NUMBITS(2) [],
+        /// Bits [15:14] Reserved, RES0.
+        Reserved14 OFFSET(14) NUMBITS(2) [],
+        /// SHCFG, bits [13:12]
+        ///
+        /// Shareability override of bypassing transactions.
+        SHCFG OFFSET(12) NUMBITS(2) [],
+        /// ALLOCCFG, bits [11:8]
+        ///
+        /// Allocation hints override of bypassing transactions.
+        ALLOCCFG OFFSET(8) NUMBITS(4) [],
+        /// Bits [7:5] Reserved, RES0.
+        Reserved5 OFFSET(5) NUMBITS(3) [],
+        /// MTCFG, bit [4]
+        ///
+        /// Whether MemAttr replaces the memory type of bypassing transactions.
+        MTCFG OFFSET(4) NUMBITS(1) [],
+        /// MemAttr, bits [3:0]
+        ///
+        /// Memory type of bypassing transactions when MTCFG == 1.
+        MEMATTR OFFSET(0) NUMBITS(4) []
+    ]
+}
+
+/// GBPA Register, read-write.
+pub type GbpaReg = ReadWrite<u32, GBPA::Register>;
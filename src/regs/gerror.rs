@@ -0,0 +1,71 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! 6.3.18 SMMU_GERROR, 6.3.19 SMMU_GERRORN
+//!
+//! ## Purpose
+//! Global error status. An error is active while its SMMU_GERROR bit differs from the same bit
+//! of SMMU_GERRORN: the SMMU toggles SMMU_GERROR to activate an error, and software toggles
+//! SMMU_GERRORN to acknowledge it.
+//!
+//! ## Attributes
+//! SMMU_GERROR and SMMU_GERRORN are 32-bit registers.
+//! These registers are part of the SMMUv3_PAGE_0 block.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::{ReadOnly, ReadWrite};
+
+register_bitfields! {u32,
+    /// SMMU_GERROR and SMMU_GERRORN fields.
+    pub GERROR [
+        /// Bits [31:11] Reserved, RES0.
+        Reserved11 OFFSET(11) NUMBITS(21) [],
+        /// DPT_ERR, bit [10]
+        ///
+        /// An error occurred while looking up a Device Permission Table.
+        DPT_ERR OFFSET(10) NUMBITS(1) [],
+        /// CMDQP_ERR, bit [9]
+        ///
+        /// An error occurred on an Enhanced Command queue.
+        CMDQP_ERR OFFSET(9) NUMBITS(1) [],
+        /// SFM_ERR, bit [8]
+        ///
+        /// The SMMU entered Service Failure Mode, traffic is terminated.
+        SFM_ERR OFFSET(8) NUMBITS(1) [],
+        /// MSI_GERROR_ABT_ERR, bit [7]
+        ///
+        /// A GERROR MSI write was aborted.
+        MSI_GERROR_ABT_ERR OFFSET(7) NUMBITS(1) [],
+        /// MSI_PRIQ_ABT_ERR, bit [6]
+        ///
+        /// A PRI queue MSI write was aborted.
+        MSI_PRIQ_ABT_ERR OFFSET(6) NUMBITS(1) [],
+        /// MSI_EVENTQ_ABT_ERR, bit [5]
+        ///
+        /// An Event queue MSI write was aborted.
+        MSI_EVENTQ_ABT_ERR OFFSET(5) NUMBITS(1) [],
+        /// MSI_CMDQ_ABT_ERR, bit [4]
+        ///
+        /// A CMD_SYNC MSI write was aborted.
+        MSI_CMDQ_ABT_ERR OFFSET(4) NUMBITS(1) [],
+        /// PRIQ_ABT_ERR, bit [3]
+        ///
+        /// An access to the PRI queue was aborted, page requests might have been lost.
+        PRIQ_ABT_ERR OFFSET(3) NUMBITS(1) [],
+        /// EVENTQ_ABT_ERR, bit [2]
+        ///
+        /// An access to the Event queue was aborted, events might have been lost.
+        EVENTQ_ABT_ERR OFFSET(2) NUMBITS(1) [],
+        /// Bit [1] Reserved, RES0.
+        Reserved1 OFFSET(1) NUMBITS(1) [],
+        /// CMDQ_ERR, bit [0]
+        ///
+        /// Command queue error, the reason is in SMMU_CMDQ_CONS.ERR and the queue stopped on the
+        /// faulting command.
+        CMDQ_ERR OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+/// SMMU global error status register, Read-Only.
+pub type GerrorReg = ReadOnly<u32, GERROR::Register>;
+/// SMMU global error acknowledgment register, Read-Write, with the SMMU_GERROR layout.
+pub type GerrorNReg = ReadWrite<u32, GERROR::Register>;
@@ -24,6 +24,31 @@ register_bitfields! {u32,
             LinearStreamTable = 0b00,
             TwoLevelStreamTableInAdditionToLinearStreamTable = 0b01
         ],
+        /// Stalling fault model support.
+        ///
+        /// - 0b00 Stall and Terminate models supported.
+        /// - 0b01 Stall is not supported, all faults terminate the transaction.
+        /// - 0b10 Stall is forced, all faults are stalled.
+        STALL_MODEL OFFSET(24) NUMBITS(2) [
+            StallAndTerminate = 0b00,
+            Terminate = 0b01,
+            StallForced = 0b10
+        ],
+        /// Endianness of the translation table walks.
+        ///
+        /// - 0b00 Mixed-endian, CD.ENDI selects the endianness.
+        /// - 0b10 Little-endian only.
+        /// - 0b11 Big-endian only.
+        TTENDIAN OFFSET(21) NUMBITS(2) [
+            Mixed = 0b00,
+            Little = 0b10,
+            Big = 0b11
+        ],
+        /// 2-level Context descriptor table supported.
+        CD2L OFFSET(19) NUMBITS(1) [
+            NotSupported = 0,
+            Supported = 1
+        ],
         /// 16-bit VMID supported.
         ///
         /// - 0b0 16-bit VMID not supported.
@@ -33,6 +58,14 @@ register_bitfields! {u32,
             NotSupported = 0,
             Supported = 1
         ],
+        /// Page Request Interface supported.
+        ///
+        /// - 0b0 PRI not supported, the SMMU_PRIQ_* registers are Reserved.
+        /// - 0b1 PRI supported, with the PRI queue.
+        PRI OFFSET(16) NUMBITS(1) [
+            NotSupported = 0,
+            Supported = 1
+        ],
         /// Address Translation Operations supported.
         ///
         /// - 0b0 Address Translation Operations not supported.
@@ -42,6 +75,23 @@ register_bitfields! {u32,
             NotSupported = 0,
             Supported = 1
         ],
+        /// Message Signalled Interrupts supported.
+        ///
+        /// - 0b0 The SMMU only signals wired interrupts, the SMMU_*_IRQ_CFG{0,1,2} registers are Reserved.
+        /// - 0b1 MSIs supported, a source uses MSIs when its SMMU_*_IRQ_CFG0.ADDR is not zero.
+        MSI OFFSET(13) NUMBITS(1) [
+            NotSupported = 0,
+            Supported = 1
+        ],
+        /// 16-bit ASID supported.
+        ///
+        /// - 0b0 16-bit ASID not supported.
+        ///     - ASID[15:8] is RES0 in command parameters and must be zero in CD.ASID.
+        /// - 0b1 16-bit ASID supported.
+        ASID16 OFFSET(12) NUMBITS(1) [
+            NotSupported = 0,
+            Supported = 1
+        ],
         /// H/W translation table Access flag and Dirty state of the page updates supported.
         ///
         /// - 0b00 No flag updates supported.
@@ -0,0 +1,79 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! 6.3.4 SMMU_IDR3
+//!
+//! ## Purpose
+//! Provides information about the features implemented by the SMMU from SMMUv3.1 onwards.
+//!
+//! ## Attributes
+//! SMMU_IDR3 is a 32-bit register.
+//!
+//! This register is part of the SMMUv3_PAGE_0 block.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::ReadOnly;
+
+register_bitfields! {u32,
+    /// SMMU_IDR3 fields.
+    pub IDR3 [
+        /// Bits [31:16] Reserved, RES0.
+        Reserved16 OFFSET(16) NUMBITS(16) [],
+        /// DPT, bit [15]
+        ///
+        /// Device Permission Table walks are supported for Non-secure state, enabled with
+        /// SMMU_CR0.DPT_WALK_EN.
+        DPT OFFSET(15) NUMBITS(1) [],
+        /// Bits [14:13] Reserved, RES0.
+        Reserved13 OFFSET(13) NUMBITS(2) [],
+        /// BBML, bits [12:11]
+        ///
+        /// Break-before-make level supported when changing the size of a translation.
+        BBML OFFSET(11) NUMBITS(2) [
+            /// Level 0, break-before-make is required.
+            Level0 = 0b00,
+            /// Level 1.
+            Level1 = 0b01,
+            /// Level 2.
+            Level2 = 0b10
+        ],
+        /// RIL, bit [10]
+        ///
+        /// Range-based TLB invalidations are supported.
+        RIL OFFSET(10) NUMBITS(1) [],
+        /// STT, bit [9]
+        ///
+        /// Small translation tables are supported.
+        STT OFFSET(9) NUMBITS(1) [],
+        /// FWB, bit [8]
+        ///
+        /// Stage 2 control of memory types and attributes is supported.
+        FWB OFFSET(8) NUMBITS(1) [],
+        /// MPAM, bit [7]
+        ///
+        /// Memory Partitioning And Monitoring is supported.
+        MPAM OFFSET(7) NUMBITS(1) [],
+        /// Bit [6] Reserved, RES0.
+        Reserved6 OFFSET(6) NUMBITS(1) [],
+        /// PPS, bit [5]
+        ///
+        /// The PASID of a PRI Page Request is taken from the STE when the request has none.
+        PPS OFFSET(5) NUMBITS(1) [],
+        /// XNX, bit [4]
+        ///
+        /// Stage 2 execute-never distinguishes EL0 from EL1.
+        XNX OFFSET(4) NUMBITS(1) [],
+        /// PBHA, bit [3]
+        ///
+        /// Page-based hardware attributes are supported.
+        PBHA OFFSET(3) NUMBITS(1) [],
+        /// HAD, bit [2]
+        ///
+        /// Hierarchical attribute disables are supported.
+        HAD OFFSET(2) NUMBITS(1) [],
+        /// Bits [1:0] Reserved, RES0.
+        Reserved0 OFFSET(0) NUMBITS(2) []
+    ]
+}
+
+/// IDR3 Register, read-only.
+pub type IDR3Reg = ReadOnly<u32, IDR3::Register>;
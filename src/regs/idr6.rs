@@ -0,0 +1,35 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! 6.3.7 SMMU_IDR6
+//!
+//! ## Purpose
+//! Provides information about the Enhanced Command queue interfaces, when SMMU_IDR1.ECMDQ == 1.
+//!
+//! ## Attributes
+//! SMMU_IDR6 is a 32-bit register.
+//!
+//! This register is part of the SMMUv3_PAGE_0 block.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::ReadOnly;
+
+register_bitfields! {u32,
+    /// SMMU_IDR6 fields.
+    pub IDR6 [
+        /// Bits [31:28] Reserved, RES0.
+        Reserved28 OFFSET(28) NUMBITS(4) [],
+        /// CMDQ_CONTROL_PAGE_LOG2NUMP, bits [27:24]
+        ///
+        /// Log2 of the number of Command queue control pages.
+        LOG2NUMP OFFSET(24) NUMBITS(4) [],
+        /// Bits [23:20] Reserved, RES0.
+        Reserved20 OFFSET(20) NUMBITS(4) [],
+        /// CMDQ_CONTROL_PAGE_LOG2NUMQ, bits [19:16]
+        ///
+        /// Log2 of the number of Enhanced Command queues in each control page.
+        LOG2NUMQ OFFSET(16) NUMBITS(4) []
+    ]
+}
+
+/// IDR6 Register, read-only.
+pub type IDR6Reg = ReadOnly<u32, IDR6::Register>;
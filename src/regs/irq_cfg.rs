@@ -0,0 +1,80 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! 6.3.20 SMMU_GERROR_IRQ_CFG0, 6.3.21 SMMU_GERROR_IRQ_CFG1, 6.3.22 SMMU_GERROR_IRQ_CFG2,
+//! and the SMMU_EVENTQ_IRQ_CFG{0,1,2} and SMMU_PRIQ_IRQ_CFG{0,1,2} registers with the same layout.
+//!
+//! ## Purpose
+//! MSI configuration of an interrupt source: the address, payload and memory attributes of the
+//! MSI write. A source signals its wired interrupt instead while IRQ_CFG0.ADDR is zero.
+//!
+//! ## Attributes
+//! IRQ_CFG0 is a 64-bit register, IRQ_CFG1 and IRQ_CFG2 are 32-bit registers.
+//! These registers are part of the SMMUv3_PAGE_0 block, and are Reserved when SMMU_IDR0.MSI == 0.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::ReadWrite;
+
+register_bitfields! {u64,
+    /// SMMU_*_IRQ_CFG0 fields, the MSI address.
+    pub IRQ_CFG0 [
+        /// Bits [63:52] Reserved, RES0.
+        Reserved52 OFFSET(52) NUMBITS(12) [],
+        /// ADDR, bits [51:2]
+        ///
+        /// Physical address of the MSI target register, bits [51:2]. Zero disables MSIs.
+        ADDR OFFSET(2) NUMBITS(50) [],
+        /// Bits [1:0] Reserved, RES0.
+        Reserved0 OFFSET(0) NUMBITS(2) []
+    ]
+}
+
+register_bitfields! {u32,
+    /// SMMU_*_IRQ_CFG1 fields, the MSI payload.
+    pub IRQ_CFG1 [
+        /// DATA, bits [31:0]
+        ///
+        /// MSI data payload.
+        DATA OFFSET(0) NUMBITS(32) []
+    ]
+}
+
+register_bitfields! {u32,
+    /// SMMU_*_IRQ_CFG2 fields, the MSI attributes.
+    pub IRQ_CFG2 [
+        /// Bits [31:6] Reserved, RES0.
+        Reserved6 OFFSET(6) NUMBITS(26) [],
+        /// SH, bits [5:4]
+        ///
+        /// Shareability of the MSI write, when MemAttr selects Normal memory.
+        /// - 0b00 Non-shareable.
+        /// - 0b10 Outer Shareable.
+        /// - 0b11 Inner Shareable.
+        SH OFFSET(4) NUMBITS(2) [
+            /// Non-shareable.
+            NonShareable = 0b00,
+            /// Outer Shareable.
+            OuterShareable = 0b10,
+            /// Inner Shareable.
+            InnerShareable = 0b11
+        ],
+        /// MemAttr, bits [3:0]
+        ///
+        /// Memory type of the MSI write, encoded like the stage 2 MemAttr field, for example
+        /// 0b0001 Device-nGnRE or 0b1111 Normal Write-Back cacheable.
+        MEMATTR OFFSET(0) NUMBITS(4) [
+            /// Device-nGnRnE.
+            DeviceNGnRnE = 0b0000,
+            /// Device-nGnRE.
+            DeviceNGnRE = 0b0001,
+            /// Normal Write-Back cacheable.
+            NormalWriteBack = 0b1111
+        ]
+    ]
+}
+
+/// SMMU_*_IRQ_CFG0, MSI address register, Read-Write.
+pub type IrqCfg0Reg = ReadWrite<u64, IRQ_CFG0::Register>;
+/// SMMU_*_IRQ_CFG1, MSI data register, Read-Write.
+pub type IrqCfg1Reg = ReadWrite<u32, IRQ_CFG1::Register>;
+/// SMMU_*_IRQ_CFG2, MSI attributes register, Read-Write.
+pub type IrqCfg2Reg = ReadWrite<u32, IRQ_CFG2::Register>;
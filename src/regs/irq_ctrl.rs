@@ -0,0 +1,56 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! 6.3.16 SMMU_IRQ_CTRL, 6.3.17 SMMU_IRQ_CTRLACK
+//!
+//! ## Purpose
+//! Interrupt enables of the SMMU interrupt sources, and their acknowledgment. An update of
+//! SMMU_IRQ_CTRL is complete once SMMU_IRQ_CTRLACK reads the same value.
+//!
+//! ## Attributes
+//! SMMU_IRQ_CTRL and SMMU_IRQ_CTRLACK are 32-bit registers.
+//! These registers are part of the SMMUv3_PAGE_0 block.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::{ReadOnly, ReadWrite};
+
+register_bitfields! {u32,
+    /// SMMU_IRQ_CTRL and SMMU_IRQ_CTRLACK fields.
+    pub IRQ_CTRL [
+        /// Bits [31:3] Reserved, RES0.
+        Reserved3 OFFSET(3) NUMBITS(29) [],
+        /// EVENTQ_IRQEN, bit [2]
+        ///
+        /// Event queue interrupt enable.
+        /// - 0b0 Interrupts from the Event queue are disabled.
+        /// - 0b1 Interrupts from the Event queue are enabled.
+        ///
+        /// The reset behavior of this field is:
+        /// - This field resets to 0b0.
+        EVENTQ_IRQEN OFFSET(2) NUMBITS(1) [],
+        /// PRIQ_IRQEN, bit [1]
+        ///
+        /// When SMMU_IDR0.PRI == 1: PRI queue interrupt enable.
+        /// - 0b0 Interrupts from the PRI queue are disabled.
+        /// - 0b1 Interrupts from the PRI queue are enabled.
+        ///
+        /// Otherwise: Reserved, RES0.
+        PRIQ_IRQEN OFFSET(1) NUMBITS(1) [],
+        /// GERROR_IRQEN, bit [0]
+        ///
+        /// Global Error interrupt enable.
+        /// - 0b0 Interrupts from global errors are disabled.
+        /// - 0b1 Interrupts from global errors are enabled.
+        ///
+        /// The reset behavior of this field is:
+        /// - This field resets to 0b0.
+        GERROR_IRQEN OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+/// SMMU interrupt control register, Read-Write.
+///
+/// The SMMU_*_IRQ_CFG{0,1,2} registers of a source are only modified while its IRQEN bit is 0
+/// in both SMMU_IRQ_CTRL and SMMU_IRQ_CTRLACK.
+pub type IrqCtrlReg = ReadWrite<u32, IRQ_CTRL::Register>;
+/// SMMU interrupt control acknowledgment register, Read-Only, with the SMMU_IRQ_CTRL layout.
+pub type IrqCtrlAckReg = ReadOnly<u32, IRQ_CTRL::Register>;
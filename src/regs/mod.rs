@@ -6,8 +6,18 @@ mod cr0;
 mod cr0ack;
 mod cr1;
 mod cr2;
+mod dpt;
+mod ecmdq;
+mod gatos;
+mod gbpa;
+mod gerror;
 mod idr0;
 mod idr1;
+mod idr3;
+mod idr6;
+mod irq_cfg;
+mod irq_ctrl;
+mod secure;
 mod strtab_base;
 mod strtab_base_cfg;
 
@@ -19,7 +29,17 @@ pub use cr0::*;
 pub use cr0ack::*;
 pub use cr1::*;
 pub use cr2::*;
+pub use dpt::*;
+pub use ecmdq::*;
+pub use gatos::*;
+pub use gbpa::*;
+pub use gerror::*;
 pub use idr0::*;
 pub use idr1::*;
+pub use idr3::*;
+pub use idr6::*;
+pub use irq_cfg::*;
+pub use irq_ctrl::*;
+pub use secure::*;
 pub use strtab_base::*;
 pub use strtab_base_cfg::*;
@@ -0,0 +1,57 @@
+//! Chapter 6. Memory map and registers
+//! 6.3. Register formats
+//! SMMU_S_IDR1, SMMU_S_INIT
+//!
+//! ## Purpose
+//! The Secure programming interface, present when SMMU_S_IDR1.SECURE_IMPL == 1. The Secure
+//! registers of page 0 start at 0x8000, SMMU_S_CR0 to SMMU_S_EVENTQ_IRQ_CFG2 duplicating the
+//! Non-secure registers of the same name for the Secure Stream table and queues, with their
+//! layout. Only the registers without a Non-secure counterpart are described here.
+//!
+//! ## Attributes
+//! SMMU_S_IDR1 and SMMU_S_INIT are 32-bit registers, accessible from Secure state only, reading
+//! as zero from Non-secure state.
+
+use tock_registers::register_bitfields;
+use tock_registers::registers::{ReadOnly, ReadWrite};
+
+register_bitfields! {u32,
+    /// SMMU_S_IDR1 fields.
+    pub S_IDR1 [
+        /// SECURE_IMPL, bit [31]
+        ///
+        /// The Secure programming interface is implemented.
+        SECURE_IMPL OFFSET(31) NUMBITS(1) [],
+        /// Bit [30] Reserved, RES0.
+        Reserved30 OFFSET(30) NUMBITS(1) [],
+        /// SEL2, bit [29]
+        ///
+        /// Secure stage 2 translation is supported.
+        SEL2 OFFSET(29) NUMBITS(1) [],
+        /// Bits [28:6] Reserved, RES0.
+        Reserved6 OFFSET(6) NUMBITS(23) [],
+        /// S_SIDSIZE, bits [5:0]
+        ///
+        /// Number of bits of Secure StreamID supported.
+        S_SIDSIZE OFFSET(0) NUMBITS(6) []
+    ]
+}
+
+register_bitfields! {u32,
+    /// SMMU_S_INIT fields.
+    pub S_INIT [
+        /// Bits [31:1] Reserved, RES0.
+        Reserved1 OFFSET(1) NUMBITS(31) [],
+        /// INV_ALL, bit [0]
+        ///
+        /// Writing 1 invalidates all configuration and translation caches of the SMMU, reading
+        /// as 1 until the invalidation completes. Only written before the SMMU is first enabled.
+        INV_ALL OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+/// S_IDR1 Register, read-only.
+pub type SIdr1Reg = ReadOnly<u32, S_IDR1::Register>;
+
+/// S_INIT Register, read-write.
+pub type SInitReg = ReadWrite<u32, S_INIT::Register>;
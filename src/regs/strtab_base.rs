@@ -5,11 +5,11 @@
 //! The SMMU_STRTAB_BASE characteristics are:
 //!
 //! ## Purpose
-//!     Configuration of Stream table base address.
+//! Configuration of Stream table base address.
 //!
 //! ## Attributes
-//!     SMMU_STRTAB_BASE is a 64-bit register.
-//!     This register is part of the SMMUv3_PAGE_0 block.
+//! SMMU_STRTAB_BASE is a 64-bit register.
+//! This register is part of the SMMUv3_PAGE_0 block.
 
 use tock_registers::register_bitfields;
 use tock_registers::registers::ReadWrite;
@@ -0,0 +1,64 @@
+//! Secure programming interface.
+//!
+//! An SMMU may implement a Secure programming interface, SMMU_S_IDR1.SECURE_IMPL, with its own
+//! Stream table, Command and Event queues for the Secure streams, programmed through Secure
+//! copies of the Non-secure registers. Software running in Secure state, such as a secure
+//! partition manager, selects it with [`SMMUv3::set_secure`] before [`SMMUv3::init`], the driver
+//! then programming the Secure Stream table and queues instead of the Non-secure ones.
+
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::backend::RegisterBackend;
+use crate::hal::PagingHandler;
+use crate::id_alloc::VmidError;
+use crate::{SMMUv3, ARM_SMMU_SYNC_TIMEOUT, S_IDR1, S_INIT};
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Whether the SMMU implements the Secure programming interface, SMMU_S_IDR1.SECURE_IMPL.
+    ///
+    /// The Secure registers read as zero from Non-secure state, so this is false there.
+    pub fn secure_supported(&self) -> bool {
+        self.regs().S_IDR1.is_set(S_IDR1::SECURE_IMPL)
+    }
+
+    /// Whether the Secure Stream table and queues are programmed, see [`SMMUv3::set_secure`].
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Program the Secure Stream table and queues instead of the Non-secure ones, returning
+    /// whether the Secure programming interface is implemented.
+    ///
+    /// Must be called from Secure state, before [`SMMUv3::init`]. The PRI queue, the Enhanced
+    /// Command queues and the Device Permission Tables have no Secure counterpart and are left
+    /// unused. Without SMMU_S_IDR1.SEL2, stage 2 is refused with [`VmidError::NoStage2`].
+    pub fn set_secure(&mut self) -> bool {
+        if !self.secure_supported() {
+            warn!("Secure programming interface not implemented");
+            return false;
+        }
+        if !self.regs().S_IDR1.is_set(S_IDR1::SEL2) {
+            warn!("Secure stage 2 not supported, Secure streams bypass or use stage 1");
+        }
+        self.secure = true;
+        true
+    }
+
+    /// Fail with [`VmidError::NoStage2`] when stage 2 STEs would be ILLEGAL, the Secure streams
+    /// having no stage 2 without SMMU_S_IDR1.SEL2.
+    pub(crate) fn check_stage2(&self) -> Result<(), VmidError> {
+        if self.secure && !self.regs().S_IDR1.is_set(S_IDR1::SEL2) {
+            return Err(VmidError::NoStage2);
+        }
+        Ok(())
+    }
+
+    /// Invalidate all the SMMU caches through SMMU_S_INIT, required before the Secure
+    /// programming interface is first enabled.
+    pub(crate) fn secure_invalidate_all(&self) {
+        self.regs().S_INIT.write(S_INIT::INV_ALL::SET);
+        if !(0..ARM_SMMU_SYNC_TIMEOUT).any(|_| !self.regs().S_INIT.is_set(S_INIT::INV_ALL)) {
+            error!("SMMU_S_INIT invalidation timeout");
+        }
+    }
+}
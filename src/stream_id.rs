@@ -0,0 +1,214 @@
+//! StreamIDs of PCIe devices.
+//!
+//! A PCIe device is identified on the bus by its requester ID (RID), `bus << 8 | device << 3 |
+//! function`. The root complex forwards the RID to the SMMU, usually with an offset given by the
+//! `iommu-map` property of its device tree node or by its IORT ID mappings, which yields the
+//! StreamID. Devices behind a PCIe-to-PCI bridge issue DMA with the RID of the bridge instead,
+//! see [`PciBdf::dma_aliases`].
+
+use core::fmt;
+
+/// A StreamID, the index of a stream in the Stream table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId(u32);
+
+impl StreamId {
+    /// The StreamID `sid`.
+    pub const fn new(sid: u32) -> Self {
+        Self(sid)
+    }
+
+    /// The StreamID of `bdf` on a root complex forwarding requester IDs unchanged, as on the
+    /// QEMU virt machine.
+    pub const fn from_bdf(bdf: PciBdf) -> Self {
+        Self(bdf.rid() as u32)
+    }
+
+    /// The StreamID as a number.
+    pub const fn as_u32(self) -> u32 {
+        self.0
+    }
+
+    /// The StreamID as an index into the Stream table.
+    pub const fn as_usize(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl From<u32> for StreamId {
+    fn from(sid: u32) -> Self {
+        Self(sid)
+    }
+}
+
+impl fmt::LowerHex for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+/// Location of a PCI function, its segment, bus, device and function numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciBdf {
+    /// PCI segment, the root complex of the function.
+    pub segment: u16,
+    /// Bus number.
+    pub bus: u8,
+    /// Device number, 5 bits.
+    pub device: u8,
+    /// Function number, 3 bits.
+    pub function: u8,
+}
+
+impl PciBdf {
+    /// The function `segment:bus:device.function`, `device` and `function` being truncated to
+    /// their 5 and 3 bits.
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device: device & 0x1f,
+            function: function & 0x7,
+        }
+    }
+
+    /// The function with requester ID `rid` in `segment`.
+    pub const fn from_rid(segment: u16, rid: u16) -> Self {
+        Self::new(segment, (rid >> 8) as u8, (rid >> 3) as u8, rid as u8)
+    }
+
+    /// The requester ID, `bus << 8 | device << 3 | function`.
+    pub const fn rid(&self) -> u16 {
+        (self.bus as u16) << 8 | (self.device as u16) << 3 | self.function as u16
+    }
+
+    /// The functions whose requester ID the root complex may see on DMA from this function.
+    ///
+    /// `bridges` are the aliasing bridges between the function and the root complex, starting
+    /// from the closest one. The function itself comes first, a PCIe-to-PCI-X bridge forwards
+    /// some requests with their original requester ID.
+    pub fn dma_aliases<'a>(&self, bridges: &'a [PciBridge]) -> impl Iterator<Item = PciBdf> + 'a {
+        core::iter::once(*self).chain(bridges.iter().map(|bridge| bridge.alias()))
+    }
+}
+
+impl fmt::Display for PciBdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// A bridge taking ownership of the requests it forwards upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciBridge {
+    /// PCIe-to-PCI or PCI-X bridge, the requests from its secondary bus carry the requester ID
+    /// of device 0 function 0 on that bus.
+    PcieToPci {
+        /// The bridge itself.
+        bridge: PciBdf,
+        /// Bus number of the secondary side of the bridge.
+        secondary_bus: u8,
+    },
+    /// Conventional PCI bridge, the requests carry its own requester ID.
+    Pci {
+        /// The bridge itself.
+        bridge: PciBdf,
+    },
+}
+
+impl PciBridge {
+    /// The function the forwarded requests appear to come from.
+    pub const fn alias(&self) -> PciBdf {
+        match *self {
+            Self::PcieToPci {
+                bridge,
+                secondary_bus,
+            } => PciBdf::new(bridge.segment, secondary_bus, 0, 0),
+            Self::Pci { bridge } => bridge,
+        }
+    }
+}
+
+/// Requester IDs `rid_base..rid_base + len` of a root complex, mapped to the StreamIDs
+/// `sid_base..sid_base + len`: an `iommu-map` entry or an IORT ID mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RidMap {
+    /// First requester ID of the range.
+    pub rid_base: u32,
+    /// StreamID of the first requester ID.
+    pub sid_base: u32,
+    /// Number of requester IDs in the range.
+    pub len: u32,
+}
+
+impl RidMap {
+    /// Whether requester `rid` is in the range.
+    pub fn contains(&self, rid: u32) -> bool {
+        rid.wrapping_sub(self.rid_base) < self.len
+    }
+
+    /// The StreamID of requester `rid`, if in the range.
+    pub fn map(&self, rid: u32) -> Option<StreamId> {
+        if !self.contains(rid) {
+            return None;
+        }
+        self.sid_base.checked_add(rid - self.rid_base).map(StreamId)
+    }
+
+    /// The StreamID of requester `rid` through the first range of `maps` containing it.
+    pub fn map_all(maps: &[RidMap], rid: u32) -> Option<StreamId> {
+        maps.iter().find_map(|map| map.map(rid))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rid_mapping() {
+        let bdf = PciBdf::new(0, 0x03, 0x1f, 0x7);
+        assert_eq!(bdf.rid(), 0x03ff);
+        assert_eq!(PciBdf::from_rid(0, 0x03ff), bdf);
+        assert_eq!(
+            StreamId::from_bdf(PciBdf::new(1, 0x01, 0x02, 0x3)).as_u32(),
+            0x0113
+        );
+
+        let maps = [
+            RidMap {
+                rid_base: 0,
+                sid_base: 0x1_0000,
+                len: 0x100,
+            },
+            RidMap {
+                rid_base: 0x100,
+                sid_base: 0x20,
+                len: 0x10,
+            },
+        ];
+        assert_eq!(RidMap::map_all(&maps, 0x12), Some(StreamId::new(0x1_0012)));
+        assert_eq!(RidMap::map_all(&maps, 0x105), Some(StreamId::new(0x25)));
+        assert_eq!(RidMap::map_all(&maps, 0x110), None);
+
+        // A device behind a PCIe-to-PCI bridge, itself behind a conventional PCI bridge.
+        let bridges = [
+            PciBridge::PcieToPci {
+                bridge: PciBdf::new(0, 0x02, 0, 0),
+                secondary_bus: 0x05,
+            },
+            PciBridge::Pci {
+                bridge: PciBdf::new(0, 0x01, 0x04, 0),
+            },
+        ];
+        let dev = PciBdf::new(0, 0x05, 0x03, 0);
+        let rids: [u16; 3] =
+            core::array::from_fn(|i| dev.dma_aliases(&bridges).nth(i).unwrap().rid());
+        assert_eq!(rids, [0x0518, 0x0500, 0x0120]);
+        assert_eq!(dev.dma_aliases(&bridges).count(), 3);
+    }
+}
@@ -27,8 +27,34 @@ const STRTAB_STE_0_V: u64 = 0b1 << 0;
 /// * 0b101 Yes  Translate    Bypass      S1* valid
 /// * 0b110 Yes  Bypass       Translate   S2* valid
 /// * 0b111 Yes  Translate    Translate   S1* and S2* valid.
+const STRTAB_STE_0_CFG_MASK: u64 = 0b111 << 1;
+const STRTAB_STE_0_CFG_S2_TRANS: u64 = 0b010 << 1;
 const STRTAB_STE_0_CFG_S1_BYPASS_S2_BYPASS: u64 = 0b100 << 1;
+const STRTAB_STE_0_CFG_S1_TRANS_S2_BYPASS: u64 = 0b101 << 1;
 const STRTAB_STE_0_CFG_S1_BYPASS_S2_TRANS: u64 = 0b110 << 1;
+/// S1Fmt, bits [5:4]
+/// Format of the CD table, 0b00 is a linear table of S1CDMax CDs.
+const STRTAB_STE_0_S1FMT_LINEAR: u64 = 0b00 << 4;
+const STRTAB_STE_0_S1FMT_MASK: u64 = 0b11 << 4;
+/// S1ContextPtr, bits [51:6]
+/// Address of the CD table, bits [51:6].
+const STRTAB_STE_0_S1CTXPTR_MASK: u64 = ((1 << 52) - 1) & !((1 << 6) - 1);
+/// S1CDMax, bits [63:59]
+/// Number of CDs pointed to by S1ContextPtr, as log2(CDs). 0 disables substreams.
+const STRTAB_STE_0_S1CDMAX_OFFSET: u64 = 59;
+/// S1DSS, bits [65:64]
+/// Default substream behavior for transactions without a SubstreamID when S1CDMax != 0.
+///
+/// - 0b00 Terminate.
+/// - 0b01 Bypass stage 1.
+/// - 0b10 Use CD 0.
+const STRTAB_STE_1_S1DSS_SSID0: u64 = 0b10; // 0 = 64 - 64
+const STRTAB_STE_1_S1DSS_MASK: u64 = 0b11;
+/// S1CIR, bits [67:66], S1COR, bits [69:68], S1CSH, bits [71:70]
+/// Attributes of CD and stage 1 table walks: Write-Back Read-Allocate, Inner Shareable.
+const STRTAB_STE_1_S1CIR_WBRA: u64 = 0b01 << 2; // 2 = 66 - 64
+const STRTAB_STE_1_S1COR_WBRA: u64 = 0b01 << 4; // 4 = 68 - 64
+const STRTAB_STE_1_S1CSH_ISH: u64 = 0b11 << 6; // 6 = 70 - 64
 /// SHCFG, bits [109:108]
 /// Shareability configuration.
 ///
@@ -60,13 +86,13 @@ const STRTAB_STE_2_S2T0SZ_OFFSET: u64 = 32; // 32 = 160 - 128
 /// Overall, bits [178:160] refers to the lower 19 bits of [`aarch64_cpu::registers::VTCR_EL2`].
 const STRTAB_STE_2_S2VTCR_LEN: u64 = 19;
 
-const DEFAULT_S2VTCR: u64 = VTCR_EL2::PS::PA_40B_1TB.value
+pub(crate) const DEFAULT_S2VTCR: u64 = VTCR_EL2::PS::PA_40B_1TB.value
     | VTCR_EL2::TG0::Granule4KB.value
     | VTCR_EL2::SH0::Inner.value
     | VTCR_EL2::ORGN0::NormalWBRAWA.value
     | VTCR_EL2::IRGN0::NormalWBRAWA.value
     | VTCR_EL2::SL0.val(0b01).value
-    | VTCR_EL2::T0SZ.val(64-39).value;
+    | VTCR_EL2::T0SZ.val(64 - 39).value;
 
 /// S2AA64, bit [179]
 ///
@@ -88,6 +114,22 @@ const STRTAB_STE_2_S2AA64: u64 = 1 << 51; // 51 = 179 - 128
 /// - 0b0 If SMMU_IDR3.PTWNNC == 0: CD fetch and stage 1 translation table walks allowed to any valid stage 2 address. If SMMU_IDR3.PTWNNC == 1: A translation table access or CD fetch mapped as any Device type occurs as if it is to Normal Non-cacheable memory.
 /// - 0b1 CD fetch or Stage 1 translation table walks to stage 2 addresses mapped as any Device are terminated. A stage 2 Permission fault is recorded.
 const STRTAB_STE_2_S2PTW: u64 = 1 << 54; // 54 = 182 - 128
+/// S2HD, bit [183]
+/// Stage 2 Hardware Translation Table Dirty state update enable.
+///
+/// - 0b0 Dirty state update disabled, writes to pages with DBM == 1 and S2AP[1] == 0 cause a Permission fault.
+/// - 0b1 Dirty state update enabled, the SMMU sets S2AP[1] of a DBM page on write.
+///
+/// If SMMU_IDR0.HTTU < 0b10, this field is RES0. S2HD == 1 is only permitted when S2HA == 1.
+const STRTAB_STE_2_S2HD: u64 = 1 << 55; // 55 = 183 - 128
+/// S2HA, bit [184]
+/// Stage 2 Hardware Translation Table Access flag update enable.
+///
+/// - 0b0 Access flag update disabled.
+/// - 0b1 Access flag update enabled, the SMMU sets AF of a descriptor instead of raising an Access flag fault.
+///
+/// If SMMU_IDR0.HTTU == 0b00, this field is RES0.
+const STRTAB_STE_2_S2HA: u64 = 1 << 56; // 56 = 184 - 128
 
 /// S2S, bit [185]
 /// Stage 2 fault behavior - Stall
@@ -105,11 +147,11 @@ const STRTAB_STE_2_S2R: u64 = 1 << 58; // 58 = 186 - 128
 /// S2TTB, bits [247:196]
 /// In SMMUv3.1 and later, if STE.S2AA64 selects VMSAv9-128, then bits[247:196] represent the address of Stage 2 Translation Table base, bits[55:4]. Otherwise:
 /// - In SMMUv3.1 and later:
-///     – Bits[243:196] represent the address of Stage 2 Translation Table base, bits[51:4].
-///     – Bits[247:244] are RES0.
+///   – Bits[243:196] represent the address of Stage 2 Translation Table base, bits[51:4].
+///   – Bits[247:244] are RES0.
 /// - In SMMUv3.0:
-///     – Bits[239:196] represent the address of Stage 2 Translation Table base, bits[47:4].
-///     – Bits[247:240] are RES0.
+///   – Bits[239:196] represent the address of Stage 2 Translation Table base, bits[47:4].
+///   – Bits[247:240] are RES0.
 ///
 /// Address bits above and below the field range are treated as zero.
 ///
@@ -123,6 +165,74 @@ const fn extract_bits(value: u64, start: u64, length: u64) -> u64 {
     (value >> start) & mask
 }
 
+/// Hardware Translation Table Update (HTTU) configuration, see 3.13 Translation table entries and Access/Dirty flags.
+///
+/// The SMMU only honours a mode up to the level advertised in SMMU_IDR0.HTTU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum HttuMode {
+    /// No hardware updates, Access flag faults are reported as usual.
+    #[default]
+    Disabled,
+    /// Hardware Access flag updates only.
+    AccessFlag,
+    /// Hardware Access flag and Dirty state updates.
+    ///
+    /// Dirty state is tracked through the DBM bit of the leaf descriptors, so the owner of the
+    /// translation tables must set DBM on writable pages it wants to track.
+    AccessFlagDirtyState,
+}
+
+impl HttuMode {
+    /// STE stage 2 HTTU bits for this mode.
+    const fn ste_s2_bits(self) -> u64 {
+        match self {
+            HttuMode::Disabled => 0,
+            HttuMode::AccessFlag => STRTAB_STE_2_S2HA,
+            HttuMode::AccessFlagDirtyState => STRTAB_STE_2_S2HA | STRTAB_STE_2_S2HD,
+        }
+    }
+}
+
+/// Stage 1 fields of a decoded STE.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SteS1 {
+    /// STE.S1ContextPtr.
+    pub cd_table: PhysAddr,
+    /// STE.S1CDMax, log2 of the number of CDs.
+    pub cd_max: u32,
+    /// STE.S1DSS.
+    pub dss: u64,
+    /// Whether STE.S1Fmt selects a linear CD table.
+    pub linear: bool,
+}
+
+/// Stage 2 fields of a decoded STE.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SteS2 {
+    /// STE.S2TTB.
+    pub ttb: PhysAddr,
+    /// STE.{S2T0SZ, S2SL0, S2IR0, S2OR0, S2SH0, S2TG, S2PS}, in VTCR_EL2 layout.
+    pub vtcr: u64,
+    /// STE.S2HA.
+    pub ha: bool,
+}
+
+/// Stream configuration selected by STE.{V, Config}.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SteConfig {
+    /// STE.V is 0, or STE.Config is a reserved value.
+    Invalid,
+    /// Traffic is aborted without recording an event.
+    Abort,
+    /// Both stages bypassed.
+    Bypass,
+    /// At least one stage translates.
+    Translate {
+        s1: Option<SteS1>,
+        s2: Option<SteS2>,
+    },
+}
+
 #[derive(Debug)]
 #[allow(unused)]
 pub struct StreamTableEntry([u64; STRTAB_STE_DWORDS]);
@@ -141,22 +251,130 @@ impl StreamTableEntry {
         ])
     }
 
+    /// Traffic is terminated without recording events, STE.Config == 0b000.
+    pub const fn abort_entry() -> Self {
+        Self([STRTAB_STE_0_V, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    /// Stage 1 translation with the Context Descriptors at `cd_table`, holding `1 << ssid_bits` CDs.
+    ///
+    /// Stage 2 is bypassed, the CD ASIDs tag the TLB entries.
+    pub const fn s1_translated_entry(cd_table: PhysAddr, ssid_bits: u32) -> Self {
+        let s1dss = if ssid_bits == 0 {
+            0
+        } else {
+            STRTAB_STE_1_S1DSS_SSID0
+        };
+        Self([
+            STRTAB_STE_0_V
+                | STRTAB_STE_0_CFG_S1_TRANS_S2_BYPASS
+                | STRTAB_STE_0_S1FMT_LINEAR
+                | (cd_table.as_usize() as u64 & STRTAB_STE_0_S1CTXPTR_MASK)
+                | (ssid_bits as u64) << STRTAB_STE_0_S1CDMAX_OFFSET,
+            s1dss
+                | STRTAB_STE_1_S1CIR_WBRA
+                | STRTAB_STE_1_S1COR_WBRA
+                | STRTAB_STE_1_S1CSH_ISH
+                | STRTAB_STE_1_SHCFG_INCOMING,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])
+    }
+
+    /// The S2VMID of a valid STE with stage 2 translation enabled.
+    pub const fn s2_vmid(&self) -> Option<u16> {
+        let config = self.0[0] & STRTAB_STE_0_CFG_MASK;
+        if self.0[0] & STRTAB_STE_0_V == 0
+            || config & STRTAB_STE_0_CFG_S1_BYPASS_S2_BYPASS == 0
+            || config & STRTAB_STE_0_CFG_S2_TRANS == 0
+        {
+            return None;
+        }
+        Some((self.0[2] >> STRTAB_STE_2_S2VMID_OFFSET) as u16)
+    }
+
+    /// Decode the stream configuration.
+    pub(crate) const fn config(&self) -> SteConfig {
+        if self.0[0] & STRTAB_STE_0_V == 0 {
+            return SteConfig::Invalid;
+        }
+        let config = self.0[0] & STRTAB_STE_0_CFG_MASK;
+        if config & STRTAB_STE_0_CFG_S1_BYPASS_S2_BYPASS == 0 {
+            return SteConfig::Abort;
+        }
+        if config == STRTAB_STE_0_CFG_S1_BYPASS_S2_BYPASS {
+            return SteConfig::Bypass;
+        }
+
+        let s1 = if config & STRTAB_STE_0_CFG_S1_TRANS_S2_BYPASS
+            == STRTAB_STE_0_CFG_S1_TRANS_S2_BYPASS
+        {
+            Some(SteS1 {
+                cd_table: PhysAddr::from_usize((self.0[0] & STRTAB_STE_0_S1CTXPTR_MASK) as usize),
+                cd_max: (self.0[0] >> STRTAB_STE_0_S1CDMAX_OFFSET) as u32,
+                dss: self.0[1] & STRTAB_STE_1_S1DSS_MASK,
+                linear: self.0[0] & STRTAB_STE_0_S1FMT_MASK == STRTAB_STE_0_S1FMT_LINEAR,
+            })
+        } else {
+            None
+        };
+        let s2 = if config & STRTAB_STE_0_CFG_S1_BYPASS_S2_TRANS
+            == STRTAB_STE_0_CFG_S1_BYPASS_S2_TRANS
+        {
+            Some(SteS2 {
+                ttb: PhysAddr::from_usize(
+                    (extract_bits(self.0[3], STRTAB_STE_3_S2TTB_OFF, STRTAB_STE_3_S2TTB_LEN)
+                        << STRTAB_STE_3_S2TTB_OFF) as usize,
+                ),
+                vtcr: extract_bits(
+                    self.0[2],
+                    STRTAB_STE_2_S2T0SZ_OFFSET,
+                    STRTAB_STE_2_S2VTCR_LEN,
+                ),
+                ha: self.0[2] & STRTAB_STE_2_S2HA != 0,
+            })
+        } else {
+            None
+        };
+        SteConfig::Translate { s1, s2 }
+    }
+
+    /// Whether the STE is valid and translates at stage 1, stage 2 or both.
+    pub const fn is_translated(&self) -> bool {
+        let config = self.0[0] & STRTAB_STE_0_CFG_MASK;
+        self.0[0] & STRTAB_STE_0_V != 0
+            && config & STRTAB_STE_0_CFG_S1_BYPASS_S2_BYPASS != 0
+            && config != STRTAB_STE_0_CFG_S1_BYPASS_S2_BYPASS
+    }
+
     /// STE.S2VMID[15:0] is IGNORED and no VMID tagging occurs when any of the following are true:
     /// • Stage 2 is not implemented in the Security state corresponding to the STE.
     /// • STE.Config[1:0] == 0b00. Note: In this case, no TLB entries are inserted as translation is bypassed.
     /// • A Non-secure STE StreamWorld is not NS-EL1.
     /// • A Secure STE has a StreamWorld other than “Secure”.
     /// • A Realm STE StreamWorld is not Realm-EL1.
-    pub const fn s2_translated_entry(vmid: u64, s2pt_base: PhysAddr) -> Self {
+    ///
+    /// `s2vtcr` describes the stage 2 table in VTCR_EL2 layout, see [`DEFAULT_S2VTCR`].
+    /// `httu` selects the STE.{S2HA, S2HD} hardware update behavior, the caller must have checked it against SMMU_IDR0.HTTU.
+    pub const fn s2_translated_entry(
+        vmid: u64,
+        s2pt_base: PhysAddr,
+        s2vtcr: u64,
+        httu: HttuMode,
+    ) -> Self {
         Self([
             STRTAB_STE_0_V | STRTAB_STE_0_CFG_S1_BYPASS_S2_TRANS,
             STRTAB_STE_1_SHCFG_INCOMING,
             (vmid << STRTAB_STE_2_S2VMID_OFFSET)
-                | extract_bits(DEFAULT_S2VTCR, 0, STRTAB_STE_2_S2VTCR_LEN)
-                    << STRTAB_STE_2_S2T0SZ_OFFSET
+                | extract_bits(s2vtcr, 0, STRTAB_STE_2_S2VTCR_LEN) << STRTAB_STE_2_S2T0SZ_OFFSET
                 | STRTAB_STE_2_S2AA64
                 | STRTAB_STE_2_S2PTW
-                | STRTAB_STE_2_S2R,
+                | STRTAB_STE_2_S2R
+                | httu.ste_s2_bits(),
             extract_bits(
                 s2pt_base.as_usize() as u64,
                 STRTAB_STE_3_S2TTB_OFF,
@@ -199,6 +417,7 @@ impl<H: PagingHandler> LinearStreamTable<H> {
         self.base
     }
 
+    #[allow(clippy::mut_from_ref)]
     pub fn ste(&self, sid: usize) -> &mut StreamTableEntry {
         let base = self.base + sid * STRTAB_STE_SIZE;
         unsafe { &mut *(base.as_usize() as *mut StreamTableEntry) }
@@ -209,16 +428,42 @@ impl<H: PagingHandler> LinearStreamTable<H> {
         *tab = StreamTableEntry::bypass_entry();
     }
 
-    pub(crate) fn set_s2_translated_ste(&self, sid: usize, vmid: usize, s2pt_base: PhysAddr) {
+    pub fn set_abort_ste(&self, sid: usize) {
+        let tab = self.ste(sid);
+        *tab = StreamTableEntry::abort_entry();
+    }
+
+    pub(crate) fn set_s2_translated_ste(
+        &self,
+        sid: usize,
+        vmid: usize,
+        s2pt_base: PhysAddr,
+        s2vtcr: u64,
+        httu: HttuMode,
+    ) {
         let entry: &mut StreamTableEntry = self.ste(sid);
-        *entry = StreamTableEntry::s2_translated_entry(vmid as _, s2pt_base);
+        *entry = StreamTableEntry::s2_translated_entry(vmid as _, s2pt_base, s2vtcr, httu);
 
         info!(
-            "write ste, sid: 0x{:x}, vmid: 0x{:x}, ste_addr:0x{:x}, root_pt:0x {:x?}",
+            "write ste, sid: 0x{:x}, vmid: 0x{:x}, ste_addr:0x{:x}, root_pt:0x {:x?}, httu: {:?}",
             sid,
             vmid,
             self.base + sid * STRTAB_STE_SIZE,
             s2pt_base,
+            httu,
+        );
+    }
+
+    pub(crate) fn set_s1_translated_ste(&self, sid: usize, cd_table: PhysAddr, ssid_bits: u32) {
+        let entry: &mut StreamTableEntry = self.ste(sid);
+        *entry = StreamTableEntry::s1_translated_entry(cd_table, ssid_bits);
+
+        info!(
+            "write ste, sid: 0x{:x}, ste_addr:0x{:x}, cd_table:0x {:x?}, ssid_bits: {}",
+            sid,
+            self.base + sid * STRTAB_STE_SIZE,
+            cd_table,
+            ssid_bits,
         );
     }
 
@@ -226,3 +471,30 @@ impl<H: PagingHandler> LinearStreamTable<H> {
         self.entry_count
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_s2_httu_bits() {
+        // STE.S2HD is bit 183 and STE.S2HA bit 184, bits 55 and 56 of the third doubleword.
+        for (httu, bits) in [
+            (HttuMode::Disabled, 0),
+            (HttuMode::AccessFlag, 1 << 56),
+            (HttuMode::AccessFlagDirtyState, 1 << 56 | 1 << 55),
+        ] {
+            let ste = StreamTableEntry::s2_translated_entry(
+                1,
+                PhysAddr::from_usize(0x8000_0000),
+                DEFAULT_S2VTCR,
+                httu,
+            );
+            assert_eq!(ste.0[2] & (0b11 << 55), bits);
+            assert!(matches!(
+                ste.config(),
+                SteConfig::Translate { s2: Some(s2), .. } if s2.ha == (httu != HttuMode::Disabled)
+            ));
+        }
+    }
+}
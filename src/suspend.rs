@@ -0,0 +1,231 @@
+//! Suspend and resume.
+//!
+//! The SMMU may lose its register state across a system suspend, while the Stream table, the
+//! Context Descriptors and the page tables in memory are kept. [`SMMUv3::suspend`] waits for the
+//! commands issued so far, aborts all traffic with SMMU_GBPA.ABORT, disables the SMMU and saves
+//! its registers. [`SMMUv3::resume`] programs them again and invalidates all the configuration
+//! and TLB entries the SMMU may have cached before translating again, so that the devices added
+//! before the suspend keep their translations.
+
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::backend::RegisterBackend;
+use crate::hal::PagingHandler;
+use crate::queue::Cmd;
+use crate::{
+    EcmdqError, SMMUv3, ARM_SMMU_SYNC_TIMEOUT, CMDQ_CONS, CMDQ_PROD, CR0, EVENTQ_CONS, EVENTQ_PROD,
+    GBPA, PRIQ_CONS, PRIQ_PROD,
+};
+
+/// Reasons [`SMMUv3::suspend`] or [`SMMUv3::resume`] fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspendError {
+    /// [`SMMUv3::suspend`] was called twice without [`SMMUv3::resume`].
+    AlreadySuspended,
+    /// [`SMMUv3::resume`] was called without [`SMMUv3::suspend`].
+    NotSuspended,
+    /// The SMMU did not acknowledge an update of SMMU_CR0, SMMU_IRQ_CTRL, SMMU_GBPA or of an
+    /// Enhanced Command queue.
+    Timeout,
+}
+
+impl From<EcmdqError> for SuspendError {
+    fn from(_: EcmdqError) -> Self {
+        Self::Timeout
+    }
+}
+
+/// SMMU_*_IRQ_CFG{0,1,2} of an interrupt source.
+#[derive(Debug, Clone, Copy, Default)]
+struct IrqCfg {
+    cfg0: u64,
+    cfg1: u32,
+    cfg2: u32,
+}
+
+/// Registers saved by [`SMMUv3::suspend`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SavedRegs {
+    cr0: u32,
+    cr1: u32,
+    cr2: u32,
+    gbpa: u32,
+    irq_ctrl: u32,
+    gerror_irq: IrqCfg,
+    eventq_irq: IrqCfg,
+    priq_irq: IrqCfg,
+    strtab_base: u64,
+    strtab_base_cfg: u32,
+    cmdq_base: u64,
+    eventq_base: u64,
+    priq_base: u64,
+    dpt_base: u64,
+    dpt_base_cfg: u32,
+}
+
+impl<H: PagingHandler, B: RegisterBackend> SMMUv3<H, B> {
+    /// Whether [`SMMUv3::suspend`] succeeded and [`SMMUv3::resume`] was not called since.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.is_some()
+    }
+
+    /// Quiesce and disable the SMMU ahead of a system suspend, saving its registers.
+    ///
+    /// Waits for the commands issued so far and reports the pending events to the fault handler.
+    /// Transactions are then aborted until [`SMMUv3::resume`]. No command may be issued in
+    /// between.
+    pub fn suspend(&mut self) -> Result<(), SuspendError> {
+        if self.suspended.is_some() {
+            return Err(SuspendError::AlreadySuspended);
+        }
+        self.add_cmds(core::iter::empty(), true);
+        self.handle_event_irq();
+
+        let regs = self.regs();
+        let saved = SavedRegs {
+            cr0: regs.CR0.get(),
+            cr1: regs.CR1.get(),
+            cr2: regs.CR2.get(),
+            gbpa: regs.GBPA.get() & !GBPA::UPDATE::SET.value,
+            irq_ctrl: regs.IRQ_CTRL.get(),
+            gerror_irq: IrqCfg {
+                cfg0: regs.GERROR_IRQ_CFG0.get(),
+                cfg1: regs.GERROR_IRQ_CFG1.get(),
+                cfg2: regs.GERROR_IRQ_CFG2.get(),
+            },
+            eventq_irq: IrqCfg {
+                cfg0: regs.EVENTQ_IRQ_CFG0.get(),
+                cfg1: regs.EVENTQ_IRQ_CFG1.get(),
+                cfg2: regs.EVENTQ_IRQ_CFG2.get(),
+            },
+            priq_irq: if self.pri_supported() {
+                IrqCfg {
+                    cfg0: regs.PRIQ_IRQ_CFG0.get(),
+                    cfg1: regs.PRIQ_IRQ_CFG1.get(),
+                    cfg2: regs.PRIQ_IRQ_CFG2.get(),
+                }
+            } else {
+                IrqCfg::default()
+            },
+            strtab_base: regs.STRTAB_BASE.get(),
+            strtab_base_cfg: regs.STRTAB_BASE_CFG.get(),
+            cmdq_base: regs.CMDQ_BASE.get(),
+            eventq_base: regs.EVENTQ_BASE.get(),
+            priq_base: if self.pri_supported() {
+                regs.PRIQ_BASE.get()
+            } else {
+                0
+            },
+            dpt_base: if self.dpt_supported() {
+                regs.DPT_BASE.get()
+            } else {
+                0
+            },
+            dpt_base_cfg: if self.dpt_supported() {
+                regs.DPT_BASE_CFG.get()
+            } else {
+                0
+            },
+        };
+
+        // Abort rather than bypass the transactions arriving once the SMMU is disabled.
+        self.update_gbpa(saved.gbpa | GBPA::ABORT::SET.value)?;
+        self.suspend_ecmdqs()?;
+        self.write_cr0(0)?;
+        self.suspended = Some(saved);
+        info!("SMMUv3 suspended");
+        Ok(())
+    }
+
+    /// Program the registers saved by [`SMMUv3::suspend`] again and enable the SMMU.
+    ///
+    /// All configuration and TLB entries are invalidated before the SMMU translates again, in
+    /// case the SMMU kept some across the suspend.
+    pub fn resume(&mut self) -> Result<(), SuspendError> {
+        let Some(saved) = self.suspended else {
+            return Err(SuspendError::NotSuspended);
+        };
+        if self.secure {
+            self.secure_invalidate_all();
+        }
+        // Transactions keep being aborted until the SMMU is enabled.
+        self.update_gbpa(saved.gbpa | GBPA::ABORT::SET.value)?;
+
+        let regs = self.regs();
+        regs.CR1.set(saved.cr1);
+        regs.CR2.set(saved.cr2);
+        regs.STRTAB_BASE_CFG.set(saved.strtab_base_cfg);
+        regs.STRTAB_BASE.set(saved.strtab_base);
+        regs.CMDQ_BASE.set(saved.cmdq_base);
+        let cmdq_prod = self.cmd_queue.prod_value();
+        regs.CMDQ_PROD.write(CMDQ_PROD::WR.val(cmdq_prod));
+        regs.CMDQ_CONS.write(CMDQ_CONS::RD.val(cmdq_prod));
+        // Events recorded after the suspend are lost with the queue indexes.
+        let eventq_cons = self.event_queue.cons_value();
+        regs.EVENTQ_BASE.set(saved.eventq_base);
+        regs.EVENTQ_PROD.write(EVENTQ_PROD::WR.val(eventq_cons));
+        regs.EVENTQ_CONS.write(EVENTQ_CONS::RD.val(eventq_cons));
+        regs.GERROR_IRQ_CFG0.set(saved.gerror_irq.cfg0);
+        regs.GERROR_IRQ_CFG1.set(saved.gerror_irq.cfg1);
+        regs.GERROR_IRQ_CFG2.set(saved.gerror_irq.cfg2);
+        regs.EVENTQ_IRQ_CFG0.set(saved.eventq_irq.cfg0);
+        regs.EVENTQ_IRQ_CFG1.set(saved.eventq_irq.cfg1);
+        regs.EVENTQ_IRQ_CFG2.set(saved.eventq_irq.cfg2);
+        let priq_cons = self.pri_supported().then(|| self.pri_queue.cons_value());
+        if let Some(priq_cons) = priq_cons {
+            regs.PRIQ_BASE.set(saved.priq_base);
+            regs.PRIQ_PROD.write(PRIQ_PROD::WR.val(priq_cons));
+            regs.PRIQ_CONS.write(PRIQ_CONS::RD.val(priq_cons));
+            regs.PRIQ_IRQ_CFG0.set(saved.priq_irq.cfg0);
+            regs.PRIQ_IRQ_CFG1.set(saved.priq_irq.cfg1);
+            regs.PRIQ_IRQ_CFG2.set(saved.priq_irq.cfg2);
+        }
+        let dpt = self.dpt_supported() && saved.cr0 & CR0::DPT_WALK_EN::SET.value != 0;
+        if dpt {
+            regs.DPT_BASE.set(saved.dpt_base);
+            regs.DPT_BASE_CFG.set(saved.dpt_base_cfg);
+        }
+        self.event_queue.set_prod_value(eventq_cons);
+        if let Some(priq_cons) = priq_cons {
+            self.pri_queue.set_prod_value(priq_cons);
+        }
+
+        // Enable the queues alone first, to invalidate before translating.
+        let queues = CR0::CMDQEN::SET + CR0::EVENTQEN::SET + CR0::PRIQEN::SET;
+        self.write_cr0(saved.cr0 & queues.value)?;
+        self.resume_ecmdqs()?;
+        let cmds = [Cmd::cmd_cfgi_all(), Cmd::cmd_tlbi_nsnh_all()];
+        self.add_cmds(cmds.into_iter().chain(dpt.then(Cmd::cmd_dpti_all)), true);
+        self.write_cr0(saved.cr0)?;
+
+        self.regs().IRQ_CTRL.set(saved.irq_ctrl);
+        if !(0..ARM_SMMU_SYNC_TIMEOUT).any(|_| self.regs().IRQ_CTRLACK.get() == saved.irq_ctrl) {
+            error!("SMMUv3 IRQ_CTRL update timeout");
+            return Err(SuspendError::Timeout);
+        }
+        self.update_gbpa(saved.gbpa)?;
+        self.suspended = None;
+        info!("SMMUv3 resumed");
+        Ok(())
+    }
+
+    /// Write SMMU_CR0 and wait for SMMU_CR0ACK to reflect it.
+    fn write_cr0(&self, cr0: u32) -> Result<(), SuspendError> {
+        self.regs().CR0.set(cr0);
+        if !(0..ARM_SMMU_SYNC_TIMEOUT).any(|_| self.regs().CR0ACK.get() == cr0) {
+            error!("SMMUv3 CR0 update timeout");
+            return Err(SuspendError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Write SMMU_GBPA with Update set and wait for the SMMU to clear it.
+    fn update_gbpa(&self, gbpa: u32) -> Result<(), SuspendError> {
+        self.regs().GBPA.set(gbpa | GBPA::UPDATE::SET.value);
+        if !(0..ARM_SMMU_SYNC_TIMEOUT).any(|_| !self.regs().GBPA.is_set(GBPA::UPDATE)) {
+            error!("SMMUv3 GBPA update timeout");
+            return Err(SuspendError::Timeout);
+        }
+        Ok(())
+    }
+}
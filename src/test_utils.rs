@@ -0,0 +1,36 @@
+//! Helpers shared by the unit tests.
+
+extern crate std;
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+use memory_addr::{pa, va, PhysAddr, VirtAddr, PAGE_SIZE_4K};
+
+use crate::hal::PagingHandler;
+
+/// Alignment of host allocations, enough for 64KB translation granules.
+const HOST_PAGE_ALIGN: usize = 0x10000;
+
+/// [`PagingHandler`] backed by the host heap, with physical addresses equal to virtual ones.
+pub struct HostPagingHandler;
+
+impl PagingHandler for HostPagingHandler {
+    const SID_BITS_SET: u32 = 8;
+    const CMDQ_EVENTQ_BITS_SET: u32 = 8;
+
+    fn alloc_pages(num_pages: usize) -> Option<PhysAddr> {
+        let layout = Layout::from_size_align(num_pages * PAGE_SIZE_4K, HOST_PAGE_ALIGN).unwrap();
+        Some(pa!(unsafe { alloc_zeroed(layout) } as usize))
+    }
+
+    fn dealloc_pages(paddr: PhysAddr, num_pages: usize) {
+        let layout = Layout::from_size_align(num_pages * PAGE_SIZE_4K, HOST_PAGE_ALIGN).unwrap();
+        unsafe { dealloc(paddr.as_usize() as *mut u8, layout) }
+    }
+
+    fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+        va!(paddr.as_usize())
+    }
+
+    fn flush(_start: usize, _len: usize) {}
+}
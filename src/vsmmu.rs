@@ -0,0 +1,736 @@
+//! Emulated SMMUv3 register frame for guests.
+//!
+//! A hypervisor giving a guest its own SMMU traps the guest accesses to the [`SMMUv3Regs`] frame
+//! and forwards them to a [`VSmmu`]. The emulated SMMU offers stage 1 translation only, with the
+//! ID registers of the host SMMU filtered down to the features the hypervisor can emulate on top
+//! of its own stage 2 tables: no stage 2, PRI, ATS, stalls, MSIs or address translation
+//! operations.
+//!
+//! [`VSmmu`] implements the register semantics, the CR0/CR0ACK and IRQ_CTRL/IRQ_CTRLACK
+//! acknowledgements and the GERROR/GERRORN handshake, and keeps the Stream table and queue base
+//! registers the guest programs. Everything touching guest memory is left to the hypervisor,
+//! through the [`VSmmuHooks`] called on the writes that need it: reading the guest Stream table
+//! when the guest enables translation, consuming the guest Command queue when it produces
+//! commands, and so on.
+
+use core::mem::offset_of;
+
+use tock_registers::interfaces::Readable;
+use tock_registers::LocalRegisterCopy;
+
+use crate::backend::RegisterBackend;
+use crate::hal::PagingHandler;
+use crate::irq::{GlobalErrors, IrqSource};
+use crate::queue::EVTQ_ENT_DWORDS;
+use crate::regs::*;
+use crate::{SMMUv3, SMMUv3Regs};
+
+/// Offsets of the emulated registers.
+mod offset {
+    use super::*;
+
+    pub const IDR0: usize = offset_of!(SMMUv3Regs, IDR0);
+    pub const IDR5: usize = offset_of!(SMMUv3Regs, IDR5);
+    pub const IIDR: usize = offset_of!(SMMUv3Regs, IIDR);
+    pub const AIDR: usize = offset_of!(SMMUv3Regs, AIDR);
+    pub const CR0: usize = offset_of!(SMMUv3Regs, CR0);
+    pub const CR0ACK: usize = offset_of!(SMMUv3Regs, CR0ACK);
+    pub const CR1: usize = offset_of!(SMMUv3Regs, CR1);
+    pub const CR2: usize = offset_of!(SMMUv3Regs, CR2);
+    pub const IRQ_CTRL: usize = offset_of!(SMMUv3Regs, IRQ_CTRL);
+    pub const IRQ_CTRLACK: usize = offset_of!(SMMUv3Regs, IRQ_CTRLACK);
+    pub const GERROR: usize = offset_of!(SMMUv3Regs, GERROR);
+    pub const GERRORN: usize = offset_of!(SMMUv3Regs, GERRORN);
+    pub const STRTAB_BASE: usize = offset_of!(SMMUv3Regs, STRTAB_BASE);
+    pub const STRTAB_BASE_CFG: usize = offset_of!(SMMUv3Regs, STRTAB_BASE_CFG);
+    pub const CMDQ_BASE: usize = offset_of!(SMMUv3Regs, CMDQ_BASE);
+    pub const CMDQ_PROD: usize = offset_of!(SMMUv3Regs, CMDQ_PROD);
+    pub const CMDQ_CONS: usize = offset_of!(SMMUv3Regs, CMDQ_CONS);
+    pub const EVENTQ_BASE: usize = offset_of!(SMMUv3Regs, EVENTQ_BASE);
+    pub const EVENTQ_PROD: usize = offset_of!(SMMUv3Regs, EVENTQ_PROD);
+    pub const EVENTQ_CONS: usize = offset_of!(SMMUv3Regs, EVENTQ_CONS);
+}
+
+/// IDR5 fields passed through to the guest: VAX, the translation granules and OAS.
+const IDR5_GUEST_MASK: u32 = 0b11 << 10 | 0b111 << 4 | 0b111;
+
+/// Whether the 64-bit register at `offset` is emulated.
+fn is_reg64(offset: usize) -> bool {
+    matches!(
+        offset,
+        offset::STRTAB_BASE | offset::CMDQ_BASE | offset::EVENTQ_BASE
+    )
+}
+
+/// Callbacks of the hypervisor on the guest register writes with side effects.
+///
+/// Hooks are called once the written register is updated, and may update the emulated state in
+/// turn, for example consume the commands and advance CMDQ_CONS. The default implementations do
+/// nothing.
+pub trait VSmmuHooks {
+    /// The guest wrote CR0, previously `old`.
+    ///
+    /// CR0ACK takes the value of CR0 once this returns: the hypervisor reads the guest Stream table
+    /// here when SMMUEN is set, and stops using it when SMMUEN is cleared.
+    fn cr0_update(&mut self, _vsmmu: &mut VSmmu, _old: u32) {}
+
+    /// The guest wrote IRQ_CTRL, previously `old`, IRQ_CTRLACK follows once this returns.
+    fn irq_ctrl_update(&mut self, _vsmmu: &mut VSmmu, _old: u32) {}
+
+    /// The guest acknowledged global errors by writing GERRORN.
+    fn gerrorn_update(&mut self, _vsmmu: &mut VSmmu) {}
+
+    /// The guest produced commands, up to [`VSmmu::cmdq_prod`], while the Command queue is
+    /// enabled.
+    fn cmdq_prod_update(&mut self, _vsmmu: &mut VSmmu) {}
+
+    /// The guest consumed events, up to [`VSmmu::eventq_cons`], while the Event queue is enabled.
+    fn eventq_cons_update(&mut self, _vsmmu: &mut VSmmu) {}
+
+    /// Write `data` at the guest physical address `ipa`, returns whether it is guest memory.
+    fn write_guest(&mut self, _ipa: u64, _data: &[u64]) -> bool {
+        false
+    }
+
+    /// Signal the guest interrupt `source`.
+    fn inject_irq(&mut self, _source: IrqSource) {}
+}
+
+impl VSmmuHooks for () {}
+
+/// Reasons an event record is not delivered to the guest Event queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestEventError {
+    /// The guest has not enabled the SMMU and its Event queue, records are discarded.
+    Disabled,
+    /// The guest Event queue is full, the record is lost.
+    Overflow,
+    /// The guest Event queue is not in guest memory, EVENTQ_ABT_ERR is raised.
+    Abort,
+}
+
+/// Registers of an emulated SMMUv3.
+///
+/// Registers that are not emulated are RAZ/WI, so are accesses of the wrong size.
+#[derive(Debug, Clone)]
+pub struct VSmmu {
+    idr: [u32; 6],
+    iidr: u32,
+    aidr: u32,
+    cr0: u32,
+    cr0ack: u32,
+    cr1: u32,
+    cr2: u32,
+    irq_ctrl: u32,
+    irq_ctrlack: u32,
+    gerror: u32,
+    gerrorn: u32,
+    strtab_base: u64,
+    strtab_base_cfg: u32,
+    cmdq_base: u64,
+    cmdq_prod: u32,
+    cmdq_cons: u32,
+    eventq_base: u64,
+    eventq_prod: u32,
+    eventq_cons: u32,
+}
+
+impl VSmmu {
+    /// An emulated SMMU in its reset state, advertising the features of `host_idr`, the ID
+    /// registers IDR0 to IDR5 of the host SMMU, that a guest can use.
+    pub fn new(host_idr: [u32; 6]) -> Self {
+        let idr0 = LocalRegisterCopy::<u32, IDR0::Register>::new(host_idr[0]);
+        let guest_idr0 = IDR0::ST_LEVEL.val(idr0.read(IDR0::ST_LEVEL))
+            + IDR0::STALL_MODEL::Terminate
+            + IDR0::TTENDIAN.val(idr0.read(IDR0::TTENDIAN))
+            + IDR0::CD2L.val(idr0.read(IDR0::CD2L))
+            + IDR0::ASID16.val(idr0.read(IDR0::ASID16))
+            + IDR0::HTTU.val(idr0.read(IDR0::HTTU))
+            + IDR0::CHOACC.val(idr0.read(IDR0::CHOACC))
+            + IDR0::TTF.val(idr0.read(IDR0::TTF))
+            + IDR0::S1P.val(idr0.read(IDR0::S1P));
+
+        let idr1 = LocalRegisterCopy::<u32, IDR1::Register>::new(host_idr[1]);
+        let guest_idr1 = IDR1::CMDQS.val(idr1.read(IDR1::CMDQS))
+            + IDR1::EVENTQS.val(idr1.read(IDR1::EVENTQS))
+            + IDR1::SSIDSIZE.val(idr1.read(IDR1::SSIDSIZE))
+            + IDR1::SIDSIZE.val(idr1.read(IDR1::SIDSIZE));
+
+        Self {
+            idr: [
+                guest_idr0.value,
+                guest_idr1.value,
+                0,
+                0,
+                0,
+                host_idr[5] & IDR5_GUEST_MASK,
+            ],
+            iidr: 0,
+            aidr: 0,
+            cr0: 0,
+            cr0ack: 0,
+            cr1: 0,
+            cr2: 0,
+            irq_ctrl: 0,
+            irq_ctrlack: 0,
+            gerror: 0,
+            gerrorn: 0,
+            strtab_base: 0,
+            strtab_base_cfg: 0,
+            cmdq_base: 0,
+            cmdq_prod: 0,
+            cmdq_cons: 0,
+            eventq_base: 0,
+            eventq_prod: 0,
+            eventq_cons: 0,
+        }
+    }
+
+    /// An emulated SMMU advertising the features of `smmu` that a guest can use, with the same
+    /// IIDR and AIDR.
+    pub fn from_host<H: PagingHandler, B: RegisterBackend>(smmu: &SMMUv3<H, B>) -> Self {
+        let regs = smmu.regs();
+        let mut vsmmu = Self::new([
+            regs.IDR0.get(),
+            regs.IDR1.get(),
+            regs.IDR2.get(),
+            regs.IDR3.get(),
+            regs.IDR4.get(),
+            regs.IDR5.get(),
+        ]);
+        vsmmu.iidr = regs.IIDR.get();
+        vsmmu.aidr = regs.AIDR.get();
+        vsmmu
+    }
+
+    /// Override ID register IDR`n`, for example to reduce the queue sizes or the StreamID size.
+    pub fn set_idr(&mut self, n: usize, value: u32) {
+        self.idr[n] = value;
+    }
+
+    /// The ID register IDR`n` read by the guest.
+    pub fn idr(&self, n: usize) -> u32 {
+        self.idr[n]
+    }
+
+    /// Handle a guest read of `width` bytes at `offset` of the register frame.
+    pub fn read(&self, offset: usize, width: usize) -> u64 {
+        match width {
+            4 if is_reg64(offset & !7) => {
+                let value = self.read_reg(offset & !7);
+                (value >> ((offset & 4) * 8)) as u32 as u64
+            }
+            4 if offset & 3 == 0 => self.read_reg(offset),
+            8 if is_reg64(offset) => self.read_reg(offset),
+            _ => {
+                warn!(
+                    "vSMMU: unsupported read of {} bytes at {:#x}",
+                    width, offset
+                );
+                0
+            }
+        }
+    }
+
+    /// Handle a guest write of `width` bytes at `offset` of the register frame, calling the
+    /// `hooks` of the registers with side effects.
+    pub fn write<K: VSmmuHooks + ?Sized>(
+        &mut self,
+        offset: usize,
+        width: usize,
+        value: u64,
+        hooks: &mut K,
+    ) {
+        match width {
+            4 if is_reg64(offset & !7) => {
+                let shift = (offset & 4) * 8;
+                let old = self.read_reg(offset & !7);
+                let value = old & !(0xffff_ffff << shift) | (value & 0xffff_ffff) << shift;
+                self.write_reg64(offset & !7, value);
+            }
+            4 if offset & 3 == 0 => self.write_reg32(offset, value as u32, hooks),
+            8 if is_reg64(offset) => self.write_reg64(offset, value),
+            _ => warn!(
+                "vSMMU: unsupported write of {} bytes at {:#x}",
+                width, offset
+            ),
+        }
+    }
+
+    fn read_reg(&self, offset: usize) -> u64 {
+        let value = match offset {
+            offset::IDR0..=offset::IDR5 => self.idr[(offset - offset::IDR0) / 4],
+            offset::IIDR => self.iidr,
+            offset::AIDR => self.aidr,
+            offset::CR0 => self.cr0,
+            offset::CR0ACK => self.cr0ack,
+            offset::CR1 => self.cr1,
+            offset::CR2 => self.cr2,
+            offset::IRQ_CTRL => self.irq_ctrl,
+            offset::IRQ_CTRLACK => self.irq_ctrlack,
+            offset::GERROR => self.gerror,
+            offset::GERRORN => self.gerrorn,
+            offset::STRTAB_BASE => return self.strtab_base,
+            offset::STRTAB_BASE_CFG => self.strtab_base_cfg,
+            offset::CMDQ_BASE => return self.cmdq_base,
+            offset::CMDQ_PROD => self.cmdq_prod,
+            offset::CMDQ_CONS => self.cmdq_cons,
+            offset::EVENTQ_BASE => return self.eventq_base,
+            offset::EVENTQ_PROD => self.eventq_prod,
+            offset::EVENTQ_CONS => self.eventq_cons,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write_reg32<K: VSmmuHooks + ?Sized>(&mut self, offset: usize, value: u32, hooks: &mut K) {
+        match offset {
+            offset::CR0 => {
+                let old = self.cr0;
+                self.cr0 =
+                    value & (CR0::SMMUEN::SET + CR0::EVENTQEN::SET + CR0::CMDQEN::SET).mask();
+                hooks.cr0_update(self, old);
+                self.cr0ack = self.cr0;
+            }
+            offset::CR1 if !self.is_enabled() => self.cr1 = value & 0xfff,
+            offset::CR2 if !self.is_enabled() => self.cr2 = value & 0xf,
+            offset::IRQ_CTRL => {
+                let old = self.irq_ctrl;
+                self.irq_ctrl =
+                    value & (IRQ_CTRL::GERROR_IRQEN::SET + IRQ_CTRL::EVENTQ_IRQEN::SET).mask();
+                hooks.irq_ctrl_update(self, old);
+                self.irq_ctrlack = self.irq_ctrl;
+            }
+            offset::GERRORN => {
+                self.gerrorn = value & GlobalErrors::all().bits();
+                hooks.gerrorn_update(self);
+            }
+            offset::STRTAB_BASE_CFG if !self.is_enabled() => {
+                self.strtab_base_cfg = value
+                    & (STRTAB_BASE_CFG::FMT::SET
+                        + STRTAB_BASE_CFG::SPLIT::SET
+                        + STRTAB_BASE_CFG::LOG2SIZE::SET)
+                        .mask();
+            }
+            offset::CMDQ_PROD => {
+                self.cmdq_prod = value & CMDQ_PROD::WR::SET.mask();
+                if self.cmdq_enabled() {
+                    hooks.cmdq_prod_update(self);
+                }
+            }
+            offset::CMDQ_CONS if !self.cmdq_enabled() => {
+                self.cmdq_cons = value & CMDQ_CONS::RD::SET.mask();
+            }
+            offset::EVENTQ_PROD if !self.eventq_enabled() => {
+                self.eventq_prod = value & EVENTQ_PROD::WR::SET.mask();
+            }
+            offset::EVENTQ_CONS => {
+                self.eventq_cons =
+                    value & (EVENTQ_CONS::OVACKFLG::SET + EVENTQ_CONS::RD::SET).mask();
+                if self.eventq_enabled() {
+                    hooks.eventq_cons_update(self);
+                }
+            }
+            _ => trace!("vSMMU: ignored write {:#x} at {:#x}", value, offset),
+        }
+    }
+
+    fn write_reg64(&mut self, offset: usize, value: u64) {
+        match offset {
+            offset::STRTAB_BASE if !self.is_enabled() => {
+                self.strtab_base = value & (STRTAB_BASE::RA::SET + STRTAB_BASE::ADDR::SET).mask();
+            }
+            offset::CMDQ_BASE if !self.cmdq_enabled() => {
+                self.cmdq_base = value
+                    & (CMDQ_BASE::RA::SET + CMDQ_BASE::ADDR::SET + CMDQ_BASE::LOG2SIZE::SET).mask();
+            }
+            offset::EVENTQ_BASE if !self.eventq_enabled() => {
+                self.eventq_base = value
+                    & (EVENTQ_BASE::WA::SET + EVENTQ_BASE::ADDR::SET + EVENTQ_BASE::LOG2SIZE::SET)
+                        .mask();
+            }
+            _ => trace!("vSMMU: ignored write {:#x} at {:#x}", value, offset),
+        }
+    }
+
+    /// CR0 as last written by the guest.
+    pub fn cr0(&self) -> LocalRegisterCopy<u32, CR0::Register> {
+        LocalRegisterCopy::new(self.cr0)
+    }
+
+    /// Whether the guest enabled translation, with CR0ACK.SMMUEN.
+    pub fn is_enabled(&self) -> bool {
+        LocalRegisterCopy::<u32, CR0ACK::Register>::new(self.cr0ack).is_set(CR0ACK::SMMUEN)
+    }
+
+    /// Whether the guest Command queue is enabled, with CR0ACK.CMDQEN.
+    pub fn cmdq_enabled(&self) -> bool {
+        LocalRegisterCopy::<u32, CR0ACK::Register>::new(self.cr0ack).is_set(CR0ACK::CMDQEN)
+    }
+
+    /// Whether the guest Event queue is enabled, with CR0ACK.EVENTQEN.
+    pub fn eventq_enabled(&self) -> bool {
+        LocalRegisterCopy::<u32, CR0ACK::Register>::new(self.cr0ack).is_set(CR0ACK::EVENTQEN)
+    }
+
+    /// CR1, the guest memory attributes of the Stream table and queue accesses.
+    pub fn cr1(&self) -> LocalRegisterCopy<u32, CR1::Register> {
+        LocalRegisterCopy::new(self.cr1)
+    }
+
+    /// IRQ_CTRLACK, the interrupts enabled by the guest.
+    pub fn irq_ctrl(&self) -> LocalRegisterCopy<u32, IRQ_CTRL::Register> {
+        LocalRegisterCopy::new(self.irq_ctrlack)
+    }
+
+    /// The guest Stream table base address.
+    pub fn strtab_base(&self) -> LocalRegisterCopy<u64, STRTAB_BASE::Register> {
+        LocalRegisterCopy::new(self.strtab_base)
+    }
+
+    /// The guest Stream table format and size.
+    pub fn strtab_base_cfg(&self) -> LocalRegisterCopy<u32, STRTAB_BASE_CFG::Register> {
+        LocalRegisterCopy::new(self.strtab_base_cfg)
+    }
+
+    /// The guest Command queue base address and size.
+    pub fn cmdq_base(&self) -> LocalRegisterCopy<u64, CMDQ_BASE::Register> {
+        LocalRegisterCopy::new(self.cmdq_base)
+    }
+
+    /// CMDQ_PROD.WR, the guest Command queue write index and its wrap flag.
+    pub fn cmdq_prod(&self) -> u32 {
+        self.cmdq_prod
+    }
+
+    /// CMDQ_CONS.RD, the guest Command queue read index and its wrap flag.
+    pub fn cmdq_cons(&self) -> u32 {
+        self.cmdq_cons & CMDQ_CONS::RD::SET.mask()
+    }
+
+    /// Advance CMDQ_CONS.RD past the commands consumed by the hypervisor.
+    pub fn set_cmdq_cons(&mut self, rd: u32) {
+        self.cmdq_cons =
+            self.cmdq_cons & !CMDQ_CONS::RD::SET.mask() | rd & CMDQ_CONS::RD::SET.mask();
+    }
+
+    /// Stop the guest Command queue on the command at CMDQ_CONS.RD with error `err`, a
+    /// CMDQ_CONS.ERR code. Returns whether the guest global error interrupt must be raised.
+    pub fn cmdq_error(&mut self, err: u32) -> bool {
+        self.cmdq_cons =
+            self.cmdq_cons & !CMDQ_CONS::ERR::SET.mask() | CMDQ_CONS::ERR.val(err).value;
+        self.raise_gerror(GlobalErrors::CMDQ_ERR)
+    }
+
+    /// The guest Event queue base address and size.
+    pub fn eventq_base(&self) -> LocalRegisterCopy<u64, EVENTQ_BASE::Register> {
+        LocalRegisterCopy::new(self.eventq_base)
+    }
+
+    /// EVENTQ_PROD, the guest Event queue write index with its wrap and overflow flags.
+    pub fn eventq_prod(&self) -> LocalRegisterCopy<u32, EVENTQ_PROD::Register> {
+        LocalRegisterCopy::new(self.eventq_prod)
+    }
+
+    /// Publish the events written by the hypervisor, up to `prod`, with its overflow flag.
+    pub fn set_eventq_prod(&mut self, prod: u32) {
+        self.eventq_prod = prod & (EVENTQ_PROD::OVSLG::SET + EVENTQ_PROD::WR::SET).mask();
+    }
+
+    /// EVENTQ_CONS, the guest Event queue read index with its overflow acknowledgement.
+    pub fn eventq_cons(&self) -> LocalRegisterCopy<u32, EVENTQ_CONS::Register> {
+        LocalRegisterCopy::new(self.eventq_cons)
+    }
+
+    /// The global errors not yet acknowledged by the guest.
+    pub fn active_gerrors(&self) -> GlobalErrors {
+        GlobalErrors::from_bits_truncate(self.gerror ^ self.gerrorn)
+    }
+
+    /// Toggle the GERROR bits of `errors` that are not already active.
+    ///
+    /// Returns whether an error was raised with the guest global error interrupt enabled, in
+    /// which case the hypervisor injects it.
+    pub fn raise_gerror(&mut self, errors: GlobalErrors) -> bool {
+        let new = errors.difference(self.active_gerrors());
+        self.gerror ^= new.bits();
+        !new.is_empty() && self.irq_ctrl().is_set(IRQ_CTRL::GERROR_IRQEN)
+    }
+
+    /// Write `record` to the guest Event queue as the SMMU would, signalling the guest Event queue
+    /// interrupt if enabled.
+    ///
+    /// The record comes from [`Event::guest_record`]. Once the queue is full, records are lost and
+    /// the queue enters the overflow condition until the guest acknowledges it.
+    ///
+    /// [`Event::guest_record`]: crate::Event::guest_record
+    pub fn inject_event<K: VSmmuHooks + ?Sized>(
+        &mut self,
+        record: &[u64; EVTQ_ENT_DWORDS],
+        hooks: &mut K,
+    ) -> Result<(), GuestEventError> {
+        if !self.is_enabled() || !self.eventq_enabled() {
+            return Err(GuestEventError::Disabled);
+        }
+        let base = self.eventq_base();
+        let eventqs =
+            LocalRegisterCopy::<u32, IDR1::Register>::new(self.idr[1]).read(IDR1::EVENTQS);
+        let qs = (base.read(EVENTQ_BASE::LOG2SIZE) as u32).min(eventqs);
+        let wrap_mask = (1 << (qs + 1)) - 1;
+        let prod = self.eventq_prod().read(EVENTQ_PROD::WR) & wrap_mask;
+        let cons = self.eventq_cons().read(EVENTQ_CONS::RD) & wrap_mask;
+        let irq = self.irq_ctrl().is_set(IRQ_CTRL::EVENTQ_IRQEN);
+
+        if prod ^ cons == 1 << qs {
+            // The overflow flag toggles unless the previous overflow is not acknowledged yet.
+            if self.eventq_prod().read(EVENTQ_PROD::OVSLG)
+                == self.eventq_cons().read(EVENTQ_CONS::OVACKFLG)
+            {
+                self.eventq_prod ^= EVENTQ_PROD::OVSLG::SET.mask();
+                if irq {
+                    hooks.inject_irq(IrqSource::EventQueue);
+                }
+            }
+            return Err(GuestEventError::Overflow);
+        }
+
+        let index = (prod & ((1 << qs) - 1)) as u64;
+        let ipa = (base.read(EVENTQ_BASE::ADDR) << 5) + index * size_of_val(record) as u64;
+        if !hooks.write_guest(ipa, record) {
+            if self.raise_gerror(GlobalErrors::EVENTQ_ABT_ERR) {
+                hooks.inject_irq(IrqSource::GlobalError);
+            }
+            return Err(GuestEventError::Abort);
+        }
+        self.eventq_prod =
+            self.eventq_prod & EVENTQ_PROD::OVSLG::SET.mask() | (prod + 1) & wrap_mask;
+        if irq {
+            hooks.inject_irq(IrqSource::EventQueue);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::cell::RefCell;
+    use std::vec::Vec;
+
+    use memory_addr::pa;
+    use tock_registers::interfaces::Writeable;
+
+    use super::*;
+    use crate::test_utils::HostPagingHandler;
+    use crate::{Event, StreamId};
+
+    /// Hypervisor consuming the guest commands right away.
+    #[derive(Default)]
+    struct Hypervisor {
+        enables: Vec<u32>,
+        commands: usize,
+    }
+
+    impl VSmmuHooks for Hypervisor {
+        fn cr0_update(&mut self, vsmmu: &mut VSmmu, _old: u32) {
+            self.enables.push(vsmmu.cr0().get());
+        }
+
+        fn cmdq_prod_update(&mut self, vsmmu: &mut VSmmu) {
+            let mask = (1 << (vsmmu.cmdq_base().read(CMDQ_BASE::LOG2SIZE) + 1)) - 1;
+            self.commands += (vsmmu.cmdq_prod().wrapping_sub(vsmmu.cmdq_cons()) & mask) as usize;
+            vsmmu.set_cmdq_cons(vsmmu.cmdq_prod());
+        }
+    }
+
+    /// Guest accesses trapped to the [`VSmmu`].
+    struct Trap {
+        vsmmu: RefCell<VSmmu>,
+        hyp: RefCell<Hypervisor>,
+    }
+
+    impl RegisterBackend for Trap {
+        fn read32(&self, offset: usize) -> u32 {
+            self.vsmmu.borrow().read(offset, 4) as u32
+        }
+
+        fn write32(&self, offset: usize, value: u32) {
+            let mut hyp = self.hyp.borrow_mut();
+            self.vsmmu
+                .borrow_mut()
+                .write(offset, 4, value as u64, &mut *hyp)
+        }
+
+        fn read64(&self, offset: usize) -> u64 {
+            self.vsmmu.borrow().read(offset, 8)
+        }
+
+        fn write64(&self, offset: usize, value: u64) {
+            let mut hyp = self.hyp.borrow_mut();
+            self.vsmmu.borrow_mut().write(offset, 8, value, &mut *hyp)
+        }
+    }
+
+    #[test]
+    fn test_guest_driver() {
+        let host_idr0 = IDR0::ST_LEVEL::TwoLevelStreamTableInAdditionToLinearStreamTable
+            + IDR0::STALL_MODEL::StallAndTerminate
+            + IDR0::VMID16::Supported
+            + IDR0::PRI::Supported
+            + IDR0::ATOS::Supported
+            + IDR0::MSI::Supported
+            + IDR0::ASID16::Supported
+            + IDR0::TTF::VMSAV8_64
+            + IDR0::S1P::Supported
+            + IDR0::S2P::Supported;
+        let host_idr1 =
+            IDR1::PRIQS.val(8) + IDR1::CMDQS.val(8) + IDR1::EVENTQS.val(8) + IDR1::SIDSIZE.val(8);
+        let vsmmu = VSmmu::new([host_idr0.value, host_idr1.value, 0, 0, 0, 0x0075]);
+
+        let idr0 = LocalRegisterCopy::<u32, IDR0::Register>::new(vsmmu.idr(0));
+        assert!(idr0.is_set(IDR0::S1P) && idr0.is_set(IDR0::ASID16));
+        for field in [IDR0::S2P, IDR0::PRI, IDR0::ATOS, IDR0::MSI, IDR0::VMID16] {
+            assert!(!idr0.is_set(field));
+        }
+        assert_eq!(idr0.read(IDR0::STALL_MODEL), 0b01);
+        assert_eq!(
+            vsmmu.idr(1),
+            (IDR1::CMDQS.val(8) + IDR1::EVENTQS.val(8) + IDR1::SIDSIZE.val(8)).value
+        );
+        assert_eq!(vsmmu.idr(5), 0x0075);
+
+        let trap = Trap {
+            vsmmu: RefCell::new(vsmmu),
+            hyp: RefCell::new(Hypervisor::default()),
+        };
+        let mut smmu = SMMUv3::<HostPagingHandler, _>::with_backend(trap);
+        smmu.init();
+        assert!(smmu.regs().CR0ACK.is_set(CR0ACK::SMMUEN));
+        assert!(!smmu.regs().CR0ACK.is_set(CR0ACK::PRIQEN));
+
+        // The queues and the Stream table were programmed before the guest enabled the SMMU.
+        {
+            let vsmmu = smmu.backend().vsmmu.borrow();
+            assert!(vsmmu.is_enabled() && vsmmu.cmdq_enabled() && vsmmu.eventq_enabled());
+            assert_ne!(vsmmu.strtab_base().read(STRTAB_BASE::ADDR), 0);
+            assert_eq!(vsmmu.strtab_base_cfg().read(STRTAB_BASE_CFG::LOG2SIZE), 8);
+            assert_eq!(vsmmu.cmdq_base().read(CMDQ_BASE::LOG2SIZE), 8);
+            assert_eq!(vsmmu.eventq_base().read(EVENTQ_BASE::LOG2SIZE), 8);
+            let hyp = smmu.backend().hyp.borrow();
+            assert_eq!(hyp.enables.last(), Some(&0b1101));
+        }
+
+        // Base registers are frozen while in use, whatever the access size.
+        let eventq_base = smmu.regs().EVENTQ_BASE.get();
+        smmu.regs().STRTAB_BASE.set(0);
+        assert_ne!(smmu.regs().STRTAB_BASE.get(), 0);
+        smmu.backend().write32(offset::EVENTQ_BASE + 4, 0);
+        assert_eq!(smmu.regs().EVENTQ_BASE.get(), eventq_base);
+
+        // Commands are consumed by the hypervisor, so the driver does not time out on CMD_SYNC.
+        let commands = smmu.backend().hyp.borrow().commands;
+        let s2pt = HostPagingHandler::alloc_pages(1).unwrap();
+        smmu.add_device(StreamId::new(3), 1, pa!(s2pt.as_usize()))
+            .unwrap();
+        assert!(smmu.backend().hyp.borrow().commands > commands);
+
+        // GERROR handshake.
+        smmu.regs()
+            .IRQ_CTRL
+            .write(IRQ_CTRL::GERROR_IRQEN::SET + IRQ_CTRL::PRIQ_IRQEN::SET);
+        assert_eq!(smmu.regs().IRQ_CTRLACK.get(), 1);
+        assert!(smmu.backend().vsmmu.borrow_mut().cmdq_error(1));
+        // Already active, no new interrupt.
+        assert!(!smmu
+            .backend()
+            .vsmmu
+            .borrow_mut()
+            .raise_gerror(GlobalErrors::CMDQ_ERR));
+        assert_eq!(smmu.handle_gerror_irq(), GlobalErrors::CMDQ_ERR);
+        assert!(smmu.backend().vsmmu.borrow().active_gerrors().is_empty());
+        assert!(smmu
+            .backend()
+            .vsmmu
+            .borrow_mut()
+            .raise_gerror(GlobalErrors::CMDQ_ERR));
+    }
+
+    /// Guest memory of four Event queue records at `GUEST_EVENTQ`.
+    #[derive(Default)]
+    struct GuestMemory {
+        eventq: [[u64; EVTQ_ENT_DWORDS]; 4],
+        irqs: Vec<IrqSource>,
+    }
+
+    const GUEST_EVENTQ: u64 = 0x8000_0000;
+
+    impl VSmmuHooks for GuestMemory {
+        fn write_guest(&mut self, ipa: u64, data: &[u64]) -> bool {
+            let Some(entry) = ipa.checked_sub(GUEST_EVENTQ) else {
+                return false;
+            };
+            match self.eventq.get_mut(entry as usize / 32) {
+                Some(record) => {
+                    record.copy_from_slice(data);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn inject_irq(&mut self, source: IrqSource) {
+            self.irqs.push(source);
+        }
+    }
+
+    #[test]
+    fn test_inject_event() {
+        let mut mem = GuestMemory::default();
+        let mut vsmmu = VSmmu::new([0, IDR1::EVENTQS.val(8).value, 0, 0, 0, 0]);
+        // A translation fault of stream 0x42.
+        let event = Event::from_raw([0x10 | 0x42 << 32, 0, 0x1000, 0]);
+        let record = event.guest_record(7);
+        assert_eq!(Event::from_raw(record).sid, 7);
+        assert_eq!(record[2], 0x1000);
+        assert_eq!(
+            vsmmu.inject_event(&record, &mut mem),
+            Err(GuestEventError::Disabled)
+        );
+
+        vsmmu.write(offset::EVENTQ_BASE, 8, GUEST_EVENTQ | 2, &mut mem);
+        vsmmu.write(offset::IRQ_CTRL, 4, 0b101, &mut mem);
+        vsmmu.write(offset::CR0, 4, 0b101, &mut mem);
+        for _ in 0..4 {
+            vsmmu.inject_event(&record, &mut mem).unwrap();
+        }
+        assert_eq!(mem.eventq[3], record);
+        assert_eq!(vsmmu.eventq_prod().read(EVENTQ_PROD::WR), 0b100);
+
+        // Full, the queue overflows once until the guest acknowledges it.
+        assert_eq!(
+            vsmmu.inject_event(&record, &mut mem),
+            Err(GuestEventError::Overflow)
+        );
+        assert_eq!(
+            vsmmu.inject_event(&record, &mut mem),
+            Err(GuestEventError::Overflow)
+        );
+        assert!(vsmmu.eventq_prod().is_set(EVENTQ_PROD::OVSLG));
+        assert_eq!(mem.irqs, [IrqSource::EventQueue; 5]);
+        vsmmu.write(offset::EVENTQ_CONS, 4, 1 << 31 | 0b100, &mut mem);
+        vsmmu.inject_event(&record, &mut mem).unwrap();
+        assert_eq!(vsmmu.eventq_prod().get(), 1 << 31 | 0b101);
+
+        // A queue outside of guest memory aborts.
+        vsmmu.write(offset::CR0, 4, 0b001, &mut mem);
+        vsmmu.write(offset::EVENTQ_BASE, 8, 0x1000 | 2, &mut mem);
+        vsmmu.write(offset::CR0, 4, 0b101, &mut mem);
+        assert_eq!(
+            vsmmu.inject_event(&record, &mut mem),
+            Err(GuestEventError::Abort)
+        );
+        assert_eq!(vsmmu.active_gerrors(), GlobalErrors::EVENTQ_ABT_ERR);
+        assert_eq!(mem.irqs.last(), Some(&IrqSource::GlobalError));
+    }
+}
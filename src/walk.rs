@@ -0,0 +1,525 @@
+//! Software walk of the SMMU configuration structures and translation tables.
+//!
+//! Follows the translation procedure of 3.3: the STE selects the enabled stages, stage 1 is
+//! located through the CD table and stage 2 through STE.S2TTB, both using VMSAv8-64 descriptors.
+//! With both stages enabled, CD and stage 1 table addresses are IPAs translated by stage 2.
+//! Structures are read through [`PagingHandler::phys_to_virt`], and never updated.
+
+use memory_addr::{PhysAddr, PAGE_SIZE_4K};
+
+use crate::context_descriptor::ContextDescriptor;
+use crate::hal::PagingHandler;
+use crate::stream_table::{SteConfig, SteS1, SteS2, StreamTableEntry};
+
+/// STE.S1DSS encodings.
+const S1DSS_TERMINATE: u64 = 0b00;
+const S1DSS_BYPASS: u64 = 0b01;
+const S1DSS_SSID0: u64 = 0b10;
+
+const DESC_VALID: u64 = 1 << 0;
+const DESC_TABLE: u64 = 1 << 1;
+/// S2AP[0] at stage 2, AP[1] at stage 1.
+const DESC_AP_6: u64 = 1 << 6;
+/// S2AP[1] at stage 2, AP[2] at stage 1.
+const DESC_AP_7: u64 = 1 << 7;
+const DESC_AF: u64 = 1 << 10;
+/// UXN and PXN at stage 1, XN[1:0] at stage 2.
+const DESC_XN: u64 = 0b11 << 53;
+/// Output addresses are limited to 48 bits.
+const OA_BITS: u32 = 48;
+
+/// Access performed by a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Data read.
+    Read,
+    /// Data write.
+    Write,
+    /// Instruction fetch, which also needs read permission.
+    Exec,
+}
+
+/// Outcome of a successful walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    /// Output physical address.
+    pub pa: PhysAddr,
+    /// Size of the naturally aligned region sharing the translation, the smaller of both stages.
+    pub size: usize,
+    /// Both stages permit data reads.
+    pub readable: bool,
+    /// Both stages permit data writes.
+    pub writable: bool,
+    /// Both stages permit instruction fetches.
+    pub executable: bool,
+}
+
+/// Fault raised by a walk, named after the event the SMMU records for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslationFault {
+    /// STE.Config aborts the stream, no event is recorded.
+    Abort,
+    /// C_BAD_STREAMID, the StreamID is beyond the stream table.
+    BadStreamId,
+    /// C_BAD_STE, the STE is invalid or uses a format the walker does not support.
+    BadSte,
+    /// F_STREAM_DISABLED, STE.S1DSS terminates transactions without SubstreamID.
+    StreamDisabled,
+    /// C_BAD_SUBSTREAMID, the SubstreamID is beyond the CD table.
+    BadSubstreamId,
+    /// C_BAD_CD, the CD is invalid or unsupported.
+    BadCd,
+    /// F_TRANSLATION, no valid descriptor maps the address.
+    Translation {
+        /// The fault was raised by the stage 2 walk.
+        s2: bool,
+    },
+    /// F_ADDR_SIZE, the output address exceeds the supported range.
+    AddrSize {
+        /// The fault was raised by the stage 2 walk.
+        s2: bool,
+    },
+    /// F_ACCESS, the Access flag is clear and hardware updates are disabled.
+    Access {
+        /// The fault was raised by the stage 2 walk.
+        s2: bool,
+    },
+    /// F_PERMISSION, the descriptors forbid the access.
+    Permission {
+        /// The fault was raised by the stage 2 walk.
+        s2: bool,
+    },
+}
+
+impl TranslationFault {
+    /// Event type recorded for the fault, `None` when the transaction is aborted silently.
+    pub const fn event_id(&self) -> Option<u8> {
+        Some(match self {
+            TranslationFault::Abort => return None,
+            TranslationFault::BadStreamId => 0x02,
+            TranslationFault::BadSte => 0x04,
+            TranslationFault::StreamDisabled => 0x06,
+            TranslationFault::BadSubstreamId => 0x08,
+            TranslationFault::BadCd => 0x0a,
+            TranslationFault::Translation { .. } => 0x10,
+            TranslationFault::AddrSize { .. } => 0x11,
+            TranslationFault::Access { .. } => 0x12,
+            TranslationFault::Permission { .. } => 0x13,
+        })
+    }
+
+    /// Whether the fault was raised by stage 2.
+    pub const fn is_stage2(&self) -> bool {
+        matches!(
+            self,
+            TranslationFault::Translation { s2: true }
+                | TranslationFault::AddrSize { s2: true }
+                | TranslationFault::Access { s2: true }
+                | TranslationFault::Permission { s2: true }
+        )
+    }
+}
+
+/// Geometry of one stage of translation.
+struct Regime {
+    root: u64,
+    granule_shift: u32,
+    ia_bits: u32,
+    /// Output size from CD.IPS or STE.S2PS, checked against the output of leaf descriptors.
+    oa_bits: u32,
+    start_level: u32,
+    s2: bool,
+    ha: bool,
+}
+
+/// Leaf descriptor found by a walk.
+struct Leaf {
+    out: u64,
+    size: usize,
+    desc: u64,
+}
+
+impl Regime {
+    fn s1(cd: &ContextDescriptor) -> Result<Self, TranslationFault> {
+        if !cd.is_valid() || !cd.is_aa64() {
+            return Err(TranslationFault::BadCd);
+        }
+        if cd.epd0() {
+            return Err(TranslationFault::Translation { s2: false });
+        }
+        let granule_shift = cd.tg0_shift().ok_or(TranslationFault::BadCd)?;
+        let ia_bits = 64 - cd.t0sz();
+        if !(16..=OA_BITS).contains(&ia_bits) || ia_bits <= granule_shift {
+            return Err(TranslationFault::BadCd);
+        }
+        let levels = (ia_bits - granule_shift).div_ceil(granule_shift - 3);
+        Ok(Self {
+            root: cd.ttb0().as_usize() as u64,
+            granule_shift,
+            ia_bits,
+            oa_bits: pa_bits(cd.ips()),
+            start_level: 4 - levels,
+            s2: false,
+            ha: cd.ha(),
+        })
+    }
+
+    fn s2(s2: &SteS2) -> Result<Self, TranslationFault> {
+        let t0sz = (s2.vtcr & 0x3f) as u32;
+        let sl0 = ((s2.vtcr >> 6) & 0b11) as u32;
+        let granule_shift = match (s2.vtcr >> 14) & 0b11 {
+            0b00 => 12,
+            0b01 => 16,
+            0b10 => 14,
+            _ => return Err(TranslationFault::BadSte),
+        };
+        if sl0 == 0b11 {
+            return Err(TranslationFault::BadSte);
+        }
+        let start_level = if granule_shift == 12 {
+            2 - sl0
+        } else {
+            3 - sl0
+        };
+        let ia_bits = 64 - t0sz;
+        // Up to 16 concatenated tables at the starting level.
+        let start_bits = ia_bits.saturating_sub(level_shift(granule_shift, start_level));
+        if ia_bits > OA_BITS || start_bits == 0 || start_bits > granule_shift - 3 + 4 {
+            return Err(TranslationFault::BadSte);
+        }
+        Ok(Self {
+            root: s2.ttb.as_usize() as u64,
+            granule_shift,
+            ia_bits,
+            oa_bits: pa_bits((s2.vtcr >> 16) & 0b111),
+            start_level,
+            s2: true,
+            ha: s2.ha,
+        })
+    }
+
+    /// Walk the tables for `addr`, `table_pa` turns the address of a descriptor into a PA.
+    fn walk<H: PagingHandler>(
+        &self,
+        addr: u64,
+        table_pa: &mut dyn FnMut(u64) -> Result<u64, TranslationFault>,
+    ) -> Result<Leaf, TranslationFault> {
+        let translation = TranslationFault::Translation { s2: self.s2 };
+        if addr >> self.ia_bits != 0 {
+            return Err(translation);
+        }
+        let g = self.granule_shift;
+        let mut table = self.root;
+        let mut level = self.start_level;
+        loop {
+            let shift = level_shift(g, level);
+            let bits = if level == self.start_level {
+                self.ia_bits - shift
+            } else {
+                g - 3
+            };
+            let index = (addr >> shift) & ((1 << bits) - 1);
+            let desc = read_u64::<H>(table_pa(table + index * 8)?);
+            if desc & DESC_VALID == 0 {
+                return Err(translation);
+            }
+            if level < 3 && desc & DESC_TABLE != 0 {
+                table = desc & addr_mask(g);
+                level += 1;
+                continue;
+            }
+            // Level 3 descriptors must be pages, and blocks are not allowed at level 0, nor
+            // at level 1 with the 16KB and 64KB granules.
+            if (level == 3 && desc & DESC_TABLE == 0)
+                || level == 0
+                || (g == 14 && level == 1)
+                || (g == 16 && level == 1)
+            {
+                return Err(translation);
+            }
+
+            let size = 1usize << shift;
+            let out = (desc & addr_mask(shift)) | (addr & (size as u64 - 1));
+            if out >> self.oa_bits != 0 {
+                return Err(TranslationFault::AddrSize { s2: self.s2 });
+            }
+            if desc & DESC_AF == 0 && !self.ha {
+                return Err(TranslationFault::Access { s2: self.s2 });
+            }
+            return Ok(Leaf { out, size, desc });
+        }
+    }
+
+    /// Read, write and execute permissions granted by a leaf descriptor.
+    fn perms(&self, desc: u64) -> (bool, bool, bool) {
+        let exec = desc & DESC_XN == 0;
+        if self.s2 {
+            (desc & DESC_AP_6 != 0, desc & DESC_AP_7 != 0, exec)
+        } else {
+            (true, desc & DESC_AP_7 == 0, exec)
+        }
+    }
+
+    /// Walk for `addr` and check that the leaf allows `access`.
+    fn translate<H: PagingHandler>(
+        &self,
+        addr: u64,
+        access: Access,
+        table_pa: &mut dyn FnMut(u64) -> Result<u64, TranslationFault>,
+    ) -> Result<Translation, TranslationFault> {
+        let leaf = self.walk::<H>(addr, table_pa)?;
+        let (readable, writable, executable) = self.perms(leaf.desc);
+        let allowed = match access {
+            Access::Read => readable,
+            Access::Write => writable,
+            Access::Exec => readable && executable,
+        };
+        if !allowed {
+            return Err(TranslationFault::Permission { s2: self.s2 });
+        }
+        Ok(Translation {
+            pa: PhysAddr::from_usize(leaf.out as usize),
+            size: leaf.size,
+            readable,
+            writable,
+            executable,
+        })
+    }
+}
+
+/// Shift of the input address bits resolved at `level`.
+const fn level_shift(granule_shift: u32, level: u32) -> u32 {
+    granule_shift + (3 - level) * (granule_shift - 3)
+}
+
+/// Output address size encoded in CD.IPS or STE.S2PS, larger sizes being limited to 48 bits.
+fn pa_bits(ps: u64) -> u32 {
+    [32, 36, 40, 42, 44]
+        .get(ps as usize)
+        .copied()
+        .unwrap_or(OA_BITS)
+}
+
+/// Output address bits [47:shift] of a descriptor.
+const fn addr_mask(shift: u32) -> u64 {
+    ((1 << OA_BITS) - 1) & !((1 << shift) - 1)
+}
+
+fn read_u64<H: PagingHandler>(pa: u64) -> u64 {
+    let ptr = H::phys_to_virt(PhysAddr::from_usize(pa as usize)).as_ptr() as *const u64;
+    unsafe { ptr.read_volatile() }
+}
+
+/// Locate the CD used by a transaction, `None` when STE.S1DSS bypasses stage 1.
+fn context_descriptor<H: PagingHandler>(
+    s1: &SteS1,
+    ssid: Option<u32>,
+    ipa_to_pa: &mut dyn FnMut(u64) -> Result<u64, TranslationFault>,
+) -> Result<Option<ContextDescriptor>, TranslationFault> {
+    if !s1.linear {
+        warn!("2-level CD tables are not supported by the walker");
+        return Err(TranslationFault::BadSte);
+    }
+    let index = match ssid {
+        Some(ssid) if s1.cd_max == 0 || ssid >> s1.cd_max != 0 => {
+            return Err(TranslationFault::BadSubstreamId)
+        }
+        Some(ssid) => ssid,
+        None if s1.cd_max == 0 => 0,
+        None => match s1.dss {
+            S1DSS_TERMINATE => return Err(TranslationFault::StreamDisabled),
+            S1DSS_BYPASS => return Ok(None),
+            S1DSS_SSID0 => 0,
+            _ => return Err(TranslationFault::BadSte),
+        },
+    };
+    let cd_ipa =
+        s1.cd_table.as_usize() as u64 + index as u64 * size_of::<ContextDescriptor>() as u64;
+    let cd_pa = ipa_to_pa(cd_ipa)?;
+    let ptr =
+        H::phys_to_virt(PhysAddr::from_usize(cd_pa as usize)).as_ptr() as *const ContextDescriptor;
+    Ok(Some(unsafe { ptr.read_volatile() }))
+}
+
+/// Translate a transaction of the stream configured by `ste`.
+///
+/// `ssid` is the SubstreamID of the transaction, if it carries one.
+pub(crate) fn translate<H: PagingHandler>(
+    ste: &StreamTableEntry,
+    ssid: Option<u32>,
+    addr: u64,
+    access: Access,
+) -> Result<Translation, TranslationFault> {
+    let (s1, s2) = match ste.config() {
+        SteConfig::Invalid => return Err(TranslationFault::BadSte),
+        SteConfig::Abort => return Err(TranslationFault::Abort),
+        SteConfig::Bypass => {
+            return Ok(Translation {
+                pa: PhysAddr::from_usize(addr as usize),
+                size: PAGE_SIZE_4K,
+                readable: true,
+                writable: true,
+                executable: true,
+            })
+        }
+        SteConfig::Translate { s1, s2 } => (s1, s2),
+    };
+    let s2 = s2.as_ref().map(Regime::s2).transpose()?;
+
+    // Fetches of stage 1 structures go through stage 2 when it is enabled.
+    let mut ipa_to_pa = |ipa: u64| match &s2 {
+        Some(s2) => s2
+            .translate::<H>(ipa, Access::Read, &mut Ok)
+            .map(|t| t.pa.as_usize() as u64),
+        None => Ok(ipa),
+    };
+
+    let cd = match &s1 {
+        Some(s1) => context_descriptor::<H>(s1, ssid, &mut ipa_to_pa)?,
+        None => None,
+    };
+    let stage1 = match cd {
+        Some(cd) => Some(Regime::s1(&cd)?.translate::<H>(addr, access, &mut ipa_to_pa)?),
+        None => None,
+    };
+    let ipa = stage1.map_or(addr, |t| t.pa.as_usize() as u64);
+    let stage2 = match &s2 {
+        Some(s2) => Some(s2.translate::<H>(ipa, access, &mut Ok)?),
+        None => None,
+    };
+
+    Ok(match (stage1, stage2) {
+        (Some(t1), Some(t2)) => Translation {
+            pa: t2.pa,
+            size: t1.size.min(t2.size),
+            readable: t1.readable && t2.readable,
+            writable: t1.writable && t2.writable,
+            executable: t1.executable && t2.executable,
+        },
+        (Some(t), None) | (None, Some(t)) => t,
+        (None, None) => Translation {
+            pa: PhysAddr::from_usize(addr as usize),
+            size: PAGE_SIZE_4K,
+            readable: true,
+            writable: true,
+            executable: true,
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::PagingHandler;
+    use crate::stream_table::DEFAULT_S2VTCR;
+    use crate::test_utils::HostPagingHandler;
+    use crate::HttuMode;
+
+    /// Stage 2 read-only leaf attributes: S2AP == 0b01 and AF.
+    const S2_LEAF_RO: u64 = DESC_VALID | DESC_AP_6 | DESC_AF;
+
+    /// A zeroed 64KB translation table, returned with a pointer to its descriptors.
+    fn table() -> (u64, *mut u64) {
+        let pa = HostPagingHandler::alloc_pages(16).unwrap();
+        let va = HostPagingHandler::phys_to_virt(pa).as_mut_ptr() as *mut u64;
+        (pa.as_usize() as u64, va)
+    }
+
+    fn s2_ste(root: u64, vtcr: u64) -> StreamTableEntry {
+        StreamTableEntry::s2_translated_entry(
+            1,
+            PhysAddr::from_usize(root as usize),
+            vtcr,
+            HttuMode::Disabled,
+        )
+    }
+
+    #[test]
+    fn test_s2_blocks() {
+        // 4KB granule, 39-bit IPA starting at level 1, 40-bit PA.
+        let (l1, l1_va) = table();
+        let (l2, l2_va) = table();
+        unsafe {
+            l1_va.write(l2 | DESC_TABLE | DESC_VALID);
+            l1_va.add(1).write(0x8000_0000 | S2_LEAF_RO);
+            l2_va.add(1).write(0x4000_0000 | S2_LEAF_RO);
+            l2_va.add(2).write(1 << 40 | S2_LEAF_RO);
+            l2_va.add(3).write(0x4020_0000 | DESC_VALID | DESC_AP_6);
+        }
+        let ste = s2_ste(l1, DEFAULT_S2VTCR);
+        let walk = |addr| translate::<HostPagingHandler>(&ste, None, addr, Access::Read);
+
+        let t = walk(0x4000_1234).unwrap();
+        assert_eq!(t.pa, PhysAddr::from_usize(0x8000_1234));
+        assert_eq!(t.size, 0x4000_0000);
+        let t = walk(0x20_5678).unwrap();
+        assert_eq!(t.pa, PhysAddr::from_usize(0x4000_5678));
+        assert_eq!(t.size, 0x20_0000);
+        assert!(t.readable && !t.writable);
+        // The block of entry 2 is beyond the 40-bit PA size, entry 3 has AF clear.
+        assert_eq!(
+            walk(0x40_0000),
+            Err(TranslationFault::AddrSize { s2: true })
+        );
+        assert_eq!(walk(0x60_0000), Err(TranslationFault::Access { s2: true }));
+        assert_eq!(
+            walk(0x80_0000),
+            Err(TranslationFault::Translation { s2: true })
+        );
+        assert_eq!(
+            walk(1 << 39),
+            Err(TranslationFault::Translation { s2: true })
+        );
+    }
+
+    #[test]
+    fn test_s2_16k_level1_block() {
+        // 16KB granule, 40-bit IPA starting at level 1, 48-bit PA: level 1 blocks do not exist.
+        let (l1, l1_va) = table();
+        unsafe { l1_va.write(0x80_0000_0000 | S2_LEAF_RO) };
+        let vtcr = 5 << 16 | 0b10 << 14 | 0b10 << 6 | (64 - 40);
+        let ste = s2_ste(l1, vtcr);
+        assert_eq!(
+            translate::<HostPagingHandler>(&ste, None, 0x1234, Access::Read),
+            Err(TranslationFault::Translation { s2: true })
+        );
+    }
+
+    #[cfg(feature = "io_pgtable")]
+    #[test]
+    fn test_s1_walk() {
+        use crate::{CdTable, IoPageTable, IoPgtableConfig, IoProt};
+
+        let mut pt =
+            IoPageTable::<HostPagingHandler>::new_s1(IoPgtableConfig::default(), 1).unwrap();
+        pt.map(
+            0x20_0000,
+            PhysAddr::from_usize(0x4000_0000),
+            0x20_0000,
+            IoProt::READ,
+        )
+        .unwrap();
+        let cd_table = CdTable::<HostPagingHandler>::new(2);
+        cd_table.set_cd(1, &pt.context_descriptor(HttuMode::Disabled).unwrap());
+        let ste = StreamTableEntry::s1_translated_entry(cd_table.base_addr(), 2);
+
+        let t = translate::<HostPagingHandler>(&ste, Some(1), 0x21_2345, Access::Read).unwrap();
+        assert_eq!(t.pa, PhysAddr::from_usize(0x4001_2345));
+        assert_eq!(t.size, 0x20_0000);
+        assert!(t.readable && !t.writable);
+
+        assert_eq!(
+            translate::<HostPagingHandler>(&ste, Some(1), 0x21_2345, Access::Write),
+            Err(TranslationFault::Permission { s2: false })
+        );
+        // CD 0 is invalid, and SSIDs are limited to 2 bits.
+        assert_eq!(
+            translate::<HostPagingHandler>(&ste, None, 0x21_2345, Access::Read),
+            Err(TranslationFault::BadCd)
+        );
+        assert_eq!(
+            translate::<HostPagingHandler>(&ste, Some(4), 0x21_2345, Access::Read),
+            Err(TranslationFault::BadSubstreamId)
+        );
+    }
+}